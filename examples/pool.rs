@@ -0,0 +1,208 @@
+//! Plays many sessions of one story on a small pool of threads.
+//!
+//! The story is loaded once and shared by every session. A worker takes a
+//! session from the queue, runs it for a budget of instructions, and puts
+//! it back, so a busy session never holds a worker for long. A session
+//! waiting for input is given the next of the commands read from standard
+//! input, and ends once it has none left or the story quits.
+//!
+//! ```text
+//! cargo run --example pool -- story.gblorb [sessions] < commands.txt
+//! ```
+
+extern crate glulx;
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use glulx::{
+    Blorb,
+    Glulx,
+    Input,
+    RunStatus,
+    Story,
+    TextGlk,
+    KEY_RETURN,
+};
+
+
+/// The number of worker threads.
+const WORKERS: usize = 0x4;
+
+
+/// The number of instructions a session runs before it goes back on the
+/// queue.
+const BUDGET: u64 = 0x2000;
+
+
+/// A session, and the commands it has still to be given.
+struct Job {
+    id: usize,
+    glulx: Glulx,
+    commands: VecDeque<String>,
+    transcript: String,
+}
+
+
+/// The sessions waiting for a worker, and whether the workers should
+/// stop.
+struct Queue {
+    jobs: Mutex<(VecDeque<Job>, bool)>,
+    ready: Condvar,
+}
+
+
+impl Queue {
+    fn push(&self, job: Job) {
+        self.jobs.lock().unwrap().0.push_back(job);
+        self.ready.notify_one();
+    }
+
+    /// Waits for a session, returning `None` once the queue is closed.
+    fn pop(&self) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        loop {
+            if let Some(job) = jobs.0.pop_front() {
+                return Some(job);
+            }
+            if jobs.1 {
+                return None;
+            }
+            jobs = self.ready.wait(jobs).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.jobs.lock().unwrap().1 = true;
+        self.ready.notify_all();
+    }
+}
+
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 0x2 {
+        eprintln!("usage: {} STORY [SESSIONS] < COMMANDS", args[0x0]);
+        process::exit(0x2);
+    }
+    let sessions = args.get(0x2)
+        .map_or(Ok(0x8), |count| count.parse())
+        .unwrap_or_else(|_| fail("invalid number of sessions"));
+
+    let bytes = fs::read(&args[0x1]).unwrap_or_else(|error| {
+        fail(&format!("{}: {}", args[0x1], error))
+    });
+    let (bytes, blorb) = Blorb::load(bytes).unwrap_or_else(|error| fail(&error));
+    let story = Arc::new(Story::from_rom(bytes).unwrap_or_else(|error| {
+        fail(&error)
+    }));
+
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)
+        .unwrap_or_else(|error| fail(&error.to_string()));
+    let commands: VecDeque<String> = input.lines().map(String::from).collect();
+
+    let queue = Arc::new(Queue {
+        jobs: Mutex::new((VecDeque::new(), false)),
+        ready: Condvar::new(),
+    });
+    let (done, finished) = mpsc::channel();
+    let workers: Vec<_> = (0x0..WORKERS).map(|_| {
+        let (queue, done) = (queue.clone(), done.clone());
+        thread::spawn(move || {
+            while let Some(mut job) = queue.pop() {
+                if run(&mut job) {
+                    queue.push(job);
+                } else {
+                    done.send(job).unwrap();
+                }
+            }
+        })
+    }).collect();
+
+    for id in 0x0..sessions {
+        let mut glk = TextGlk::new();
+        if let Some(ref blorb) = blorb {
+            glk.set_blorb(blorb.clone());
+        }
+        let mut glulx = Glulx::from_story(story.clone());
+        glulx.set_glk(Box::new(glk));
+        let mut transcript = String::new();
+        if let Err(error) = glulx.init() {
+            transcript = format!("[{}]\n", error);
+        }
+        queue.push(Job { id, glulx, commands: commands.clone(), transcript });
+    }
+
+    let mut jobs: Vec<Job> = finished.iter().take(sessions).collect();
+    queue.close();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    jobs.sort_by_key(|job| job.id);
+    for job in jobs {
+        println!("== session {} ==\n{}", job.id, job.transcript);
+    }
+}
+
+
+/// Runs a session for one budget of instructions, returning whether it
+/// should go back on the queue.
+fn run(job: &mut Job) -> bool {
+    if !job.glulx.is_running() {
+        return false;
+    }
+    let status = job.glulx.run_for(BUDGET);
+    if status == RunStatus::OutOfBudget {
+        return true;
+    }
+
+    let glk = job.glulx.glk_as_mut::<TextGlk>().unwrap();
+    job.transcript.push_str(&glk.take_output());
+    if let Some(main) = job.glulx.take_channels().get("MAIN") {
+        job.transcript.push_str(main);
+    }
+    match status {
+        RunStatus::Waiting => match job.commands.pop_front() {
+            Some(command) => give(&mut job.glulx, command),
+            None => false,
+        },
+        RunStatus::Error(error) => {
+            job.transcript.push_str(&format!("\n[{}]\n", error));
+            false
+        },
+        _ => false,
+    }
+}
+
+
+/// Gives a command to a session waiting for input, returning whether it
+/// could take it.
+fn give(glulx: &mut Glulx, command: String) -> bool {
+    let glk = glulx.glk_as_mut::<TextGlk>().unwrap();
+    if glk.line_requested() {
+        glk.push_input(Input::Line(command));
+    } else if glk.char_requested() {
+        let key = command.chars().next().map_or(KEY_RETURN, |ch| ch as u32);
+        glk.push_input(Input::Char(key));
+    } else if !glk.is_waiting() {
+        glulx.push_channel_input(command);
+    } else {
+        // waiting for an event which no command can give, such as a
+        // timer.
+        return false;
+    }
+    true
+}
+
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(0x1);
+}
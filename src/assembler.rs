@@ -0,0 +1,704 @@
+//! # Glulx assembler
+//!
+//! The assembler builds small story files in memory, mostly so tests can
+//! be written as tiny programs. It lays out the header, functions, data,
+//! and strings in ROM, places any RAM data after RAMSTART, and fixes up
+//! the header fields and checksum when the story is finished.
+//!
+//! Addresses are referred to by `Label`s, which may be used before they
+//! are bound. Label operands are always encoded in 4 bytes, so the size
+//! of an instruction never depends on where its labels end up.
+//!
+//! ```
+//! use glulx::{Assembler, Glulx};
+//! use glulx::Arg::*;
+//!
+//! let mut asm = Assembler::new();
+//! let main = asm.label();
+//! let done = asm.label();
+//! asm.start(main)
+//!     .function(main, 0xC1, &[(0x4, 0x1)])
+//!     .op("add", &[Const(2), Const(3), Local(0x0)])
+//!     .op("jeq", &[Local(0x0), Const(5), Branch(done)])
+//!     .op("quit", &[])
+//!     .bind(done)
+//!     .op("return", &[Const(1)]);
+//!
+//! let story = asm.finish().unwrap();
+//! assert!(Glulx::from_rom(story).is_ok());
+//! ```
+
+use std::collections::BTreeSet;
+use std::error;
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use instruction::{
+    opcode_named,
+    OperandKind,
+};
+
+
+/// The glulx version written to assembled headers.
+const VERSION: u32 = 0x00030102;
+
+
+/// The size of the header, which the first ROM data follows.
+const HEADER_SIZE: u32 = 0x24;
+
+
+/// A location in an assembled story, which may be bound after it is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+
+/// An operand given to the assembler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arg {
+
+    /// Constant zero when loaded; the value is discarded when stored.
+    Zero,
+
+    /// A constant, encoded in as few bytes as possible.
+    Const(i32),
+
+    /// The contents of the given memory address.
+    Addr(u32),
+
+    /// A value popped off of or pushed onto the stack.
+    Stack,
+
+    /// The local at the given offset in the current call frame.
+    Local(u32),
+
+    /// The contents of the given address offset from RAMSTART.
+    Ram(u32),
+
+    /// The address of a label, as a constant.
+    Label(Label),
+
+    /// The contents of memory at the address of a label.
+    At(Label),
+
+    /// A branch offset jumping to a label.
+    Branch(Label),
+}
+
+
+/// An error found while assembling a story.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssemblerError {
+
+    /// No opcode has the given name.
+    UnknownOpcode(String),
+
+    /// An opcode was given the wrong number of operands.
+    OperandCount {
+
+        /// The name of the opcode.
+        opcode: &'static str,
+
+        /// The number of operands the opcode takes.
+        expected: usize,
+
+        /// The number of operands given.
+        found: usize,
+    },
+
+    /// A constant was given as a store operand.
+    InvalidStore {
+
+        /// The name of the opcode.
+        opcode: &'static str,
+
+        /// The index of the operand.
+        index: usize,
+    },
+
+    /// A label was bound more than once.
+    LabelRebound,
+
+    /// A label was used but never bound.
+    UnboundLabel,
+
+    /// No start function was given.
+    NoStartFunction,
+}
+
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AssemblerError::UnknownOpcode(ref name) => {
+                write!(f, "unknown opcode {}", name)
+            },
+            AssemblerError::OperandCount { opcode, expected, found } => {
+                write!(f, "{} takes {} operands, {} given",
+                    opcode, expected, found)
+            },
+            AssemblerError::InvalidStore { opcode, index } => {
+                write!(f, "operand {} of {} can not store to a constant",
+                    index, opcode)
+            },
+            AssemblerError::LabelRebound => write!(f, "label bound twice"),
+            AssemblerError::UnboundLabel => write!(f, "label never bound"),
+            AssemblerError::NoStartFunction => {
+                write!(f, "no start function given")
+            },
+        }
+    }
+}
+
+
+impl error::Error for AssemblerError {}
+
+
+/// Where a label is bound.
+#[derive(Debug, Clone, Copy)]
+enum Location {
+    Rom(u32),
+    Ram(u32),
+}
+
+
+/// A label reference in ROM to fill in once all labels are bound.
+#[derive(Debug, Clone, Copy)]
+struct Fixup {
+    ptr: u32,
+    label: Label,
+
+    /// The address following the instruction, for branch offsets.
+    relative_to: Option<u32>,
+}
+
+
+/// A symbol of the string decoding table built by the assembler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Symbol {
+    Terminator,
+    Char(char),
+}
+
+
+/// Builds a story file one instruction at a time.
+#[derive(Debug, Clone)]
+pub struct Assembler {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ext_size: u32,
+    stack_size: u32,
+    labels: Vec<Option<Location>>,
+    fixups: Vec<Fixup>,
+    start_func: Option<Label>,
+    decoding_tbl: Option<Label>,
+    error: Option<AssemblerError>,
+}
+
+
+impl Default for Assembler {
+    fn default() -> Assembler {
+        Assembler::new()
+    }
+}
+
+
+impl Assembler {
+
+    /// Creates an assembler for an empty story, with a `0x1000` byte
+    /// stack and no memory beyond the story file.
+    pub fn new() -> Assembler {
+        Assembler {
+            rom: vec![0x0; HEADER_SIZE as usize],
+            ram: vec![],
+            ext_size: 0x0,
+            stack_size: 0x1000,
+            labels: vec![],
+            fixups: vec![],
+            start_func: None,
+            decoding_tbl: None,
+            error: None,
+        }
+    }
+
+    /// Creates a new unbound label.
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 0x1)
+    }
+
+    /// Binds a label to the current position in ROM.
+    pub fn bind(&mut self, label: Label) -> &mut Assembler {
+        let location = Location::Rom(self.rom.len() as u32);
+        self.bind_to(label, location)
+    }
+
+    /// Sets the function the story starts with.
+    pub fn start(&mut self, label: Label) -> &mut Assembler {
+        self.start_func = Some(label);
+        self
+    }
+
+    /// Sets the stack size, which is rounded up to a multiple of `0x100`.
+    pub fn stack_size(&mut self, size: u32) -> &mut Assembler {
+        self.stack_size = align(size);
+        self
+    }
+
+    /// Sets the size of the zeroed memory after the story file, which is
+    /// rounded up to a multiple of `0x100`.
+    pub fn ext_size(&mut self, size: u32) -> &mut Assembler {
+        self.ext_size = align(size);
+        self
+    }
+
+    /// Starts a function of the given type and local format, binding the
+    /// label to it.
+    pub fn function(&mut self,
+            label: Label,
+            function_type: u8,
+            locals: &[(u8, u8)]) -> &mut Assembler {
+        self.bind(label);
+        self.rom.push(function_type);
+        for &(size, count) in locals {
+            self.rom.push(size);
+            self.rom.push(count);
+        }
+        self.rom.extend_from_slice(&[0x0, 0x0]);
+        self
+    }
+
+    /// Appends the instruction with the given opcode name and operands.
+    pub fn op(&mut self, name: &str, args: &[Arg]) -> &mut Assembler {
+        let opcode = match opcode_named(name) {
+            Some(opcode) => opcode,
+            None => return self.fail(AssemblerError::UnknownOpcode(
+                name.to_string())),
+        };
+        if args.len() != opcode.operands.len() {
+            return self.fail(AssemblerError::OperandCount {
+                opcode: opcode.name,
+                expected: opcode.operands.len(),
+                found: args.len(),
+            });
+        }
+
+        match opcode.number {
+            0x0..=0x7F => self.rom.push(opcode.number as u8),
+            0x80..=0x3FFF => {
+                self.push_u16(0x8000 | opcode.number as u16);
+            },
+            _ => self.push_u32(0xC0000000 | opcode.number),
+        }
+
+        let modes_ptr = self.rom.len();
+        self.rom.resize(modes_ptr + args.len().div_ceil(0x2), 0x0);
+
+        let mut labels = vec![];
+        for (index, (&arg, &kind)) in args.iter()
+                .zip(opcode.operands)
+                .enumerate() {
+            let ptr = self.rom.len() as u32;
+            let mode = match self.push_arg(arg, kind) {
+                Some(mode) => mode,
+                None => return self.fail(AssemblerError::InvalidStore {
+                    opcode: opcode.name,
+                    index,
+                }),
+            };
+            self.rom[modes_ptr + index / 0x2] |= mode << (0x4 * (index % 0x2));
+
+            match arg {
+                Arg::Label(label) | Arg::At(label) => {
+                    labels.push((ptr, label, false))
+                },
+                Arg::Branch(label) => labels.push((ptr, label, true)),
+                _ => (),
+            }
+        }
+
+        // Branch offsets are relative to the end of the instruction.
+        let next = self.rom.len() as u32;
+        for (ptr, label, relative) in labels {
+            self.fixups.push(Fixup {
+                ptr,
+                label,
+                relative_to: if relative { Some(next) } else { None },
+            });
+        }
+        self
+    }
+
+    /// Appends a call to the Glk function with the given selector, which
+    /// pushes the arguments last to first and stores the result. Arguments
+    /// given as `Stack` are left where they are, so they must come last.
+    pub fn glk(&mut self, selector: u32, args: &[Arg], result: Arg)
+            -> &mut Assembler {
+        for &arg in args.iter().rev().filter(|&&arg| arg != Arg::Stack) {
+            self.op("copy", &[arg, Arg::Stack]);
+        }
+        self.op("glk", &[
+            Arg::Const(selector as i32),
+            Arg::Const(args.len() as i32),
+            result,
+        ])
+    }
+
+    /// Appends raw bytes to ROM.
+    pub fn bytes(&mut self, data: &[u8]) -> &mut Assembler {
+        self.rom.extend_from_slice(data);
+        self
+    }
+
+    /// Appends a 4 byte word holding the address of a label to ROM.
+    pub fn word(&mut self, label: Label) -> &mut Assembler {
+        let ptr = self.rom.len() as u32;
+        self.fixups.push(Fixup { ptr, label, relative_to: None });
+        self.push_u32(0x0);
+        self
+    }
+
+    /// Appends an unencoded string to ROM and binds the label to it. The
+    /// string is stored as Latin-1 if possible, and as Unicode otherwise.
+    pub fn string(&mut self, label: Label, text: &str) -> &mut Assembler {
+        self.bind(label);
+        if text.chars().all(|ch| (ch as u32) < 0x100) {
+            self.rom.push(0xE0);
+            self.rom.extend(text.chars().map(|ch| ch as u8));
+            self.rom.push(0x0);
+        } else {
+            self.rom.extend_from_slice(&[0xE2, 0x0, 0x0, 0x0]);
+            for ch in text.chars() {
+                self.push_u32(ch as u32);
+            }
+            self.push_u32(0x0);
+        }
+        self
+    }
+
+    /// Appends a string decoding table holding the characters of the
+    /// given strings, followed by each string compressed with it. The
+    /// table becomes the story's decoding table, and each label is bound
+    /// to its string.
+    pub fn compressed_strings(&mut self,
+            table: Label,
+            strings: &[(Label, &str)]) -> &mut Assembler {
+        let mut symbols: BTreeSet<Symbol> = strings.iter()
+            .flat_map(|&(_, text)| text.chars().map(Symbol::Char))
+            .collect();
+        symbols.insert(Symbol::Terminator);
+        let symbols: Vec<Symbol> = symbols.into_iter().collect();
+
+        self.bind(table);
+        self.decoding_tbl = Some(table);
+        let base = self.rom.len() as u32;
+        let mut nodes = vec![];
+        let mut codes = vec![];
+        let root = build_node(&symbols, base + 0xC, &mut nodes, &mut codes,
+            &mut vec![]);
+        let node_count = codes.len() as u32 * 0x2 - 0x1;
+
+        self.push_u32(0xC + nodes.len() as u32);
+        self.push_u32(node_count);
+        self.push_u32(root);
+        self.rom.extend_from_slice(&nodes);
+
+        for &(label, text) in strings {
+            self.bind(label);
+            self.rom.push(0xE1);
+            let mut bits = vec![];
+            for symbol in text.chars().map(Symbol::Char)
+                    .chain(Some(Symbol::Terminator)) {
+                let (_, code) = codes.iter()
+                    .find(|&&(s, _)| s == symbol)
+                    .unwrap();
+                bits.extend_from_slice(code);
+            }
+            for chunk in bits.chunks(0x8) {
+                let byte = chunk.iter().enumerate()
+                    .fold(0x0, |byte, (i, &bit)| byte | ((bit as u8) << i));
+                self.rom.push(byte);
+            }
+        }
+        self
+    }
+
+    /// Appends data to RAM and binds the label to it.
+    pub fn ram(&mut self, label: Label, data: &[u8]) -> &mut Assembler {
+        let location = Location::Ram(self.ram.len() as u32);
+        self.ram.extend_from_slice(data);
+        self.bind_to(label, location)
+    }
+
+    /// Lays out the story and returns its bytes, with label references,
+    /// header fields, and the checksum filled in.
+    pub fn finish(&self) -> Result<Vec<u8>, AssemblerError> {
+        if let Some(ref error) = self.error {
+            return Err(error.clone());
+        }
+
+        let ramstart = align(self.rom.len() as u32).max(0x100);
+        let extstart = ramstart + align(self.ram.len() as u32);
+        let endmem = extstart + self.ext_size;
+
+        let mut story = self.rom.clone();
+        story.resize(ramstart as usize, 0x0);
+        story.extend_from_slice(&self.ram);
+        story.resize(extstart as usize, 0x0);
+
+        let address = |label: Label| match self.labels[label.0] {
+            Some(Location::Rom(ptr)) => Ok(ptr),
+            Some(Location::Ram(ptr)) => Ok(ramstart + ptr),
+            None => Err(AssemblerError::UnboundLabel),
+        };
+        for fixup in &self.fixups {
+            let target = address(fixup.label)?;
+            let value = match fixup.relative_to {
+                Some(next) => target.wrapping_sub(next).wrapping_add(0x2),
+                None => target,
+            };
+            BigEndian::write_u32(&mut story[fixup.ptr as usize..], value);
+        }
+
+        let start_func = match self.start_func {
+            Some(label) => address(label)?,
+            None => return Err(AssemblerError::NoStartFunction),
+        };
+        let decoding_tbl = match self.decoding_tbl {
+            Some(label) => address(label)?,
+            None => 0x0,
+        };
+
+        story[0x0..0x4].copy_from_slice(b"Glul");
+        BigEndian::write_u32(&mut story[0x4..], VERSION);
+        BigEndian::write_u32(&mut story[0x8..], ramstart);
+        BigEndian::write_u32(&mut story[0xC..], extstart);
+        BigEndian::write_u32(&mut story[0x10..], endmem);
+        BigEndian::write_u32(&mut story[0x14..], self.stack_size);
+        BigEndian::write_u32(&mut story[0x18..], start_func);
+        BigEndian::write_u32(&mut story[0x1C..], decoding_tbl);
+
+        let checksum = story.chunks(0x4)
+            .fold(0u32, |sum, word| sum.wrapping_add(BigEndian::read_u32(word)));
+        BigEndian::write_u32(&mut story[0x20..], checksum);
+        Ok(story)
+    }
+
+    fn fail(&mut self, error: AssemblerError) -> &mut Assembler {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+        self
+    }
+
+    fn bind_to(&mut self, label: Label, location: Location)
+            -> &mut Assembler {
+        if self.labels[label.0].is_some() {
+            return self.fail(AssemblerError::LabelRebound);
+        }
+        self.labels[label.0] = Some(location);
+        self
+    }
+
+    fn push_u16(&mut self, value: u16) {
+        let mut buf = [0x0; 0x2];
+        BigEndian::write_u16(&mut buf, value);
+        self.rom.extend_from_slice(&buf);
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        let mut buf = [0x0; 0x4];
+        BigEndian::write_u32(&mut buf, value);
+        self.rom.extend_from_slice(&buf);
+    }
+
+    /// Appends the data of an operand, returning its addressing mode, or
+    /// `None` if a constant is given as a store operand.
+    fn push_arg(&mut self, arg: Arg, kind: OperandKind) -> Option<u8> {
+        let store = kind == OperandKind::Store;
+        let mode = match arg {
+            Arg::Zero => 0x0,
+            Arg::Const(_) | Arg::Label(_) | Arg::Branch(_) if store => {
+                return None
+            },
+            Arg::Const(0x0) => 0x0,
+            Arg::Const(value) => 0x1 + self.push_sized(value as u32,
+                (-0x80..0x80).contains(&value),
+                (-0x8000..0x8000).contains(&value)),
+            Arg::Addr(address) => 0x5 + self.push_unsigned(address),
+            Arg::Stack => 0x8,
+            Arg::Local(offset) => 0x9 + self.push_unsigned(offset),
+            Arg::Ram(address) => 0xD + self.push_unsigned(address),
+            Arg::Label(_) | Arg::Branch(_) => {
+                self.push_u32(0x0);
+                0x3
+            },
+            Arg::At(_) => {
+                self.push_u32(0x0);
+                0x7
+            },
+        };
+        Some(mode)
+    }
+
+    fn push_unsigned(&mut self, value: u32) -> u8 {
+        self.push_sized(value, value < 0x100, value < 0x10000)
+    }
+
+    /// Appends a value in 1, 2, or 4 bytes, returning `0`, `1`, or `2`
+    /// respectively.
+    fn push_sized(&mut self, value: u32, byte: bool, short: bool) -> u8 {
+        if byte {
+            self.rom.push(value as u8);
+            0x0
+        } else if short {
+            self.push_u16(value as u16);
+            0x1
+        } else {
+            self.push_u32(value);
+            0x2
+        }
+    }
+
+}
+
+
+/// Rounds a size up to a multiple of `0x100`.
+fn align(size: u32) -> u32 {
+    size.div_ceil(0x100) * 0x100
+}
+
+
+/// Writes a balanced decoding tree for the given symbols to `nodes`, which
+/// starts at address `base`, recording the bits of each symbol's code.
+/// Returns the address of the subtree's root.
+fn build_node(symbols: &[Symbol],
+        base: u32,
+        nodes: &mut Vec<u8>,
+        codes: &mut Vec<(Symbol, Vec<bool>)>,
+        code: &mut Vec<bool>) -> u32 {
+    let address = base + nodes.len() as u32;
+    if symbols.len() == 0x1 {
+        match symbols[0x0] {
+            Symbol::Terminator => nodes.push(0x01),
+            Symbol::Char(ch) if (ch as u32) < 0x100 => {
+                nodes.push(0x02);
+                nodes.push(ch as u8);
+            },
+            Symbol::Char(ch) => {
+                nodes.push(0x04);
+                let mut buf = [0x0; 0x4];
+                BigEndian::write_u32(&mut buf, ch as u32);
+                nodes.extend_from_slice(&buf);
+            },
+        }
+        codes.push((symbols[0x0], code.clone()));
+        return address;
+    }
+
+    let ptr = nodes.len();
+    nodes.resize(ptr + 0x9, 0x0);
+    let (left, right) = symbols.split_at(symbols.len() / 0x2);
+
+    code.push(false);
+    let left = build_node(left, base, nodes, codes, code);
+    code.pop();
+    code.push(true);
+    let right = build_node(right, base, nodes, codes, code);
+    code.pop();
+
+    BigEndian::write_u32(&mut nodes[ptr + 0x1..], left);
+    BigEndian::write_u32(&mut nodes[ptr + 0x5..], right);
+    address
+}
+
+
+#[cfg(test)]
+mod tests {
+    use memory::GlulxMemory;
+    use string::decode_text;
+    use instruction::{decode, Branch, Operand};
+
+    use super::{Assembler, AssemblerError};
+    use super::Arg::*;
+
+    #[test]
+    fn lays_out_valid_story() {
+        let mut asm = Assembler::new();
+        let (main, global) = (asm.label(), asm.label());
+        asm.start(main)
+            .function(main, 0xC1, &[])
+            .op("copy", &[Const(0x1234), At(global)])
+            .op("quit", &[])
+            .ram(global, &[0x0; 0x4])
+            .ext_size(0x80);
+        let memory = GlulxMemory::from_rom(asm.finish().unwrap()).unwrap();
+
+        assert_eq!(memory.start_func(), 0x24);
+        assert_eq!(memory.get_mem_size(), 0x300);
+        let instruction = decode(&memory, 0x27).unwrap();
+        assert_eq!(instruction.operands,
+            vec![Operand::Const(0x1234), Operand::Addr(0x100)]);
+    }
+
+    #[test]
+    fn resolves_branches() {
+        let mut asm = Assembler::new();
+        let (main, back, forward) = (asm.label(), asm.label(), asm.label());
+        asm.start(main)
+            .function(main, 0xC1, &[])
+            .bind(back)
+            .op("jz", &[Stack, Branch(forward)])
+            .op("jump", &[Branch(back)])
+            .bind(forward)
+            .op("jlt", &[Const(1), Const(2), Const(1)]);
+        let memory = GlulxMemory::from_rom(asm.finish().unwrap()).unwrap();
+
+        let jz = decode(&memory, 0x27).unwrap();
+        let jump = decode(&memory, jz.next()).unwrap();
+        let jlt = decode(&memory, jump.next()).unwrap();
+        assert_eq!(jz.branch(), Some(Branch::Jump(jlt.address)));
+        assert_eq!(jump.branch(), Some(Branch::Jump(0x27)));
+        assert_eq!(jlt.branch(), Some(Branch::Return(1)));
+    }
+
+    #[test]
+    fn builds_string_tables() {
+        let mut asm = Assembler::new();
+        let (main, table) = (asm.label(), asm.label());
+        let (hello, plain) = (asm.label(), asm.label());
+        asm.start(main)
+            .function(main, 0xC1, &[])
+            .op("streamstr", &[Label(hello)])
+            .op("streamstr", &[Label(plain)])
+            .op("quit", &[])
+            .compressed_strings(table, &[(hello, "Hello, \u{3A9}!")])
+            .string(plain, "plain");
+        let memory = GlulxMemory::from_rom(asm.finish().unwrap()).unwrap();
+
+        let first = decode(&memory, 0x27).unwrap();
+        let second = decode(&memory, first.next()).unwrap();
+        for (instruction, expected) in [
+                (first, "Hello, \u{3A9}!"),
+                (second, "plain")] {
+            let address = match instruction.operands[0x0] {
+                Operand::Const(address) => address as u32,
+                ref operand => panic!("unexpected operand {:?}", operand),
+            };
+            let text = decode_text(&memory, address, memory.decoding_tbl());
+            assert_eq!(text.unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut asm = Assembler::new();
+        let main = asm.label();
+        asm.function(main, 0xC1, &[]).op("quit", &[]);
+        assert_eq!(asm.finish(), Err(AssemblerError::NoStartFunction));
+
+        asm.start(main).op("add", &[Const(1), Const(2), Const(3)]);
+        assert_eq!(asm.finish(),
+            Err(AssemblerError::InvalidStore { opcode: "add", index: 0x2 }));
+    }
+}
@@ -0,0 +1,254 @@
+//! An interactive debugger for glulx story files.
+//!
+//! Usage: `glulx-debug <story.ulx> [gameinfo.dbg]`
+//!
+//! Commands are read from standard input, one per line. Enter `help` for
+//! a list of commands. When an Inform debug file is given, addresses may
+//! be given as the names of routines, globals, and arrays.
+
+extern crate glulx;
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+use glulx::{
+    Access,
+    DebugInfo,
+    Debugger,
+    Glulx,
+    GlulxError,
+    Stop,
+    TrapAction,
+    WatchAction,
+};
+
+
+const HELP: &str = "\
+step, s             execute one instruction
+next, n             execute one instruction, running calls to completion
+finish, out         run until the current function returns
+continue, c         run until a breakpoint or the end of the story
+break, b ADDR       stop before the instruction at ADDR
+fbreak, f ADDR      stop on entry to the function at ADDR
+delete, d ADDR      remove the breakpoint at ADDR
+watch ADDR [LEN]    stop after any write to LEN bytes at ADDR
+unwatch ID          remove the watchpoint with the given ID
+info, i             list breakpoints
+where, w            show the next instruction
+locals, l           show the locals of the current call frame
+stack, st           show the values of the current call frame
+backtrace, bt       show the call frames on the stack
+x ADDR [LEN]        show LEN bytes of memory at ADDR
+quit, q             exit the debugger";
+
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 && args.len() != 3 {
+        eprintln!("usage: {} <story.ulx> [gameinfo.dbg]", args[0]);
+        process::exit(2);
+    }
+
+    let glulx = fs::read(&args[1])
+        .map_err(|error| error.to_string())
+        .and_then(Glulx::from_rom);
+    let mut debugger = match glulx.map(Debugger::new) {
+        Ok(Ok(debugger)) => debugger,
+        Ok(Err(error)) => {
+            eprintln!("{}: {}", args[1], error);
+            process::exit(1);
+        },
+        Err(error) => {
+            eprintln!("{}: {}", args[1], error);
+            process::exit(1);
+        },
+    };
+
+    if let Some(path) = args.get(2) {
+        let debug_info = fs::read_to_string(path)
+            .map_err(|error| error.to_string())
+            .and_then(|text| {
+                DebugInfo::parse(&text).map_err(|error| error.to_string())
+            });
+        match debug_info {
+            Ok(debug_info) => debugger.set_debug_info(debug_info),
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                process::exit(1);
+            },
+        }
+    }
+
+    debugger.glulx_mut()
+        .set_trap_handler(Box::new(|_, _| TrapAction::Pause));
+    print_next(&debugger);
+    let stdin = io::stdin();
+    loop {
+        print!("(glulx) ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            return;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+
+        if let Err(message) = command(&mut debugger, &words) {
+            println!("{}", message);
+        }
+    }
+}
+
+
+/// Runs a single command, returning a message for invalid commands.
+fn command(debugger: &mut Debugger, words: &[&str]) -> Result<(), String> {
+    let arg = |debugger: &Debugger, index: usize| -> Result<u32, String> {
+        words.get(index)
+            .ok_or_else(|| format!("{} needs an address", words[0x0]))
+            .and_then(|word| {
+                parse_number(word)
+                    .or_else(|error| debugger.lookup(word).ok_or(error))
+            })
+    };
+
+    match words[0x0] {
+        "step" | "s" => report(debugger, |d| d.step()),
+        "next" | "n" => report(debugger, |d| d.step_over()),
+        "finish" | "out" => report(debugger, |d| d.step_out()),
+        "continue" | "c" => report(debugger, |d| d.resume()),
+        "break" | "b" => debugger.add_breakpoint(arg(debugger, 0x1)?),
+        "fbreak" | "f" => {
+            let start = debugger.add_function_breakpoint(arg(debugger, 0x1)?)
+                .map_err(|error| error.to_string())?;
+            println!("breakpoint at {:#X}", start);
+        },
+        "delete" | "d" => {
+            if !debugger.remove_breakpoint(arg(debugger, 0x1)?) {
+                return Err("no breakpoint at that address".to_string());
+            }
+        },
+        "watch" => {
+            let address = arg(debugger, 0x1)?;
+            let len = if words.len() > 0x2 { arg(debugger, 0x2)? } else { 0x4 };
+            let id = debugger.glulx_mut().add_watchpoint(
+                address..address.saturating_add(len),
+                Access::Write,
+                WatchAction::Pause);
+            println!("watchpoint {}", id);
+        },
+        "unwatch" => {
+            let id = arg(debugger, 0x1)?;
+            if !debugger.glulx_mut().remove_watchpoint(id) {
+                return Err("no watchpoint with that id".to_string());
+            }
+        },
+        "info" | "i" => {
+            for address in debugger.breakpoints() {
+                println!("{:#X}", address);
+            }
+        },
+        "where" | "w" => print_next(debugger),
+        "locals" | "l" => {
+            for local in debugger.glulx().locals() {
+                println!("local{} = {:#X} ({} bytes)",
+                    local.offset, local.value, local.size);
+            }
+        },
+        "stack" | "st" => {
+            for (index, value) in debugger.glulx().values().iter().enumerate() {
+                println!("{}: {:#X}", index, value);
+            }
+        },
+        "backtrace" | "bt" => {
+            let backtrace = debugger.glulx().backtrace();
+            for (index, frame) in backtrace.iter().enumerate() {
+                let name = frame.function
+                    .and_then(|function| debugger.describe(function));
+                match name {
+                    Some(name) => println!("#{} {} in {}", index, frame, name),
+                    None => println!("#{} {}", index, frame),
+                }
+            }
+        },
+        "x" => {
+            let address = arg(debugger, 0x1)?;
+            let len = if words.len() > 0x2 { arg(debugger, 0x2)? } else { 0x10 };
+            dump(debugger, address, len);
+        },
+        "quit" | "q" => process::exit(0),
+        "help" | "h" => println!("{}", HELP),
+        _ => return Err(format!("unknown command {}", words[0x0])),
+    }
+    Ok(())
+}
+
+
+fn report<F>(debugger: &mut Debugger, step: F)
+        where F: FnOnce(&mut Debugger) -> Result<Stop, GlulxError> {
+    match step(debugger) {
+        Ok(Stop::Halted) => println!("the story has ended"),
+        Ok(Stop::Waiting) => println!("the story is waiting for input"),
+        Ok(Stop::Breakpoint(address)) => {
+            println!("breakpoint at {:#X}", address);
+            print_next(debugger);
+        },
+        Ok(Stop::FunctionEntry(address)) => {
+            println!("entered function {:#X}", address);
+            print_next(debugger);
+        },
+        Ok(Stop::Watchpoint(event)) => {
+            println!("watchpoint {} hit by {:#X}: {:02X?} -> {:02X?} at {:#X}",
+                event.watchpoint, event.program_counter,
+                event.old, event.new, event.address);
+            print_next(debugger);
+        },
+        Ok(Stop::DebugTrap(value)) => {
+            println!("debug trap {:#X}", value);
+            print_next(debugger);
+        },
+        Ok(Stop::Stepped) => print_next(debugger),
+        Err(error) => println!("error: {}", error),
+    }
+}
+
+
+fn print_next(debugger: &Debugger) {
+    let glulx = debugger.glulx();
+    if let Some(location) = debugger.describe(glulx.program_counter()) {
+        println!("{}", location);
+    }
+    match glulx.next_instruction() {
+        Ok(instruction) => {
+            println!("{:08X}  {}", instruction.address, instruction)
+        },
+        Err(error) => println!("{:08X}  {}", glulx.program_counter(), error),
+    }
+}
+
+
+fn dump(debugger: &Debugger, address: u32, len: u32) {
+    let memory = debugger.glulx().memory();
+    let end = address.saturating_add(len).min(memory.get_mem_size());
+    for row in (address..end).step_by(0x10) {
+        let bytes: Vec<String> = memory.bytes(row..end.min(row + 0x10))
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        println!("{:08X}  {}", row, bytes.join(" "));
+    }
+}
+
+
+fn parse_number(word: &str) -> Result<u32, String> {
+    let parsed = if word.starts_with("0x") || word.starts_with("0X") {
+        u32::from_str_radix(&word[0x2..], 0x10)
+    } else {
+        word.parse()
+    };
+    parsed.map_err(|_| format!("invalid number {}", word))
+}
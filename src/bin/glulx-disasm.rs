@@ -0,0 +1,59 @@
+//! Prints the disassembly of a glulx story file.
+//!
+//! Usage: `glulx-disasm <story.ulx> [gameinfo.dbg]`
+//!
+//! When an Inform debug file is given, routines, globals, and locals are
+//! shown by name.
+
+extern crate glulx;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use glulx::{disassemble, DebugInfo, GlulxMemory};
+
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 && args.len() != 3 {
+        eprintln!("usage: {} <story.ulx> [gameinfo.dbg]", args[0]);
+        process::exit(2);
+    }
+
+    let rom = match fs::read(&args[1]) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("{}: {}", args[1], error);
+            process::exit(1);
+        },
+    };
+    let memory = match GlulxMemory::from_rom(rom) {
+        Ok(memory) => memory,
+        Err(error) => {
+            eprintln!("{}: {}", args[1], error);
+            process::exit(1);
+        },
+    };
+
+    let disassembly = disassemble(&memory);
+    match args.get(2) {
+        Some(path) => {
+            let debug_info = fs::read_to_string(path)
+                .map_err(|error| error.to_string())
+                .and_then(|text| {
+                    DebugInfo::parse(&text).map_err(|error| error.to_string())
+                });
+            match debug_info {
+                Ok(debug_info) => {
+                    print!("{}", disassembly.with_debug_info(&debug_info))
+                },
+                Err(error) => {
+                    eprintln!("{}: {}", path, error);
+                    process::exit(1);
+                },
+            }
+        },
+        None => print!("{}", disassembly),
+    }
+}
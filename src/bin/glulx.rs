@@ -0,0 +1,386 @@
+//! Plays a glulx story file in the terminal.
+//!
+//! Usage: `glulx [options] <story.ulx | story.gblorb>`
+//!
+//! Options:
+//!
+//! * `--seed N` -- Seed the random number generator, so runs repeat
+//! * `--transcript FILE` -- Copy the output and input to FILE
+//! * `--autosave FILE` -- Save the game to FILE whenever it waits for
+//!   input, and resume from FILE when it exists
+//! * `--trace FILE` -- Write a trace of every instruction to FILE
+//! * `--record FILE` -- Record the input and the random seed to FILE, for
+//!   replaying the session exactly
+//! * `--replay FILE` -- Replay a recorded session instead of reading
+//!   input, and report where its output first differs
+//! * `--debug` -- Stop at debug traps and fatal errors with a prompt for
+//!   inspecting the machine
+//!
+//! Exits with 0 when the story ends, 1 when the story cannot be loaded, 2
+//! for invalid arguments, 3 when the story stops with an error, and 4
+//! when a replay diverges from its recording.
+
+extern crate glulx;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use glulx::{
+    Blorb,
+    Glulx,
+    Input,
+    Replay,
+    ReplayError,
+    TextGlk,
+    TextTracer,
+    TrapAction,
+};
+
+
+const USAGE: &str = "usage: glulx [--seed N] [--transcript FILE] \
+[--autosave FILE] [--trace FILE] [--record FILE] [--replay FILE] [--debug] \
+<story>";
+
+
+const DEBUG_HELP: &str = "\
+backtrace, bt       show the call frames on the stack
+locals, l           show the locals of the current call frame
+stack, st           show the values of the current call frame
+x ADDR [LEN]        show LEN bytes of memory at ADDR
+continue, c         continue the story
+quit, q             exit";
+
+
+#[derive(Default)]
+struct Options {
+    story: Option<String>,
+    seed: Option<u32>,
+    transcript: Option<PathBuf>,
+    autosave: Option<PathBuf>,
+    trace: Option<PathBuf>,
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    debug: bool,
+}
+
+
+fn main() {
+    let options = match parse_args(env::args().skip(0x1).collect()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            process::exit(2);
+        },
+    };
+    let story = options.story.clone().unwrap();
+
+    let (mut glulx, blorb) = match fs::read(&story)
+            .map_err(|error| error.to_string())
+            .and_then(load) {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!("{}: {}", story, error);
+            process::exit(1);
+        },
+    };
+
+    let mut glk = TextGlk::new();
+    if let Some(blorb) = blorb {
+        glk.set_blorb(blorb);
+    }
+    glk.set_echo_input(false);
+    glk.set_prompt_handler(Box::new(|_, _| prompt_path()));
+    glulx.set_glk(Box::new(glk));
+    if let Some(seed) = options.seed {
+        glulx.set_random_seed(seed);
+    }
+    if options.debug {
+        glulx.set_trap_handler(Box::new(|_, _| TrapAction::Pause));
+    }
+    if let Some(ref path) = options.trace {
+        match File::create(path) {
+            Ok(file) => {
+                glulx.set_tracer(Box::new(TextTracer::new(BufWriter::new(file))))
+            },
+            Err(error) => {
+                eprintln!("{}: {}", path.display(), error);
+                process::exit(1);
+            },
+        }
+    }
+    if let Some(ref path) = options.replay {
+        let replay = fs::read(path)
+            .map_err(|error| error.to_string())
+            .and_then(|bytes| Replay::from_bytes(&bytes))
+            .unwrap_or_else(|error| {
+                eprintln!("{}: {}", path.display(), error);
+                process::exit(1);
+            });
+        let code = match replay.run(&mut glulx) {
+            Ok(None) => 0x0,
+            Ok(Some(error)) => {
+                eprintln!("replay ended with fatal error: {}", error);
+                0x3
+            },
+            Err(ReplayError::Diverged(divergence)) => {
+                eprintln!("{}", divergence);
+                0x4
+            },
+            Err(error) => {
+                eprintln!("{}", error);
+                0x1
+            },
+        };
+        process::exit(code);
+    }
+    if options.record.is_some() {
+        let seed = options.seed.unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH)
+                .map_or(0x0, |time| time.as_secs() as u32)
+        });
+        Replay::record(&mut glulx, seed);
+    }
+
+    let mut transcript = match options.transcript {
+        Some(ref path) => match File::create(path) {
+            Ok(file) => Some(BufWriter::new(file)),
+            Err(error) => {
+                eprintln!("{}: {}", path.display(), error);
+                process::exit(1);
+            },
+        },
+        None => None,
+    };
+
+    let restored = match options.autosave {
+        Some(ref path) if path.is_file() => {
+            let restored = fs::read(path)
+                .map_err(|error| error.to_string())
+                .and_then(|bytes| glulx.restore_autosave(&bytes)
+                    .map_err(|error| error.to_string()));
+            if let Err(error) = restored {
+                eprintln!("{}: {}", path.display(), error);
+                process::exit(1);
+            }
+            true
+        },
+        _ => false,
+    };
+
+    let mut result = if restored { glulx.resume() } else { glulx.run() };
+    let stdin = io::stdin();
+    let code = loop {
+        let mut output = glulx.glk_as_mut::<TextGlk>().unwrap().take_output();
+        // stories using FyreVM channels print the story and the prompt.
+        let mut channels = glulx.take_channels();
+        for name in &["MAIN", "PRPT"] {
+            output.push_str(&channels.remove(*name).unwrap_or_default());
+        }
+        print!("{}", output);
+        io::stdout().flush().unwrap();
+        if let Some(ref mut transcript) = transcript {
+            let _ = transcript.write_all(output.as_bytes());
+        }
+
+        if let Err(error) = result {
+            eprintln!("\nfatal error: {}", error);
+            if options.debug {
+                debug_prompt(&mut glulx, false);
+            }
+            break 0x3;
+        }
+        if !glulx.is_running() {
+            if let Some(ref path) = options.autosave {
+                let _ = fs::remove_file(path);
+            }
+            break 0x0;
+        }
+        if glulx.is_paused() {
+            if let Some(value) = glulx.take_trap() {
+                eprintln!("\ndebug trap {:#X}", value);
+                debug_prompt(&mut glulx, true);
+            }
+            result = glulx.resume();
+            continue;
+        }
+
+        if let Some(ref path) = options.autosave {
+            let saved = glulx.autosave()
+                .map_err(|error| error.to_string())
+                .and_then(|bytes| {
+                    fs::write(path, bytes).map_err(|error| error.to_string())
+                });
+            if let Err(error) = saved {
+                eprintln!("{}: {}", path.display(), error);
+            }
+        }
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0x0) == 0x0 {
+            break 0x0;
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if let Some(ref mut transcript) = transcript {
+            let _ = writeln!(transcript, "{}", line);
+        }
+        let glk = glulx.glk_as_mut::<TextGlk>().unwrap();
+        if glk.is_waiting() {
+            glk.push_input(Input::Line(line));
+        } else {
+            glulx.push_channel_input(line);
+        }
+        result = glulx.resume();
+    };
+
+    if let Some(mut transcript) = transcript {
+        let _ = transcript.flush();
+    }
+    if let Some(ref path) = options.record {
+        let replay = Replay::finish(&mut glulx).unwrap_or_default();
+        if let Err(error) = fs::write(path, replay.to_bytes()) {
+            eprintln!("{}: {}", path.display(), error);
+        }
+    }
+    // the tracer flushes its output when dropped.
+    drop(glulx.take_tracer());
+    process::exit(code);
+}
+
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("{} needs a value", name))
+        };
+        match arg.as_str() {
+            "--seed" => {
+                let seed = value("--seed")?;
+                options.seed = Some(parse_number(&seed)?);
+            },
+            "--transcript" => {
+                options.transcript = Some(PathBuf::from(value("--transcript")?))
+            },
+            "--autosave" => {
+                options.autosave = Some(PathBuf::from(value("--autosave")?))
+            },
+            "--trace" => options.trace = Some(PathBuf::from(value("--trace")?)),
+            "--record" => {
+                options.record = Some(PathBuf::from(value("--record")?))
+            },
+            "--replay" => {
+                options.replay = Some(PathBuf::from(value("--replay")?))
+            },
+            "--debug" => options.debug = true,
+            _ if arg.starts_with("--") => {
+                return Err(format!("unknown option {}", arg));
+            },
+            _ if options.story.is_none() => options.story = Some(arg),
+            _ => return Err("only one story may be given".to_string()),
+        }
+    }
+    if options.story.is_none() {
+        return Err("no story given".to_string());
+    }
+    Ok(options)
+}
+
+
+/// Loads a story file, or the executable of a Blorb file along with the
+/// rest of its resources.
+fn load(bytes: Vec<u8>) -> Result<(Glulx, Option<Blorb>), String> {
+    let (story, blorb) = Blorb::load(bytes)?;
+    Ok((Glulx::from_rom(story)?, blorb))
+}
+
+
+/// Asks for the path of a file the story wants to open.
+fn prompt_path() -> Option<PathBuf> {
+    print!("\nfile name: ");
+    io::stdout().flush().unwrap();
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line).ok()?;
+    let name = line.trim();
+    if name.is_empty() { None } else { Some(PathBuf::from(name)) }
+}
+
+
+/// Inspects the machine after a debug trap or a fatal error, until the
+/// story is continued. Fatal errors cannot be continued.
+fn debug_prompt(glulx: &mut Glulx, can_continue: bool) {
+    let stdin = io::stdin();
+    loop {
+        eprint!("(debug) ");
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0x0) == 0x0 {
+            return;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+
+        let arg = |index: usize, default: u32| match words.get(index) {
+            Some(word) => parse_number(word),
+            None => Ok(default),
+        };
+        match words[0x0] {
+            "backtrace" | "bt" => {
+                for (index, frame) in glulx.backtrace().iter().enumerate() {
+                    eprintln!("#{} {}", index, frame);
+                }
+            },
+            "locals" | "l" => {
+                for local in glulx.locals() {
+                    eprintln!("local{} = {:#X} ({} bytes)",
+                        local.offset, local.value, local.size);
+                }
+            },
+            "stack" | "st" => {
+                for (index, value) in glulx.values().iter().enumerate() {
+                    eprintln!("{}: {:#X}", index, value);
+                }
+            },
+            "x" => match (arg(0x1, 0x0), arg(0x2, 0x10)) {
+                (Ok(address), Ok(len)) => dump(glulx, address, len),
+                (Err(message), _) | (_, Err(message)) => eprintln!("{}", message),
+            },
+            "continue" | "c" if can_continue => return,
+            "continue" | "c" => eprintln!("the story cannot continue"),
+            "quit" | "q" => {
+                drop(glulx.take_tracer());
+                process::exit(if can_continue { 0x0 } else { 0x3 });
+            },
+            "help" | "h" => eprintln!("{}", DEBUG_HELP),
+            _ => eprintln!("unknown command {}", words[0x0]),
+        }
+    }
+}
+
+
+fn dump(glulx: &Glulx, address: u32, len: u32) {
+    let memory = glulx.memory();
+    let end = address.saturating_add(len).min(memory.get_mem_size());
+    for row in (address..end).step_by(0x10) {
+        let bytes: Vec<String> = memory.bytes(row..end.min(row + 0x10))
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        eprintln!("{:08X}  {}", row, bytes.join(" "));
+    }
+}
+
+
+fn parse_number(word: &str) -> Result<u32, String> {
+    let parsed = if word.starts_with("0x") || word.starts_with("0X") {
+        u32::from_str_radix(&word[0x2..], 0x10)
+    } else {
+        word.parse()
+    };
+    parsed.map_err(|_| format!("invalid number {}", word))
+}
@@ -0,0 +1,308 @@
+//! # Blorb files
+//!
+//! Stories are often distributed as Blorb files, an IFF `FORM` of type
+//! `IFRS` which packages the story with its pictures and sounds. The
+//! chunks read are:
+//!
+//! * `RIdx` -- The resource index, listing the usage, number, and chunk
+//!   of every resource
+//! * `GLUL` -- The glulx story file, as the executable resource
+//! * `PNG `, `JPEG` and `Rect` -- Pictures, with `Rect` giving only the
+//!   size of a picture which is not included
+//! * `OGGV`, `MOD `, `AIFF` and `SONG` -- Sounds
+//! * `TEXT` and `BINA` -- Data files, opened as resource streams
+//! * `IFmd` -- Metadata about the story, in the iFiction XML format
+//! * `Fspc` -- The number of the picture to use as a frontispiece
+//!
+//! Resources held in `FORM` chunks, such as `AIFF` sounds, keep their
+//! whole chunk, including the header.
+
+use std::collections::BTreeMap;
+
+use byteorder::{BigEndian, ByteOrder};
+
+
+/// What a resource in a Blorb file is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Usage {
+    Picture,
+    Sound,
+    Data,
+    Executable,
+}
+
+
+impl Usage {
+    fn from_id(id: &[u8]) -> Option<Usage> {
+        match id {
+            b"Pict" => Some(Usage::Picture),
+            b"Snd " => Some(Usage::Sound),
+            b"Data" => Some(Usage::Data),
+            b"Exec" => Some(Usage::Executable),
+            _ => None,
+        }
+    }
+}
+
+
+/// A resource in a Blorb file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub usage: Usage,
+    pub number: u32,
+
+    /// The type of the chunk holding the resource, such as `PNG ` or
+    /// `GLUL`, or the type of the form for `FORM` chunks.
+    pub kind: [u8; 0x4],
+    pub data: Vec<u8>,
+}
+
+
+/// The resources and metadata of a Blorb file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blorb {
+    resources: BTreeMap<(Usage, u32), Resource>,
+    metadata: Option<String>,
+    frontispiece: Option<u32>,
+}
+
+
+impl Blorb {
+
+    /// Whether the bytes look like a Blorb file rather than a story file.
+    pub fn is_blorb(bytes: &[u8]) -> bool {
+        bytes.len() >= 0xC && &bytes[..0x4] == b"FORM"
+            && &bytes[0x8..0xC] == b"IFRS"
+    }
+
+    /// Splits a story file or Blorb file into the glulx story file and,
+    /// for a Blorb file, the rest of its resources.
+    pub fn load(bytes: Vec<u8>) -> Result<(Vec<u8>, Option<Blorb>), String> {
+        if !Blorb::is_blorb(&bytes) {
+            return Ok((bytes, None));
+        }
+        let blorb = Blorb::from_bytes(&bytes)?;
+        let story = blorb.executable()
+            .ok_or_else(|| "Blorb file has no glulx executable".to_string())?
+            .to_vec();
+        Ok((story, Some(blorb)))
+    }
+
+    /// Reads a Blorb file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Blorb, String> {
+        if !Blorb::is_blorb(bytes) {
+            return Err("not a Blorb file".to_string());
+        }
+        let len = BigEndian::read_u32(&bytes[0x4..0x8]) as usize;
+        let end = (0x8 + len).min(bytes.len());
+
+        // chunks by their offset in the file, as the index refers to them.
+        let mut chunks = BTreeMap::new();
+        let mut index = None;
+        let mut blorb = Blorb {
+            resources: BTreeMap::new(),
+            metadata: None,
+            frontispiece: None,
+        };
+
+        let mut ptr = 0xC;
+        while ptr + 0x8 <= end {
+            let id = &bytes[ptr..ptr + 0x4];
+            let len = BigEndian::read_u32(&bytes[ptr + 0x4..]) as usize;
+            let data = bytes.get(ptr + 0x8..ptr + 0x8 + len)
+                .ok_or_else(|| "truncated chunk".to_string())?;
+            match id {
+                b"RIdx" => index = Some(data),
+                b"IFmd" => {
+                    blorb.metadata =
+                        Some(String::from_utf8_lossy(data).into_owned());
+                },
+                b"Fspc" if data.len() >= 0x4 => {
+                    blorb.frontispiece = Some(BigEndian::read_u32(data));
+                },
+                _ => (),
+            }
+            let mut kind = [0x0; 0x4];
+            if id == b"FORM" && data.len() >= 0x4 {
+                kind.copy_from_slice(&data[..0x4]);
+                chunks.insert(ptr, (kind, &bytes[ptr..ptr + 0x8 + len]));
+            } else {
+                kind.copy_from_slice(id);
+                chunks.insert(ptr, (kind, data));
+            }
+            // chunks are padded to an even length.
+            ptr += 0x8 + len + len % 0x2;
+        }
+
+        let index = index
+            .ok_or_else(|| "Blorb file has no resource index".to_string())?;
+        if index.len() < 0x4 {
+            return Err("truncated resource index".to_string());
+        }
+        let count = BigEndian::read_u32(index) as usize;
+        if index.len() < 0x4 + count * 0xC {
+            return Err("truncated resource index".to_string());
+        }
+        for entry in index[0x4..0x4 + count * 0xC].chunks(0xC) {
+            let usage = match Usage::from_id(&entry[..0x4]) {
+                Some(usage) => usage,
+                None => continue,
+            };
+            let number = BigEndian::read_u32(&entry[0x4..]);
+            let start = BigEndian::read_u32(&entry[0x8..]) as usize;
+            let &(kind, data) = chunks.get(&start).ok_or_else(|| {
+                format!("resource index entry {:#X} is not a chunk", start)
+            })?;
+            blorb.resources.insert((usage, number), Resource {
+                usage,
+                number,
+                kind,
+                data: data.to_vec(),
+            });
+        }
+        Ok(blorb)
+    }
+
+    /// The glulx story file, if the executable resource is one.
+    pub fn executable(&self) -> Option<&[u8]> {
+        self.resource(Usage::Executable, 0x0)
+            .filter(|resource| &resource.kind == b"GLUL")
+            .map(|resource| &resource.data[..])
+    }
+
+    /// The resource with the given usage and number.
+    pub fn resource(&self, usage: Usage, number: u32) -> Option<&Resource> {
+        self.resources.get(&(usage, number))
+    }
+
+    /// All of the resources, ordered by usage and number.
+    pub fn resources(&self) -> impl Iterator<Item = &Resource> {
+        self.resources.values()
+    }
+
+    /// Whether there are any resources with the given usage.
+    pub fn has(&self, usage: Usage) -> bool {
+        self.resources.keys().any(|&(other, _)| other == usage)
+    }
+
+    /// The width and height of the given picture, in pixels, if it can be
+    /// read from the picture.
+    pub fn image_size(&self, number: u32) -> Option<(u32, u32)> {
+        let resource = self.resource(Usage::Picture, number)?;
+        let data = &resource.data[..];
+        match &resource.kind {
+            b"PNG " if data.len() >= 0x18 && &data[0xC..0x10] == b"IHDR" => {
+                Some((BigEndian::read_u32(&data[0x10..]),
+                    BigEndian::read_u32(&data[0x14..])))
+            },
+            b"JPEG" => jpeg_size(data),
+            b"Rect" if data.len() >= 0x8 => {
+                Some((BigEndian::read_u32(data), BigEndian::read_u32(&data[0x4..])))
+            },
+            _ => None,
+        }
+    }
+
+    /// The iFiction metadata of the story, if any.
+    pub fn metadata(&self) -> Option<&str> {
+        self.metadata.as_ref().map(|metadata| &metadata[..])
+    }
+
+    /// The number of the picture to show as the cover of the story, if
+    /// any.
+    pub fn frontispiece(&self) -> Option<u32> {
+        self.frontispiece
+    }
+}
+
+
+/// Finds the size of a JPEG image in its start of frame segment.
+fn jpeg_size(data: &[u8]) -> Option<(u32, u32)> {
+    let mut ptr = 0x2;
+    while ptr + 0x4 <= data.len() {
+        if data[ptr] != 0xFF {
+            return None;
+        }
+        let marker = data[ptr + 0x1];
+        let len = BigEndian::read_u16(&data[ptr + 0x2..]) as usize;
+        let is_frame = (0xC0..=0xCF).contains(&marker)
+            && marker != 0xC4 && marker != 0xC8 && marker != 0xCC;
+        if is_frame {
+            let frame = data.get(ptr + 0x5..ptr + 0x9)?;
+            return Some((BigEndian::read_u16(&frame[0x2..]) as u32,
+                BigEndian::read_u16(frame) as u32));
+        }
+        ptr += 0x2 + len;
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, ByteOrder};
+
+    use super::{Blorb, Usage};
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        let mut len = [0x0; 0x4];
+        BigEndian::write_u32(&mut len, data.len() as u32);
+        bytes.extend_from_slice(&len);
+        bytes.extend_from_slice(data);
+        if data.len() % 0x2 == 0x1 {
+            bytes.push(0x0);
+        }
+        bytes
+    }
+
+    #[test]
+    fn reads_resources() {
+        let mut png = b"\x89PNG\r\n\x1A\n\0\0\0\x0DIHDR".to_vec();
+        png.extend_from_slice(&[0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0x0, 0x80]);
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0x0, 0x2, 0xFF, 0xC0, 0x0, 0x8,
+            0x8, 0x0, 0x20, 0x0, 0x40];
+        let chunks = [
+            chunk(b"GLUL", b"Glul"),
+            chunk(b"PNG ", &png),
+            chunk(b"JPEG", &jpeg),
+            chunk(b"FORM", b"AIFFdata"),
+            chunk(b"IFmd", b"<ifindex/>"),
+            chunk(b"Fspc", &[0x0, 0x0, 0x0, 0x1]),
+        ];
+
+        // the index comes first, so chunks start after it.
+        let entries: [(&[u8], u32); 0x4] =
+            [(b"Exec", 0x0), (b"Pict", 0x1), (b"Pict", 0x2), (b"Snd ", 0x3)];
+        let mut index = vec![0x0; 0x4 + entries.len() * 0xC];
+        BigEndian::write_u32(&mut index, entries.len() as u32);
+        let mut ptr = 0xC + 0x8 + index.len() as u32;
+        for (number, &(usage, _)) in entries.iter().enumerate() {
+            let entry = &mut index[0x4 + number * 0xC..];
+            entry[..0x4].copy_from_slice(usage);
+            BigEndian::write_u32(&mut entry[0x4..], entries[number].1);
+            BigEndian::write_u32(&mut entry[0x8..], ptr);
+            ptr += chunks[number].len() as u32;
+        }
+
+        let mut body = b"IFRS".to_vec();
+        body.extend(chunk(b"RIdx", &index));
+        for chunk in chunks.iter() {
+            body.extend_from_slice(chunk);
+        }
+        let bytes = chunk(b"FORM", &body);
+        assert!(Blorb::is_blorb(&bytes));
+
+        let blorb = Blorb::from_bytes(&bytes).unwrap();
+        assert_eq!(blorb.executable(), Some(&b"Glul"[..]));
+        assert_eq!(blorb.image_size(0x1), Some((0x100, 0x80)));
+        assert_eq!(blorb.image_size(0x2), Some((0x40, 0x20)));
+        let sound = blorb.resource(Usage::Sound, 0x3).unwrap();
+        assert_eq!((&sound.kind, &sound.data[..0x4]), (b"AIFF", &b"FORM"[..]));
+        assert!(blorb.has(Usage::Sound) && !blorb.has(Usage::Data));
+        assert_eq!(blorb.metadata(), Some("<ifindex/>"));
+        assert_eq!(blorb.frontispiece(), Some(0x1));
+
+        assert!(Blorb::from_bytes(&chunk(b"FORM", b"IFRS")).is_err());
+    }
+}
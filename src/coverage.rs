@@ -0,0 +1,380 @@
+//! # Code coverage
+//!
+//! The coverage collector counts how many times each instruction address
+//! and each opcode is executed, and remembers every function called.
+//!
+//! Reports compare these counts against a disassembly of the story,
+//! which starts from the start function and every function called while
+//! collecting, so code which never ran is reported as well.
+//!
+//! * Routines -- For each function, the instructions and basic blocks
+//!   executed, out of those found by the disassembler
+//! * LCOV -- The tracefile format read by `genhtml` and similar tools.
+//!   With debug information, counts are given by source file and line.
+//!   Without it, every instruction is a line of a single file named
+//!   `story`, numbered by its address.
+//! * JSON -- The counts by address and by opcode name, along with the
+//!   routine summaries
+//!
+//! A basic block starts at the first instruction of a function, at the
+//! target of a branch or jump, and after any instruction which branches
+//! or does not continue.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use debug_info::DebugInfo;
+
+use disassembler::{
+    disassemble_functions,
+    function_name,
+    Function,
+};
+
+use instruction::{
+    opcode,
+    Branch,
+    Operand,
+};
+
+use memory::GlulxMemory;
+
+
+/// The coverage of a single function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutineCoverage {
+
+    /// The address of the function.
+    pub address: u32,
+
+    /// The name of the function, from debug information if it names the
+    /// function.
+    pub name: String,
+
+    /// The number of instructions found in the function.
+    pub instructions: u32,
+
+    /// The number of those instructions which were executed.
+    pub instructions_executed: u32,
+
+    /// The number of basic blocks found in the function.
+    pub blocks: u32,
+
+    /// The number of those blocks which were executed.
+    pub blocks_executed: u32,
+}
+
+
+/// Counts the instructions and opcodes executed.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    addresses: BTreeMap<u32, u64>,
+    opcodes: BTreeMap<u32, u64>,
+    functions: BTreeSet<u32>,
+}
+
+
+impl Coverage {
+
+    /// Creates an empty collector.
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// The number of times each instruction was executed, by address.
+    pub fn addresses(&self) -> &BTreeMap<u32, u64> {
+        &self.addresses
+    }
+
+    /// The number of times each opcode was executed, by opcode number.
+    pub fn opcodes(&self) -> &BTreeMap<u32, u64> {
+        &self.opcodes
+    }
+
+    /// The addresses of the functions called.
+    pub fn functions(&self) -> &BTreeSet<u32> {
+        &self.functions
+    }
+
+    /// Counts an executed instruction.
+    pub(crate) fn instruction(&mut self, address: u32, opcode: u32) {
+        *self.addresses.entry(address).or_insert(0x0) += 0x1;
+        *self.opcodes.entry(opcode).or_insert(0x0) += 0x1;
+    }
+
+    /// Records a call to the given function.
+    pub(crate) fn enter(&mut self, function: u32) {
+        self.functions.insert(function);
+    }
+
+    /// The number of times the instruction at the given address was
+    /// executed.
+    pub fn count(&self, address: u32) -> u64 {
+        self.addresses.get(&address).cloned().unwrap_or(0x0)
+    }
+
+    /// The coverage of each function in the story, by address.
+    pub fn routines(&self,
+            memory: &GlulxMemory,
+            debug_info: Option<&DebugInfo>) -> Vec<RoutineCoverage> {
+        let functions = self.disassemble(memory);
+        functions.values()
+            .map(|function| {
+                let blocks = blocks(function);
+                RoutineCoverage {
+                    address: function.address,
+                    name: function_name(function.address, debug_info),
+                    instructions: function.instructions.len() as u32,
+                    instructions_executed:
+                        self.executed(function.instructions.keys()),
+                    blocks: blocks.len() as u32,
+                    blocks_executed: self.executed(blocks.iter()),
+                }
+            })
+            .collect()
+    }
+
+    /// Writes the coverage in the LCOV tracefile format.
+    pub fn write_lcov<W: Write>(&self,
+            writer: &mut W,
+            memory: &GlulxMemory,
+            debug_info: Option<&DebugInfo>) -> io::Result<()> {
+        let functions = self.disassemble(memory);
+
+        // the lines and functions of each file, by file name.
+        let mut files: BTreeMap<String, (BTreeMap<u32, u64>, Vec<_>)> =
+            BTreeMap::new();
+        for function in functions.values() {
+            // functions are counted as called when their first
+            // instruction is executed.
+            let name = function_name(function.address, debug_info);
+            let start = function.instructions.keys().next().cloned()
+                .unwrap_or(function.address);
+            let calls = self.count(start);
+
+            for &address in function.instructions.keys() {
+                let (file, line) = source_line(address, debug_info);
+                let lines = &mut files.entry(file).or_default().0;
+                let count = lines.entry(line).or_insert(0x0);
+                *count = (*count).max(self.count(address));
+            }
+
+            let (file, line) = source_line(start, debug_info);
+            files.entry(file).or_default().1.push((line, name, calls));
+        }
+
+        for (file, (lines, functions)) in files {
+            writeln!(writer, "TN:")?;
+            writeln!(writer, "SF:{}", file)?;
+            for &(line, ref name, _) in &functions {
+                writeln!(writer, "FN:{},{}", line, name)?;
+            }
+            for &(_, ref name, calls) in &functions {
+                writeln!(writer, "FNDA:{},{}", calls, name)?;
+            }
+            writeln!(writer, "FNF:{}", functions.len())?;
+            writeln!(writer, "FNH:{}",
+                functions.iter().filter(|function| function.2 > 0x0).count())?;
+            for (line, count) in &lines {
+                writeln!(writer, "DA:{},{}", line, count)?;
+            }
+            writeln!(writer, "LF:{}", lines.len())?;
+            writeln!(writer, "LH:{}",
+                lines.values().filter(|&&count| count > 0x0).count())?;
+            writeln!(writer, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    /// Writes the coverage as a JSON object, with the counts by address
+    /// and by opcode, and the coverage of each function.
+    pub fn write_json<W: Write>(&self,
+            writer: &mut W,
+            memory: &GlulxMemory,
+            debug_info: Option<&DebugInfo>) -> io::Result<()> {
+        let addresses: Vec<String> = self.addresses.iter()
+            .map(|(address, count)| format!("\"{:#X}\": {}", address, count))
+            .collect();
+        let opcodes: Vec<String> = self.opcodes.iter()
+            .map(|(&number, count)| {
+                let name = opcode(number)
+                    .map(|opcode| opcode.name.to_string())
+                    .unwrap_or_else(|| format!("{:#X}", number));
+                format!("\"{}\": {}", name, count)
+            })
+            .collect();
+        let routines: Vec<String> = self.routines(memory, debug_info).iter()
+            .map(|routine| {
+                format!("{{\"address\": \"{:#X}\", \"name\": {}, \
+                    \"instructions\": {}, \"instructions_executed\": {}, \
+                    \"blocks\": {}, \"blocks_executed\": {}}}",
+                    routine.address, json_string(&routine.name),
+                    routine.instructions, routine.instructions_executed,
+                    routine.blocks, routine.blocks_executed)
+            })
+            .collect();
+
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"addresses\": {{{}}},", addresses.join(", "))?;
+        writeln!(writer, "  \"opcodes\": {{{}}},", opcodes.join(", "))?;
+        writeln!(writer, "  \"routines\": [")?;
+        for (index, routine) in routines.iter().enumerate() {
+            let separator = if index + 0x1 < routines.len() { "," } else { "" };
+            writeln!(writer, "    {}{}", routine, separator)?;
+        }
+        writeln!(writer, "  ]")?;
+        writeln!(writer, "}}")
+    }
+
+    /// The number of the given instruction addresses which were executed.
+    fn executed<'a, I>(&self, addresses: I) -> u32
+            where I: Iterator<Item = &'a u32> {
+        addresses.filter(|&&address| self.count(address) > 0x0).count() as u32
+    }
+
+    /// Disassembles the story, starting from every function called.
+    fn disassemble(&self, memory: &GlulxMemory) -> BTreeMap<u32, Function> {
+        let functions: Vec<u32> = self.functions.iter().cloned().collect();
+        disassemble_functions(memory, &functions).functions
+    }
+}
+
+
+/// The addresses of the first instructions of the basic blocks of a
+/// function.
+fn blocks(function: &Function) -> BTreeSet<u32> {
+    let mut leaders = BTreeSet::new();
+    leaders.extend(function.instructions.keys().next());
+    for instruction in function.instructions.values() {
+        if let Some(Branch::Jump(target)) = instruction.branch() {
+            leaders.insert(target);
+        }
+        if let (0x104, Some(&Operand::Const(target))) =
+                (instruction.opcode.number, instruction.operands.first()) {
+            leaders.insert(target as u32);
+        }
+        if instruction.opcode.branches() || !instruction.opcode.continues() {
+            leaders.insert(instruction.next());
+        }
+    }
+    leaders.into_iter()
+        .filter(|address| function.instructions.contains_key(address))
+        .collect()
+}
+
+
+/// The source file and line of the given address. Without debug
+/// information covering it, the file is `story` and the line is the
+/// address.
+fn source_line(address: u32, debug_info: Option<&DebugInfo>)
+        -> (String, u32) {
+    debug_info
+        .and_then(|info| {
+            let location = info.source_location(address)?;
+            let file = info.sources.get(&location.file)?;
+            Some((file.clone(), location.line))
+        })
+        .unwrap_or_else(|| ("story".to_string(), address))
+}
+
+
+/// Quotes a string for JSON.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                quoted.push_str(&format!("\\u{:04x}", c as u32))
+            },
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+
+#[cfg(test)]
+mod tests {
+    use assembler::Arg::*;
+    use assembler::Assembler;
+    use debug_info::DebugInfo;
+    use interpreter::Glulx;
+
+    use super::RoutineCoverage;
+
+    /// Runs a program where the start function skips an instruction, and
+    /// calls `used` through a local, which the disassembler can not see.
+    fn covered() -> Glulx {
+        let mut asm = Assembler::new();
+        let (main, used, skip) = (asm.label(), asm.label(), asm.label());
+        asm.start(main)
+            .function(main, 0xC1, &[(0x4, 0x1)])
+            .op("copy", &[Label(used), Local(0x0)])
+            .op("jnz", &[Local(0x0), Branch(skip)])
+            .op("copy", &[Const(1), Local(0x0)])
+            .bind(skip)
+            .op("callf", &[Local(0x0), Zero])
+            .op("return", &[Zero])
+            .function(used, 0xC1, &[])
+            .op("return", &[Const(1)]);
+        let mut glulx = Glulx::from_rom(asm.finish().unwrap()).unwrap();
+        glulx.init().unwrap();
+        glulx.start_coverage();
+        glulx.resume().unwrap();
+        glulx
+    }
+
+    #[test]
+    fn summarises_routines() {
+        let glulx = covered();
+        let coverage = glulx.coverage().unwrap();
+        let used = *coverage.functions().iter().nth(0x1).unwrap();
+        let info = DebugInfo::parse(&format!("<inform-story-file>
+            <routine><identifier>Used</identifier><value>{}</value>
+            <byte-count>4</byte-count></routine>
+            </inform-story-file>", used)).unwrap();
+
+        let routines = coverage.routines(glulx.memory(), Some(&info));
+        assert_eq!(routines, vec![
+            RoutineCoverage {
+                address: 0x24,
+                name: "Func_00000024".to_string(),
+                instructions: 0x5,
+                instructions_executed: 0x4,
+                blocks: 0x3,
+                blocks_executed: 0x2,
+            },
+            RoutineCoverage {
+                address: used,
+                name: "Used".to_string(),
+                instructions: 0x1,
+                instructions_executed: 0x1,
+                blocks: 0x1,
+                blocks_executed: 0x1,
+            },
+        ]);
+    }
+
+    #[test]
+    fn writes_lcov_and_json() {
+        let glulx = covered();
+        let coverage = glulx.coverage().unwrap();
+
+        let mut lcov = vec![];
+        coverage.write_lcov(&mut lcov, glulx.memory(), None).unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert!(lcov.starts_with("TN:\nSF:story\nFN:41,Func_00000024\n"));
+        assert!(lcov.contains("FNF:2\nFNH:2\n"));
+        assert!(lcov.contains("LF:6\nLH:5\nend_of_record\n"));
+
+        let mut json = vec![];
+        coverage.write_json(&mut json, glulx.memory(), None).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains(
+            "\"opcodes\": {\"jnz\": 1, \"return\": 2, \"copy\": 1, \"callf\": 1},"));
+        assert!(json.contains("\"instructions_executed\": 4"));
+    }
+}
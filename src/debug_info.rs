@@ -0,0 +1,566 @@
+//! # Inform debug information
+//!
+//! Inform 6 writes a `gameinfo.dbg` file alongside each story, which maps
+//! the names used in the source to the addresses in the story file. The
+//! file is XML, with a top level `inform-story-file` element holding:
+//!
+//! * `source` -- The path of each source file, by index
+//! * `routine` -- The name, address, and length of each routine, along
+//!   with its local variables and sequence points
+//! * `global-variable` -- The name and address of each global variable
+//! * `array` -- The name and address of each array
+//! * `object`, `property`, `attribute`, `constant` -- The name and value
+//!   of each symbol
+//!
+//! Sequence points map the address of an instruction, given as an offset
+//! from the start of its routine, to the source line it was compiled from.
+//!
+//! Only the parts of XML used by debug files are supported: elements,
+//! attributes, text, comments, `CDATA` sections, and character references.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+
+
+/// An error found while parsing a debug file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebugInfoError {
+
+    /// The file is not well formed XML.
+    Syntax {
+
+        /// The byte offset of the error in the file.
+        offset: usize,
+    },
+
+    /// An element which should hold a number holds other text.
+    InvalidNumber {
+
+        /// The text of the element.
+        text: String,
+    },
+
+    /// The top level element is not `inform-story-file`.
+    NotDebugInfo,
+}
+
+
+impl fmt::Display for DebugInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DebugInfoError::Syntax { offset } => {
+                write!(f, "invalid XML at byte {}", offset)
+            },
+            DebugInfoError::InvalidNumber { ref text } => {
+                write!(f, "invalid number {:?}", text)
+            },
+            DebugInfoError::NotDebugInfo => {
+                write!(f, "not an Inform debug information file")
+            },
+        }
+    }
+}
+
+
+impl error::Error for DebugInfoError {}
+
+
+/// A position in a source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation {
+
+    /// The index of the source file.
+    pub file: u32,
+
+    /// The line number, starting from 1.
+    pub line: u32,
+}
+
+
+/// A routine of the story.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Routine {
+
+    /// The name of the routine.
+    pub name: String,
+
+    /// The address of the routine's type byte.
+    pub address: u32,
+
+    /// The length of the routine in bytes.
+    pub length: u32,
+
+    /// Where the routine is defined.
+    pub location: Option<SourceLocation>,
+
+    /// The names of the local variables, by offset in the call frame.
+    pub locals: BTreeMap<u32, String>,
+
+    /// The source location of each sequence point, by address.
+    pub sequence_points: BTreeMap<u32, SourceLocation>,
+}
+
+
+/// The symbols of a story, read from its debug file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+
+    /// The paths of the source files, by index.
+    pub sources: BTreeMap<u32, String>,
+
+    /// The routines, by address.
+    pub routines: BTreeMap<u32, Routine>,
+
+    /// The global variables, by name, holding their addresses.
+    pub globals: BTreeMap<String, u32>,
+
+    /// The arrays, by name, holding their addresses.
+    pub arrays: BTreeMap<String, u32>,
+
+    /// The objects, by name, holding their values.
+    pub objects: BTreeMap<String, u32>,
+
+    /// The properties, by name, holding their values.
+    pub properties: BTreeMap<String, u32>,
+
+    /// The attributes, by name, holding their values.
+    pub attributes: BTreeMap<String, u32>,
+
+    /// The constants, by name, holding their values.
+    pub constants: BTreeMap<String, u32>,
+}
+
+
+impl DebugInfo {
+
+    /// Parses the text of a `gameinfo.dbg` file.
+    pub fn parse(text: &str) -> Result<DebugInfo, DebugInfoError> {
+        let root = Parser { text, pos: 0x0 }.parse_document()?;
+        if root.name != "inform-story-file" {
+            return Err(DebugInfoError::NotDebugInfo);
+        }
+
+        let mut info = DebugInfo::default();
+        for element in &root.children {
+            match element.name.as_str() {
+                "source" => {
+                    if let Some(path) = element.child("given-path") {
+                        let index = element.attribute("index")
+                            .and_then(|index| index.parse().ok())
+                            .unwrap_or(0x0);
+                        info.sources.insert(index, path.text.clone());
+                    }
+                },
+                "routine" => {
+                    let routine = Routine::from_element(element)?;
+                    info.routines.insert(routine.address, routine);
+                },
+                "global-variable" => {
+                    let address = element.number("address")?;
+                    insert(&mut info.globals, element, address);
+                },
+                "array" => insert(&mut info.arrays, element,
+                    element.number("value")?),
+                "object" => insert(&mut info.objects, element,
+                    element.number("value")?),
+                "property" => insert(&mut info.properties, element,
+                    element.number("value")?),
+                "attribute" => insert(&mut info.attributes, element,
+                    element.number("value")?),
+                "constant" => insert(&mut info.constants, element,
+                    element.number("value")?),
+                _ => (),
+            }
+        }
+        Ok(info)
+    }
+
+    /// The routine containing the given address.
+    pub fn routine_at(&self, address: u32) -> Option<&Routine> {
+        self.routines.range(..=address).next_back()
+            .map(|(_, routine)| routine)
+            .filter(|routine| address < routine.address + routine.length.max(0x1))
+    }
+
+    /// The routine with the given name.
+    pub fn routine_named(&self, name: &str) -> Option<&Routine> {
+        self.routines.values().find(|routine| routine.name == name)
+    }
+
+    /// The name of the global variable at the given address.
+    pub fn global_at(&self, address: u32) -> Option<&str> {
+        self.globals.iter()
+            .find(|&(_, &global)| global == address)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The source location of the last sequence point at or before the
+    /// given address, within its routine.
+    pub fn source_location(&self, address: u32) -> Option<SourceLocation> {
+        self.routine_at(address)?
+            .sequence_points.range(..=address).next_back()
+            .map(|(_, &location)| location)
+    }
+
+    /// The name of the symbol at the given address: a routine, global
+    /// variable, or array.
+    pub fn name_of(&self, address: u32) -> Option<&str> {
+        if let Some(routine) = self.routines.get(&address) {
+            return Some(&routine.name);
+        }
+        self.global_at(address).or_else(|| {
+            self.arrays.iter()
+                .find(|&(_, &array)| array == address)
+                .map(|(name, _)| name.as_str())
+        })
+    }
+
+    /// The address or value of the symbol with the given name, looking at
+    /// routines, global variables, arrays, objects, and constants in turn.
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.routine_named(name).map(|routine| routine.address)
+            .or_else(|| self.globals.get(name).cloned())
+            .or_else(|| self.arrays.get(name).cloned())
+            .or_else(|| self.objects.get(name).cloned())
+            .or_else(|| self.constants.get(name).cloned())
+    }
+}
+
+
+impl Routine {
+    fn from_element(element: &Element) -> Result<Routine, DebugInfoError> {
+        let address = element.number("value")?.unwrap_or(0x0);
+        let mut routine = Routine {
+            name: element.text_of("identifier").unwrap_or("").to_string(),
+            address,
+            length: element.number("byte-count")?.unwrap_or(0x0),
+            location: location(element)?,
+            locals: BTreeMap::new(),
+            sequence_points: BTreeMap::new(),
+        };
+
+        for child in &element.children {
+            match child.name.as_str() {
+                "local-variable" => {
+                    let offset = child.number("frame-offset")?
+                        .or(child.number("index")?)
+                        .unwrap_or(0x0);
+                    let name = child.text_of("identifier").unwrap_or("");
+                    routine.locals.insert(offset, name.to_string());
+                },
+                "sequence-point" => {
+                    let offset = child.number("address")?.unwrap_or(0x0);
+                    if let Some(location) = location(child)? {
+                        routine.sequence_points
+                            .insert(address + offset, location);
+                    }
+                },
+                _ => (),
+            }
+        }
+        Ok(routine)
+    }
+}
+
+
+/// Inserts the named symbol held by an element, if it has a value.
+fn insert(map: &mut BTreeMap<String, u32>, element: &Element, value: Option<u32>) {
+    if let (Some(name), Some(value)) = (element.text_of("identifier"), value) {
+        map.insert(name.to_string(), value);
+    }
+}
+
+
+/// The first position of an element's `source-code-location`.
+fn location(element: &Element) -> Result<Option<SourceLocation>, DebugInfoError> {
+    let location = match element.child("source-code-location") {
+        Some(location) => location,
+        None => return Ok(None),
+    };
+    Ok(Some(SourceLocation {
+        file: location.number("file-index")?.unwrap_or(0x0),
+        line: location.number("line")?.unwrap_or(0x0),
+    }))
+}
+
+
+/// An XML element, holding its attributes, child elements, and text.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+
+impl Element {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn text_of(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|child| child.text.trim())
+    }
+
+    fn number(&self, name: &str) -> Result<Option<u32>, DebugInfoError> {
+        match self.text_of(name) {
+            Some(text) => text.parse::<i64>()
+                .map(|value| Some(value as u32))
+                .map_err(|_| DebugInfoError::InvalidNumber {
+                    text: text.to_string(),
+                }),
+            None => Ok(None),
+        }
+    }
+}
+
+
+/// A parser for the subset of XML used by debug files.
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+
+impl<'a> Parser<'a> {
+    fn error(&self) -> DebugInfoError {
+        DebugInfoError::Syntax { offset: self.pos }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    /// Skips past the next occurrence of `end`.
+    fn skip_past(&mut self, end: &str) -> Result<(), DebugInfoError> {
+        match self.rest().find(end) {
+            Some(index) => {
+                self.pos += index + end.len();
+                Ok(())
+            },
+            None => Err(self.error()),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn parse_document(&mut self) -> Result<Element, DebugInfoError> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<!") {
+                self.skip_past(">")?;
+            } else {
+                return self.parse_element();
+            }
+        }
+    }
+
+    fn parse_element(&mut self) -> Result<Element, DebugInfoError> {
+        if !self.rest().starts_with('<') {
+            return Err(self.error());
+        }
+        self.pos += 0x1;
+        let name = self.parse_name()?;
+        let mut element = Element { name, ..Element::default() };
+
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 0x2;
+                return Ok(element);
+            } else if self.rest().starts_with('>') {
+                self.pos += 0x1;
+                break;
+            }
+            let attribute = self.parse_attribute()?;
+            element.attributes.push(attribute);
+        }
+
+        loop {
+            let rest = self.rest();
+            let text_len = rest.find('<').ok_or_else(|| self.error())?;
+            unescape(&rest[..text_len], &mut element.text)
+                .map_err(|offset| DebugInfoError::Syntax {
+                    offset: self.pos + offset,
+                })?;
+            self.pos += text_len;
+
+            let rest = self.rest();
+            if rest.starts_with("</") {
+                self.pos += 0x2;
+                if self.parse_name()? != element.name {
+                    return Err(self.error());
+                }
+                self.skip_whitespace();
+                if !self.rest().starts_with('>') {
+                    return Err(self.error());
+                }
+                self.pos += 0x1;
+                return Ok(element);
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<![CDATA[") {
+                self.pos += 0x9;
+                let end = self.rest().find("]]>").ok_or_else(|| self.error())?;
+                element.text.push_str(&self.rest()[..end]);
+                self.pos += end + 0x3;
+            } else {
+                let child = self.parse_element()?;
+                element.children.push(child);
+            }
+        }
+    }
+
+    fn parse_attribute(&mut self) -> Result<(String, String), DebugInfoError> {
+        let name = self.parse_name()?;
+        self.skip_whitespace();
+        if !self.rest().starts_with('=') {
+            return Err(self.error());
+        }
+        self.pos += 0x1;
+        self.skip_whitespace();
+
+        let quote = match self.rest().chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => quote,
+            _ => return Err(self.error()),
+        };
+        self.pos += 0x1;
+        let len = self.rest().find(quote).ok_or_else(|| self.error())?;
+        let mut value = String::new();
+        unescape(&self.rest()[..len], &mut value)
+            .map_err(|offset| DebugInfoError::Syntax {
+                offset: self.pos + offset,
+            })?;
+        self.pos += len + 0x1;
+        Ok((name, value))
+    }
+
+    fn parse_name(&mut self) -> Result<String, DebugInfoError> {
+        let rest = self.rest();
+        let len = rest.find(|ch: char| {
+                ch.is_whitespace() || ch == '>' || ch == '/' || ch == '='
+            })
+            .unwrap_or(rest.len());
+        if len == 0x0 {
+            return Err(self.error());
+        }
+        self.pos += len;
+        Ok(rest[..len].to_string())
+    }
+}
+
+
+/// Appends text to `out`, replacing entity and character references.
+/// Returns the offset of an invalid reference on failure.
+fn unescape(text: &str, out: &mut String) -> Result<(), usize> {
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find(';').ok_or(text.len() - rest.len() + start)?;
+        let entity = &rest[start + 0x1..start + end];
+        let ch = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[0x2..], 0x10)
+                .ok().and_then(::std::char::from_u32),
+            _ if entity.starts_with('#') => entity[0x1..].parse().ok()
+                .and_then(::std::char::from_u32),
+            _ => None,
+        };
+        out.push(ch.ok_or(text.len() - rest.len() + start)?);
+        rest = &rest[start + end + 0x1..];
+    }
+    out.push_str(rest);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{DebugInfo, DebugInfoError, SourceLocation};
+
+    const DEBUG_FILE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<inform-story-file version="1.0" content-creator="Inform">
+  <story-file-prefix>R2x1bA==</story-file-prefix>
+  <source index="1">
+    <given-path>story.inf</given-path>
+    <language>Inform 6</language>
+  </source>
+  <global-variable>
+    <identifier>location</identifier>
+    <address>1024</address>
+  </global-variable>
+  <constant><identifier>MAX_SCORE</identifier><value>10</value></constant>
+  <!-- the routine below prints "<&>" -->
+  <routine>
+    <identifier artificial="true">Main</identifier>
+    <value>36</value>
+    <byte-count>20</byte-count>
+    <source-code-location>
+      <file-index>0</file-index><file-position>5</file-position>
+      <line>3</line><character>1</character>
+    </source-code-location>
+    <local-variable>
+      <identifier>x</identifier><frame-offset>4</frame-offset>
+    </local-variable>
+    <sequence-point>
+      <address>7</address>
+      <source-code-location><file-index>0</file-index><line>4</line></source-code-location>
+    </sequence-point>
+    <sequence-point>
+      <address>12</address>
+      <source-code-location><file-index>0</file-index><line>5</line></source-code-location>
+    </sequence-point>
+  </routine>
+  <object><identifier>Kitchen&amp;Hall</identifier><value>1200</value></object>
+</inform-story-file>
+"#;
+
+    #[test]
+    fn parses_symbols() {
+        let info = DebugInfo::parse(DEBUG_FILE).unwrap();
+
+        assert_eq!(info.sources[&0x1], "story.inf");
+        assert_eq!(info.lookup("location"), Some(1024));
+        assert_eq!(info.lookup("MAX_SCORE"), Some(10));
+        assert_eq!(info.lookup("Kitchen&Hall"), Some(1200));
+        assert_eq!(info.name_of(1024), Some("location"));
+
+        let main = info.routine_named("Main").unwrap();
+        assert_eq!((main.address, main.length), (36, 20));
+        assert_eq!(main.locals[&0x4], "x");
+        assert_eq!(info.routine_at(55).map(|r| r.address), Some(36));
+        assert!(info.routine_at(56).is_none());
+        assert_eq!(info.source_location(46),
+            Some(SourceLocation { file: 0x0, line: 4 }));
+        assert_eq!(info.source_location(48),
+            Some(SourceLocation { file: 0x0, line: 5 }));
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert_eq!(DebugInfo::parse("<other></other>"),
+            Err(DebugInfoError::NotDebugInfo));
+        assert_eq!(DebugInfo::parse("<inform-story-file><routine>"),
+            Err(DebugInfoError::Syntax { offset: 28 }));
+        assert!(DebugInfo::parse("<a></b>").is_err());
+    }
+}
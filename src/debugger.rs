@@ -0,0 +1,366 @@
+//! # Glulx debugger
+//!
+//! The debugger runs a machine one instruction at a time, stopping at
+//! breakpoints and after each kind of step. Between steps the state of
+//! the machine can be inspected through the debugger's `Glulx`.
+//!
+//!
+//! ## Steps
+//!
+//! * Step -- Executes a single instruction
+//! * Step Over -- Executes instructions until control returns to the
+//!   current call frame, so calls are run to completion
+//! * Step Out -- Executes instructions until the current function
+//!   returns
+//!
+//! Step over and step out compare the call depth, which is the number of
+//! call frames on the stack, before and after each instruction.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use debug_info::DebugInfo;
+
+use disassembler::read_function;
+
+use error::GlulxError;
+
+use interpreter::Glulx;
+
+use watch::WatchEvent;
+
+
+/// Why the debugger stopped executing instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+
+    /// The requested step finished.
+    Stepped,
+
+    /// The next instruction is at the contained breakpoint address.
+    Breakpoint(u32),
+
+    /// The next instruction is the first of the contained function, which
+    /// has a breakpoint.
+    FunctionEntry(u32),
+
+    /// The last instruction hit a pausing watchpoint.
+    Watchpoint(WatchEvent),
+
+    /// The last instruction was a debug trap with the contained argument,
+    /// and its handler paused execution.
+    DebugTrap(u32),
+
+    /// The machine is waiting in `glk_select` for an event, or in a FyreVM
+    /// call for input, which must be given to it before it can continue.
+    Waiting,
+
+    /// The machine stopped running.
+    Halted,
+}
+
+
+/// Runs a machine under the control of breakpoints and steps.
+pub struct Debugger {
+    glulx: Glulx,
+    breakpoints: BTreeSet<u32>,
+
+    /// Function breakpoints, by the address of the function's first
+    /// instruction.
+    function_breakpoints: BTreeMap<u32, u32>,
+    debug_info: Option<DebugInfo>,
+}
+
+
+impl Debugger {
+
+    /// Starts the machine, stopping before the first instruction of its
+    /// start function.
+    pub fn new(mut glulx: Glulx) -> Result<Debugger, GlulxError> {
+        glulx.init()?;
+        Ok(Debugger {
+            glulx,
+            breakpoints: BTreeSet::new(),
+            function_breakpoints: BTreeMap::new(),
+            debug_info: None,
+        })
+    }
+
+    /// Uses the given debug information to name addresses.
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo) {
+        self.debug_info = Some(debug_info);
+    }
+
+    /// The debug information naming addresses, if any.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// The address or value of the symbol with the given name, such as a
+    /// routine or global variable.
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.debug_info.as_ref().and_then(|info| info.lookup(name))
+    }
+
+    /// Describes the given code address by its routine and source line,
+    /// such as `Main+0x7 (story.inf:12)`, if debug information covers it.
+    pub fn describe(&self, address: u32) -> Option<String> {
+        let info = self.debug_info.as_ref()?;
+        let routine = info.routine_at(address)?;
+        let mut text = format!("{}+{:#X}", routine.name,
+            address - routine.address);
+        if let Some(location) = info.source_location(address) {
+            let file = info.sources.get(&location.file)
+                .map(String::as_str)
+                .unwrap_or("?");
+            text.push_str(&format!(" ({}:{})", file, location.line));
+        }
+        Some(text)
+    }
+
+    /// The machine being debugged.
+    pub fn glulx(&self) -> &Glulx {
+        &self.glulx
+    }
+
+    /// The machine being debugged, for changing its state.
+    pub fn glulx_mut(&mut self) -> &mut Glulx {
+        &mut self.glulx
+    }
+
+    /// Stops execution before the instruction at the given address.
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Stops execution when the function at the given address is entered,
+    /// before its first instruction. Returns the address of the first
+    /// instruction.
+    pub fn add_function_breakpoint(&mut self, address: u32)
+            -> Result<u32, GlulxError> {
+        let (_, start) = read_function(self.glulx.memory(), address)?;
+        self.function_breakpoints.insert(start, address);
+        Ok(start)
+    }
+
+    /// Removes the address or function breakpoint at the given address,
+    /// returning whether there was one.
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        let function = self.function_breakpoints.iter()
+            .find(|&(_, &function)| function == address)
+            .map(|(&start, _)| start);
+        if let Some(start) = function {
+            self.function_breakpoints.remove(&start);
+            return true;
+        }
+        self.breakpoints.remove(&address)
+    }
+
+    /// The addresses of all breakpoints, followed by the addresses of the
+    /// functions with breakpoints.
+    pub fn breakpoints(&self) -> Vec<u32> {
+        self.breakpoints.iter()
+            .chain(self.function_breakpoints.values())
+            .cloned()
+            .collect()
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> Result<Stop, GlulxError> {
+        self.run_while(|_| false)
+    }
+
+    /// Executes a single instruction, continuing through any function it
+    /// calls until control returns to the current call frame.
+    pub fn step_over(&mut self) -> Result<Stop, GlulxError> {
+        let depth = self.glulx.call_depth();
+        self.run_while(|glulx| glulx.call_depth() > depth)
+    }
+
+    /// Executes instructions until the current function returns.
+    pub fn step_out(&mut self) -> Result<Stop, GlulxError> {
+        let depth = self.glulx.call_depth();
+        self.run_while(|glulx| glulx.call_depth() >= depth)
+    }
+
+    /// Executes instructions until a breakpoint is reached or the machine
+    /// stops.
+    pub fn resume(&mut self) -> Result<Stop, GlulxError> {
+        self.run_while(|_| true)
+    }
+
+    /// Executes at least one instruction, and then more while `condition`
+    /// holds, stopping early at breakpoints.
+    fn run_while<F>(&mut self, condition: F) -> Result<Stop, GlulxError>
+            where F: Fn(&Glulx) -> bool {
+        loop {
+            if !self.glulx.is_running() {
+                return Ok(Stop::Halted);
+            }
+            self.glulx.step()?;
+            if !self.glulx.is_running() {
+                return Ok(Stop::Halted);
+            }
+            if self.glulx.is_waiting() {
+                return Ok(Stop::Waiting);
+            }
+
+            if self.glulx.is_paused() {
+                if let Some(value) = self.glulx.take_trap() {
+                    return Ok(Stop::DebugTrap(value));
+                }
+                let events = self.glulx.take_watch_events();
+                if let Some(event) = events.into_iter().next() {
+                    return Ok(Stop::Watchpoint(event));
+                }
+            }
+
+            let program_counter = self.glulx.program_counter();
+            if let Some(&function) =
+                    self.function_breakpoints.get(&program_counter) {
+                return Ok(Stop::FunctionEntry(function));
+            }
+            if self.breakpoints.contains(&program_counter) {
+                return Ok(Stop::Breakpoint(program_counter));
+            }
+            if !condition(&self.glulx) {
+                return Ok(Stop::Stepped);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use assembler::Arg::*;
+    use assembler::Assembler;
+    use debug_info::DebugInfo;
+    use interpreter::Glulx;
+    use stack::Local;
+    use trap::TrapAction;
+    use watch::{Access, WatchAction};
+
+    use super::{Debugger, Stop};
+
+    /// The address of `inner`, which follows the header, the start
+    /// function's local format, and its `0x11` bytes of code.
+    const INNER: u32 = 0x24 + 0x5 + 0x11;
+
+    /// Builds a program where the start function calls `inner` twice,
+    /// and `inner` calls `leaf`.
+    fn debugger() -> Debugger {
+        let mut asm = Assembler::new();
+        let labels: Vec<_> = (0x0..0x3).map(|_| asm.label()).collect();
+        let (main, inner, leaf) = (labels[0x0], labels[0x1], labels[0x2]);
+        asm.start(main)
+            .function(main, 0xC1, &[(0x4, 0x1)])
+            .op("callf", &[Label(inner), Stack])
+            .op("callf", &[Label(inner), Local(0x0)])
+            .op("quit", &[])
+            .function(inner, 0xC1, &[(0x2, 0x1), (0x4, 0x1)])
+            .op("copy", &[Const(7), Local(0x4)])
+            .op("callf", &[Label(leaf), Stack])
+            .op("return", &[Stack])
+            .function(leaf, 0xC1, &[])
+            .op("return", &[Const(3)]);
+        let glulx = Glulx::from_rom(asm.finish().unwrap()).unwrap();
+        Debugger::new(glulx).unwrap()
+    }
+
+    #[test]
+    fn steps_over_and_out_of_calls() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.glulx().call_depth(), 0x1);
+
+        assert_eq!(debugger.step().unwrap(), Stop::Stepped);
+        assert_eq!(debugger.glulx().call_depth(), 0x2);
+        assert_eq!(debugger.step().unwrap(), Stop::Stepped);
+        assert_eq!(debugger.glulx().locals(), vec![
+            Local { offset: 0x0, size: 0x2, value: 0x0 },
+            Local { offset: 0x4, size: 0x4, value: 0x7 },
+        ]);
+
+        assert_eq!(debugger.step_out().unwrap(), Stop::Stepped);
+        assert_eq!(debugger.glulx().call_depth(), 0x1);
+        assert_eq!(debugger.glulx().values(), vec![0x3]);
+
+        assert_eq!(debugger.step_over().unwrap(), Stop::Stepped);
+        assert_eq!(debugger.glulx().call_depth(), 0x1);
+        assert_eq!(debugger.glulx().locals()[0x0].value, 0x3);
+        assert_eq!(debugger.glulx().program_counter(), INNER - 0x2);
+
+        assert_eq!(debugger.step_over().unwrap(), Stop::Halted);
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let mut debugger = debugger();
+        let start = debugger.add_function_breakpoint(INNER).unwrap();
+        assert_eq!(start, INNER + 0x7);
+
+        assert_eq!(debugger.resume().unwrap(), Stop::FunctionEntry(INNER));
+        assert_eq!(debugger.glulx().program_counter(), start);
+        assert_eq!(debugger.resume().unwrap(), Stop::FunctionEntry(INNER));
+
+        assert!(debugger.remove_breakpoint(INNER));
+        debugger.add_breakpoint(start + 0x4);
+        assert_eq!(debugger.step_out().unwrap(), Stop::Breakpoint(start + 0x4));
+        assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    }
+
+    #[test]
+    fn steps_past_watchpoints_and_traps() {
+        let mut asm = Assembler::new();
+        let (main, ram) = (asm.label(), asm.label());
+        asm.start(main)
+            .ram(ram, &[0x0; 0x4])
+            .function(main, 0xC1, &[])
+            .op("copy", &[Const(5), Ram(0x0)])
+            .op("nop", &[])
+            .op("debugtrap", &[Const(1)])
+            .op("nop", &[])
+            .op("quit", &[]);
+        let mut glulx = Glulx::from_rom(asm.finish().unwrap()).unwrap();
+        glulx.add_watchpoint(0x100..0x104, Access::Write, WatchAction::Pause);
+        glulx.set_trap_handler(Box::new(|_, _| TrapAction::Pause));
+        let mut debugger = Debugger::new(glulx).unwrap();
+
+        match debugger.step().unwrap() {
+            Stop::Watchpoint(event) => assert_eq!(event.new, vec![0, 0, 0, 5]),
+            stop => panic!("unexpected stop: {:?}", stop),
+        }
+        assert_eq!(debugger.step().unwrap(), Stop::Stepped);
+        assert!(!debugger.glulx().is_paused());
+        assert_eq!(debugger.step().unwrap(), Stop::DebugTrap(0x1));
+        assert_eq!(debugger.step().unwrap(), Stop::Stepped);
+        assert_eq!(debugger.glulx_mut().take_trap(), None);
+        assert_eq!(debugger.step().unwrap(), Stop::Halted);
+    }
+
+    #[test]
+    fn names_addresses_with_debug_info() {
+        let mut debugger = debugger();
+        let info = DebugInfo::parse(&format!("<inform-story-file>
+            <source index='0'><given-path>story.inf</given-path></source>
+            <routine>
+              <identifier>Inner</identifier><value>{}</value>
+              <byte-count>20</byte-count>
+              <sequence-point>
+                <address>7</address>
+                <source-code-location>
+                  <file-index>0</file-index><line>12</line>
+                </source-code-location>
+              </sequence-point>
+            </routine>
+            </inform-story-file>", INNER)).unwrap();
+        debugger.set_debug_info(info);
+
+        let inner = debugger.lookup("Inner").unwrap();
+        assert_eq!(inner, INNER);
+        debugger.add_function_breakpoint(inner).unwrap();
+        debugger.resume().unwrap();
+        let program_counter = debugger.glulx().program_counter();
+        assert_eq!(debugger.describe(program_counter).unwrap(),
+            "Inner+0x7 (story.inf:12)");
+    }
+}
@@ -0,0 +1,435 @@
+//! # Glulx disassembler
+//!
+//! The disassembler walks a story file starting from its start function.
+//! Functions are discovered by following calls with constant addresses,
+//! and the code within a function is found by following its branches and
+//! jumps. Strings printed with constant addresses are decoded so they can
+//! be shown alongside the instructions which print them.
+//!
+//! Code which is only reached through computed addresses, such as calls
+//! through a property table, can not be found this way.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use debug_info::DebugInfo;
+
+use error::GlulxError;
+
+use instruction::{
+    decode,
+    Branch,
+    Instruction,
+    Operand,
+};
+
+use memory::{
+    GlulxMemory,
+    Memory,
+};
+
+use string::decode_text;
+
+
+/// A function found by the disassembler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+
+    /// The address of the function's type byte.
+    pub address: u32,
+
+    /// The function type, `0xC0` for stack-argument functions and `0xC1`
+    /// for local-argument functions.
+    pub function_type: u8,
+
+    /// The local format of the function, as pairs of local size and count.
+    pub locals: Vec<(u8, u8)>,
+
+    /// The instructions reached within the function, by address.
+    pub instructions: BTreeMap<u32, Instruction>,
+}
+
+
+/// The disassembly of a story file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembly {
+
+    /// The address of the story's start function.
+    pub start_func: u32,
+
+    /// The functions found, by address.
+    pub functions: BTreeMap<u32, Function>,
+
+    /// The decoded text of strings printed with constant addresses.
+    pub strings: BTreeMap<u32, String>,
+
+    /// Errors found while disassembling. Code after an error is skipped.
+    pub errors: Vec<GlulxError>,
+}
+
+
+/// Disassembles the code reachable from the start function of the given
+/// memory.
+pub fn disassemble(memory: &GlulxMemory) -> Disassembly {
+    disassemble_functions(memory, &[])
+}
+
+
+/// Disassembles the code reachable from the start function and the given
+/// functions, such as functions found to be called while running.
+pub fn disassemble_functions(memory: &GlulxMemory, functions: &[u32])
+        -> Disassembly {
+    let mut disassembly = Disassembly {
+        start_func: memory.start_func(),
+        functions: BTreeMap::new(),
+        strings: BTreeMap::new(),
+        errors: vec![],
+    };
+
+    let mut pending = functions.to_vec();
+    pending.push(memory.start_func());
+    while let Some(address) = pending.pop() {
+        if disassembly.functions.contains_key(&address) {
+            continue;
+        }
+        match read_function(memory, address) {
+            Ok(function) => {
+                let function = disassembly.walk(memory, function, &mut pending);
+                disassembly.functions.insert(address, function);
+            },
+            Err(error) => disassembly.errors.push(error),
+        }
+    }
+    disassembly
+}
+
+
+/// Reads the header of the function at the given address, returning it
+/// along with the address of its first instruction.
+pub fn read_function(memory: &GlulxMemory, address: u32)
+        -> Result<(Function, u32), GlulxError> {
+    if address >= memory.get_mem_size() {
+        return Err(GlulxError::InvalidAddress { address });
+    }
+    let function_type: u8 = memory.read(address);
+    if function_type != 0xC0 && function_type != 0xC1 {
+        return Err(GlulxError::InvalidFunction { address });
+    }
+
+    let mut locals = vec![];
+    let mut ptr = address + 0x1;
+    loop {
+        if ptr + 0x2 > memory.get_mem_size() {
+            return Err(GlulxError::InvalidAddress { address: ptr });
+        }
+        let pair: (u8, u8) = (memory.read(ptr), memory.read(ptr + 0x1));
+        ptr += 0x2;
+        if pair.0 == 0x0 {
+            break;
+        }
+        locals.push(pair);
+    }
+
+    let function = Function {
+        address,
+        function_type,
+        locals,
+        instructions: BTreeMap::new(),
+    };
+    Ok((function, ptr))
+}
+
+
+/// The name of the function at the given address, from debug information
+/// if it names the function.
+pub(crate) fn function_name(address: u32, debug_info: Option<&DebugInfo>)
+        -> String {
+    debug_info
+        .and_then(|info| info.routines.get(&address))
+        .map(|routine| routine.name.clone())
+        .unwrap_or_else(|| format!("Func_{:08X}", address))
+}
+
+
+/// Whether the byte at the given address starts a function.
+fn is_function(memory: &GlulxMemory, address: u32) -> bool {
+    if address >= memory.get_mem_size() {
+        return false;
+    }
+    let function_type: u8 = memory.read(address);
+    function_type == 0xC0 || function_type == 0xC1
+}
+
+
+impl Disassembly {
+
+    /// Decodes the instructions reachable from the start of a function,
+    /// adding called functions to `pending`.
+    fn walk(&mut self,
+            memory: &GlulxMemory,
+            (mut function, start): (Function, u32),
+            pending: &mut Vec<u32>) -> Function {
+        let mut blocks = vec![start];
+        while let Some(mut address) = blocks.pop() {
+            while !function.instructions.contains_key(&address) {
+                let instruction = match decode(memory, address) {
+                    Ok(instruction) => instruction,
+                    Err(error) => {
+                        self.errors.push(error);
+                        break;
+                    },
+                };
+
+                if let Some(Branch::Jump(target)) = instruction.branch() {
+                    blocks.push(target);
+                }
+                let first = instruction.operands.first().cloned();
+                match (instruction.opcode.number, first) {
+                    (0x30, Some(Operand::Const(target)))
+                    | (0x34, Some(Operand::Const(target)))
+                    | (0x160..=0x163, Some(Operand::Const(target)))
+                            if is_function(memory, target as u32) => {
+                        pending.push(target as u32);
+                    },
+                    (0x104, Some(Operand::Const(target))) => {
+                        blocks.push(target as u32);
+                    },
+                    (0x72, Some(Operand::Const(target))) => {
+                        self.read_string(memory, target as u32);
+                    },
+                    _ => (),
+                }
+
+                let continues = instruction.opcode.continues();
+                address = instruction.next();
+                function.instructions.insert(instruction.address, instruction);
+                if !continues {
+                    break;
+                }
+            }
+        }
+        function
+    }
+
+    fn read_string(&mut self, memory: &GlulxMemory, address: u32) {
+        if self.strings.contains_key(&address) {
+            return;
+        }
+        match decode_text(memory, address, memory.decoding_tbl()) {
+            Ok(text) => {
+                self.strings.insert(address, text);
+            },
+            Err(error) => self.errors.push(error),
+        }
+    }
+}
+
+
+impl Disassembly {
+
+    /// Displays the disassembly with the names of routines, globals, and
+    /// locals taken from the given debug information, along with the
+    /// source lines of sequence points.
+    pub fn with_debug_info<'a>(&'a self, debug_info: &'a DebugInfo)
+            -> WithDebugInfo<'a> {
+        WithDebugInfo { disassembly: self, debug_info }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, debug_info: Option<&DebugInfo>)
+            -> fmt::Result {
+        writeln!(f, "! start function {:#010X}", self.start_func)?;
+        for function in self.functions.values() {
+            let routine = debug_info
+                .and_then(|info| info.routines.get(&function.address));
+
+            writeln!(f)?;
+            write!(f, "[ {}", function_name(function.address, debug_info))?;
+            let mut offset = 0x0u32;
+            for &(size, count) in &function.locals {
+                let size = u32::from(size);
+                offset = offset.div_ceil(size) * size;
+                for _ in 0x0..count {
+                    match routine.and_then(|r| r.locals.get(&offset)) {
+                        Some(name) => write!(f, " {}", name)?,
+                        None => write!(f, " local{}", offset)?,
+                    }
+                    offset += size;
+                }
+            }
+            writeln!(f, ";")?;
+            if function.function_type == 0xC0 {
+                writeln!(f, "    ! arguments passed on the stack")?;
+            }
+
+            let mut next = None;
+            for instruction in function.instructions.values() {
+                if next.is_some() && next != Some(instruction.address) {
+                    writeln!(f, "    ! ...")?;
+                }
+                let location = routine
+                    .and_then(|r| r.sequence_points.get(&instruction.address));
+                if let (Some(info), Some(location)) = (debug_info, location) {
+                    let file = info.sources.get(&location.file)
+                        .map(String::as_str)
+                        .unwrap_or("?");
+                    writeln!(f, "    ! {}:{}", file, location.line)?;
+                }
+
+                write!(f, "    {:08X}  ", instruction.address)?;
+                instruction.fmt_with(f, |operand| match (operand, debug_info) {
+                    (Operand::Const(value), Some(info)) => info.routines
+                        .get(&(value as u32))
+                        .map(|routine| routine.name.clone()),
+                    (Operand::Addr(address), Some(info)) => info
+                        .name_of(address)
+                        .map(str::to_string),
+                    (Operand::Local(offset), _) => routine
+                        .and_then(|r| r.locals.get(&offset))
+                        .cloned(),
+                    _ => None,
+                })?;
+                write!(f, ";")?;
+                if let (0x72, Some(&Operand::Const(address)))
+                        = (instruction.opcode.number, instruction.operands.first()) {
+                    if let Some(text) = self.strings.get(&(address as u32)) {
+                        write!(f, "  ! {:?}", text)?;
+                    }
+                }
+                writeln!(f)?;
+                next = Some(instruction.next());
+            }
+            writeln!(f, "];")?;
+        }
+
+        for error in &self.errors {
+            writeln!(f, "! error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+
+impl fmt::Display for Disassembly {
+
+    /// Formats the disassembly as assembly source, one function at a time.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, None)
+    }
+}
+
+
+/// Displays a disassembly using names from debug information.
+pub struct WithDebugInfo<'a> {
+    disassembly: &'a Disassembly,
+    debug_info: &'a DebugInfo,
+}
+
+
+impl<'a> fmt::Display for WithDebugInfo<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.disassembly.write(f, Some(self.debug_info))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use debug_info::{DebugInfo, Routine, SourceLocation};
+    use memory::GlulxMemory;
+
+    use super::disassemble;
+
+    /// Builds a memory with the given code placed at `0x100`, which is
+    /// also the start function.
+    fn memory_with_code(code: &[u8]) -> GlulxMemory {
+        let mut rom = vec![0x0; 0x200];
+        rom[0x0..0x4].copy_from_slice(b"Glul");
+        BigEndian::write_u32(&mut rom[0x4..], 0x00030102);
+        BigEndian::write_u32(&mut rom[0x8..], 0x200);
+        BigEndian::write_u32(&mut rom[0xC..], 0x200);
+        BigEndian::write_u32(&mut rom[0x10..], 0x200);
+        BigEndian::write_u32(&mut rom[0x14..], 0x100);
+        BigEndian::write_u32(&mut rom[0x18..], 0x100);
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+
+        let sum = rom.chunks(0x4)
+            .fold(0u32, |sum, word| sum.wrapping_add(BigEndian::read_u32(word)));
+        BigEndian::write_u32(&mut rom[0x20..], sum);
+        GlulxMemory::from_rom(rom).unwrap()
+    }
+
+    fn code() -> GlulxMemory {
+        memory_with_code(&[
+            // 0x100: C1 function with one 4 byte local
+            0xC1, 0x04, 0x01, 0x00, 0x00,
+            // 0x105: jz local0 ?0x10E
+            0x22, 0x19, 0x00, 0x07,
+            // 0x109: callf 0x120 -> discard
+            0x81, 0x60, 0x02, 0x01, 0x20,
+            // 0x10E: streamstr 0x130
+            0x72, 0x02, 0x01, 0x30,
+            // 0x112: return 0
+            0x31, 0x00,
+            // 0x114: unreachable
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            0xFF, 0xFF,
+            // 0x120: C0 function, return 1
+            0xC0, 0x00, 0x00, 0x31, 0x01, 0x01, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // 0x130: "Hi"
+            0xE0, b'H', b'i', 0x00,
+        ])
+    }
+
+    #[test]
+    fn follows_calls_and_branches() {
+        let memory = code();
+        let disassembly = disassemble(&memory);
+        assert!(disassembly.errors.is_empty());
+        assert_eq!(disassembly.functions.keys().collect::<Vec<_>>(),
+            vec![&0x100, &0x120]);
+
+        let start = &disassembly.functions[&0x100];
+        assert_eq!(start.locals, vec![(0x4, 0x1)]);
+        assert_eq!(start.instructions.keys().collect::<Vec<_>>(),
+            vec![&0x105, &0x109, &0x10E, &0x112]);
+        assert_eq!(disassembly.strings[&0x130], "Hi");
+
+        let text = disassembly.to_string();
+        assert!(text.contains("[ Func_00000100 local0;"));
+        assert!(text.contains("00000105  @jz local0 ?0x10E;"));
+        assert!(text.contains("0000010E  @streamstr 0x130;  ! \"Hi\""));
+        assert!(text.contains("00000123  @return 1;"));
+    }
+
+    #[test]
+    fn shows_debug_names() {
+        let routine = |name: &str, address| Routine {
+            name: name.to_string(),
+            address,
+            length: 0x10,
+            location: None,
+            locals: BTreeMap::new(),
+            sequence_points: BTreeMap::new(),
+        };
+        let mut main = routine("Main", 0x100);
+        main.locals.insert(0x0, "count".to_string());
+        main.sequence_points.insert(0x109, SourceLocation { file: 0x0, line: 7 });
+
+        let mut info = DebugInfo::default();
+        info.sources.insert(0x0, "story.inf".to_string());
+        info.routines.insert(0x100, main);
+        info.routines.insert(0x120, routine("Helper", 0x120));
+
+        let disassembly = disassemble(&code());
+        let text = disassembly.with_debug_info(&info).to_string();
+        assert!(text.contains("[ Main count;"));
+        assert!(text.contains("00000105  @jz count ?0x10E;"));
+        assert!(text.contains("    ! story.inf:7\n    00000109  @callf Helper 0;"));
+        assert!(text.contains("[ Helper;"));
+    }
+}
@@ -0,0 +1,245 @@
+use std::error;
+use std::fmt;
+
+use stack::Frame;
+
+
+/// Errors raised while executing a glulx program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GlulxError {
+
+    /// The stack grew past the stack size given in the header.
+    StackOverflow {
+
+        /// The number of call frames on the stack when it overflowed.
+        call_depth: u32,
+
+        /// The address of the instruction which overflowed the stack.
+        program_counter: u32,
+    },
+
+    /// An instruction needed more values than the current call frame
+    /// holds.
+    StackUnderflow {
+
+        /// The address of the instruction.
+        address: u32,
+    },
+
+    /// An instruction accessed a local outside the locals of the current
+    /// call frame.
+    InvalidLocal {
+
+        /// The offset of the local.
+        offset: u32,
+
+        /// The address of the instruction.
+        address: u32,
+    },
+
+    /// A `div` or `mod` instruction was given a divisor of zero.
+    DivisionByZero {
+
+        /// The address of the instruction.
+        address: u32,
+    },
+
+    /// An instruction used an opcode number which is not supported.
+    UnknownOpcode {
+
+        /// The opcode number.
+        opcode: u32,
+
+        /// The address of the instruction.
+        address: u32,
+    },
+
+    /// An instruction used an addressing mode which is not valid for its
+    /// operand.
+    InvalidOperandMode {
+
+        /// The addressing mode.
+        mode: u8,
+
+        /// The address of the instruction.
+        address: u32,
+    },
+
+    /// An access was made outside of memory.
+    InvalidAddress {
+
+        /// The address which was accessed.
+        address: u32,
+    },
+
+    /// An object called as a function is not a valid function.
+    InvalidFunction {
+
+        /// The address of the function.
+        address: u32,
+    },
+
+    /// An object printed as a string is not a valid string.
+    InvalidString {
+
+        /// The address of the string.
+        address: u32,
+    },
+
+    /// A string decoding table node has an unknown type.
+    InvalidStringNode {
+
+        /// The address of the node.
+        address: u32,
+    },
+
+    /// A throw was given a catch token which is not on the stack.
+    InvalidCatchToken {
+
+        /// The catch token.
+        token: u32,
+
+        /// The address of the instruction.
+        address: u32,
+    },
+
+    /// A debug trap handler aborted execution, or a debug trap was hit
+    /// with no handler installed.
+    DebugTrap {
+
+        /// The argument of the `debugtrap` opcode.
+        value: u32,
+
+        /// The address of the instruction.
+        address: u32,
+    },
+
+    /// A call stub of a type which cannot be returned to, such as the
+    /// stub which ends printing of a string.
+    InvalidCallStub {
+
+        /// The destination type of the call stub.
+        dest_type: u32,
+    },
+
+    /// The `fyrecall` opcode was given a call number which names no
+    /// FyreVM call.
+    InvalidFyreCall {
+
+        /// The call number.
+        call: u32,
+
+        /// The address of the instruction.
+        address: u32,
+    },
+
+    /// A snapshot or autosave was taken from another story, or does not
+    /// fit the story.
+    IncompatibleSnapshot,
+
+    /// A snapshot or autosave could not be read, or the Glk library could
+    /// not restore its state from it.
+    CorruptSnapshot {
+
+        /// Why it could not be restored.
+        message: String,
+    },
+
+    /// A Glk function failed, or the selector named no Glk function.
+    Glk {
+
+        /// The Glk function selector.
+        selector: u32,
+
+        /// Why the call failed.
+        message: &'static str,
+    },
+
+    /// An error which stopped a running machine, with the call frames on
+    /// the stack when it happened.
+    Fatal {
+
+        /// The error raised by the failing instruction.
+        error: Box<GlulxError>,
+
+        /// The call frames, from the innermost frame outwards.
+        backtrace: Vec<Frame>,
+    },
+}
+
+
+impl fmt::Display for GlulxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GlulxError::StackOverflow { call_depth, program_counter } => {
+                write!(f, "stack overflow at {:#X} with call depth {}",
+                    program_counter, call_depth)
+            },
+            GlulxError::StackUnderflow { address } => {
+                write!(f, "stack underflow at {:#X}", address)
+            },
+            GlulxError::InvalidLocal { offset, address } => {
+                write!(f, "invalid local {:#X} at {:#X}", offset, address)
+            },
+            GlulxError::DivisionByZero { address } => {
+                write!(f, "division by zero at {:#X}", address)
+            },
+            GlulxError::UnknownOpcode { opcode, address } => {
+                write!(f, "unknown opcode {:#X} at {:#X}", opcode, address)
+            },
+            GlulxError::InvalidOperandMode { mode, address } => {
+                write!(f, "invalid operand mode {:#X} at {:#X}", mode, address)
+            },
+            GlulxError::InvalidAddress { address } => {
+                write!(f, "invalid memory access at {:#X}", address)
+            },
+            GlulxError::InvalidFunction { address } => {
+                write!(f, "invalid function at {:#X}", address)
+            },
+            GlulxError::InvalidString { address } => {
+                write!(f, "invalid string at {:#X}", address)
+            },
+            GlulxError::InvalidStringNode { address } => {
+                write!(f, "invalid string decoding node at {:#X}", address)
+            },
+            GlulxError::InvalidCatchToken { token, address } => {
+                write!(f, "invalid catch token {:#X} at {:#X}", token, address)
+            },
+            GlulxError::DebugTrap { value, address } => {
+                write!(f, "debug trap {:#X} at {:#X}", value, address)
+            },
+            GlulxError::InvalidCallStub { dest_type } => {
+                write!(f, "invalid call stub type {:#X}", dest_type)
+            },
+            GlulxError::InvalidFyreCall { call, address } => {
+                write!(f, "invalid FyreVM call {:#X} at {:#X}", call, address)
+            },
+            GlulxError::IncompatibleSnapshot => {
+                write!(f, "snapshot was taken from another story")
+            },
+            GlulxError::CorruptSnapshot { ref message } => {
+                write!(f, "corrupt snapshot: {}", message)
+            },
+            GlulxError::Glk { selector, message } => {
+                write!(f, "glk function {:#X}: {}", selector, message)
+            },
+            GlulxError::Fatal { ref error, ref backtrace } => {
+                write!(f, "{}", error)?;
+                for (index, frame) in backtrace.iter().enumerate() {
+                    write!(f, "\n  #{} {}", index, frame)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+
+impl error::Error for GlulxError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            GlulxError::Fatal { ref error, .. } => Some(&**error),
+            _ => None,
+        }
+    }
+}
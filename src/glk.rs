@@ -0,0 +1,2457 @@
+//! # Glk
+//!
+//! Glulx programs perform all input and output through Glk, by calling
+//! the `glk` opcode with a function selector and its arguments. The
+//! machine passes each call to its `Glk`, which reads and writes the
+//! memory of the machine through a `GlkMemory`.
+//!
+//!
+//! ## Arguments
+//!
+//! Arguments follow the conventions of the Glulx dispatch layer:
+//!
+//! * Objects -- Windows, streams, filerefs and sound channels are passed
+//!   as non-zero identifiers, with zero for none
+//! * Strings -- Passed as the address of an unencoded string, starting
+//!   with `0xE0`, or `0xE2` for the Unicode functions
+//! * Arrays -- Passed as an address followed by a length
+//! * References -- Passed as an address, with zero to discard the value
+//!   and `-1` to push it onto the stack after the call
+//!
+//!
+//! ## Text Glk
+//!
+//! `TextGlk` is a Glk for hosts which only show text. Windows are laid
+//! out on a screen measured in characters, and the text printed to each
+//! window is kept until the host takes it. Input is queued by the host,
+//! and delivered to the program by `glk_select`. When no input is
+//! queued, `glk_select` returns `None` and the machine waits.
+//!
+//! When given the `Blorb` the story was loaded from, `TextGlk` answers
+//! questions about its pictures, opens its data as resource streams, and
+//! accepts requests to show pictures and play sounds, which a text host
+//! has no way to present.
+
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use blorb::{Blorb, Usage};
+
+use error::GlulxError;
+
+use memory::{
+    GlulxMemory,
+    Memory,
+};
+
+use string::{
+    read_c_string,
+    read_unicode_string,
+};
+
+
+/// The key code of the return key.
+pub const KEY_RETURN: u32 = 0xFFFFFFFA;
+
+
+/// The key code of keys with no key code of their own.
+pub const KEY_UNKNOWN: u32 = 0xFFFFFFFF;
+
+
+const EVTYPE_NONE: u32 = 0x0;
+const EVTYPE_TIMER: u32 = 0x1;
+const EVTYPE_CHAR_INPUT: u32 = 0x2;
+const EVTYPE_LINE_INPUT: u32 = 0x3;
+const EVTYPE_ARRANGE: u32 = 0x5;
+
+const WINMETHOD_DIR_MASK: u32 = 0x0F;
+const WINMETHOD_ABOVE: u32 = 0x02;
+const WINMETHOD_PROPORTIONAL: u32 = 0x20;
+
+const FILEMODE_WRITE: u32 = 0x01;
+const FILEMODE_READ: u32 = 0x02;
+const FILEMODE_READ_WRITE: u32 = 0x03;
+const FILEMODE_WRITE_APPEND: u32 = 0x05;
+
+const FILEUSAGE_TYPE_MASK: u32 = 0x0F;
+const FILEUSAGE_TEXT_MODE: u32 = 0x100;
+
+const SEEKMODE_CURRENT: u32 = 0x1;
+const SEEKMODE_END: u32 = 0x2;
+
+
+/// A Glk implementation, called by the `glk` opcode.
+pub trait Glk: Any + Send {
+
+    /// Calls the Glk function with the given selector. Returns the result
+    /// of the function, or `None` when the function is `glk_select` and
+    /// no event is ready, in which case the machine waits until `select`
+    /// returns true.
+    fn call(&mut self, selector: u32, args: &[u32], memory: &mut GlkMemory)
+        -> Result<Option<u32>, GlulxError>;
+
+    /// Completes a `glk_select` call which returned `None`, storing the
+    /// event. Returns whether an event was ready.
+    fn select(&mut self, memory: &mut GlkMemory) -> Result<bool, GlulxError>;
+
+    /// Writes bytes to the given stream, for the `save` opcode. Returns
+    /// whether the stream could be written.
+    fn write_stream(&mut self,
+            stream: u32,
+            bytes: &[u8],
+            memory: &mut GlulxMemory) -> bool;
+
+    /// Reads the remaining bytes of the given stream, for the `restore`
+    /// opcode.
+    fn read_stream(&mut self, stream: u32, memory: &GlulxMemory)
+        -> Option<Vec<u8>>;
+
+    /// The state of the library, for an autosave, if it can be saved.
+    fn save_state(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Restores the state of the library from an autosave, returning
+    /// whether it could be restored.
+    fn restore_state(&mut self, _state: &[u8]) -> bool {
+        false
+    }
+
+    /// Queues input for the program, as given by a replay. Returns false,
+    /// discarding the input, if the library does not take queued input.
+    fn queue_input(&mut self, _input: Input) -> bool {
+        false
+    }
+
+    /// Tells the recorder of the input given to the program, and the text
+    /// it prints, from now on, replacing any recorder already set.
+    /// Returns false if the library cannot be recorded.
+    fn set_recorder(&mut self, _recorder: Box<dyn Recorder>) -> bool {
+        false
+    }
+
+    /// The recorder, if one is set.
+    fn recorder(&self) -> Option<&dyn Recorder> {
+        None
+    }
+
+    /// Removes the recorder, returning it.
+    fn take_recorder(&mut self) -> Option<Box<dyn Recorder>> {
+        None
+    }
+}
+
+
+/// Told of the input events given to a program and the text it prints to
+/// text buffer windows, such as to record a `Replay`.
+pub trait Recorder: Any + Send {
+
+    /// Called as an input event is given to the program.
+    fn input(&mut self, input: &Input);
+
+    /// Called with the characters printed to a text buffer window.
+    fn output(&mut self, chars: &[u32]);
+}
+
+
+/// The memory of the machine, as seen by a Glk call.
+pub struct GlkMemory<'a> {
+    memory: &'a mut GlulxMemory,
+
+    /// Values stored through references given as `-1`, to be pushed onto
+    /// the stack after the call.
+    pushes: Vec<u32>,
+}
+
+
+impl<'a> GlkMemory<'a> {
+
+    pub fn new(memory: &'a mut GlulxMemory) -> GlkMemory<'a> {
+        GlkMemory { memory, pushes: vec![] }
+    }
+
+    /// The values to push onto the stack after the call, in order.
+    pub fn into_pushes(self) -> Vec<u32> {
+        self.pushes
+    }
+
+    /// The memory of the machine.
+    pub fn memory(&mut self) -> &mut GlulxMemory {
+        self.memory
+    }
+
+    /// Checks that `len` bytes at `ptr` lie within memory.
+    fn check(&self, ptr: u32, len: u32) -> Result<u32, GlulxError> {
+        check(self.memory, ptr, len)
+    }
+
+    pub fn read_u8(&self, ptr: u32) -> Result<u8, GlulxError> {
+        Ok(self.memory.read(self.check(ptr, 0x1)?))
+    }
+
+    pub fn read_u32(&self, ptr: u32) -> Result<u32, GlulxError> {
+        Ok(self.memory.read(self.check(ptr, 0x4)?))
+    }
+
+    pub fn write_u8(&mut self, ptr: u32, value: u8) -> Result<(), GlulxError> {
+        let ptr = self.check(ptr, 0x1)?;
+        self.memory.write(ptr, value);
+        Ok(())
+    }
+
+    pub fn write_u32(&mut self, ptr: u32, value: u32)
+            -> Result<(), GlulxError> {
+        let ptr = self.check(ptr, 0x4)?;
+        self.memory.write(ptr, value);
+        Ok(())
+    }
+
+    /// Stores a value through a reference.
+    pub fn store(&mut self, ptr: u32, value: u32) -> Result<(), GlulxError> {
+        self.store_all(ptr, &[value])
+    }
+
+    /// Stores the fields of a structure through a reference.
+    pub fn store_all(&mut self, ptr: u32, values: &[u32])
+            -> Result<(), GlulxError> {
+        match ptr {
+            0x0 => (),
+            0xFFFFFFFF => self.pushes.extend_from_slice(values),
+            _ => {
+                for (index, &value) in values.iter().enumerate() {
+                    self.write_u32(element_ptr(ptr, index as u32, 0x4)?, value)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Reads the fields of a structure at the given address.
+    pub fn read_all(&self, ptr: u32, len: u32) -> Result<Vec<u32>, GlulxError> {
+        (0x0..len).map(|index| self.read_u32(element_ptr(ptr, index, 0x4)?))
+            .collect()
+    }
+
+    /// Reads a string argument, which must be unencoded.
+    pub fn read_string(&self, ptr: u32) -> Result<String, GlulxError> {
+        match self.read_u8(ptr)? {
+            0xE0 => read_c_string(self.memory, ptr + 0x1),
+            0xE2 => read_unicode_string(self.memory, ptr + 0x4),
+            _ => Err(GlulxError::InvalidString { address: ptr }),
+        }
+    }
+}
+
+
+/// The kind of a window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowKind {
+    Pair,
+    Blank,
+    TextBuffer,
+    TextGrid,
+    Graphics,
+}
+
+
+impl WindowKind {
+    fn from_u32(value: u32) -> Option<WindowKind> {
+        match value {
+            0x1 => Some(WindowKind::Pair),
+            0x2 => Some(WindowKind::Blank),
+            0x3 => Some(WindowKind::TextBuffer),
+            0x4 => Some(WindowKind::TextGrid),
+            0x5 => Some(WindowKind::Graphics),
+            _ => None,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            WindowKind::Pair => 0x1,
+            WindowKind::Blank => 0x2,
+            WindowKind::TextBuffer => 0x3,
+            WindowKind::TextGrid => 0x4,
+            WindowKind::Graphics => 0x5,
+        }
+    }
+}
+
+
+/// A description of a window, for the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowInfo {
+    pub id: u32,
+    pub kind: WindowKind,
+    pub rock: u32,
+    pub parent: Option<u32>,
+
+    /// The width of the window in characters.
+    pub width: u32,
+
+    /// The height of the window in lines.
+    pub height: u32,
+}
+
+
+/// Input from the host, delivered to the program by `glk_select`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+
+    /// A line of text, for the window which requested line input. When
+    /// only character input was requested, the first character of the
+    /// line is sent instead, or return for an empty line.
+    Line(String),
+
+    /// A character or key code, for the window which requested character
+    /// input.
+    Char(u32),
+
+    /// A timer tick, sent only while timer events are requested.
+    Timer,
+
+    /// A change to the size of the screen.
+    Arrange {
+        width: u32,
+        height: u32,
+    },
+}
+
+
+/// The purpose of a file, given to the prompt handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileUsage {
+    Data,
+    SavedGame,
+    Transcript,
+    InputRecord,
+}
+
+
+/// Chooses the path of a file for `glk_fileref_create_by_prompt`, given
+/// its usage and whether it will be written. Returning `None` cancels
+/// the prompt.
+pub type PromptHandler =
+    Box<dyn FnMut(FileUsage, bool) -> Option<PathBuf> + Send>;
+
+
+/// A pending request for line input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LineRequest {
+    buffer: u32,
+    len: u32,
+    unicode: bool,
+}
+
+
+/// How a pair window divides its space. The size, measured in the units
+/// of the key window, is given to the window which was split off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Split {
+    method: u32,
+    size: u32,
+    key: Option<u32>,
+
+    /// The window opened by the split.
+    new: u32,
+
+    /// The window which was split.
+    old: u32,
+}
+
+
+struct Window {
+    rock: u32,
+    kind: WindowKind,
+    parent: Option<u32>,
+    stream: u32,
+    echo: Option<u32>,
+    split: Option<Split>,
+    width: u32,
+    height: u32,
+
+    /// Text printed to a text buffer window, not yet taken by the host.
+    text: String,
+    grid: Vec<Vec<char>>,
+    cursor: (u32, u32),
+    line: Option<LineRequest>,
+
+    /// Whether character input is requested, and whether it is Unicode.
+    char_input: Option<bool>,
+    echo_input: bool,
+}
+
+
+impl Window {
+
+    /// Prints text to the window.
+    fn print(&mut self, chars: &[u32]) {
+        for &ch in chars {
+            let ch = ::std::char::from_u32(ch).unwrap_or('\u{FFFD}');
+            match self.kind {
+                WindowKind::TextBuffer => self.text.push(ch),
+                WindowKind::TextGrid => self.print_grid(ch),
+                _ => (),
+            }
+        }
+    }
+
+    fn print_grid(&mut self, ch: char) {
+        let (x, y) = self.cursor;
+        if ch == '\n' {
+            self.cursor = (0x0, y + 0x1);
+            return;
+        }
+        if x >= self.width {
+            self.cursor = (0x0, y + 0x1);
+            return self.print_grid(ch);
+        }
+        if let Some(row) = self.grid.get_mut(y as usize) {
+            row[x as usize] = ch;
+        }
+        self.cursor = (x + 0x1, y);
+    }
+
+    fn clear(&mut self) {
+        self.text.clear();
+        for row in &mut self.grid {
+            for cell in row.iter_mut() {
+                *cell = ' ';
+            }
+        }
+        self.cursor = (0x0, 0x0);
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        if self.kind == WindowKind::TextGrid {
+            self.grid.resize(height as usize, vec![]);
+            for row in &mut self.grid {
+                row.resize(width as usize, ' ');
+            }
+        }
+    }
+}
+
+
+enum StreamKind {
+    Window(u32),
+
+    /// A stream of characters in the memory of the machine.
+    Memory {
+        buffer: u32,
+        len: u32,
+        unicode: bool,
+    },
+
+    File {
+        file: File,
+        path: PathBuf,
+        unicode: bool,
+        text: bool,
+    },
+
+    /// A data resource of the Blorb file, which is only read.
+    Resource {
+        data: Cursor<Vec<u8>>,
+        number: u32,
+        unicode: bool,
+        text: bool,
+    },
+}
+
+
+struct Stream {
+    rock: u32,
+    kind: StreamKind,
+    mode: u32,
+
+    /// The position in a memory stream, in characters.
+    position: u32,
+    read: u32,
+    written: u32,
+}
+
+
+struct Fileref {
+    rock: u32,
+    path: PathBuf,
+    usage: u32,
+}
+
+
+struct Channel {
+    rock: u32,
+}
+
+
+/// A Glk for hosts which only show text.
+pub struct TextGlk {
+    next_id: u32,
+    windows: BTreeMap<u32, Window>,
+    root: Option<u32>,
+    streams: BTreeMap<u32, Stream>,
+    filerefs: BTreeMap<u32, Fileref>,
+    channels: BTreeMap<u32, Channel>,
+    current: Option<u32>,
+
+    /// The size of the screen, in characters and lines.
+    screen: (u32, u32),
+    inputs: VecDeque<Input>,
+
+    /// The event structure of a `glk_select` call which is waiting for
+    /// input.
+    select: Option<u32>,
+
+    /// The interval of timer events, or zero when none are requested.
+    timer: u32,
+    echo_input: bool,
+    directory: PathBuf,
+    prompt: Option<PromptHandler>,
+    blorb: Option<Blorb>,
+
+    /// Told of the input events given to the program, and the text
+    /// printed to text buffer windows.
+    recorder: Option<Box<dyn Recorder>>,
+}
+
+
+impl Default for TextGlk {
+    fn default() -> TextGlk {
+        TextGlk::new()
+    }
+}
+
+
+impl TextGlk {
+
+    /// Creates a Glk with an 80 by 24 screen and no windows, which
+    /// creates named files in the current directory.
+    pub fn new() -> TextGlk {
+        TextGlk {
+            next_id: 0x1,
+            windows: BTreeMap::new(),
+            root: None,
+            streams: BTreeMap::new(),
+            filerefs: BTreeMap::new(),
+            channels: BTreeMap::new(),
+            current: None,
+            screen: (0x50, 0x18),
+            inputs: VecDeque::new(),
+            select: None,
+            timer: 0x0,
+            echo_input: true,
+            directory: PathBuf::from("."),
+            prompt: None,
+            blorb: None,
+            recorder: None,
+        }
+    }
+
+    /// Creates files named by the program in the given directory.
+    pub fn set_directory(&mut self, directory: PathBuf) {
+        self.directory = directory;
+    }
+
+    /// Chooses the files asked for by the program with the given handler.
+    /// Without a handler, every prompt is cancelled.
+    pub fn set_prompt_handler(&mut self, handler: PromptHandler) {
+        self.prompt = Some(handler);
+    }
+
+    /// Gives the program the pictures, sounds and data of the Blorb file
+    /// it was loaded from.
+    pub fn set_blorb(&mut self, blorb: Blorb) {
+        self.blorb = Some(blorb);
+    }
+
+    /// The Blorb file the program was loaded from, if any.
+    pub fn blorb(&self) -> Option<&Blorb> {
+        self.blorb.as_ref()
+    }
+
+    /// Sets whether line input is printed to the window which requested
+    /// it, when the program asks for input to be echoed. Hosts which show
+    /// the input themselves, such as terminals, should turn this off.
+    pub fn set_echo_input(&mut self, echo: bool) {
+        self.echo_input = echo;
+    }
+
+    /// Queues input for the program.
+    pub fn push_input(&mut self, input: Input) {
+        self.inputs.push_back(input);
+    }
+
+    /// Tells the recorder of an input event as it is given to the
+    /// program.
+    fn record(&mut self, input: Input) {
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.input(&input);
+        }
+    }
+
+    /// Whether the program is waiting in `glk_select` for input.
+    pub fn is_waiting(&self) -> bool {
+        self.select.is_some()
+    }
+
+    /// Whether any window has requested line input.
+    pub fn line_requested(&self) -> bool {
+        self.windows.values().any(|window| window.line.is_some())
+    }
+
+    /// Whether any window has requested character input.
+    pub fn char_requested(&self) -> bool {
+        self.windows.values().any(|window| window.char_input.is_some())
+    }
+
+    /// The size of the screen, in characters and lines.
+    pub fn screen_size(&self) -> (u32, u32) {
+        self.screen
+    }
+
+    /// The windows which are open, in the order they were opened.
+    pub fn windows(&self) -> Vec<WindowInfo> {
+        self.windows.iter()
+            .map(|(&id, window)| WindowInfo {
+                id,
+                kind: window.kind,
+                rock: window.rock,
+                parent: window.parent,
+                width: window.width,
+                height: window.height,
+            })
+            .collect()
+    }
+
+    /// Removes and returns the text printed to the given window since it
+    /// was last taken.
+    pub fn take_text(&mut self, window: u32) -> String {
+        self.windows.get_mut(&window)
+            .map(|window| ::std::mem::take(&mut window.text))
+            .unwrap_or_default()
+    }
+
+    /// Removes and returns the text printed to every text buffer window,
+    /// in the order the windows were opened.
+    pub fn take_output(&mut self) -> String {
+        let mut text = String::new();
+        for window in self.windows.values_mut() {
+            text.push_str(&window.text);
+            window.text.clear();
+        }
+        text
+    }
+
+    /// The lines of a text grid window, without trailing spaces.
+    pub fn grid_lines(&self, window: u32) -> Vec<String> {
+        self.windows.get(&window)
+            .map(|window| {
+                window.grid.iter()
+                    .map(|row| {
+                        row.iter().collect::<String>().trim_end().to_string()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn create_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 0x1;
+        id
+    }
+
+    fn window(&mut self, selector: u32, id: u32)
+            -> Result<&mut Window, GlulxError> {
+        self.windows.get_mut(&id)
+            .ok_or(GlulxError::Glk { selector, message: "invalid window" })
+    }
+
+    fn stream(&mut self, selector: u32, id: u32)
+            -> Result<&mut Stream, GlulxError> {
+        self.streams.get_mut(&id)
+            .ok_or(GlulxError::Glk { selector, message: "invalid stream" })
+    }
+
+    fn fileref(&self, selector: u32, id: u32)
+            -> Result<&Fileref, GlulxError> {
+        self.filerefs.get(&id)
+            .ok_or(GlulxError::Glk { selector, message: "invalid fileref" })
+    }
+
+    fn channel(&self, selector: u32, id: u32)
+            -> Result<&Channel, GlulxError> {
+        self.channels.get(&id)
+            .ok_or(GlulxError::Glk { selector, message: "invalid sound channel" })
+    }
+
+    /// Whether the Blorb file has any resources with the given usage.
+    fn has_resources(&self, usage: Usage) -> bool {
+        self.blorb.as_ref().is_some_and(|blorb| blorb.has(usage))
+    }
+
+    fn has_resource(&self, usage: Usage, number: u32) -> bool {
+        self.blorb.as_ref()
+            .is_some_and(|blorb| blorb.resource(usage, number).is_some())
+    }
+
+    /// Opens a stream reading a data resource, which is read as text when
+    /// held in a `TEXT` chunk.
+    fn open_resource(&mut self, number: u32, rock: u32, unicode: bool) -> u32 {
+        let kind = match self.blorb.as_ref()
+                .and_then(|blorb| blorb.resource(Usage::Data, number)) {
+            Some(resource) => StreamKind::Resource {
+                data: Cursor::new(resource.data.clone()),
+                number,
+                unicode,
+                text: &resource.kind == b"TEXT",
+            },
+            None => return 0x0,
+        };
+        self.open_stream(rock, kind, FILEMODE_READ)
+    }
+
+    /// Whether a picture can be drawn in the given window.
+    fn draw_image(&mut self, selector: u32, id: u32, image: u32)
+            -> Result<u32, GlulxError> {
+        let kind = self.window(selector, id)?.kind;
+        let drawable = kind == WindowKind::TextBuffer
+            || kind == WindowKind::Graphics;
+        Ok((drawable && self.has_resource(Usage::Picture, image)) as u32)
+    }
+
+    fn open_stream(&mut self, rock: u32, kind: StreamKind, mode: u32) -> u32 {
+        let id = self.create_id();
+        self.streams.insert(id, Stream {
+            rock,
+            kind,
+            mode,
+            position: 0x0,
+            read: 0x0,
+            written: 0x0,
+        });
+        id
+    }
+
+    /// Closes a stream, returning its read and write counts.
+    fn close_stream(&mut self, id: u32) -> [u32; 0x2] {
+        let counts = match self.streams.remove(&id) {
+            Some(stream) => [stream.read, stream.written],
+            None => [0x0, 0x0],
+        };
+        if self.current == Some(id) {
+            self.current = None;
+        }
+        for window in self.windows.values_mut() {
+            if window.echo == Some(id) {
+                window.echo = None;
+            }
+        }
+        counts
+    }
+
+    fn open_window(&mut self,
+            split: u32,
+            method: u32,
+            size: u32,
+            kind: u32,
+            rock: u32) -> Result<u32, GlulxError> {
+        let kind = match WindowKind::from_u32(kind) {
+            Some(WindowKind::Pair) | None => return Ok(0x0),
+            Some(kind) => kind,
+        };
+        if split == 0x0 && self.root.is_some() {
+            return Ok(0x0);
+        }
+        if split != 0x0 {
+            self.window(0x23, split)?;
+        }
+
+        let id = self.create_id();
+        let stream = self.open_stream(0x0, StreamKind::Window(id),
+            FILEMODE_WRITE);
+        self.windows.insert(id, Window::new(kind, rock, stream));
+
+        if split == 0x0 {
+            self.root = Some(id);
+        } else {
+            let pair = self.create_id();
+            let stream = self.open_stream(0x0, StreamKind::Window(pair),
+                FILEMODE_WRITE);
+            let mut window = Window::new(WindowKind::Pair, 0x0, stream);
+            let parent = self.windows[&split].parent;
+            window.parent = parent;
+            window.split = Some(Split {
+                method,
+                size,
+                key: Some(id),
+                new: id,
+                old: split,
+            });
+            self.windows.insert(pair, window);
+            self.replace_child(parent, split, pair);
+            self.windows.get_mut(&split).unwrap().parent = Some(pair);
+            self.windows.get_mut(&id).unwrap().parent = Some(pair);
+        }
+        self.layout();
+        Ok(id)
+    }
+
+    /// Replaces a child of the given pair window, or the root window.
+    fn replace_child(&mut self, parent: Option<u32>, old: u32, new: u32) {
+        match parent {
+            Some(parent) => {
+                let split = self.windows.get_mut(&parent)
+                    .and_then(|window| window.split.as_mut())
+                    .unwrap();
+                if split.new == old {
+                    split.new = new;
+                } else {
+                    split.old = new;
+                }
+            },
+            None => self.root = Some(new),
+        }
+    }
+
+    /// Closes a window, returning the read and write counts of its
+    /// stream.
+    fn close_window(&mut self, id: u32) -> Result<[u32; 0x2], GlulxError> {
+        let (parent, stream) = {
+            let window = self.window(0x24, id)?;
+            (window.parent, window.stream)
+        };
+        let counts = {
+            let stream = &self.streams[&stream];
+            [stream.read, stream.written]
+        };
+
+        match parent {
+            None => self.root = None,
+            Some(pair) => {
+                let (split, grandparent) = {
+                    let window = &self.windows[&pair];
+                    (window.split.unwrap(), window.parent)
+                };
+                let sibling = if split.new == id { split.old } else { split.new };
+                self.windows.get_mut(&sibling).unwrap().parent = grandparent;
+                self.replace_child(grandparent, pair, sibling);
+                self.windows.get_mut(&pair).unwrap().split = None;
+                self.destroy_window(pair);
+            },
+        }
+        self.destroy_window(id);
+
+        for window in self.windows.values_mut() {
+            if let Some(ref mut split) = window.split {
+                if split.key.is_some_and(|key| key == id) {
+                    split.key = None;
+                }
+            }
+        }
+        self.layout();
+        Ok(counts)
+    }
+
+    /// Removes a window and the windows within it.
+    fn destroy_window(&mut self, id: u32) {
+        if let Some(window) = self.windows.remove(&id) {
+            if let Some(split) = window.split {
+                self.destroy_window(split.new);
+                self.destroy_window(split.old);
+            }
+            self.close_stream(window.stream);
+        }
+    }
+
+    /// Divides the screen between the windows.
+    fn layout(&mut self) {
+        if let Some(root) = self.root {
+            let (width, height) = self.screen;
+            self.place(root, width, height);
+        }
+    }
+
+    fn place(&mut self, id: u32, width: u32, height: u32) {
+        let split = {
+            let window = self.windows.get_mut(&id).unwrap();
+            window.resize(width, height);
+            window.split
+        };
+        let split = match split {
+            Some(split) => split,
+            None => return,
+        };
+
+        let vertical = split.method & WINMETHOD_DIR_MASK >= WINMETHOD_ABOVE;
+        let total = if vertical { height } else { width };
+        let size = if split.method & WINMETHOD_PROPORTIONAL != 0x0 {
+            total * split.size.min(0x64) / 0x64
+        } else {
+            let key = split.key.and_then(|key| self.windows.get(&key));
+            match key.map(|window| window.kind) {
+                Some(WindowKind::TextBuffer)
+                    | Some(WindowKind::TextGrid)
+                    | Some(WindowKind::Graphics) => split.size,
+                _ => 0x0,
+            }
+        }.min(total);
+
+        if vertical {
+            self.place(split.new, width, size);
+            self.place(split.old, width, height - size);
+        } else {
+            self.place(split.new, size, height);
+            self.place(split.old, width - size, height);
+        }
+    }
+
+    /// Writes characters to a stream, and to the echo stream of a window.
+    fn put(&mut self,
+            id: u32,
+            chars: &[u32],
+            memory: &mut GlulxMemory,
+            depth: u32) -> Result<(), GlulxError> {
+        let echo = {
+            let stream = match self.streams.get_mut(&id) {
+                Some(stream) if stream.mode & FILEMODE_WRITE != 0x0 => stream,
+                _ => return Ok(()),
+            };
+            stream.written += chars.len() as u32;
+            match stream.kind {
+                StreamKind::Window(window) => {
+                    let window = self.windows.get_mut(&window).unwrap();
+                    window.print(chars);
+                    if let Some(ref mut recorder) = self.recorder {
+                        if window.kind == WindowKind::TextBuffer {
+                            recorder.output(chars);
+                        }
+                    }
+                    window.echo
+                },
+                StreamKind::Memory { buffer, len, unicode } => {
+                    for &ch in chars {
+                        if stream.position >= len {
+                            break;
+                        }
+                        let size = if unicode { 0x4 } else { 0x1 };
+                        let ptr = element_ptr(buffer, stream.position, size)?;
+                        check(memory, ptr, size)?;
+                        if unicode {
+                            memory.write(ptr, ch);
+                        } else {
+                            memory.write(ptr, latin1(ch));
+                        }
+                        stream.position += 0x1;
+                    }
+                    None
+                },
+                StreamKind::File { ref mut file, unicode, text, .. } => {
+                    let bytes = encode(chars, unicode, text);
+                    let _ = file.write_all(&bytes);
+                    None
+                },
+                StreamKind::Resource { .. } => None,
+            }
+        };
+        if let Some(echo) = echo {
+            if depth < 0x8 && echo != id {
+                self.put(echo, chars, memory, depth + 0x1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a character from a stream.
+    fn get_char(&mut self, id: u32, memory: &GlulxMemory)
+            -> Result<Option<u32>, GlulxError> {
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) if stream.mode & FILEMODE_READ != 0x0 => stream,
+            _ => return Ok(None),
+        };
+        let ch = match stream.kind {
+            StreamKind::Window(_) => None,
+            StreamKind::Memory { buffer, len, unicode } => {
+                let size = if unicode { 0x4 } else { 0x1 };
+                if stream.position >= len {
+                    None
+                } else {
+                    let ptr = element_ptr(buffer, stream.position, size)?;
+                    check(memory, ptr, size)?;
+                    if unicode {
+                        Some(memory.read(ptr))
+                    } else {
+                        let ch: u8 = memory.read(ptr);
+                        Some(ch as u32)
+                    }
+                }
+            },
+            StreamKind::File { ref mut file, unicode, text, .. } => {
+                decode(file, unicode, text)
+            },
+            StreamKind::Resource { ref mut data, unicode, text, .. } => {
+                decode(data, unicode, text)
+            },
+        };
+        if ch.is_some() {
+            stream.read += 0x1;
+            if let StreamKind::Memory { .. } = stream.kind {
+                stream.position += 0x1;
+            }
+        }
+        Ok(ch)
+    }
+
+    /// Reads characters from a stream into an array, stopping after a
+    /// newline when reading a line. Returns the number of characters
+    /// read.
+    fn get_chars(&mut self,
+            id: u32,
+            buffer: u32,
+            len: u32,
+            unicode: bool,
+            line: bool,
+            memory: &mut GlkMemory) -> Result<u32, GlulxError> {
+        let size = if unicode { 0x4 } else { 0x1 };
+        let limit = if line { len.saturating_sub(0x1) } else { len };
+        let mut count = 0x0;
+        while count < limit {
+            let ch = match self.get_char(id, memory.memory)? {
+                Some(ch) => ch,
+                None => break,
+            };
+            write_char(memory, element_ptr(buffer, count, size)?, ch, unicode)?;
+            count += 0x1;
+            if line && ch == 0xA {
+                break;
+            }
+        }
+        if line && len > 0x0 {
+            write_char(memory, element_ptr(buffer, count, size)?, 0x0, unicode)?;
+        }
+        Ok(count)
+    }
+
+    fn set_position(&mut self, id: u32, position: i32, mode: u32)
+            -> Result<(), GlulxError> {
+        let stream = self.stream(0x45, id)?;
+        match stream.kind {
+            StreamKind::Window(_) => (),
+            StreamKind::Memory { len, .. } => {
+                let base = match mode {
+                    SEEKMODE_CURRENT => stream.position as i64,
+                    SEEKMODE_END => len as i64,
+                    _ => 0x0,
+                };
+                stream.position = (base + position as i64).clamp(0x0, len as i64)
+                    as u32;
+            },
+            StreamKind::File { ref mut file, unicode, text, .. } => {
+                let _ = file.seek(seek_from(position, mode, unicode, text));
+            },
+            StreamKind::Resource { ref mut data, unicode, text, .. } => {
+                let _ = data.seek(seek_from(position, mode, unicode, text));
+            },
+        }
+        Ok(())
+    }
+
+    fn get_position(&mut self, id: u32) -> Result<u32, GlulxError> {
+        let stream = self.stream(0x46, id)?;
+        let position = match stream.kind {
+            StreamKind::Window(_) => stream.written,
+            StreamKind::Memory { .. } => stream.position,
+            StreamKind::File { ref mut file, unicode, text, .. } => {
+                let position = file.stream_position().unwrap_or(0x0) as u32;
+                if unicode && !text { position / 0x4 } else { position }
+            },
+            StreamKind::Resource { ref data, unicode, text, .. } => {
+                let position = data.position() as u32;
+                if unicode && !text { position / 0x4 } else { position }
+            },
+        };
+        Ok(position)
+    }
+
+    fn open_file(&mut self, fileref: u32, mode: u32, rock: u32, unicode: bool)
+            -> Result<u32, GlulxError> {
+        let (path, text) = {
+            let fileref = self.fileref(0x42, fileref)?;
+            (fileref.path.clone(), fileref.usage & FILEUSAGE_TEXT_MODE != 0x0)
+        };
+        let mut options = OpenOptions::new();
+        match mode {
+            FILEMODE_WRITE => options.write(true).create(true).truncate(true),
+            FILEMODE_READ => options.read(true),
+            FILEMODE_READ_WRITE => options.read(true).write(true).create(true),
+            FILEMODE_WRITE_APPEND => options.append(true).create(true),
+            _ => return Ok(0x0),
+        };
+        match options.open(&path) {
+            Ok(file) => {
+                let kind = StreamKind::File { file, path, unicode, text };
+                Ok(self.open_stream(rock, kind, mode))
+            },
+            Err(_) => Ok(0x0),
+        }
+    }
+
+    fn create_fileref(&mut self, path: PathBuf, usage: u32, rock: u32) -> u32 {
+        let id = self.create_id();
+        self.filerefs.insert(id, Fileref { rock, path, usage });
+        id
+    }
+
+    /// The path of a file created by name, in the file directory. Unsafe
+    /// characters and any extension are removed from the name, and an
+    /// extension for the usage is added.
+    fn named_path(&self, name: &str, usage: u32) -> PathBuf {
+        let name: String = name.chars()
+            .take_while(|&ch| ch != '.')
+            .filter(|&ch| !"/\\<>:|?*\"".contains(ch) && !ch.is_control())
+            .collect();
+        let name = if name.is_empty() { "null".to_string() } else { name };
+        let extension = match usage & FILEUSAGE_TYPE_MASK {
+            0x1 => "glksave",
+            _ if usage & FILEUSAGE_TEXT_MODE != 0x0 => "txt",
+            _ => "glkdata",
+        };
+        self.directory.join(format!("{}.{}", name, extension))
+    }
+
+    fn prompt_fileref(&mut self, usage: u32, mode: u32, rock: u32) -> u32 {
+        let file_usage = match usage & FILEUSAGE_TYPE_MASK {
+            0x1 => FileUsage::SavedGame,
+            0x2 => FileUsage::Transcript,
+            0x3 => FileUsage::InputRecord,
+            _ => FileUsage::Data,
+        };
+        let path = match self.prompt {
+            Some(ref mut prompt) => prompt(file_usage, mode != FILEMODE_READ),
+            None => None,
+        };
+        match path {
+            Some(path) => self.create_fileref(path, usage, rock),
+            None => 0x0,
+        }
+    }
+
+    /// Takes the next event for a select from the queued input. Input
+    /// which no window requested is discarded.
+    fn next_event(&mut self, memory: &mut GlkMemory)
+            -> Result<Option<[u32; 0x4]>, GlulxError> {
+        while let Some(input) = self.inputs.pop_front() {
+            let line = self.windows.iter()
+                .find(|&(_, window)| window.line.is_some())
+                .map(|(&id, _)| id);
+            let chars = self.windows.iter()
+                .find(|&(_, window)| window.char_input.is_some())
+                .map(|(&id, _)| id);
+
+            match input {
+                Input::Line(text) => {
+                    if let Some(window) = line {
+                        self.record(Input::Line(text.clone()));
+                        return self.line_event(window, &text, memory).map(Some);
+                    }
+                    if let Some(window) = chars {
+                        let key = text.chars().next()
+                            .map(|ch| ch as u32)
+                            .unwrap_or(KEY_RETURN);
+                        self.record(Input::Char(key));
+                        return Ok(Some(self.char_event(window, key)));
+                    }
+                },
+                Input::Char(key) => {
+                    if let Some(window) = chars {
+                        self.record(Input::Char(key));
+                        return Ok(Some(self.char_event(window, key)));
+                    }
+                },
+                Input::Timer => {
+                    if self.timer != 0x0 {
+                        self.record(Input::Timer);
+                        return Ok(Some([EVTYPE_TIMER, 0x0, 0x0, 0x0]));
+                    }
+                },
+                Input::Arrange { width, height } => {
+                    self.record(Input::Arrange { width, height });
+                    self.screen = (width, height);
+                    self.layout();
+                    return Ok(Some([EVTYPE_ARRANGE, 0x0, 0x0, 0x0]));
+                },
+            }
+        }
+        Ok(None)
+    }
+
+    fn line_event(&mut self, id: u32, text: &str, memory: &mut GlkMemory)
+            -> Result<[u32; 0x4], GlulxError> {
+        let (request, echo, stream) = {
+            let window = self.windows.get_mut(&id).unwrap();
+            (window.line.take().unwrap(), window.echo_input, window.stream)
+        };
+        let chars: Vec<u32> = text.chars()
+            .map(|ch| ch as u32)
+            .take(request.len as usize)
+            .collect();
+        let size = if request.unicode { 0x4 } else { 0x1 };
+        for (index, &ch) in chars.iter().enumerate() {
+            let ptr = element_ptr(request.buffer, index as u32, size)?;
+            write_char(memory, ptr, ch, request.unicode)?;
+        }
+        if echo && self.echo_input {
+            let mut echoed = chars.clone();
+            echoed.push(0xA);
+            self.put(stream, &echoed, memory.memory, 0x0)?;
+        }
+        Ok([EVTYPE_LINE_INPUT, id, chars.len() as u32, 0x0])
+    }
+
+    fn char_event(&mut self, id: u32, key: u32) -> [u32; 0x4] {
+        let window = self.windows.get_mut(&id).unwrap();
+        let unicode = window.char_input.take().unwrap_or(false);
+        let key = if unicode || !(0x100..0xFFFFFFE0).contains(&key) {
+            key
+        } else {
+            '?' as u32
+        };
+        [EVTYPE_CHAR_INPUT, id, key, 0x0]
+    }
+
+    /// Cancels line input, returning the event for the input so far.
+    fn cancel_line(&mut self, id: u32) -> Result<[u32; 0x4], GlulxError> {
+        let window = self.window(0xD1, id)?;
+        Ok(match window.line.take() {
+            Some(_) => [EVTYPE_LINE_INPUT, id, 0x0, 0x0],
+            None => [EVTYPE_NONE, 0x0, 0x0, 0x0],
+        })
+    }
+
+    fn gestalt(&self, selector: u32, value: u32) -> u32 {
+        match selector {
+            0x0 => 0x00070600, // version
+            0x1 | 0x2 => 0x1, // character and line input
+            0x3 => { // character output
+                if value < 0x20 && value != 0xA || (0x7F..0xA0).contains(&value) {
+                    0x0
+                } else {
+                    0x2
+                }
+            },
+            0x5 => 0x1, // timer
+            0x6 => self.has_resources(Usage::Picture) as u32, // graphics
+            0x7 => { // draw image
+                let drawable = value == WindowKind::TextBuffer.to_u32()
+                    || value == WindowKind::Graphics.to_u32();
+                (drawable && self.has_resources(Usage::Picture)) as u32
+            },
+            // sound, sound volume and music
+            0x8 | 0x9 | 0xD => self.has_resources(Usage::Sound) as u32,
+            0xF => 0x1, // unicode
+            0x11 => 0x1, // line input echo
+            0x14 => 0x1, // date and time
+            0x16 => 0x1, // resource streams
+            _ => 0x0,
+        }
+    }
+
+    /// Iterates over objects by identifier, storing the rock of the next
+    /// object.
+    fn iterate<T, F>(objects: &BTreeMap<u32, T>,
+            id: u32,
+            rock: F,
+            rockptr: u32,
+            memory: &mut GlkMemory) -> Result<u32, GlulxError>
+            where F: Fn(&T) -> u32 {
+        let next = objects.range(id + 0x1..).next();
+        match next {
+            Some((&next, object)) => {
+                memory.store(rockptr, rock(object))?;
+                Ok(next)
+            },
+            None => {
+                memory.store(rockptr, 0x0)?;
+                Ok(0x0)
+            },
+        }
+    }
+}
+
+
+impl Window {
+    fn new(kind: WindowKind, rock: u32, stream: u32) -> Window {
+        Window {
+            rock,
+            kind,
+            parent: None,
+            stream,
+            echo: None,
+            split: None,
+            width: 0x0,
+            height: 0x0,
+            text: String::new(),
+            grid: vec![],
+            cursor: (0x0, 0x0),
+            line: None,
+            char_input: None,
+            echo_input: true,
+        }
+    }
+}
+
+
+impl Glk for TextGlk {
+    fn call(&mut self, selector: u32, args: &[u32], memory: &mut GlkMemory)
+            -> Result<Option<u32>, GlulxError> {
+        let arg = |index: usize| args.get(index).cloned().unwrap_or(0x0);
+        let result = match selector {
+            0x01 => { // exit
+                let ids: Vec<u32> = self.streams.keys().cloned().collect();
+                for id in ids {
+                    if let Some(&Stream { kind: StreamKind::File { .. }, .. }) =
+                            self.streams.get(&id) {
+                        self.close_stream(id);
+                    }
+                }
+                0x0
+            },
+            0x02 | 0x03 => 0x0, // set_interrupt_handler, tick
+            0x04 => self.gestalt(arg(0x0), arg(0x1)),
+            0x05 => { // gestalt_ext
+                let result = self.gestalt(arg(0x0), arg(0x1));
+                if arg(0x0) == 0x3 && arg(0x3) > 0x0 && result != 0x0 {
+                    memory.write_u32(arg(0x2), 0x1)?;
+                }
+                result
+            },
+
+            0x20 => { // window_iterate
+                let windows = &self.windows;
+                TextGlk::iterate(windows, arg(0x0), |w| w.rock, arg(0x1), memory)?
+            },
+            0x21 => self.window(selector, arg(0x0))?.rock,
+            0x22 => self.root.unwrap_or(0x0),
+            0x23 => self.open_window(arg(0x0), arg(0x1), arg(0x2), arg(0x3),
+                arg(0x4))?,
+            0x24 => { // window_close
+                let counts = self.close_window(arg(0x0))?;
+                memory.store_all(arg(0x1), &counts)?;
+                0x0
+            },
+            0x25 => { // window_get_size
+                let window = self.window(selector, arg(0x0))?;
+                let (width, height) = (window.width, window.height);
+                memory.store(arg(0x1), width)?;
+                memory.store(arg(0x2), height)?;
+                0x0
+            },
+            0x26 => { // window_set_arrangement
+                let key = arg(0x3);
+                let window = self.window(selector, arg(0x0))?;
+                if let Some(ref mut split) = window.split {
+                    split.method = arg(0x1);
+                    split.size = arg(0x2);
+                    if key != 0x0 {
+                        split.key = Some(key);
+                    }
+                }
+                self.layout();
+                0x0
+            },
+            0x27 => { // window_get_arrangement
+                let window = self.window(selector, arg(0x0))?;
+                let split = window.split.unwrap_or(Split {
+                    method: 0x0,
+                    size: 0x0,
+                    key: None,
+                    new: 0x0,
+                    old: 0x0,
+                });
+                memory.store(arg(0x1), split.method)?;
+                memory.store(arg(0x2), split.size)?;
+                memory.store(arg(0x3), split.key.unwrap_or(0x0))?;
+                0x0
+            },
+            0x28 => self.window(selector, arg(0x0))?.kind.to_u32(),
+            0x29 => self.window(selector, arg(0x0))?.parent.unwrap_or(0x0),
+            0x2A => {
+                self.window(selector, arg(0x0))?.clear();
+                0x0
+            },
+            0x2B => { // window_move_cursor
+                self.window(selector, arg(0x0))?.cursor = (arg(0x1), arg(0x2));
+                0x0
+            },
+            0x2C => self.window(selector, arg(0x0))?.stream,
+            0x2D => { // window_set_echo_stream
+                let echo = arg(0x1);
+                self.window(selector, arg(0x0))?.echo =
+                    if echo == 0x0 { None } else { Some(echo) };
+                0x0
+            },
+            0x2E => self.window(selector, arg(0x0))?.echo.unwrap_or(0x0),
+            0x2F => { // set_window
+                self.current = match arg(0x0) {
+                    0x0 => None,
+                    id => Some(self.window(selector, id)?.stream),
+                };
+                0x0
+            },
+            0x30 => { // window_get_sibling
+                let parent = self.window(selector, arg(0x0))?.parent;
+                let split = parent.and_then(|parent| self.windows[&parent].split);
+                match split {
+                    Some(split) if split.new == arg(0x0) => split.old,
+                    Some(split) => split.new,
+                    None => 0x0,
+                }
+            },
+
+            0x40 => { // stream_iterate
+                let streams = &self.streams;
+                TextGlk::iterate(streams, arg(0x0), |s| s.rock, arg(0x1), memory)?
+            },
+            0x41 => self.stream(selector, arg(0x0))?.rock,
+            0x42 => self.open_file(arg(0x0), arg(0x1), arg(0x2), false)?,
+            0x43 | 0x139 => { // stream_open_memory
+                let kind = StreamKind::Memory {
+                    buffer: arg(0x0),
+                    len: arg(0x1),
+                    unicode: selector == 0x139,
+                };
+                let size = if selector == 0x139 { 0x4 } else { 0x1 };
+                if arg(0x0) != 0x0 {
+                    memory.check(arg(0x0), arg(0x1).saturating_mul(size))?;
+                }
+                self.open_stream(arg(0x3), kind, arg(0x2))
+            },
+            0x44 => { // stream_close
+                self.stream(selector, arg(0x0))?;
+                let counts = self.close_stream(arg(0x0));
+                memory.store_all(arg(0x1), &counts)?;
+                0x0
+            },
+            0x45 => {
+                self.set_position(arg(0x0), arg(0x1) as i32, arg(0x2))?;
+                0x0
+            },
+            0x46 => self.get_position(arg(0x0))?,
+            0x47 => { // stream_set_current
+                self.current = match arg(0x0) {
+                    0x0 => None,
+                    id => {
+                        self.stream(selector, id)?;
+                        Some(id)
+                    },
+                };
+                0x0
+            },
+            0x48 => self.current.unwrap_or(0x0),
+            0x49 | 0x13A => { // stream_open_resource
+                self.open_resource(arg(0x0), arg(0x1), selector == 0x13A)
+            },
+
+            0x60 => { // fileref_create_temp
+                let path = ::std::env::temp_dir().join(format!("glk-{}-{}",
+                    process::id(), self.next_id));
+                self.create_fileref(path, arg(0x0), arg(0x1))
+            },
+            0x61 => { // fileref_create_by_name
+                let name = memory.read_string(arg(0x1))?;
+                let path = self.named_path(&name, arg(0x0));
+                self.create_fileref(path, arg(0x0), arg(0x2))
+            },
+            0x62 => self.prompt_fileref(arg(0x0), arg(0x1), arg(0x2)),
+            0x63 => {
+                self.fileref(selector, arg(0x0))?;
+                self.filerefs.remove(&arg(0x0));
+                0x0
+            },
+            0x64 => { // fileref_iterate
+                let filerefs = &self.filerefs;
+                TextGlk::iterate(filerefs, arg(0x0), |f| f.rock, arg(0x1), memory)?
+            },
+            0x65 => self.fileref(selector, arg(0x0))?.rock,
+            0x66 => {
+                let _ = fs::remove_file(&self.fileref(selector, arg(0x0))?.path);
+                0x0
+            },
+            0x67 => self.fileref(selector, arg(0x0))?.path.is_file() as u32,
+            0x68 => { // fileref_create_from_fileref
+                let path = self.fileref(selector, arg(0x1))?.path.clone();
+                self.create_fileref(path, arg(0x0), arg(0x2))
+            },
+
+            0x80 | 0x128 => { // put_char
+                if let Some(current) = self.current {
+                    let ch = if selector == 0x80 { arg(0x0) & 0xFF } else { arg(0x0) };
+                    self.put(current, &[ch], memory.memory, 0x0)?;
+                }
+                0x0
+            },
+            0x81 | 0x12B => { // put_char_stream
+                self.stream(selector, arg(0x0))?;
+                let ch = if selector == 0x81 { arg(0x1) & 0xFF } else { arg(0x1) };
+                self.put(arg(0x0), &[ch], memory.memory, 0x0)?;
+                0x0
+            },
+            0x82 | 0x129 => { // put_string
+                let chars: Vec<u32> = memory.read_string(arg(0x0))?
+                    .chars()
+                    .map(|ch| ch as u32)
+                    .collect();
+                if let Some(current) = self.current {
+                    self.put(current, &chars, memory.memory, 0x0)?;
+                }
+                0x0
+            },
+            0x83 | 0x12C => { // put_string_stream
+                self.stream(selector, arg(0x0))?;
+                let chars: Vec<u32> = memory.read_string(arg(0x1))?
+                    .chars()
+                    .map(|ch| ch as u32)
+                    .collect();
+                self.put(arg(0x0), &chars, memory.memory, 0x0)?;
+                0x0
+            },
+            0x84 | 0x12A => { // put_buffer
+                let chars = read_chars(memory, arg(0x0), arg(0x1),
+                    selector == 0x12A)?;
+                if let Some(current) = self.current {
+                    self.put(current, &chars, memory.memory, 0x0)?;
+                }
+                0x0
+            },
+            0x85 | 0x12D => { // put_buffer_stream
+                self.stream(selector, arg(0x0))?;
+                let chars = read_chars(memory, arg(0x1), arg(0x2),
+                    selector == 0x12D)?;
+                self.put(arg(0x0), &chars, memory.memory, 0x0)?;
+                0x0
+            },
+            0x86 | 0x87 => 0x0, // set_style, set_style_stream
+
+            0x90 | 0x130 => { // get_char_stream
+                self.stream(selector, arg(0x0))?;
+                match self.get_char(arg(0x0), memory.memory)? {
+                    Some(ch) if selector == 0x90 => latin1(ch) as u32,
+                    Some(ch) => ch,
+                    None => 0xFFFFFFFF,
+                }
+            },
+            0x91 | 0x132 => { // get_line_stream
+                self.stream(selector, arg(0x0))?;
+                self.get_chars(arg(0x0), arg(0x1), arg(0x2), selector == 0x132,
+                    true, memory)?
+            },
+            0x92 | 0x131 => { // get_buffer_stream
+                self.stream(selector, arg(0x0))?;
+                self.get_chars(arg(0x0), arg(0x1), arg(0x2), selector == 0x131,
+                    false, memory)?
+            },
+
+            0xA0 => match arg(0x0) & 0xFF {
+                ch @ (0x41..=0x5A | 0xC0..=0xD6 | 0xD8..=0xDE) => ch + 0x20,
+                ch => ch,
+            },
+            0xA1 => match arg(0x0) & 0xFF {
+                ch @ (0x61..=0x7A | 0xE0..=0xF6 | 0xF8..=0xFE) => ch - 0x20,
+                ch => ch,
+            },
+
+            0xB0 | 0xB1 => 0x0, // stylehint_set, stylehint_clear
+            0xB2 | 0xB3 => 0x0, // style_distinguish, style_measure
+
+            0xC0 => { // select
+                match self.next_event(memory)? {
+                    Some(event) => {
+                        memory.store_all(arg(0x0), &event)?;
+                        0x0
+                    },
+                    None => {
+                        self.select = Some(arg(0x0));
+                        return Ok(None);
+                    },
+                }
+            },
+            0xC1 => { // select_poll
+                let event = match self.inputs.front() {
+                    Some(&Input::Timer) | Some(&Input::Arrange { .. }) => {
+                        self.next_event(memory)?
+                    },
+                    _ => None,
+                };
+                let event = event.unwrap_or([EVTYPE_NONE, 0x0, 0x0, 0x0]);
+                memory.store_all(arg(0x0), &event)?;
+                0x0
+            },
+
+            0xD0 | 0x141 => { // request_line_event
+                let (buffer, len, initlen) = (arg(0x1), arg(0x2), arg(0x3));
+                let unicode = selector == 0x141;
+                memory.check(buffer, len.saturating_mul(if unicode { 0x4 } else { 0x1 }))?;
+                let window = self.window(selector, arg(0x0))?;
+                window.line = Some(LineRequest { buffer, len, unicode });
+                let _ = initlen;
+                0x0
+            },
+            0xD1 => { // cancel_line_event
+                let event = self.cancel_line(arg(0x0))?;
+                memory.store_all(arg(0x1), &event)?;
+                0x0
+            },
+            0xD2 | 0x140 => { // request_char_event
+                self.window(selector, arg(0x0))?.char_input =
+                    Some(selector == 0x140);
+                0x0
+            },
+            0xD3 => {
+                self.window(selector, arg(0x0))?.char_input = None;
+                0x0
+            },
+            0xD4 | 0xD5 => 0x0, // request_mouse_event, cancel_mouse_event
+            0xD6 => {
+                self.timer = arg(0x0);
+                0x0
+            },
+
+            0xE0 => { // image_get_info
+                let size = self.blorb.as_ref()
+                    .and_then(|blorb| blorb.image_size(arg(0x0)));
+                match size {
+                    Some((width, height)) => {
+                        memory.store(arg(0x1), width)?;
+                        memory.store(arg(0x2), height)?;
+                        0x1
+                    },
+                    None => 0x0,
+                }
+            },
+            0xE1 | 0xE2 => self.draw_image(selector, arg(0x0), arg(0x1))?,
+            0xE8..=0xEB => 0x0, // flow_break, erase_rect, fill_rect, background
+
+            0xF0 => { // schannel_iterate
+                let channels = &self.channels;
+                TextGlk::iterate(channels, arg(0x0), |c| c.rock, arg(0x1), memory)?
+            },
+            0xF1 => self.channel(selector, arg(0x0))?.rock,
+            0xF2 | 0xF4 => { // schannel_create
+                if self.has_resources(Usage::Sound) {
+                    let id = self.create_id();
+                    self.channels.insert(id, Channel { rock: arg(0x0) });
+                    id
+                } else {
+                    0x0
+                }
+            },
+            0xF3 => {
+                self.channel(selector, arg(0x0))?;
+                self.channels.remove(&arg(0x0));
+                0x0
+            },
+            0xF7 => { // schannel_play_multi
+                let count = arg(0x1).min(arg(0x3));
+                let channels = memory.read_all(arg(0x0), count)?;
+                let sounds = memory.read_all(arg(0x2), count)?;
+                let mut played = 0x0;
+                for (&channel, &sound) in channels.iter().zip(sounds.iter()) {
+                    self.channel(selector, channel)?;
+                    played += self.has_resource(Usage::Sound, sound) as u32;
+                }
+                played
+            },
+            0xF8 | 0xF9 => { // schannel_play, schannel_play_ext
+                self.channel(selector, arg(0x0))?;
+                let repeats = if selector == 0xF9 { arg(0x2) } else { 0x1 };
+                (repeats == 0x0 || self.has_resource(Usage::Sound, arg(0x1)))
+                    as u32
+            },
+            0xFC => 0x0, // sound_load_hint
+            // stop, set_volume, set_volume_ext, pause, unpause
+            0xFA | 0xFB | 0xFD..=0xFF => {
+                self.channel(selector, arg(0x0))?;
+                0x0
+            },
+            0x100..=0x103 => 0x0, // hyperlinks
+
+            0x120..=0x122 => { // buffer_to_lower_case_uni
+                let (buffer, len, count) = (arg(0x0), arg(0x1), arg(0x2));
+                let text: String = read_chars(memory, buffer, count.min(len), true)?
+                    .into_iter()
+                    .map(|ch| ::std::char::from_u32(ch).unwrap_or('\u{FFFD}'))
+                    .collect();
+                let changed = match selector {
+                    0x120 => text.to_lowercase(),
+                    0x121 => text.to_uppercase(),
+                    _ => title_case(&text, arg(0x3) != 0x0),
+                };
+                let chars: Vec<u32> = changed.chars().map(|ch| ch as u32).collect();
+                for (index, &ch) in chars.iter().take(len as usize).enumerate() {
+                    memory.write_u32(buffer + index as u32 * 0x4, ch)?;
+                }
+                chars.len() as u32
+            },
+
+            0x138 => self.open_file(arg(0x0), arg(0x1), arg(0x2), true)?,
+            0x150 => { // set_echo_line_event
+                self.window(selector, arg(0x0))?.echo_input = arg(0x1) != 0x0;
+                0x0
+            },
+            0x151 => 0x0, // set_terminators_line_event
+
+            0x160 => { // current_time
+                let time = now();
+                memory.store_all(arg(0x0), &time_value(time))?;
+                0x0
+            },
+            0x161 => { // current_simple_time
+                let factor = arg(0x0).max(0x1) as i64;
+                (now().0.div_euclid(factor)) as u32
+            },
+            0x168 | 0x169 => { // time_to_date
+                let time = memory.read_all(arg(0x0), 0x3)?;
+                let seconds = ((time[0x0] as i32 as i64) << 0x20)
+                    | time[0x1] as i64;
+                let date = to_date(seconds, time[0x2] as i32);
+                memory.store_all(arg(0x1), &date)?;
+                0x0
+            },
+            0x16A | 0x16B => { // simple_time_to_date
+                let seconds = arg(0x0) as i32 as i64 * arg(0x1).max(0x1) as i64;
+                memory.store_all(arg(0x2), &to_date(seconds, 0x0))?;
+                0x0
+            },
+            0x16C | 0x16D => { // date_to_time
+                let date = memory.read_all(arg(0x0), 0x8)?;
+                let time = from_date(&date);
+                memory.store_all(arg(0x1), &time_value(time))?;
+                0x0
+            },
+            0x16E | 0x16F => { // date_to_simple_time
+                let date = memory.read_all(arg(0x0), 0x8)?;
+                let factor = arg(0x1).max(0x1) as i64;
+                from_date(&date).0.div_euclid(factor) as u32
+            },
+
+            _ => return Err(GlulxError::Glk {
+                selector,
+                message: "unknown function",
+            }),
+        };
+        Ok(Some(result))
+    }
+
+    fn select(&mut self, memory: &mut GlkMemory) -> Result<bool, GlulxError> {
+        let ptr = match self.select {
+            Some(ptr) => ptr,
+            None => return Ok(true),
+        };
+        match self.next_event(memory)? {
+            Some(event) => {
+                memory.store_all(ptr, &event)?;
+                self.select = None;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn write_stream(&mut self,
+            stream: u32,
+            bytes: &[u8],
+            memory: &mut GlulxMemory) -> bool {
+        match self.streams.get_mut(&stream) {
+            Some(&mut Stream { kind: StreamKind::File { ref mut file, .. }, .. }) => {
+                file.write_all(bytes).is_ok()
+            },
+            Some(&mut Stream { kind: StreamKind::Memory { .. }, .. }) => {
+                let chars: Vec<u32> = bytes.iter().map(|&b| b as u32).collect();
+                self.put(stream, &chars, memory, 0x0).is_ok()
+            },
+            _ => false,
+        }
+    }
+
+    fn read_stream(&mut self, stream: u32, memory: &GlulxMemory)
+            -> Option<Vec<u8>> {
+        let stream = self.streams.get_mut(&stream)?;
+        if stream.mode & FILEMODE_READ == 0x0 {
+            return None;
+        }
+        match stream.kind {
+            StreamKind::File { ref mut file, .. } => {
+                let mut bytes = vec![];
+                file.read_to_end(&mut bytes).ok()?;
+                Some(bytes)
+            },
+            StreamKind::Resource { ref mut data, .. } => {
+                let mut bytes = vec![];
+                data.read_to_end(&mut bytes).ok()?;
+                Some(bytes)
+            },
+            StreamKind::Memory { buffer, len, unicode: false } => {
+                let start = buffer.checked_add(stream.position)?;
+                let end = buffer.checked_add(len)?;
+                check(memory, start, end - start).ok()?;
+                stream.position = len;
+                Some(memory.bytes(start..end))
+            },
+            _ => None,
+        }
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let mut state = StateWriter(vec![]);
+        state.u32(self.next_id);
+        state.u32(self.root.unwrap_or(0x0));
+        state.u32(self.current.unwrap_or(0x0));
+        state.u32(self.screen.0);
+        state.u32(self.screen.1);
+        state.u32(self.select.map_or(0x0, |ptr| ptr));
+        state.u32(self.select.is_some() as u32);
+        state.u32(self.timer);
+
+        state.u32(self.windows.len() as u32);
+        for (&id, window) in &self.windows {
+            state.u32(id);
+            state.u32(window.rock);
+            state.u32(window.kind.to_u32());
+            state.u32(window.parent.unwrap_or(0x0));
+            state.u32(window.stream);
+            state.u32(window.echo.unwrap_or(0x0));
+            let split = window.split.map_or([0x0; 0x5], |split| {
+                [split.method, split.size, split.key.unwrap_or(0x0),
+                    split.new, split.old]
+            });
+            for value in split.iter() {
+                state.u32(*value);
+            }
+            state.u32(window.cursor.0);
+            state.u32(window.cursor.1);
+            let grid: Vec<String> = window.grid.iter()
+                .map(|row| row.iter().collect())
+                .collect();
+            state.string(&grid.join("\n"));
+            let line = window.line.map_or([0x0; 0x3], |line| {
+                [line.buffer, line.len, line.unicode as u32 + 0x1]
+            });
+            for value in line.iter() {
+                state.u32(*value);
+            }
+            state.u32(window.char_input.map_or(0x0, |unicode| unicode as u32 + 0x1));
+            state.u32(window.echo_input as u32);
+        }
+
+        // file streams are saved by path, and reopened at their position.
+        state.u32(self.streams.len() as u32);
+        for (&id, stream) in &self.streams {
+            state.u32(id);
+            state.u32(stream.rock);
+            state.u32(stream.mode);
+            state.u32(stream.position);
+            state.u32(stream.read);
+            state.u32(stream.written);
+            match stream.kind {
+                StreamKind::Window(window) => {
+                    state.u32(0x0);
+                    state.u32(window);
+                },
+                StreamKind::Memory { buffer, len, unicode } => {
+                    state.u32(0x1);
+                    state.u32(buffer);
+                    state.u32(len);
+                    state.u32(unicode as u32);
+                },
+                StreamKind::File { ref file, ref path, unicode, text } => {
+                    let mut file = file;
+                    state.u32(0x2);
+                    state.string(&path.to_string_lossy());
+                    state.u32(unicode as u32);
+                    state.u32(text as u32);
+                    state.u32(file.stream_position().unwrap_or(0x0) as u32);
+                },
+                StreamKind::Resource { ref data, number, unicode, .. } => {
+                    state.u32(0x3);
+                    state.u32(number);
+                    state.u32(unicode as u32);
+                    state.u32(data.position() as u32);
+                },
+            }
+        }
+
+        state.u32(self.filerefs.len() as u32);
+        for (&id, fileref) in &self.filerefs {
+            state.u32(id);
+            state.u32(fileref.rock);
+            state.u32(fileref.usage);
+            state.string(&fileref.path.to_string_lossy());
+        }
+
+        state.u32(self.channels.len() as u32);
+        for (&id, channel) in &self.channels {
+            state.u32(id);
+            state.u32(channel.rock);
+        }
+        Some(state.0)
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> bool {
+        let mut reader = StateReader { bytes: state, position: 0x0 };
+        match self.read_state(&mut reader) {
+            Some(()) => {
+                self.layout();
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn queue_input(&mut self, input: Input) -> bool {
+        self.push_input(input);
+        true
+    }
+
+    fn set_recorder(&mut self, recorder: Box<dyn Recorder>) -> bool {
+        self.recorder = Some(recorder);
+        true
+    }
+
+    fn recorder(&self) -> Option<&dyn Recorder> {
+        self.recorder.as_deref()
+    }
+
+    fn take_recorder(&mut self) -> Option<Box<dyn Recorder>> {
+        self.recorder.take()
+    }
+}
+
+
+impl TextGlk {
+    fn read_state(&mut self, state: &mut StateReader) -> Option<()> {
+        let optional = |value: u32| if value == 0x0 { None } else { Some(value) };
+        self.next_id = state.u32()?;
+        self.root = optional(state.u32()?);
+        self.current = optional(state.u32()?);
+        self.screen = (state.u32()?, state.u32()?);
+        let select = state.u32()?;
+        self.select = if state.u32()? != 0x0 { Some(select) } else { None };
+        self.timer = state.u32()?;
+
+        self.windows.clear();
+        for _ in 0x0..state.u32()? {
+            let id = state.u32()?;
+            let rock = state.u32()?;
+            let kind = WindowKind::from_u32(state.u32()?)?;
+            let mut window = Window::new(kind, rock, 0x0);
+            window.parent = optional(state.u32()?);
+            window.stream = state.u32()?;
+            window.echo = optional(state.u32()?);
+            let split = [state.u32()?, state.u32()?, state.u32()?,
+                state.u32()?, state.u32()?];
+            if kind == WindowKind::Pair {
+                window.split = Some(Split {
+                    method: split[0x0],
+                    size: split[0x1],
+                    key: optional(split[0x2]),
+                    new: split[0x3],
+                    old: split[0x4],
+                });
+            }
+            window.cursor = (state.u32()?, state.u32()?);
+            let grid = state.string()?;
+            if kind == WindowKind::TextGrid {
+                window.grid = grid.split('\n')
+                    .map(|row| row.chars().collect())
+                    .collect();
+            }
+            let line = [state.u32()?, state.u32()?, state.u32()?];
+            if line[0x2] != 0x0 {
+                window.line = Some(LineRequest {
+                    buffer: line[0x0],
+                    len: line[0x1],
+                    unicode: line[0x2] == 0x2,
+                });
+            }
+            window.char_input = match state.u32()? {
+                0x0 => None,
+                value => Some(value == 0x2),
+            };
+            window.echo_input = state.u32()? != 0x0;
+            self.windows.insert(id, window);
+        }
+
+        self.streams.clear();
+        for _ in 0x0..state.u32()? {
+            let id = state.u32()?;
+            let rock = state.u32()?;
+            let mode = state.u32()?;
+            let position = state.u32()?;
+            let (read, written) = (state.u32()?, state.u32()?);
+            let kind = match state.u32()? {
+                0x0 => StreamKind::Window(state.u32()?),
+                0x1 => StreamKind::Memory {
+                    buffer: state.u32()?,
+                    len: state.u32()?,
+                    unicode: state.u32()? != 0x0,
+                },
+                // resource streams are reopened from the Blorb file.
+                0x3 => {
+                    let number = state.u32()?;
+                    let unicode = state.u32()? != 0x0;
+                    let offset = state.u32()?;
+                    let resource = self.blorb.as_ref()?
+                        .resource(Usage::Data, number)?;
+                    let mut data = Cursor::new(resource.data.clone());
+                    data.set_position(offset as u64);
+                    let text = &resource.kind == b"TEXT";
+                    StreamKind::Resource { data, number, unicode, text }
+                },
+                _ => {
+                    let path = PathBuf::from(state.string()?);
+                    let (unicode, text) = (state.u32()? != 0x0, state.u32()? != 0x0);
+                    let offset = state.u32()?;
+                    let mut file = OpenOptions::new()
+                        .read(mode & FILEMODE_READ != 0x0)
+                        .write(mode & FILEMODE_WRITE != 0x0)
+                        .open(&path)
+                        .ok()?;
+                    file.seek(SeekFrom::Start(offset as u64)).ok()?;
+                    StreamKind::File { file, path, unicode, text }
+                },
+            };
+            self.streams.insert(id, Stream {
+                rock,
+                kind,
+                mode,
+                position,
+                read,
+                written,
+            });
+        }
+
+        self.filerefs.clear();
+        for _ in 0x0..state.u32()? {
+            let id = state.u32()?;
+            let rock = state.u32()?;
+            let usage = state.u32()?;
+            let path = PathBuf::from(state.string()?);
+            self.filerefs.insert(id, Fileref { rock, path, usage });
+        }
+
+        self.channels.clear();
+        for _ in 0x0..state.u32()? {
+            let id = state.u32()?;
+            let rock = state.u32()?;
+            self.channels.insert(id, Channel { rock });
+        }
+        Some(())
+    }
+}
+
+
+struct StateWriter(Vec<u8>);
+
+
+impl StateWriter {
+    fn u32(&mut self, value: u32) {
+        let mut buf = [0x0; 0x4];
+        BigEndian::write_u32(&mut buf, value);
+        self.0.extend_from_slice(&buf);
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value.as_bytes());
+    }
+}
+
+
+struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+
+impl<'a> StateReader<'a> {
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.bytes.get(self.position..self.position + 0x4)?;
+        self.position += 0x4;
+        Some(BigEndian::read_u32(bytes))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.bytes.get(self.position..self.position + len)?;
+        self.position += len;
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+
+/// Checks that `len` bytes at `ptr` lie within memory.
+fn check(memory: &GlulxMemory, ptr: u32, len: u32) -> Result<u32, GlulxError> {
+    match ptr.checked_add(len) {
+        Some(end) if end <= memory.get_mem_size() => Ok(ptr),
+        _ => Err(GlulxError::InvalidAddress { address: ptr }),
+    }
+}
+
+
+/// The address of the element at the given index of an array of
+/// elements of the given size.
+fn element_ptr(ptr: u32, index: u32, size: u32) -> Result<u32, GlulxError> {
+    index.checked_mul(size)
+        .and_then(|offset| ptr.checked_add(offset))
+        .ok_or(GlulxError::InvalidAddress { address: ptr })
+}
+
+
+/// A character as Latin-1, replacing characters outside of it with `?`.
+fn latin1(ch: u32) -> u8 {
+    if ch < 0x100 { ch as u8 } else { b'?' }
+}
+
+
+fn write_char(memory: &mut GlkMemory, ptr: u32, ch: u32, unicode: bool)
+        -> Result<(), GlulxError> {
+    if unicode {
+        memory.write_u32(ptr, ch)
+    } else {
+        memory.write_u8(ptr, latin1(ch))
+    }
+}
+
+
+/// Reads an array of Latin-1 or Unicode characters.
+fn read_chars(memory: &GlkMemory, ptr: u32, len: u32, unicode: bool)
+        -> Result<Vec<u32>, GlulxError> {
+    if unicode {
+        memory.read_all(ptr, len)
+    } else {
+        (0x0..len)
+            .map(|index| memory.read_u8(element_ptr(ptr, index, 0x1)?))
+            .map(|ch| ch.map(u32::from))
+            .collect()
+    }
+}
+
+
+/// Encodes characters for a file. Unicode text files are UTF-8, Unicode
+/// binary files hold 4 bytes per character, and other files hold Latin-1.
+fn encode(chars: &[u32], unicode: bool, text: bool) -> Vec<u8> {
+    let mut bytes = vec![];
+    for &ch in chars {
+        if !unicode {
+            bytes.push(latin1(ch));
+        } else if text {
+            let ch = ::std::char::from_u32(ch).unwrap_or('\u{FFFD}');
+            let mut buf = [0x0; 0x4];
+            bytes.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+        } else {
+            let mut buf = [0x0; 0x4];
+            BigEndian::write_u32(&mut buf, ch);
+            bytes.extend_from_slice(&buf);
+        }
+    }
+    bytes
+}
+
+
+/// Where to seek to in a file, for a position in characters.
+fn seek_from(position: i32, mode: u32, unicode: bool, text: bool) -> SeekFrom {
+    let scale = if unicode && !text { 0x4 } else { 0x1 };
+    let offset = position as i64 * scale;
+    match mode {
+        SEEKMODE_CURRENT => SeekFrom::Current(offset),
+        SEEKMODE_END => SeekFrom::End(offset),
+        _ => SeekFrom::Start(offset.max(0x0) as u64),
+    }
+}
+
+
+/// Reads a character from a file, encoded as by `encode`.
+fn decode<R: Read>(file: &mut R, unicode: bool, text: bool) -> Option<u32> {
+    let mut buf = [0x0; 0x4];
+    if !unicode {
+        file.read_exact(&mut buf[..0x1]).ok()?;
+        return Some(buf[0x0] as u32);
+    }
+    if !text {
+        file.read_exact(&mut buf).ok()?;
+        return Some(BigEndian::read_u32(&buf));
+    }
+
+    file.read_exact(&mut buf[..0x1]).ok()?;
+    let len = match buf[0x0] {
+        0x00..=0x7F => 0x1,
+        0xC0..=0xDF => 0x2,
+        0xE0..=0xEF => 0x3,
+        _ => 0x4,
+    };
+    file.read_exact(&mut buf[0x1..len]).ok()?;
+    let ch = ::std::str::from_utf8(&buf[..len]).ok()
+        .and_then(|text| text.chars().next())
+        .unwrap_or('\u{FFFD}');
+    Some(ch as u32)
+}
+
+
+fn title_case(text: &str, lower_rest: bool) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => {
+            let rest: String = chars.collect();
+            let rest = if lower_rest { rest.to_lowercase() } else { rest };
+            first.to_uppercase().collect::<String>() + &rest
+        },
+        None => String::new(),
+    }
+}
+
+
+/// The current time, in seconds and microseconds since 1970.
+fn now() -> (i64, i32) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (time.as_secs() as i64, time.subsec_micros() as i32)
+}
+
+
+/// The fields of a `glktimeval_t`.
+fn time_value((seconds, micros): (i64, i32)) -> [u32; 0x3] {
+    [(seconds >> 0x20) as u32, seconds as u32, micros as u32]
+}
+
+
+/// The fields of a `glkdate_t` for a time in seconds since 1970.
+fn to_date(seconds: i64, micros: i32) -> [u32; 0x8] {
+    let days = seconds.div_euclid(0x15180);
+    let time = seconds.rem_euclid(0x15180);
+
+    // civil from days, after Howard Hinnant.
+    let z = days + 0xAFA6C;
+    let era = z.div_euclid(0x23AB1);
+    let doe = z - era * 0x23AB1;
+    let yoe = (doe - doe / 0x5B4 + doe / 0x8EAC - doe / 0x23AB0) / 0x16D;
+    let doy = doe - (0x16D * yoe + yoe / 0x4 - yoe / 0x64);
+    let mp = (0x5 * doy + 0x2) / 0x99;
+    let day = doy - (0x99 * mp + 0x2) / 0x5 + 0x1;
+    let month = if mp < 0xA { mp + 0x3 } else { mp - 0x9 };
+    let year = yoe + era * 0x190 + (month <= 0x2) as i64;
+
+    [
+        year as u32,
+        month as u32,
+        day as u32,
+        (days + 0x4).rem_euclid(0x7) as u32,
+        (time / 0xE10) as u32,
+        (time / 0x3C % 0x3C) as u32,
+        (time % 0x3C) as u32,
+        micros as u32,
+    ]
+}
+
+
+/// The time in seconds and microseconds since 1970 of the fields of a
+/// `glkdate_t`, which may be out of range.
+fn from_date(date: &[u32]) -> (i64, i32) {
+    let field = |index: usize| date[index] as i32 as i64;
+    let months = field(0x0) * 0xC + field(0x1) - 0x1;
+    let (year, month) = (months.div_euclid(0xC), months.rem_euclid(0xC) + 0x1);
+
+    // days from civil, after Howard Hinnant.
+    let y = year - (month <= 0x2) as i64;
+    let era = y.div_euclid(0x190);
+    let yoe = y - era * 0x190;
+    let mp = if month > 0x2 { month - 0x3 } else { month + 0x9 };
+    let doy = (0x99 * mp + 0x2) / 0x5;
+    let doe = yoe * 0x16D + yoe / 0x4 - yoe / 0x64 + doy;
+    let days = era * 0x23AB1 + doe - 0xAFA6C + field(0x2) - 0x1;
+
+    let micros = field(0x7);
+    let seconds = days * 0x15180 + field(0x4) * 0xE10 + field(0x5) * 0x3C
+        + field(0x6) + micros.div_euclid(0xF4240);
+    (seconds, micros.rem_euclid(0xF4240) as i32)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use assembler::Assembler;
+    use blorb::Blorb;
+    use error::GlulxError;
+    use memory::GlulxMemory;
+
+    use super::{
+        from_date,
+        to_date,
+        Glk,
+        GlkMemory,
+        Input,
+        TextGlk,
+        WindowKind,
+    };
+
+    fn memory() -> GlulxMemory {
+        let mut asm = Assembler::new();
+        let (main, ram) = (asm.label(), asm.label());
+        asm.start(main)
+            .ram(ram, &[0x0; 0x100])
+            .function(main, 0xC1, &[])
+            .op("return", &[::assembler::Arg::Zero]);
+        GlulxMemory::from_rom(asm.finish().unwrap()).unwrap()
+    }
+
+    fn call(glk: &mut TextGlk, memory: &mut GlulxMemory, selector: u32,
+            args: &[u32]) -> Option<u32> {
+        glk.call(selector, args, &mut GlkMemory::new(memory)).unwrap()
+    }
+
+    #[test]
+    fn windows_split_the_screen() {
+        let (mut glk, mut memory) = (TextGlk::new(), memory());
+        let main = call(&mut glk, &mut memory, 0x23, &[0x0, 0x0, 0x0, 0x3, 0x1])
+            .unwrap();
+        let status = call(&mut glk, &mut memory, 0x23,
+            &[main, 0x12, 0x1, 0x4, 0x2]).unwrap();
+
+        let windows = glk.windows();
+        assert_eq!(windows.len(), 0x3);
+        let pair = windows.iter().find(|w| w.kind == WindowKind::Pair).unwrap();
+        assert_eq!((pair.width, pair.height), (0x50, 0x18));
+        let info = |glk: &TextGlk, id| {
+            glk.windows().into_iter().find(|w| w.id == id).unwrap()
+        };
+        assert_eq!((info(&glk, status).height, info(&glk, main).height),
+            (0x1, 0x17));
+
+        call(&mut glk, &mut memory, 0x2F, &[status]);
+        call(&mut glk, &mut memory, 0x2B, &[status, 0x2, 0x0]);
+        call(&mut glk, &mut memory, 0x80, &[0x41]);
+        assert_eq!(glk.grid_lines(status), vec!["  A".to_string()]);
+
+        call(&mut glk, &mut memory, 0x24, &[status, 0x0]);
+        assert_eq!(glk.windows().len(), 0x1);
+        assert_eq!(call(&mut glk, &mut memory, 0x22, &[]), Some(main));
+        assert_eq!(info(&glk, main).height, 0x18);
+    }
+
+    #[test]
+    fn memory_streams_and_line_input() {
+        let (mut glk, mut memory) = (TextGlk::new(), memory());
+        let main = call(&mut glk, &mut memory, 0x23, &[0x0, 0x0, 0x0, 0x3, 0x0])
+            .unwrap();
+        let stream = call(&mut glk, &mut memory, 0x43,
+            &[0x100, 0x4, 0x1, 0x7]).unwrap();
+        call(&mut glk, &mut memory, 0x85, &[stream, 0x30, 0x0]);
+        for &ch in b"hello" {
+            call(&mut glk, &mut memory, 0x81, &[stream, ch as u32]);
+        }
+        call(&mut glk, &mut memory, 0x44, &[stream, 0x110]);
+        assert_eq!(&memory.bytes(0x100..0x104)[..], b"hell");
+        assert_eq!(&memory.bytes(0x110..0x118)[..],
+            &[0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x5]);
+
+        call(&mut glk, &mut memory, 0xD0, &[main, 0x120, 0x10, 0x0]);
+        assert!(glk.line_requested());
+        assert_eq!(call(&mut glk, &mut memory, 0xC0, &[0x140]), None);
+        assert!(glk.is_waiting());
+
+        glk.push_input(Input::Line("look".to_string()));
+        assert!(glk.select(&mut GlkMemory::new(&mut memory)).unwrap());
+        assert_eq!(&memory.bytes(0x120..0x124)[..], b"look");
+        assert_eq!(&memory.bytes(0x140..0x150)[..], &[
+            0x0, 0x0, 0x0, 0x3,
+            0x0, 0x0, 0x0, main as u8,
+            0x0, 0x0, 0x0, 0x4,
+            0x0, 0x0, 0x0, 0x0,
+        ]);
+        assert_eq!(glk.take_output(), "look\n");
+
+        let state = glk.save_state().unwrap();
+        let mut restored = TextGlk::new();
+        assert!(restored.restore_state(&state));
+        assert_eq!(restored.windows(), glk.windows());
+    }
+
+    #[test]
+    fn arrays_past_the_end_of_memory_are_errors() {
+        fn invalid<T>(address: u32) -> Result<T, GlulxError> {
+            Err(GlulxError::InvalidAddress { address })
+        }
+
+        let (mut glk, mut memory) = (TextGlk::new(), memory());
+        {
+            let mut memory = GlkMemory::new(&mut memory);
+            assert_eq!(memory.store_all(0xFFFFFFF8, &[0x1, 0x2, 0x3]),
+                invalid(0xFFFFFFF8));
+            assert_eq!(memory.read_all(0xFFFFFFFC, 0x2), invalid(0xFFFFFFFC));
+        }
+
+        // a Unicode stream whose position lies past the end of memory.
+        let stream = call(&mut glk, &mut memory, 0x139,
+            &[0x0, 0xFFFFFFFF, 0x3, 0x0]).unwrap();
+        call(&mut glk, &mut memory, 0x45, &[stream, 0x40000000, 0x0]);
+        let mut glk_memory = GlkMemory::new(&mut memory);
+        assert_eq!(glk.call(0x12B, &[stream, 0x41], &mut glk_memory),
+            invalid(0x0));
+        assert_eq!(glk.call(0x130, &[stream], &mut glk_memory), invalid(0x0));
+
+        let stream = call(&mut glk, &mut memory, 0x43,
+            &[0x100, 0x4, 0x3, 0x0]).unwrap();
+        let mut glk_memory = GlkMemory::new(&mut memory);
+        assert_eq!(glk.call(0x131, &[stream, 0xFFFFFFFC, 0x4], &mut glk_memory),
+            invalid(0xFFFFFFFC));
+    }
+
+    #[test]
+    fn dates_convert_both_ways() {
+        // 2000-02-29 12:34:56, a Tuesday.
+        let date = to_date(0x38BBBCF0, 0x7);
+        assert_eq!(date, [2000, 2, 29, 2, 12, 34, 56, 7]);
+        assert_eq!(from_date(&date), (0x38BBBCF0, 0x7));
+        assert_eq!(from_date(&[2000, 14, 1, 0, 0, 0, 0, 0]),
+            from_date(&[2001, 2, 1, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn blorb_pictures_and_data() {
+        // a picture given only by its size, and a text resource.
+        let bytes = b"FORM\0\0\0\x44IFRS\
+            RIdx\0\0\0\x1C\0\0\0\x02\
+            Pict\0\0\0\x01\0\0\0\x30Data\0\0\0\x02\0\0\0\x40\
+            Rect\0\0\0\x08\0\0\0\x03\0\0\0\x04\
+            TEXT\0\0\0\x03hi\n\0";
+        let mut glk = TextGlk::new();
+        glk.set_blorb(Blorb::from_bytes(bytes).unwrap());
+        let mut memory = memory();
+        let main = call(&mut glk, &mut memory, 0x23, &[0x0, 0x0, 0x0, 0x3, 0x0])
+            .unwrap();
+
+        assert_eq!(call(&mut glk, &mut memory, 0x04, &[0x7, 0x3]), Some(0x1));
+        assert_eq!(call(&mut glk, &mut memory, 0xE0, &[0x1, 0x100, 0x104]),
+            Some(0x1));
+        assert_eq!(&memory.bytes(0x100..0x108)[..],
+            &[0x0, 0x0, 0x0, 0x3, 0x0, 0x0, 0x0, 0x4]);
+        assert_eq!(call(&mut glk, &mut memory, 0xE1, &[main, 0x1, 0x0, 0x0]),
+            Some(0x1));
+        assert_eq!(call(&mut glk, &mut memory, 0xE1, &[main, 0x2, 0x0, 0x0]),
+            Some(0x0));
+        assert_eq!(call(&mut glk, &mut memory, 0xF2, &[0x0]), Some(0x0));
+
+        let stream = call(&mut glk, &mut memory, 0x49, &[0x2, 0x0]).unwrap();
+        assert_eq!(call(&mut glk, &mut memory, 0x91, &[stream, 0x110, 0x10]),
+            Some(0x3));
+        assert_eq!(&memory.bytes(0x110..0x114)[..], b"hi\n\0");
+        assert_eq!(call(&mut glk, &mut memory, 0x49, &[0x3, 0x0]), Some(0x0));
+    }
+}
@@ -0,0 +1,215 @@
+//! # Glulx header
+//!
+//! Every story file starts with a header of nine `u32` values:
+//!
+//! * `0x00` -- The magic number, `b"Glul"`
+//! * `0x04` -- The version of glulx the story was compiled for
+//! * `0x08` -- RAMSTART
+//! * `0x0C` -- EXTSTART
+//! * `0x10` -- ENDMEM
+//! * `0x14` -- The size of the stack
+//! * `0x18` -- The address of the function called to start the story
+//! * `0x1C` -- The address of the string decoding table
+//! * `0x20` -- The checksum of the story file
+//!
+//! Stories compiled by Inform follow the header with their own, which
+//! starts with `b"Info"`:
+//!
+//! * `0x28` -- The version of the Inform header, as a `u32`
+//! * `0x2C` -- The version of the Inform compiler, as four characters
+//! * `0x30` -- The version of the glulx back end of the compiler, as
+//!   four characters
+//! * `0x34` -- The release number of the story, as a `u16`
+//! * `0x36` -- The serial number of the story, as six characters
+
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+
+
+/// Glulx magic number
+const MAGIC_NUMBER: u32 = 0x476C756C;
+
+
+/// Minimum supported glulx target
+const MIN_VERSION: u32 = 0x0020000;
+
+
+/// Maximum supported glulx target
+const MAX_VERSION: u32 = 0x00301FF;
+
+
+/// The size of the glulx header.
+const HEADER_SIZE: usize = 0x24;
+
+
+/// The end of the Inform header.
+const INFORM_END: usize = 0x3C;
+
+
+/// A glulx version, which is stored as a `u32` with the major version in
+/// the top two bytes, followed by a byte each for the minor version and
+/// the patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+
+impl Version {
+
+    /// The oldest version of glulx which can be run.
+    pub const MIN_SUPPORTED: Version = Version::from_u32(MIN_VERSION);
+
+    /// The newest version of glulx which can be run. Any patch of this
+    /// version is supported.
+    pub const MAX_SUPPORTED: Version = Version::from_u32(MAX_VERSION);
+
+    pub const fn from_u32(value: u32) -> Version {
+        Version {
+            major: (value >> 0x10) as u16,
+            minor: (value >> 0x8) as u8,
+            patch: value as u8,
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        (self.major as u32) << 0x10 | (self.minor as u32) << 0x8
+            | self.patch as u32
+    }
+
+    /// Whether stories compiled for this version can be run.
+    pub fn is_supported(self) -> bool {
+        Version::MIN_SUPPORTED <= self && self <= Version::MAX_SUPPORTED
+    }
+}
+
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+
+/// The header written by Inform after the glulx header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InformHeader {
+
+    /// The version of the Inform header itself.
+    pub header_version: u32,
+
+    /// The version of the compiler, such as `"6.42"`.
+    pub compiler_version: String,
+
+    /// The version of the glulx back end of the compiler.
+    pub glulx_version: String,
+    pub release: u16,
+
+    /// The serial number, usually the date the story was compiled as
+    /// `YYMMDD`.
+    pub serial: String,
+}
+
+
+/// The header of a story file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlulxHeader {
+    pub version: Version,
+    pub ramstart: u32,
+    pub extstart: u32,
+    pub endmem: u32,
+    pub stack_size: u32,
+    pub start_func: u32,
+    pub decoding_tbl: u32,
+    pub checksum: u32,
+
+    /// The Inform header, for stories compiled by Inform.
+    pub inform: Option<InformHeader>,
+}
+
+
+impl GlulxHeader {
+
+    /// Reads the header at the start of a story file. Only the magic
+    /// number is checked; the other values are read as they are.
+    pub fn from_bytes(bytes: &[u8]) -> Result<GlulxHeader, String> {
+        if bytes.len() < HEADER_SIZE {
+            return Err("executable code is shorter than the header"
+                .to_string());
+        }
+        if BigEndian::read_u32(bytes) != MAGIC_NUMBER {
+            return Err("executable code starts with invalid magic number"
+                .to_string());
+        }
+        let word = |offset: usize| BigEndian::read_u32(&bytes[offset..]);
+        let text = |range: ::std::ops::Range<usize>| {
+            String::from_utf8_lossy(&bytes[range]).into_owned()
+        };
+
+        let inform = if bytes.len() >= INFORM_END
+                && &bytes[0x24..0x28] == b"Info" {
+            Some(InformHeader {
+                header_version: word(0x28),
+                compiler_version: text(0x2C..0x30),
+                glulx_version: text(0x30..0x34),
+                release: BigEndian::read_u16(&bytes[0x34..]),
+                serial: text(0x36..0x3C),
+            })
+        } else {
+            None
+        };
+
+        Ok(GlulxHeader {
+            version: Version::from_u32(word(0x4)),
+            ramstart: word(0x8),
+            extstart: word(0xC),
+            endmem: word(0x10),
+            stack_size: word(0x14),
+            start_func: word(0x18),
+            decoding_tbl: word(0x1C),
+            checksum: word(0x20),
+            inform,
+        })
+    }
+
+    /// Whether the story was compiled for a version of glulx which can
+    /// be run.
+    pub fn is_supported(&self) -> bool {
+        self.version.is_supported()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{GlulxHeader, Version};
+
+    #[test]
+    fn parses_headers() {
+        let mut bytes = b"Glul\x00\x03\x01\x03\0\0\x01\0\0\0\x02\0\0\0\x03\0\
+            \0\0\x04\0\0\0\0\x24\0\0\0\0\x12\x34\x56\x78".to_vec();
+        let header = GlulxHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.version, Version { major: 0x3, minor: 0x1, patch: 0x3 });
+        assert_eq!(header.version.to_string(), "3.1.3");
+        assert!(header.is_supported());
+        assert_eq!((header.ramstart, header.extstart, header.endmem),
+            (0x100, 0x200, 0x300));
+        assert_eq!((header.start_func, header.checksum), (0x24, 0x12345678));
+        assert_eq!(header.inform, None);
+
+        bytes.extend_from_slice(b"Info\0\x01\0\x006.420.5.\x00\x07260101");
+        let inform = GlulxHeader::from_bytes(&bytes).unwrap().inform.unwrap();
+        assert_eq!(inform.compiler_version, "6.42");
+        assert_eq!((inform.release, &inform.serial[..]), (0x7, "260101"));
+
+        bytes[0x5] = 0x4;
+        assert!(!GlulxHeader::from_bytes(&bytes).unwrap().is_supported());
+        assert!(Version::from_u32(0x00020000).is_supported());
+        assert!(GlulxHeader::from_bytes(b"Glul").is_err());
+        bytes[0x0] = b'X';
+        assert!(GlulxHeader::from_bytes(&bytes).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+//! # Glulx heap
+//!
+//! The heap is created by the first `malloc`, and starts at the end of
+//! memory as it was then. Memory grows in steps of 0x100 bytes whenever
+//! a block does not fit, and the heap is destroyed once every block has
+//! been freed, returning memory to its original size.
+//!
+//! Blocks are allocated first-fit, and adjacent free blocks are merged
+//! when a block is freed.
+
+use std::collections::BTreeMap;
+
+
+/// The blocks of an active heap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heap {
+    start: u32,
+
+    /// The length of the heap, which ends at the end of memory.
+    len: u32,
+
+    /// Every block of the heap, by address, with its length and whether
+    /// it is allocated. The blocks cover the heap without gaps.
+    blocks: BTreeMap<u32, (u32, bool)>,
+}
+
+
+impl Heap {
+
+    /// Creates an empty heap starting at the given address.
+    pub fn new(start: u32) -> Heap {
+        Heap { start, len: 0x0, blocks: BTreeMap::new() }
+    }
+
+    /// Rebuilds a heap from the address and length of each allocated
+    /// block, as stored in a save file, ending at the given end of
+    /// memory. Returns `None` if the heap does not lie within memory, or
+    /// its blocks are empty, overlap or lie outside it.
+    pub fn from_blocks(start: u32, end: u32, allocated: &[(u32, u32)])
+            -> Option<Heap> {
+        if start > end {
+            return None;
+        }
+        let mut heap = Heap::new(start);
+        heap.len = end - start;
+        let mut ptr = start;
+        let mut allocated = allocated.to_vec();
+        allocated.sort_unstable();
+        for (address, len) in allocated {
+            let next = address.checked_add(len)?;
+            if address < ptr || len == 0x0 || next > end {
+                return None;
+            }
+            if address > ptr {
+                heap.blocks.insert(ptr, (address - ptr, false));
+            }
+            heap.blocks.insert(address, (len, true));
+            ptr = next;
+        }
+        if end > ptr {
+            heap.blocks.insert(ptr, (end - ptr, false));
+        }
+        Some(heap)
+    }
+
+    /// The address of the start of the heap.
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// The address and length of each allocated block.
+    pub fn allocated(&self) -> Vec<(u32, u32)> {
+        self.blocks.iter()
+            .filter(|&(_, &(_, used))| used)
+            .map(|(&address, &(len, _))| (address, len))
+            .collect()
+    }
+
+    /// Whether no blocks are allocated.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.values().all(|&(_, used)| !used)
+    }
+
+    /// Allocates a block of the given length. Returns its address and the
+    /// new end of memory, which is past the current end when the heap
+    /// must grow.
+    pub fn allocate(&mut self, len: u32) -> (u32, u32) {
+        let free = self.blocks.iter()
+            .find(|&(_, &(size, used))| !used && size >= len)
+            .map(|(&address, &(size, _))| (address, size));
+
+        let (address, size) = match free {
+            Some(block) => block,
+            None => {
+                // grow the heap, extending a free block at its end.
+                let end = self.start + self.len;
+                let last = self.blocks.iter().next_back()
+                    .filter(|&(_, &(_, used))| !used)
+                    .map(|(&address, &(size, _))| (address, size));
+                let (address, size) = last.unwrap_or((end, 0x0));
+                let grow = (len - size).div_ceil(0x100) * 0x100;
+                self.len += grow;
+                (address, size + grow)
+            },
+        };
+
+        self.blocks.insert(address, (len, true));
+        if size > len {
+            self.blocks.insert(address + len, (size - len, false));
+        }
+        (address, self.start + self.len)
+    }
+
+    /// Frees the block at the given address, returning whether it was an
+    /// allocated block.
+    pub fn free(&mut self, address: u32) -> bool {
+        let len = match self.blocks.get(&address) {
+            Some(&(len, true)) => len,
+            _ => return false,
+        };
+        let mut start = address;
+        let mut len = len;
+
+        let next = address + len;
+        if let Some(&(next_len, false)) = self.blocks.get(&next) {
+            self.blocks.remove(&next);
+            len += next_len;
+        }
+        let previous = self.blocks.range(..address).next_back()
+            .map(|(&address, &block)| (address, block));
+        if let Some((previous, (previous_len, false))) = previous {
+            self.blocks.remove(&address);
+            start = previous;
+            len += previous_len;
+        }
+        self.blocks.insert(start, (len, false));
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Heap;
+
+    #[test]
+    fn allocates_and_merges_blocks() {
+        let mut heap = Heap::new(0x1000);
+        assert_eq!(heap.allocate(0x10), (0x1000, 0x1100));
+        assert_eq!(heap.allocate(0x20), (0x1010, 0x1100));
+        assert_eq!(heap.allocate(0x100), (0x1030, 0x1200));
+        assert_eq!(heap.allocated(),
+            vec![(0x1000, 0x10), (0x1010, 0x20), (0x1030, 0x100)]);
+
+        assert!(heap.free(0x1000));
+        assert!(!heap.free(0x1000));
+        assert!(heap.free(0x1010));
+        assert_eq!(heap.allocate(0x28), (0x1000, 0x1200));
+        assert!(heap.free(0x1030));
+        assert!(heap.free(0x1000));
+        assert!(heap.is_empty());
+
+        let rebuilt = Heap::from_blocks(0x1000, 0x1200, &[(0x1010, 0x20)]);
+        let mut rebuilt = rebuilt.unwrap();
+        assert_eq!(rebuilt.allocated(), vec![(0x1010, 0x20)]);
+        assert_eq!(rebuilt.allocate(0x10), (0x1000, 0x1200));
+    }
+
+    #[test]
+    fn rejects_invalid_blocks() {
+        assert_eq!(Heap::from_blocks(0x1300, 0x1200, &[]), None);
+        assert_eq!(Heap::from_blocks(0x1000, 0x1200, &[(0xF00, 0x10)]), None);
+        assert_eq!(Heap::from_blocks(0x1000, 0x1200, &[(0x11F0, 0x20)]), None);
+        assert_eq!(Heap::from_blocks(0x1000, 0x1200,
+            &[(0x1000, 0x20), (0x1010, 0x20)]), None);
+        assert_eq!(Heap::from_blocks(0x1000, 0x1200, &[(0x1000, 0x0)]), None);
+        assert_eq!(Heap::from_blocks(0x1000, 0x1200,
+            &[(0xFFFF_FFF0, 0x20)]), None);
+        assert!(Heap::from_blocks(0x1000, 0x1200,
+            &[(0x1100, 0x100), (0x1000, 0x10)]).is_some());
+    }
+}
@@ -0,0 +1,605 @@
+//! # Glulx instructions
+//!
+//! Instructions are decoded from memory without executing them, so the
+//! same decoder can be shared by the interpreter, disassemblers, and
+//! debuggers.
+//!
+//!
+//! ## Encoding
+//!
+//! An instruction consists of:
+//!
+//! * Opcode Number -- 1, 2, or 4 bytes, as determined by the top two bits
+//!   of the first byte
+//! * Operand Addressing Modes -- 4 bits per operand, packed two to a byte
+//!   with the low bits first
+//! * Operand Data -- 0, 1, 2, or 4 bytes per operand, as determined by its
+//!   addressing mode
+//!
+//! The number of operands, and whether each operand is loaded or stored,
+//! is fixed for each opcode.
+
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use error::GlulxError;
+
+use memory::GlulxMemory;
+
+
+/// Whether an operand is read from or written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKind {
+
+    /// The operand is read by the instruction.
+    Load,
+
+    /// The result of the instruction is written to the operand.
+    Store,
+}
+
+
+/// The addressing mode of an operand, along with its raw value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+
+    /// Constant zero when loaded; the value is discarded when stored.
+    /// (mode `0x0`)
+    Zero,
+
+    /// A constant, sign extended from its 1, 2, or 4 byte encoding.
+    /// (modes `0x1..0x3`)
+    Const(i32),
+
+    /// The contents of the contained memory address. (modes `0x5..0x7`)
+    Addr(u32),
+
+    /// A value popped off of or pushed onto the stack. (mode `0x8`)
+    Stack,
+
+    /// The local at the contained offset in the current call frame.
+    /// (modes `0x9..0xB`)
+    Local(u32),
+
+    /// The contents of the contained address offset from RAMSTART.
+    /// (modes `0xD..0xF`)
+    Ram(u32),
+}
+
+
+/// Static information about a glulx opcode.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Opcode {
+
+    /// The opcode number.
+    pub number: u32,
+
+    /// The name of the opcode used in assembly.
+    pub name: &'static str,
+
+    /// Whether each operand of the opcode is loaded or stored, in order.
+    pub operands: &'static [OperandKind],
+}
+
+
+impl Opcode {
+
+    /// Whether the last operand of the opcode is a branch offset.
+    pub fn branches(&self) -> bool {
+        matches!(self.number,
+            0x20 | 0x22..=0x2D | 0x32 | 0x1C0..=0x1C5 | 0x1C8 | 0x1C9)
+    }
+
+    /// Whether execution may continue with the following instruction.
+    /// This is false for opcodes which always jump, return, or stop.
+    pub fn continues(&self) -> bool {
+        !matches!(self.number,
+            0x20 | 0x31 | 0x33 | 0x34 | 0x104 | 0x120 | 0x122)
+    }
+}
+
+
+/// The destination of a branch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Branch {
+
+    /// Return the contained value, either `0` or `1`, from the current
+    /// function.
+    Return(u32),
+
+    /// Continue execution at the contained address.
+    Jump(u32),
+}
+
+
+/// A decoded instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+
+    /// The address of the first byte of the instruction.
+    pub address: u32,
+
+    /// The opcode of the instruction.
+    pub opcode: &'static Opcode,
+
+    /// The operands of the instruction, in order.
+    pub operands: Vec<Operand>,
+
+    /// The length of the instruction in bytes.
+    pub length: u32,
+}
+
+
+impl Instruction {
+
+    /// The address of the instruction following this one.
+    pub fn next(&self) -> u32 {
+        self.address.wrapping_add(self.length)
+    }
+
+    /// The operands which are loaded by the instruction, in order.
+    pub fn loads(&self) -> Vec<Operand> {
+        self.operands_of(OperandKind::Load)
+    }
+
+    /// The operands which the instruction stores results to, in order.
+    pub fn stores(&self) -> Vec<Operand> {
+        self.operands_of(OperandKind::Store)
+    }
+
+    /// The destination of the instruction's branch, if it has one with a
+    /// constant offset.
+    pub fn branch(&self) -> Option<Branch> {
+        if !self.opcode.branches() {
+            return None;
+        }
+
+        let offset = match self.operands.last() {
+            Some(&Operand::Zero) => 0x0,
+            Some(&Operand::Const(offset)) => offset as u32,
+            _ => return None,
+        };
+        match offset {
+            0x0 | 0x1 => Some(Branch::Return(offset)),
+            _ => Some(Branch::Jump(
+                self.next().wrapping_add(offset).wrapping_sub(0x2))),
+        }
+    }
+
+    fn operands_of(&self, kind: OperandKind) -> Vec<Operand> {
+        self.opcode.operands.iter()
+            .zip(&self.operands)
+            .filter(|&(&operand_kind, _)| operand_kind == kind)
+            .map(|(_, &operand)| operand)
+            .collect()
+    }
+}
+
+
+impl fmt::Display for Operand {
+
+    /// Formats the operand in assembly syntax. Memory operands are shown
+    /// as `*0x...`, and RAM operands as `*(RAM+0x...)`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Zero => write!(f, "0"),
+            Operand::Const(value) if value > -0x100 && value < 0x100 => {
+                write!(f, "{}", value)
+            },
+            Operand::Const(value) => write!(f, "{:#X}", value),
+            Operand::Addr(address) => write!(f, "*{:#X}", address),
+            Operand::Stack => write!(f, "sp"),
+            Operand::Local(offset) => write!(f, "local{}", offset),
+            Operand::Ram(address) => write!(f, "*(RAM+{:#X})", address),
+        }
+    }
+}
+
+
+impl fmt::Display for Instruction {
+
+    /// Formats the instruction in assembly syntax, such as
+    /// `@jlt local0 10 ?0x1234`. Branches with constant offsets are shown
+    /// by their destination, or as `?rfalse` and `?rtrue`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, |_| None)
+    }
+}
+
+
+impl Instruction {
+
+    /// Formats the instruction in assembly syntax, showing operands by the
+    /// names given by `name`, where it returns one.
+    pub fn fmt_with<F>(&self, f: &mut fmt::Formatter, name: F) -> fmt::Result
+            where F: Fn(Operand) -> Option<String> {
+        write!(f, "@{}", self.opcode.name)?;
+        let branch = self.branch();
+        for (i, &operand) in self.operands.iter().enumerate() {
+            let last = i + 0x1 == self.operands.len();
+            match branch {
+                Some(Branch::Return(0x0)) if last => write!(f, " ?rfalse")?,
+                Some(Branch::Return(_)) if last => write!(f, " ?rtrue")?,
+                Some(Branch::Jump(address)) if last => {
+                    write!(f, " ?{:#X}", address)?
+                },
+                _ => match name(operand) {
+                    Some(name) => write!(f, " {}", name)?,
+                    None => write!(f, " {}", operand)?,
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+
+use self::OperandKind::{Load as L, Store as S};
+
+
+/// All supported opcodes, sorted by opcode number.
+static OPCODES: &[Opcode] = &[
+    Opcode { number: 0x00, name: "nop", operands: &[] },
+    Opcode { number: 0x10, name: "add", operands: &[L, L, S] },
+    Opcode { number: 0x11, name: "sub", operands: &[L, L, S] },
+    Opcode { number: 0x12, name: "mul", operands: &[L, L, S] },
+    Opcode { number: 0x13, name: "div", operands: &[L, L, S] },
+    Opcode { number: 0x14, name: "mod", operands: &[L, L, S] },
+    Opcode { number: 0x15, name: "neg", operands: &[L, S] },
+    Opcode { number: 0x18, name: "bitand", operands: &[L, L, S] },
+    Opcode { number: 0x19, name: "bitor", operands: &[L, L, S] },
+    Opcode { number: 0x1A, name: "bitxor", operands: &[L, L, S] },
+    Opcode { number: 0x1B, name: "bitnot", operands: &[L, S] },
+    Opcode { number: 0x1C, name: "shiftl", operands: &[L, L, S] },
+    Opcode { number: 0x1D, name: "sshiftr", operands: &[L, L, S] },
+    Opcode { number: 0x1E, name: "ushiftr", operands: &[L, L, S] },
+    Opcode { number: 0x20, name: "jump", operands: &[L] },
+    Opcode { number: 0x22, name: "jz", operands: &[L, L] },
+    Opcode { number: 0x23, name: "jnz", operands: &[L, L] },
+    Opcode { number: 0x24, name: "jeq", operands: &[L, L, L] },
+    Opcode { number: 0x25, name: "jne", operands: &[L, L, L] },
+    Opcode { number: 0x26, name: "jlt", operands: &[L, L, L] },
+    Opcode { number: 0x27, name: "jge", operands: &[L, L, L] },
+    Opcode { number: 0x28, name: "jgt", operands: &[L, L, L] },
+    Opcode { number: 0x29, name: "jle", operands: &[L, L, L] },
+    Opcode { number: 0x2A, name: "jltu", operands: &[L, L, L] },
+    Opcode { number: 0x2B, name: "jgeu", operands: &[L, L, L] },
+    Opcode { number: 0x2C, name: "jgtu", operands: &[L, L, L] },
+    Opcode { number: 0x2D, name: "jleu", operands: &[L, L, L] },
+    Opcode { number: 0x30, name: "call", operands: &[L, L, S] },
+    Opcode { number: 0x31, name: "return", operands: &[L] },
+    Opcode { number: 0x32, name: "catch", operands: &[S, L] },
+    Opcode { number: 0x33, name: "throw", operands: &[L, L] },
+    Opcode { number: 0x34, name: "tailcall", operands: &[L, L] },
+    Opcode { number: 0x40, name: "copy", operands: &[L, S] },
+    Opcode { number: 0x41, name: "copys", operands: &[L, S] },
+    Opcode { number: 0x42, name: "copyb", operands: &[L, S] },
+    Opcode { number: 0x44, name: "sexs", operands: &[L, S] },
+    Opcode { number: 0x45, name: "sexb", operands: &[L, S] },
+    Opcode { number: 0x48, name: "aload", operands: &[L, L, S] },
+    Opcode { number: 0x49, name: "aloads", operands: &[L, L, S] },
+    Opcode { number: 0x4A, name: "aloadb", operands: &[L, L, S] },
+    Opcode { number: 0x4B, name: "aloadbit", operands: &[L, L, S] },
+    Opcode { number: 0x4C, name: "astore", operands: &[L, L, L] },
+    Opcode { number: 0x4D, name: "astores", operands: &[L, L, L] },
+    Opcode { number: 0x4E, name: "astoreb", operands: &[L, L, L] },
+    Opcode { number: 0x4F, name: "astorebit", operands: &[L, L, L] },
+    Opcode { number: 0x50, name: "stkcount", operands: &[S] },
+    Opcode { number: 0x51, name: "stkpeek", operands: &[L, S] },
+    Opcode { number: 0x52, name: "stkswap", operands: &[] },
+    Opcode { number: 0x53, name: "stkroll", operands: &[L, L] },
+    Opcode { number: 0x54, name: "stkcopy", operands: &[L] },
+    Opcode { number: 0x70, name: "streamchar", operands: &[L] },
+    Opcode { number: 0x71, name: "streamnum", operands: &[L] },
+    Opcode { number: 0x72, name: "streamstr", operands: &[L] },
+    Opcode { number: 0x73, name: "streamunichar", operands: &[L] },
+    Opcode { number: 0x100, name: "gestalt", operands: &[L, L, S] },
+    Opcode { number: 0x101, name: "debugtrap", operands: &[L] },
+    Opcode { number: 0x102, name: "getmemsize", operands: &[S] },
+    Opcode { number: 0x103, name: "setmemsize", operands: &[L, S] },
+    Opcode { number: 0x104, name: "jumpabs", operands: &[L] },
+    Opcode { number: 0x110, name: "random", operands: &[L, S] },
+    Opcode { number: 0x111, name: "setrandom", operands: &[L] },
+    Opcode { number: 0x120, name: "quit", operands: &[] },
+    Opcode { number: 0x121, name: "verify", operands: &[S] },
+    Opcode { number: 0x122, name: "restart", operands: &[] },
+    Opcode { number: 0x123, name: "save", operands: &[L, S] },
+    Opcode { number: 0x124, name: "restore", operands: &[L, S] },
+    Opcode { number: 0x125, name: "saveundo", operands: &[S] },
+    Opcode { number: 0x126, name: "restoreundo", operands: &[S] },
+    Opcode { number: 0x127, name: "protect", operands: &[L, L] },
+    Opcode { number: 0x130, name: "glk", operands: &[L, L, S] },
+    Opcode { number: 0x140, name: "getstringtbl", operands: &[S] },
+    Opcode { number: 0x141, name: "setstringtbl", operands: &[L] },
+    Opcode { number: 0x148, name: "getiosys", operands: &[S, S] },
+    Opcode { number: 0x149, name: "setiosys", operands: &[L, L] },
+    Opcode {
+        number: 0x150,
+        name: "linearsearch",
+        operands: &[L, L, L, L, L, L, L, S],
+    },
+    Opcode {
+        number: 0x151,
+        name: "binarysearch",
+        operands: &[L, L, L, L, L, L, L, S],
+    },
+    Opcode {
+        number: 0x152,
+        name: "linkedsearch",
+        operands: &[L, L, L, L, L, L, S],
+    },
+    Opcode { number: 0x160, name: "callf", operands: &[L, S] },
+    Opcode { number: 0x161, name: "callfi", operands: &[L, L, S] },
+    Opcode { number: 0x162, name: "callfii", operands: &[L, L, L, S] },
+    Opcode { number: 0x163, name: "callfiii", operands: &[L, L, L, L, S] },
+    Opcode { number: 0x170, name: "mzero", operands: &[L, L] },
+    Opcode { number: 0x171, name: "mcopy", operands: &[L, L, L] },
+    Opcode { number: 0x178, name: "malloc", operands: &[L, S] },
+    Opcode { number: 0x179, name: "mfree", operands: &[L] },
+    Opcode { number: 0x180, name: "accelfunc", operands: &[L, L] },
+    Opcode { number: 0x181, name: "accelparam", operands: &[L, L] },
+    Opcode { number: 0x190, name: "numtof", operands: &[L, S] },
+    Opcode { number: 0x191, name: "ftonumz", operands: &[L, S] },
+    Opcode { number: 0x192, name: "ftonumn", operands: &[L, S] },
+    Opcode { number: 0x198, name: "ceil", operands: &[L, S] },
+    Opcode { number: 0x199, name: "floor", operands: &[L, S] },
+    Opcode { number: 0x1A0, name: "fadd", operands: &[L, L, S] },
+    Opcode { number: 0x1A1, name: "fsub", operands: &[L, L, S] },
+    Opcode { number: 0x1A2, name: "fmul", operands: &[L, L, S] },
+    Opcode { number: 0x1A3, name: "fdiv", operands: &[L, L, S] },
+    Opcode { number: 0x1A4, name: "fmod", operands: &[L, L, S, S] },
+    Opcode { number: 0x1A8, name: "sqrt", operands: &[L, S] },
+    Opcode { number: 0x1A9, name: "exp", operands: &[L, S] },
+    Opcode { number: 0x1AA, name: "log", operands: &[L, S] },
+    Opcode { number: 0x1AB, name: "pow", operands: &[L, L, S] },
+    Opcode { number: 0x1B0, name: "sin", operands: &[L, S] },
+    Opcode { number: 0x1B1, name: "cos", operands: &[L, S] },
+    Opcode { number: 0x1B2, name: "tan", operands: &[L, S] },
+    Opcode { number: 0x1B3, name: "asin", operands: &[L, S] },
+    Opcode { number: 0x1B4, name: "acos", operands: &[L, S] },
+    Opcode { number: 0x1B5, name: "atan", operands: &[L, S] },
+    Opcode { number: 0x1B6, name: "atan2", operands: &[L, L, S] },
+    Opcode { number: 0x1C0, name: "jfeq", operands: &[L, L, L, L] },
+    Opcode { number: 0x1C1, name: "jfne", operands: &[L, L, L, L] },
+    Opcode { number: 0x1C2, name: "jflt", operands: &[L, L, L] },
+    Opcode { number: 0x1C3, name: "jfle", operands: &[L, L, L] },
+    Opcode { number: 0x1C4, name: "jfgt", operands: &[L, L, L] },
+    Opcode { number: 0x1C5, name: "jfge", operands: &[L, L, L] },
+    Opcode { number: 0x1C8, name: "jisnan", operands: &[L, L] },
+    Opcode { number: 0x1C9, name: "jisinf", operands: &[L, L] },
+    Opcode { number: 0x1000, name: "fyrecall", operands: &[L, L, L, S] },
+];
+
+
+/// Looks up the opcode with the given number.
+pub fn opcode(number: u32) -> Option<&'static Opcode> {
+    OPCODES.binary_search_by_key(&number, |opcode| opcode.number)
+        .ok()
+        .map(|index| &OPCODES[index])
+}
+
+
+/// Looks up the opcode with the given assembly name.
+pub fn opcode_named(name: &str) -> Option<&'static Opcode> {
+    OPCODES.iter().find(|opcode| opcode.name == name)
+}
+
+
+/// Reads the bytes of an instruction, checking that each read lies
+/// within memory.
+struct Reader<'a> {
+    memory: &'a GlulxMemory,
+    ptr: u32,
+}
+
+
+impl<'a> Reader<'a> {
+    fn check(&self, len: u32) -> Result<u32, GlulxError> {
+        match self.ptr.checked_add(len) {
+            Some(end) if end <= self.memory.get_mem_size() => Ok(self.ptr),
+            _ => Err(GlulxError::InvalidAddress { address: self.ptr }),
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, GlulxError> {
+        let ptr = self.check(0x1)?;
+        self.ptr += 0x1;
+        let mut buf = [0x0; 0x1];
+        self.memory.peek(ptr, &mut buf);
+        Ok(buf[0x0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, GlulxError> {
+        let ptr = self.check(0x2)?;
+        self.ptr += 0x2;
+        let mut buf = [0x0; 0x2];
+        self.memory.peek(ptr, &mut buf);
+        Ok(BigEndian::read_u16(&buf))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, GlulxError> {
+        let ptr = self.check(0x4)?;
+        self.ptr += 0x4;
+        let mut buf = [0x0; 0x4];
+        self.memory.peek(ptr, &mut buf);
+        Ok(BigEndian::read_u32(&buf))
+    }
+
+    /// Reads the operand data for the given addressing mode.
+    fn read_operand(&mut self, mode: u8, kind: OperandKind, address: u32)
+            -> Result<Operand, GlulxError> {
+        let operand = match (mode, kind) {
+            (0x0, _) => Operand::Zero,
+            (0x1, OperandKind::Load) =>
+                Operand::Const(self.read_u8()? as i8 as i32),
+            (0x2, OperandKind::Load) =>
+                Operand::Const(self.read_u16()? as i16 as i32),
+            (0x3, OperandKind::Load) =>
+                Operand::Const(self.read_u32()? as i32),
+            (0x5, _) => Operand::Addr(self.read_u8()? as u32),
+            (0x6, _) => Operand::Addr(self.read_u16()? as u32),
+            (0x7, _) => Operand::Addr(self.read_u32()?),
+            (0x8, _) => Operand::Stack,
+            (0x9, _) => Operand::Local(self.read_u8()? as u32),
+            (0xA, _) => Operand::Local(self.read_u16()? as u32),
+            (0xB, _) => Operand::Local(self.read_u32()?),
+            (0xD, _) => Operand::Ram(self.read_u8()? as u32),
+            (0xE, _) => Operand::Ram(self.read_u16()? as u32),
+            (0xF, _) => Operand::Ram(self.read_u32()?),
+            (mode, _) => {
+                return Err(GlulxError::InvalidOperandMode { mode, address });
+            },
+        };
+        Ok(operand)
+    }
+}
+
+
+/// Decodes the instruction at the given address without modifying any
+/// machine state.
+pub fn decode(memory: &GlulxMemory, address: u32)
+        -> Result<Instruction, GlulxError> {
+    let mut reader = Reader { memory, ptr: address };
+
+    let top = reader.read_u8()?;
+    let number = match top {
+        _ if top < 0x80 => top as u32,
+        _ if top < 0xC0 => {
+            reader.ptr = address;
+            reader.read_u16()? as u32 - 0x8000
+        },
+        _ => {
+            reader.ptr = address;
+            reader.read_u32()? - 0xC000_0000
+        },
+    };
+
+    let opcode = match opcode(number) {
+        Some(opcode) => opcode,
+        None => return Err(GlulxError::UnknownOpcode { opcode: number, address }),
+    };
+
+    let mut modes = Vec::with_capacity(opcode.operands.len());
+    while modes.len() < opcode.operands.len() {
+        let byte = reader.read_u8()?;
+        modes.push(byte & 0x0F);
+        modes.push((byte & 0xF0) >> 0x4);
+    }
+
+    let mut operands = Vec::with_capacity(opcode.operands.len());
+    for (&mode, &kind) in modes.iter().zip(opcode.operands) {
+        operands.push(reader.read_operand(mode, kind, address)?);
+    }
+
+    Ok(Instruction {
+        address,
+        opcode,
+        operands,
+        length: reader.ptr - address,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, ByteOrder};
+
+    use memory::GlulxMemory;
+
+    use super::{decode, opcode, Operand, OPCODES};
+    use error::GlulxError;
+
+    /// Builds a memory with the given code placed at `0x100`.
+    fn memory_with_code(code: &[u8]) -> GlulxMemory {
+        let mut rom = vec![0x0; 0x200];
+        rom[0x0..0x4].copy_from_slice(b"Glul");
+        BigEndian::write_u32(&mut rom[0x4..], 0x00030102);
+        BigEndian::write_u32(&mut rom[0x8..], 0x200);
+        BigEndian::write_u32(&mut rom[0xC..], 0x200);
+        BigEndian::write_u32(&mut rom[0x10..], 0x200);
+        BigEndian::write_u32(&mut rom[0x14..], 0x100);
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+
+        let sum = rom.chunks(0x4)
+            .fold(0u32, |sum, word| sum.wrapping_add(BigEndian::read_u32(word)));
+        BigEndian::write_u32(&mut rom[0x20..], sum);
+        GlulxMemory::from_rom(rom).unwrap()
+    }
+
+    #[test]
+    fn opcodes_are_sorted() {
+        assert!(OPCODES.windows(0x2).all(|w| w[0].number < w[1].number));
+    }
+
+    #[test]
+    fn decodes_operand_modes() {
+        // add -2 Frame[0x10] -> Push
+        let memory = memory_with_code(&[0x10, 0x91, 0x08, 0xFE, 0x10]);
+        let instruction = decode(&memory, 0x100).unwrap();
+
+        assert_eq!(instruction.opcode, opcode(0x10).unwrap());
+        assert_eq!(instruction.operands, vec![
+            Operand::Const(-2),
+            Operand::Local(0x10),
+            Operand::Stack,
+        ]);
+        assert_eq!(instruction.stores(), vec![Operand::Stack]);
+        assert_eq!(instruction.length, 0x5);
+        assert_eq!(instruction.next(), 0x105);
+    }
+
+    #[test]
+    fn decodes_long_opcodes() {
+        // callfi 0x12345678 Ram[0x1234] -> discard, as a 2 byte opcode
+        let memory = memory_with_code(&[
+            0x81, 0x61, 0xE3, 0x00, 0x12, 0x34, 0x56, 0x78, 0x12, 0x34,
+            // gestalt 4 2 -> Addr[0xAB], as a 4 byte opcode
+            0xC0, 0x00, 0x01, 0x00, 0x11, 0x05, 0x04, 0x02, 0xAB,
+        ]);
+
+        let instruction = decode(&memory, 0x100).unwrap();
+        assert_eq!(instruction.opcode.name, "callfi");
+        assert_eq!(instruction.operands, vec![
+            Operand::Const(0x12345678),
+            Operand::Ram(0x1234),
+            Operand::Zero,
+        ]);
+
+        let instruction = decode(&memory, instruction.next()).unwrap();
+        assert_eq!(instruction.opcode.name, "gestalt");
+        assert_eq!(instruction.stores(), vec![Operand::Addr(0xAB)]);
+        assert_eq!(instruction.length, 0x9);
+    }
+
+    #[test]
+    fn rejects_invalid_instructions() {
+        let memory = memory_with_code(&[0x7F, 0x40, 0x11, 0x05, 0x40, 0x04]);
+
+        assert_eq!(decode(&memory, 0x100),
+            Err(GlulxError::UnknownOpcode { opcode: 0x7F, address: 0x100 }));
+        assert_eq!(decode(&memory, 0x101),
+            Err(GlulxError::InvalidOperandMode { mode: 0x1, address: 0x101 }));
+        assert_eq!(decode(&memory, 0x104),
+            Err(GlulxError::InvalidOperandMode { mode: 0x4, address: 0x104 }));
+        assert_eq!(decode(&memory, 0x200),
+            Err(GlulxError::InvalidAddress { address: 0x200 }));
+    }
+
+    #[test]
+    fn formats_assembly() {
+        // jlt Frame[0x4] 300 ?+0x10, add Ram[0x8] *0x10 -> Push, jz 0 ?rtrue
+        let memory = memory_with_code(&[
+            0x26, 0x29, 0x01, 0x04, 0x01, 0x2C, 0x10,
+            0x10, 0x5D, 0x08, 0x08, 0x10,
+            0x22, 0x10, 0x01,
+        ]);
+
+        let instruction = decode(&memory, 0x100).unwrap();
+        assert_eq!(instruction.to_string(), "@jlt local4 0x12C ?0x115");
+        let instruction = decode(&memory, instruction.next()).unwrap();
+        assert_eq!(instruction.to_string(), "@add *(RAM+0x8) *0x10 sp");
+        let instruction = decode(&memory, instruction.next()).unwrap();
+        assert_eq!(instruction.to_string(), "@jz 0 ?rtrue");
+    }
+}
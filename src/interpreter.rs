@@ -1,110 +1,896 @@
-use std::mem::size_of;
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Range;
+use std::sync::Arc;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use coverage::Coverage;
+
+use error::GlulxError;
+
+use glk::{
+    Glk,
+    GlkMemory,
+    TextGlk,
+};
+
+use heap::Heap;
+
+use instruction::{
+    decode,
+    Instruction,
+    Operand,
+};
 
 use memory::{
     GlulxMemory,
     Memory,
 };
 
+use pages::Pages;
+
+use profile::Profiler;
+
+use quetzal::Quetzal;
+
+use random::Random;
+
 use stack::{
+    Frame,
     GlulxStack,
+    Local,
     Stack,
+    StackError,
+};
+
+use story::Story;
+
+use string::{
+    read_node,
+    StringNode,
+};
+
+use trace::{
+    TraceRecord,
+    Tracer,
+};
+
+use trap::{
+    TrapAction,
+    TrapHandler,
+};
+
+use watch::{
+    Access,
+    WatchAction,
+    WatchEvent,
 };
 
 
 pub struct Glulx {
     program_counter: u32,
+    instruction_ptr: u32,
     stack: GlulxStack,
     memory: GlulxMemory,
     running: bool,
+
+    /// Set when execution should stop after the current instruction, and
+    /// cleared when it resumes.
+    paused: bool,
+    watch_actions: BTreeMap<u32, WatchAction>,
+    next_watchpoint: u32,
+
+    /// Hits of pausing watchpoints, not yet taken by the caller.
+    watch_events: Vec<WatchEvent>,
+    trap_handler: Option<TrapHandler>,
+
+    /// The argument of a debug trap which paused execution, not yet
+    /// taken by the caller.
+    trap: Option<u32>,
+    tracer: Option<Box<dyn Tracer + Send>>,
+
+    /// The record of the current instruction, while a tracer is
+    /// installed.
+    trace: Option<TraceRecord>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    glk: Box<dyn Glk>,
+
+    /// The I/O system and its rock, as set by `setiosys`.
+    iosys: (u32, u32),
+    string_table: u32,
+    random: Random,
+
+    /// The seed used by `setrandom` in place of a seed from the clock,
+    /// so runs can be repeated.
+    fixed_seed: Option<u32>,
+
+    /// The start and length of the range of memory kept by `restore`,
+    /// `restoreundo` and `restart`.
+    protect: (u32, u32),
+    undo: Vec<UndoState>,
+
+    /// The functions given to `accelfunc`, by address. They are recorded
+    /// but never accelerated.
+    accel_functions: BTreeMap<u32, u32>,
+    accel_params: [u32; 0x9],
+
+    /// Where the result of a `glk_select` call is stored, while it waits
+    /// for an event.
+    waiting: Option<Save>,
+
+    /// The channel printed to by the FyreVM I/O system, as four
+    /// characters packed into a `u32`.
+    channel: u32,
+
+    /// The text printed to each channel since it was last taken.
+    channels: BTreeMap<String, String>,
+    channel_input: VecDeque<String>,
+
+    /// The FyreVM input call which is waiting for input.
+    channel_wait: Option<ChannelWait>,
+}
+
+
+/// The I/O system which discards output.
+const IOSYS_NULL: u32 = 0x0;
+
+
+/// The I/O system which calls a function for each character.
+const IOSYS_FILTER: u32 = 0x1;
+
+
+/// The I/O system which prints through Glk.
+const IOSYS_GLK: u32 = 0x2;
+
+
+/// The FyreVM I/O system, which prints to named channels.
+const IOSYS_CHANNELS: u32 = 0x14;
+
+
+/// The channel printed to by default, `b"MAIN"`.
+const CHANNEL_MAIN: u32 = 0x4D41494E;
+
+
+/// The number of states kept by `saveundo`.
+const MAX_UNDO: usize = 0x8;
+
+
+/// How printing part of a string ended.
+enum Streamed {
+
+    /// The string ended.
+    Ended,
+
+    /// A function was called, which resumes printing when it returns.
+    Called,
+
+    /// Printing continues with the string of the given type at the given
+    /// address, or of the type given by its first byte for a type of `0`.
+    Nested(u32, u8),
+}
+
+
+/// A FyreVM input call waiting for input.
+#[derive(Debug, Clone, Copy)]
+enum ChannelWait {
+
+    /// A line is stored in the buffer of the given length, after a `u32`
+    /// giving its length.
+    Line { buffer: u32, len: u32 },
+
+    /// A character is stored.
+    Key(Save),
+}
+
+
+/// The state saved by `saveundo`.
+#[derive(Clone)]
+struct UndoState {
+    memory: Pages,
+    stack: Vec<u8>,
+    heap: Option<Heap>,
+}
+
+
+/// The whole state of a running machine, as taken by `Glulx::snapshot`.
+/// Snapshots share their state, so cloning one is cheap, and they can
+/// be sent to other threads. The memory of a snapshot shares its pages
+/// with the machine until either writes them.
+#[derive(Clone)]
+pub struct VmSnapshot {
+    state: Arc<SnapshotState>,
+}
+
+
+struct SnapshotState {
+
+    /// The glulx header of the story, to check the snapshot is restored
+    /// into the same story.
+    header: Vec<u8>,
+    memory: Pages,
+    heap: Option<Heap>,
+    stack: GlulxStack,
+    program_counter: u32,
+    instruction_ptr: u32,
+    running: bool,
+    iosys: (u32, u32),
+    string_table: u32,
+    random: Random,
+    protect: (u32, u32),
+    accel_functions: BTreeMap<u32, u32>,
+    accel_params: [u32; 0x9],
+    waiting: Option<Save>,
+    channel: u32,
+    channel_wait: Option<ChannelWait>,
+    glk: Option<Vec<u8>>,
+}
+
+
+impl VmSnapshot {
+
+    /// The address of the next instruction the machine executes.
+    pub fn program_counter(&self) -> u32 {
+        self.state.program_counter
+    }
+
+    /// The size of memory, including the heap.
+    pub fn memory_size(&self) -> u32 {
+        self.state.memory.len()
+    }
 }
 
 
 macro_rules! opcode_match {
-    (@inner $self_:ident, $arg1:ident $arg2:ident $($args:ident)*) => {
-        let ($arg1, $arg2) = $self_.lo_hi();
-        opcode_match!(@inner $self_, $($args)*);
-    };
-    (@inner $self_:ident, $arg:ident) => {
-        let ($arg, _) = $self_.lo_hi();
-    };
-    (@inner $self_:ident,) => (());
     ($self_:ident,
-        $val:expr,
+        $instruction:expr,
         $($num:pat => $opcode:ident ( $($args:ident),* $(,)* ) ),* $(,)*
-    ) => (
-        match $val {
+    ) => ({
+        let mut operands = $instruction.operands.iter().cloned();
+        match $instruction.opcode.number {
             $(
                 $num => {
-                    opcode_match!(@inner $self_, $($args)*);
-                    $(let $args = $self_.read_register($args);)*
-                    $self_.$opcode($($args),*)
+                    $(
+                        let $args = $self_.read_register(
+                            operands.next().unwrap())?;
+                        $self_.trace_load(&$args);
+                    )*
+                    OpcodeResult::into_result($self_.$opcode($($args),*))
                 },
             )*
             x => panic!("unsupported opcode: {:#X}", x),
         }
-    )
+    })
+}
+
+
+/// Converts the value returned by an opcode into the result of a step,
+/// so opcodes which cannot fail need not return a `Result`.
+trait OpcodeResult {
+    fn into_result(self) -> Result<(), GlulxError>;
+}
+
+
+impl OpcodeResult for () {
+    fn into_result(self) -> Result<(), GlulxError> {
+        Ok(())
+    }
+}
+
+
+impl OpcodeResult for Result<(), GlulxError> {
+    fn into_result(self) -> Result<(), GlulxError> {
+        self
+    }
+}
+
+
+/// Converts the value of an operand into a traced load, so stores, which
+/// are not loaded, can be skipped.
+trait TraceValue {
+    fn trace_value(&self) -> Option<u32>;
+}
+
+
+impl TraceValue for u8 {
+    fn trace_value(&self) -> Option<u32> {
+        Some(*self as u32)
+    }
+}
+
+
+impl TraceValue for u16 {
+    fn trace_value(&self) -> Option<u32> {
+        Some(*self as u32)
+    }
+}
+
+
+impl TraceValue for u32 {
+    fn trace_value(&self) -> Option<u32> {
+        Some(*self)
+    }
+}
+
+
+impl TraceValue for i32 {
+    fn trace_value(&self) -> Option<u32> {
+        Some(*self as u32)
+    }
+}
+
+
+impl TraceValue for f32 {
+    fn trace_value(&self) -> Option<u32> {
+        Some(self.to_bits())
+    }
+}
+
+
+impl TraceValue for Save {
+    fn trace_value(&self) -> Option<u32> {
+        None
+    }
 }
 
 
 impl Glulx {
     /// Create a glulx machine with the given ROM loaded.
     pub fn from_rom(rom: Vec<u8>) -> Result<Glulx, String> {
-        GlulxMemory::from_rom(rom).and_then(|memory| {
-            let stack = GlulxStack::new(memory.stack_size());
-            Ok(Glulx {
-                program_counter: 0,
-                stack: stack,
-                memory: memory,
-                running: false,
-            })
-        })
+        Story::from_rom(rom).map(|story| Glulx::from_story(Arc::new(story)))
+    }
+
+    /// Create a glulx machine running a story shared with other machines.
+    pub fn from_story(story: Arc<Story>) -> Glulx {
+        let memory = GlulxMemory::from_story(story);
+        let stack = GlulxStack::new(memory.stack_size());
+        let string_table = memory.decoding_tbl();
+        Glulx {
+            program_counter: 0,
+            instruction_ptr: 0,
+            stack,
+            memory,
+            running: false,
+            paused: false,
+            watch_actions: BTreeMap::new(),
+            next_watchpoint: 0x0,
+            watch_events: vec![],
+            trap_handler: None,
+            trap: None,
+            tracer: None,
+            trace: None,
+            profiler: None,
+            coverage: None,
+            glk: Box::new(TextGlk::new()),
+            iosys: (IOSYS_NULL, 0x0),
+            string_table,
+            random: Random::new(0x0),
+            fixed_seed: None,
+            protect: (0x0, 0x0),
+            undo: vec![],
+            accel_functions: BTreeMap::new(),
+            accel_params: [0x0; 0x9],
+            waiting: None,
+            channel: CHANNEL_MAIN,
+            channels: BTreeMap::new(),
+            channel_input: VecDeque::new(),
+            channel_wait: None,
+        }
+    }
+
+    /// Builds the error for a failed stack operation by the instruction
+    /// currently being executed.
+    fn stack_error(&self, error: StackError) -> GlulxError {
+        match error {
+            StackError::Overflow => GlulxError::StackOverflow {
+                call_depth: self.stack.call_depth(),
+                program_counter: self.instruction_ptr,
+            },
+            StackError::Underflow => GlulxError::StackUnderflow {
+                address: self.instruction_ptr,
+            },
+            // call_func reports the address of the function instead.
+            StackError::InvalidLocalType(_) => GlulxError::InvalidFunction {
+                address: self.instruction_ptr,
+            },
+            StackError::InvalidLocal(offset) => GlulxError::InvalidLocal {
+                offset,
+                address: self.instruction_ptr,
+            },
+        }
+    }
+
+    /// Pushes a value onto the stack, failing if the stack is full.
+    fn push<T>(&mut self, value: T) -> Result<(), GlulxError>
+            where GlulxStack: Stack<T> {
+        self.stack.push(value).map_err(|error| self.stack_error(error))
+    }
+
+    /// Pops a value off the stack, failing if the current call frame
+    /// holds none.
+    fn pop<T>(&mut self) -> Result<T, GlulxError>
+            where GlulxStack: Stack<T> {
+        self.stack.pop().map_err(|error| self.stack_error(error))
+    }
+
+    /// Reads the local at the given offset in the current call frame.
+    fn read_local<T>(&self, offset: u32) -> Result<T, GlulxError>
+            where GlulxStack: Stack<T> {
+        self.stack.read(offset).map_err(|error| self.stack_error(error))
+    }
+
+    /// Writes the local at the given offset in the current call frame.
+    fn write_local<T>(&mut self, offset: u32, value: T)
+            -> Result<(), GlulxError> where GlulxStack: Stack<T> {
+        self.stack.write(offset, value)
+            .map_err(|error| self.stack_error(error))
     }
 
     /// Parses the save location to determine the destination type and
     /// address, and then pushes that information (along with the
     /// current program counter value) onto the stack.
-    fn push_call_stub(&mut self, save: Save) {
-        let (dest_type, dest_addr) = match save {
-            Save::Null => (0, 0),
-            Save::Addr(addr) => (1, addr),
-            Save::Frame(addr) => (2, addr),
-            Save::Push => (3, 0),
-            x => panic!("save mode not supported: {:?}", x),
-        };
-        self.stack.push_call_stub(dest_type, dest_addr, self.program_counter);
+    fn push_call_stub(&mut self, save: Save) -> Result<(), GlulxError> {
+        let (dest_type, dest_addr) = self.save_stub(save);
+        let program_counter = self.program_counter;
+        self.push_stub(dest_type, dest_addr, program_counter)
     }
 
-    fn call_func(&mut self, address: u32, args: Vec<u32>) {
+    fn call_func(&mut self, address: u32, args: Vec<u32>)
+            -> Result<(), GlulxError> {
         self.program_counter = address;
 
-        let func_type: u8 = self.memory.read(self.program_counter);
+        let func_type: u8 = self.read_checked(address)
+            .map_err(|_| GlulxError::InvalidFunction { address })?;
         self.program_counter += 0x1;
 
-        let locals = self.read_locals();
+        let locals = self.read_locals()
+            .map_err(|_| GlulxError::InvalidFunction { address })?;
 
         match func_type {
-            0xC0 => self.stack.push_call_frame_c0(locals, args),
-            0xC1 => self.stack.push_call_frame_c1(locals, args),
-            _ => panic!("unsupported function type called"),
+            0xC0 => self.stack.push_call_frame_c0(&locals, &args),
+            0xC1 => self.stack.push_call_frame_c1(&locals, &args),
+            _ => return Err(GlulxError::InvalidFunction { address }),
+        }.map_err(|error| match error {
+            StackError::InvalidLocalType(_) => {
+                GlulxError::InvalidFunction { address }
+            },
+            error => self.stack_error(error),
+        })?;
+        self.stack.set_function(address);
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.enter(address);
+        }
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.enter(address);
+        }
+        Ok(())
+    }
+
+    /// Pops the call stub beneath the current call frame, which has
+    /// already been popped, and stores the given value at its
+    /// destination. Stubs pushed while printing a string resume printing
+    /// instead.
+    fn return_to_stub(&mut self, value: u32) -> Result<(), GlulxError> {
+        let (dest_type, dest_addr, program_counter) = self.stack.pop_call_stub();
+        self.program_counter = program_counter;
+        let save = match dest_type {
+            0x0 => Save::Null,
+            0x1 => Save::Addr(dest_addr),
+            0x2 => Save::Frame(dest_addr),
+            0x3 => Save::Push,
+            0x10 => return self.stream_string(program_counter, 0xE1, dest_addr),
+            0x12 => return self.stream_num(program_counter, true, dest_addr),
+            0x13 => return self.stream_string(program_counter, 0xE0, 0x0),
+            0x14 => return self.stream_string(program_counter, 0xE2, 0x0),
+            x => return Err(GlulxError::InvalidCallStub { dest_type: x }),
+        };
+        self.save(save, value)
+    }
+
+    /// The call stub for a result stored at the given location.
+    fn save_stub(&self, save: Save) -> (u32, u32) {
+        match save {
+            Save::Null => (0x0, 0x0),
+            Save::Addr(addr) => (0x1, addr),
+            Save::Frame(addr) => (0x2, addr),
+            Save::Push => (0x3, 0x0),
+            Save::Ram(addr) => (0x1, self.ram_ptr(addr)),
+        }
+    }
 
+    /// Pushes a call stub of the given type, returning to the given
+    /// address.
+    fn push_stub(&mut self, dest_type: u32, dest_addr: u32, program_counter: u32)
+            -> Result<(), GlulxError> {
+        self.stack.push_call_stub(dest_type, dest_addr, program_counter)
+            .map_err(|error| self.stack_error(error))
+    }
+
+    /// Prints a character with the Glk I/O system.
+    fn glk_put_char(&mut self, ch: u32) -> Result<(), GlulxError> {
+        let mut memory = GlkMemory::new(&mut self.memory);
+        self.glk.call(0x128, &[ch], &mut memory)?;
+        Ok(())
+    }
+
+    /// Prints a character with the current I/O system. With the filter
+    /// system, the filter function is called, returning to the given
+    /// call stub.
+    fn put_char(&mut self, ch: u32, stub: (u32, u32, u32))
+            -> Result<bool, GlulxError> {
+        match self.iosys.0 {
+            IOSYS_GLK => self.glk_put_char(ch).map(|_| false),
+            IOSYS_CHANNELS => {
+                let name = channel_name(self.channel);
+                let ch = ::std::char::from_u32(ch).unwrap_or('\u{FFFD}');
+                self.channels.entry(name).or_default().push(ch);
+                Ok(false)
+            },
+            IOSYS_FILTER => {
+                let (dest_type, dest_addr, program_counter) = stub;
+                self.push_stub(dest_type, dest_addr, program_counter)?;
+                let rock = self.iosys.1;
+                self.call_func(rock, vec![ch])?;
+                Ok(true)
+            },
+            _ => Ok(false),
         }
     }
 
+    /// Prints the string at the given address, or continues printing a
+    /// string part way through when `inmiddle` is the type of the string.
+    /// Strings which call functions, including the filter function, push
+    /// call stubs to resume printing, and the first of these marks the end
+    /// of the string with a stub of type `0x11`.
+    fn stream_string(&mut self, address: u32, inmiddle: u8, bit: u32)
+            -> Result<(), GlulxError> {
+        let mut substring = inmiddle != 0x0;
+        let (mut address, mut inmiddle, mut bit) = (address, inmiddle, bit);
+
+        loop {
+            let string_type = if inmiddle == 0x0 {
+                let string_type: u8 = self.read_checked(address)?;
+                match string_type {
+                    0xE0 | 0xE1 => address += 0x1,
+                    0xE2 => address += 0x4,
+                    _ => return Err(GlulxError::InvalidString { address }),
+                }
+                bit = 0x0;
+                string_type
+            } else {
+                inmiddle
+            };
+
+            let next = match string_type {
+                0xE1 => self.stream_compressed(address, bit, &mut substring)?,
+                _ => {
+                    let unicode = string_type == 0xE2;
+                    let size = if unicode { 0x4 } else { 0x1 };
+                    loop {
+                        let ch = if unicode {
+                            self.read_checked::<u32>(address)?
+                        } else {
+                            self.read_checked::<u8>(address)? as u32
+                        };
+                        address += size;
+                        if ch == 0x0 {
+                            break;
+                        }
+                        if self.iosys.0 == IOSYS_FILTER {
+                            self.mark_substring(&mut substring)?;
+                        }
+                        let dest_type = if unicode { 0x14 } else { 0x13 };
+                        if self.put_char(ch, (dest_type, 0x0, address))? {
+                            return Ok(());
+                        }
+                    }
+                    Streamed::Ended
+                },
+            };
+
+            match next {
+                // a function was called, which resumes the string.
+                Streamed::Called => return Ok(()),
+
+                Streamed::Nested(next, next_type) => {
+                    address = next;
+                    inmiddle = next_type;
+                },
+
+                // resume the string this one was nested in.
+                Streamed::Ended => {
+                    if !substring {
+                        return Ok(());
+                    }
+                    let (dest_type, dest_addr, program_counter) =
+                        self.stack.pop_call_stub();
+                    self.program_counter = program_counter;
+                    match dest_type {
+                        0x11 => return Ok(()),
+                        0x10 => {
+                            address = program_counter;
+                            inmiddle = 0xE1;
+                            bit = dest_addr;
+                        },
+                        x => return Err(GlulxError::InvalidCallStub {
+                            dest_type: x,
+                        }),
+                    }
+                },
+            }
+        }
+    }
+
+    /// Prints a compressed string from the given bit of the given address.
+    fn stream_compressed(&mut self,
+            address: u32,
+            bit: u32,
+            substring: &mut bool) -> Result<Streamed, GlulxError> {
+        let table = self.string_table;
+        if table == 0x0 {
+            return Err(GlulxError::InvalidString { address });
+        }
+        let root: u32 = self.read_checked(table + 0x8)?;
+        let (mut address, mut bit) = (address, bit);
+
+        loop {
+            let mut node = read_node(&self.memory, root)?;
+            while let StringNode::Branch(left, right) = node {
+                let byte: u8 = self.read_checked(address)?;
+                let next = if byte & (0x1 << bit) == 0x0 { left } else { right };
+                bit += 0x1;
+                if bit == 0x8 {
+                    bit = 0x0;
+                    address += 0x1;
+                }
+                node = read_node(&self.memory, next)?;
+            }
+
+            // the stub which resumes this string after the current node.
+            let resume = (0x10, bit, address);
+            match node {
+                StringNode::Branch(..) => unreachable!(),
+                StringNode::Terminator => return Ok(Streamed::Ended),
+                StringNode::Char(..) | StringNode::UniChar(..) => {
+                    let ch = match node {
+                        StringNode::Char(ch) => ch as u32,
+                        StringNode::UniChar(ch) => ch,
+                        _ => unreachable!(),
+                    };
+                    if self.iosys.0 == IOSYS_FILTER {
+                        self.mark_substring(substring)?;
+                    }
+                    if self.put_char(ch, resume)? {
+                        return Ok(Streamed::Called);
+                    }
+                },
+                StringNode::CString(ptr) | StringNode::UniString(ptr) => {
+                    let string_type = match node {
+                        StringNode::CString(_) => 0xE0,
+                        _ => 0xE2,
+                    };
+                    match self.iosys.0 {
+                        IOSYS_FILTER => {
+                            self.mark_substring(substring)?;
+                            self.push_stub(resume.0, resume.1, resume.2)?;
+                            return Ok(Streamed::Nested(ptr, string_type));
+                        },
+                        IOSYS_GLK | IOSYS_CHANNELS => {
+                            self.put_string(ptr, string_type)?
+                        },
+                        _ => (),
+                    }
+                },
+                StringNode::Indirect { address: target, double, args } => {
+                    let target = if double {
+                        self.read_checked(target)?
+                    } else {
+                        target
+                    };
+                    let object_type: u8 = self.read_checked(target)?;
+                    self.mark_substring(substring)?;
+                    self.push_stub(resume.0, resume.1, resume.2)?;
+                    match object_type {
+                        0xE0..=0xFF => return Ok(Streamed::Nested(target, 0x0)),
+                        0xC0..=0xDF => {
+                            self.call_func(target, args)?;
+                            return Ok(Streamed::Called);
+                        },
+                        _ => return Err(GlulxError::InvalidString {
+                            address: target,
+                        }),
+                    }
+                },
+            }
+        }
+    }
+
+    /// Pushes the stub which ends printing of a string, unless one has
+    /// been pushed already.
+    fn mark_substring(&mut self, substring: &mut bool) -> Result<(), GlulxError> {
+        if !*substring {
+            let program_counter = self.program_counter;
+            self.push_stub(0x11, 0x0, program_counter)?;
+            *substring = true;
+        }
+        Ok(())
+    }
+
+    /// Prints an unencoded string from a string decoding table node with
+    /// an I/O system which calls no functions.
+    fn put_string(&mut self, address: u32, string_type: u8)
+            -> Result<(), GlulxError> {
+        let size = if string_type == 0xE2 { 0x4 } else { 0x1 };
+        let mut ptr = address;
+        loop {
+            let ch = if size == 0x4 {
+                self.read_checked::<u32>(ptr)?
+            } else {
+                self.read_checked::<u8>(ptr)? as u32
+            };
+            if ch == 0x0 {
+                return Ok(());
+            }
+            self.put_char(ch, (0x0, 0x0, 0x0))?;
+            ptr += size;
+        }
+    }
+
+    /// Prints a signed decimal number, starting from the given digit. The
+    /// filter function is called for each character, returning to a stub
+    /// of type `0x12`.
+    fn stream_num(&mut self, value: u32, inmiddle: bool, digit: u32)
+            -> Result<(), GlulxError> {
+        let text = (value as i32).to_string();
+        let mut substring = inmiddle;
+        for (index, ch) in text.bytes().enumerate().skip(digit as usize) {
+            if self.iosys.0 == IOSYS_FILTER {
+                self.mark_substring(&mut substring)?;
+            }
+            if self.put_char(ch as u32, (0x12, index as u32 + 0x1, value))? {
+                return Ok(());
+            }
+        }
+        if substring {
+            let (dest_type, _, program_counter) = self.stack.pop_call_stub();
+            self.program_counter = program_counter;
+            if dest_type != 0x11 {
+                return Err(GlulxError::InvalidCallStub { dest_type });
+            }
+        }
+        Ok(())
+    }
+
+    /// The bytes of the protected range of memory.
+    fn protected(&self) -> (u32, Vec<u8>) {
+        let (start, len) = self.protect;
+        let size = self.memory.get_mem_size();
+        let end = start.saturating_add(len).min(size);
+        let start = start.min(end);
+        (start, self.memory.bytes(start..end))
+    }
+
+    /// Writes back the bytes of the protected range, as far as they lie
+    /// within memory.
+    fn restore_protected(&mut self, (start, bytes): (u32, Vec<u8>)) {
+        let size = self.memory.get_mem_size();
+        let len = (bytes.len() as u32).min(size.saturating_sub(start));
+        for (index, &byte) in bytes[..len as usize].iter().enumerate() {
+            self.memory.write(start + index as u32, byte);
+        }
+    }
+
+    /// The state of the machine as a save file, with the given extra
+    /// chunks. A call stub must already be pushed.
+    fn quetzal(&self, chunks: Vec<([u8; 0x4], Vec<u8>)>) -> Quetzal {
+        let ramstart = self.memory.ramstart();
+        let size = self.memory.get_mem_size();
+        Quetzal {
+            header: self.memory.story().bytes(0x0..0x80),
+            ram: self.memory.bytes(ramstart..size),
+            stack: self.stack.as_bytes().to_vec(),
+            heap: self.memory.heap()
+                .map(|heap| (heap.start(), heap.allocated())),
+            chunks,
+        }
+    }
+
+    /// Restores the memory, heap and stack of a save file, keeping the
+    /// protected range. Returns false, leaving the machine unchanged, if
+    /// the save file does not fit.
+    fn restore_quetzal(&mut self, quetzal: &Quetzal) -> bool {
+        let ramstart = self.memory.ramstart();
+        let size = match ramstart.checked_add(quetzal.ram.len() as u32) {
+            Some(size) => size,
+            None => return false,
+        };
+        if quetzal.stack.len() > self.memory.stack_size() as usize
+                || quetzal.stack.len() < 0x10
+                || !size.is_multiple_of(0x100) {
+            return false;
+        }
+        let heap = match quetzal.heap {
+            Some((start, ref blocks)) => {
+                match Heap::from_blocks(start, size, blocks) {
+                    Some(heap) => Some(heap),
+                    None => return false,
+                }
+            },
+            None => None,
+        };
+
+        let protected = self.protected();
+        // only the pages which differ from the current state are copied.
+        let mut memory = self.memory.pages().clone();
+        memory.resize(size);
+        memory.write(ramstart, &quetzal.ram);
+        self.memory.restore(memory, heap);
+        self.restore_protected(protected);
+        self.stack.restore(&quetzal.stack)
+    }
+
+    /// The bytes of the key of a search, held in the key argument itself
+    /// unless the options ask for it to be read from memory.
+    fn search_key(&self, key: u32, size: u32, options: u32)
+            -> Result<Vec<u8>, GlulxError> {
+        if options & 0x1 != 0x0 {
+            return self.search_bytes(key, size);
+        }
+        let mut bytes = [0x0; 0x4];
+        BigEndian::write_u32(&mut bytes, key);
+        Ok(bytes[0x4 - size.min(0x4) as usize..].to_vec())
+    }
+
+    fn search_bytes(&self, ptr: u32, len: u32) -> Result<Vec<u8>, GlulxError> {
+        self.check_range(ptr, len)?;
+        Ok(self.memory.bytes(ptr..ptr + len))
+    }
+
+    /// The address of the given offset into RAM.
+    fn ram_ptr(&self, offset: u32) -> u32 {
+        self.memory.ramstart().wrapping_add(offset)
+    }
+
+    /// Checks that the given number of bytes from the address lie within
+    /// memory.
+    fn check_range(&self, ptr: u32, len: u32) -> Result<(), GlulxError> {
+        match ptr.checked_add(len) {
+            Some(end) if end <= self.memory.get_mem_size() => Ok(()),
+            _ => Err(GlulxError::InvalidAddress { address: ptr }),
+        }
+    }
+
+    /// Reads memory, failing for addresses outside of it.
+    fn read_checked<T>(&self, ptr: u32) -> Result<T, GlulxError>
+            where GlulxMemory: Memory<T> {
+        self.check_range(ptr, ::std::mem::size_of::<T>() as u32)?;
+        Ok(self.memory.read(ptr))
+    }
+
+    /// Writes memory, failing for addresses outside of it.
+    fn write_checked<T>(&mut self, ptr: u32, value: T) -> Result<(), GlulxError>
+            where GlulxMemory: Memory<T> {
+        self.check_range(ptr, ::std::mem::size_of::<T>() as u32)?;
+        self.memory.write(ptr, value);
+        Ok(())
+    }
+
     /// Loops through the loals and return a copy of them.
-    fn read_locals(&mut self) -> Vec<u8> {
+    fn read_locals(&mut self) -> Result<Vec<u8>, GlulxError> {
         let mut vec = Vec::new();
 
         loop {
             let (local_type, local_count) = (
-                self.memory.read(self.program_counter),
-                self.memory.read(self.program_counter + 0x1),
+                self.read_checked(self.program_counter)?,
+                self.read_checked(self.program_counter.wrapping_add(0x1))?,
             );
             self.program_counter += 0x2;
             vec.push(local_type);
             vec.push(local_count);
 
             if let (0, 0) = (local_type, local_count) {
-                return vec;
+                return Ok(vec);
             }
         }
     }
@@ -112,267 +898,384 @@ impl Glulx {
     /// Does nothing.
     pub fn op_nop(&mut self) {}
     /// Add l1 to l2 and save the result in s1.
-    pub fn op_add(&mut self, l1: i32, l2: i32, s1: Save) {
-        self.save(s1, l1.wrapping_add(l2));
+    pub fn op_add(&mut self, l1: i32, l2: i32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.save(s1, l1.wrapping_add(l2))
     }
     /// Subtract l2 from l1 and save the result in s1.
-    pub fn op_sub(&mut self, l1: i32, l2: i32, s1: Save) {
-        self.save(s1, l1.wrapping_sub(l2));
+    pub fn op_sub(&mut self, l1: i32, l2: i32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.save(s1, l1.wrapping_sub(l2))
     }
     /// Multiply l1 and l2 and save the result in s1.
-    pub fn op_mul(&mut self, l1: i32, l2: i32, s1: Save) {
-        self.save(s1, l1.wrapping_mul(l2));
+    pub fn op_mul(&mut self, l1: i32, l2: i32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.save(s1, l1.wrapping_mul(l2))
     }
     /// Divide l1 by l2 and save the result in s1.
-    pub fn op_div(&mut self, l1: i32, l2: i32, s1: Save) {
-        self.save(s1, l1.wrapping_div(l2));
+    pub fn op_div(&mut self, l1: i32, l2: i32, s1: Save)
+            -> Result<(), GlulxError> {
+        if l2 == 0x0 {
+            return Err(GlulxError::DivisionByZero {
+                address: self.instruction_ptr,
+            });
+        }
+        self.save(s1, l1.wrapping_div(l2))
     }
     /// Mod l1 by l2 and save the result in s1.
-    pub fn op_mod(&mut self, l1: i32, l2: i32, s1: Save) {
-        self.save(s1, l1.wrapping_rem(l2));
+    pub fn op_mod(&mut self, l1: i32, l2: i32, s1: Save)
+            -> Result<(), GlulxError> {
+        if l2 == 0x0 {
+            return Err(GlulxError::DivisionByZero {
+                address: self.instruction_ptr,
+            });
+        }
+        self.save(s1, l1.wrapping_rem(l2))
     }
     /// Negate l1 and save the result in s1.
-    pub fn op_neg(&mut self, l1: i32, s1: Save) {
-        self.save(s1, l1.wrapping_neg());
+    pub fn op_neg(&mut self, l1: i32, s1: Save) -> Result<(), GlulxError> {
+        self.save(s1, l1.wrapping_neg())
     }
     /// TODO
-    pub fn op_bitand(&mut self, l1: i32, l2: i32, s1: Save) {
-        self.save(s1, l1 & l2);
+    pub fn op_bitand(&mut self, l1: i32, l2: i32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.save(s1, l1 & l2)
     }
     /// TODO
-    pub fn op_bitor(&mut self, l1: i32, l2: i32, s1: Save) {
-        self.save(s1, l1 | l2);
+    pub fn op_bitor(&mut self, l1: i32, l2: i32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.save(s1, l1 | l2)
     }
     /// TODO
-    pub fn op_bitxor(&mut self, l1: i32, l2: i32, s1: Save) {
-        self.save(s1, l1 ^ l2);
+    pub fn op_bitxor(&mut self, l1: i32, l2: i32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.save(s1, l1 ^ l2)
     }
     /// TODO
-    pub fn op_bitnot(&mut self, l1: i32, s1: Save) {
-        self.save(s1, !l1);
+    pub fn op_bitnot(&mut self, l1: i32, s1: Save) -> Result<(), GlulxError> {
+        self.save(s1, !l1)
     }
-    /// TODO
-    pub fn op_shiftl(&mut self, l1: u32, l2: u32, s1: Save) {
-        self.save(s1, l1 << l2);
+    /// Shift l1 left by l2 places and save the result in s1. Shifts of
+    /// 32 places or more give 0x0.
+    pub fn op_shiftl(&mut self, l1: u32, l2: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.save(s1, l1.checked_shl(l2).unwrap_or(0x0))
     }
-    /// TODO
-    pub fn op_sshiftr(&mut self, l1: i32, l2: i32, s1: Save) {
-        self.save(s1, l1 >> l2);
+    /// Shift l1 right by l2 places, extending the sign bit, and save the
+    /// result in s1. Shifts of 32 places or more give 0x0, or -0x1 if
+    /// l1 is negative.
+    pub fn op_sshiftr(&mut self, l1: i32, l2: i32, s1: Save)
+            -> Result<(), GlulxError> {
+        let fill = if l1 < 0x0 { -0x1 } else { 0x0 };
+        self.save(s1, l1.checked_shr(l2 as u32).unwrap_or(fill))
     }
-    /// TODO
-    pub fn op_ushiftr(&mut self, l1: u32, l2: u32, s1: Save) {
-        self.save(s1, l1 >> l2);
+    /// Shift l1 right by l2 places, filling with zeros, and save the
+    /// result in s1. Shifts of 32 places or more give 0x0.
+    pub fn op_ushiftr(&mut self, l1: u32, l2: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.save(s1, l1.checked_shr(l2).unwrap_or(0x0))
     }
-    /// TODO
-    pub fn op_jump(&mut self, l1: u32) {
-        self.program_counter = self.program_counter
-            .wrapping_add(l1)
-            .wrapping_sub(0x2);
+    /// Jump to the offset l1 from the next instruction, minus 0x2. The
+    /// offsets 0x0 and 0x1 instead return that value from the current
+    /// function.
+    pub fn op_jump(&mut self, l1: u32) -> Result<(), GlulxError> {
+        match l1 {
+            0x0 | 0x1 => self.op_return(l1),
+            _ => {
+                self.program_counter = self.program_counter
+                    .wrapping_add(l1)
+                    .wrapping_sub(0x2);
+                Ok(())
+            },
+        }
     }
     /// If l1 is 0x0, jump to l2.
-    pub fn op_jz(&mut self, l1: i32, l2: u32) {
-        if l1 == 0x0 { self.op_jump(l2) };
+    pub fn op_jz(&mut self, l1: i32, l2: u32) -> Result<(), GlulxError> {
+        if l1 == 0x0 { self.op_jump(l2) } else { Ok(()) }
     }
     /// If l1 is not 0x0, jump to l2.
-    pub fn op_jnz(&mut self, l1: i32, l2: u32) {
-        if l1 != 0x0 { self.op_jump(l2) };
+    pub fn op_jnz(&mut self, l1: i32, l2: u32) -> Result<(), GlulxError> {
+        if l1 != 0x0 { self.op_jump(l2) } else { Ok(()) }
     }
     /// If l1 equals l2, jump to l3.
-    pub fn op_jeq(&mut self, l1: i32, l2: i32, l3: u32) {
-        if l1 == l2 { self.op_jump(l3) };
+    pub fn op_jeq(&mut self, l1: i32, l2: i32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 == l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If l1 is not equal to l2, jump to l3.
-    pub fn op_jne(&mut self, l1: i32, l2: i32, l3: u32) {
-        if l1 != l2 { self.op_jump(l3) };
+    pub fn op_jne(&mut self, l1: i32, l2: i32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 != l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If l1 is less than l2, jump to l3.
-    pub fn op_jlt(&mut self, l1: i32, l2: i32, l3: u32) {
-        if l1 < l2 { self.op_jump(l3) };
+    pub fn op_jlt(&mut self, l1: i32, l2: i32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 < l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If l1 is greater than or equal to l2, jump to l3.
-    pub fn op_jge(&mut self, l1: i32, l2: i32, l3: u32) {
-        if l1 >= l2 { self.op_jump(l3) };
+    pub fn op_jge(&mut self, l1: i32, l2: i32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 >= l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If l1 is greater than l2, jump to l3.
-    pub fn op_jgt(&mut self, l1: i32, l2: i32, l3: u32) {
-        if l1 > l2 { self.op_jump(l3) };
+    pub fn op_jgt(&mut self, l1: i32, l2: i32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 > l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If l1 is less than or equal to l2, jump to l3.
-    pub fn op_jle(&mut self, l1: i32, l2: i32, l3: u32) {
-        if l1 <= l2 { self.op_jump(l3) };
+    pub fn op_jle(&mut self, l1: i32, l2: i32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 <= l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If unsigned l1 is less than unsigned l2, jump to l3.
-    pub fn op_jltu(&mut self, l1: u32, l2: u32, l3: u32) {
-        if l1 < l2 { self.op_jump(l3) };
+    pub fn op_jltu(&mut self, l1: u32, l2: u32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 < l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If unsigned l1 is greater than or equal to unsigned l2, jump to l3.
-    pub fn op_jgeu(&mut self, l1: u32, l2: u32, l3: u32) {
-        if l1 >= l2 { self.op_jump(l3) };
+    pub fn op_jgeu(&mut self, l1: u32, l2: u32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 >= l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If unsigned l1 is greather than unsigned l2, jump to l3.
-    pub fn op_jgtu(&mut self, l1: u32, l2: u32, l3: u32) {
-        if l1 > l2 { self.op_jump(l3) };
+    pub fn op_jgtu(&mut self, l1: u32, l2: u32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 > l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If unsigned l1 is less than or equal to unsigned l2, jump to l3.
-    pub fn op_jleu(&mut self, l1: u32, l2: u32, l3: u32) {
-        if l1 <= l2 { self.op_jump(l3) };
+    pub fn op_jleu(&mut self, l1: u32, l2: u32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 <= l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// Call function at address l1 with l2 arguments.
-    pub fn op_call(&mut self, l1: u32, l2: u32, s1: Save) {
-        let args = self.stack.pop_args(l2);
-        self.push_call_stub(s1);
-        self.call_func(l1, args);
-    }
-    /// Return l1 from a function call.
-    pub fn op_return(&mut self, l1: u32) {
+    pub fn op_call(&mut self, l1: u32, l2: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        let args = self.stack.pop_args(l2)
+            .map_err(|error| self.stack_error(error))?;
+        self.push_call_stub(s1)?;
+        self.call_func(l1, args)
+    }
+    /// Return l1 from a function call. Returning from the start function
+    /// stops the machine.
+    pub fn op_return(&mut self, l1: u32) -> Result<(), GlulxError> {
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.leave();
+        }
+        if !self.stack.has_call_stub() {
+            self.stack.pop_call_frame();
+            self.running = false;
+            return Ok(());
+        }
         self.stack.pop_call_frame();
-        let (dest_type, dest_addr, program_counter) = self.stack.pop_call_stub();
-        self.program_counter = program_counter;
-        let save = match dest_type {
-            0x0 => Save::Null,
-            0x1 => Save::Addr(dest_addr),
-            0x2 => Save::Frame(dest_addr),
-            0x3 => Save::Push,
-            x => panic!("invalid dest_type returned from stack: {:#X}", x),
-        };
-        self.save(save, l1);
-    }
-    /// TODO
-    pub fn op_catch(&mut self, s1: Save, l1: u32) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_throw(&mut self, l1: u32, l2: u32) {
-        unimplemented!()
-    }
-    /// Tailcall the function at l1 with l2 number of args
-    pub fn op_tailcall(&mut self, l1: u32, l2: u32) {
-        unimplemented!()
+        self.return_to_stub(l1)
+    }
+    /// Push a call stub which returns to the next instruction, store the
+    /// resulting catch token in s1, and jump to l1.
+    pub fn op_catch(&mut self, s1: Save, l1: u32) -> Result<(), GlulxError> {
+        self.push_call_stub(s1)?;
+        let token = self.stack.len();
+        self.save(s1, token)?;
+        self.op_jump(l1)
+    }
+    /// Unwind the stack to the catch token l2, and store l1 as the result
+    /// of the catch.
+    pub fn op_throw(&mut self, l1: u32, l2: u32) -> Result<(), GlulxError> {
+        if !self.stack.unwind(l2) {
+            return Err(GlulxError::InvalidCatchToken {
+                token: l2,
+                address: self.instruction_ptr,
+            });
+        }
+        let result = self.return_to_stub(l1);
+        let depth = self.stack.call_depth();
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.unwind(depth as usize);
+        }
+        result
+    }
+    /// Call function l1 with l2 arguments, in place of the current
+    /// function, so its result is returned to the current caller.
+    pub fn op_tailcall(&mut self, l1: u32, l2: u32) -> Result<(), GlulxError> {
+        let args = self.stack.pop_args(l2)
+            .map_err(|error| self.stack_error(error))?;
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.leave();
+        }
+        self.stack.pop_call_frame();
+        self.call_func(l1, args)
     }
     /// Copy a u32 to s1.
-    pub fn op_copy(&mut self, l1: u32, s1: Save) {
+    pub fn op_copy(&mut self, l1: u32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1)
     }
     /// Copy a u16 to s1.
-    pub fn op_copys(&mut self, l1: u16, s1: Save) {
+    pub fn op_copys(&mut self, l1: u16, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1)
     }
     /// Copy a u8 to s1.
-    pub fn op_copyb(&mut self, l1: u8, s1: Save) {
+    pub fn op_copyb(&mut self, l1: u8, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1)
     }
     /// Load a u32, and sign extend the lower 0x10 bits to a i32.
-    pub fn op_sexs(&mut self, l1: u32, s1: Save) {
+    pub fn op_sexs(&mut self, l1: u32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1 as i16 as i32)
     }
     /// Load a u32, and sign extend the lower 0x8 bits to a i32.
-    pub fn op_sexb(&mut self, l1: u32, s1: Save) {
+    pub fn op_sexb(&mut self, l1: u32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1 as i8 as i32)
     }
     /// Load the value at l1 + 4*l2 and save at s1.
-    pub fn op_aload(&mut self, l1: u32, l2: u32, s1: Save) {
-        let ret: u32 = self.memory.read(l1 + (l2 << 0x2));
-        self.save(s1, ret);
+    pub fn op_aload(&mut self, l1: u32, l2: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        let ret: u32 = self.read_checked(l1.wrapping_add(l2 << 0x2))?;
+        self.save(s1, ret)
     }
     /// Load a u16 from l1 + 2*l2 and store at s1 as a u32.
-    pub fn op_aloads(&mut self, l1: u32, l2: u32, s1: Save) {
-        let ret: u16 = self.memory.read(l1 + (l2 << 0x1));
-        self.save(s1, ret);
+    pub fn op_aloads(&mut self, l1: u32, l2: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        let ret: u16 = self.read_checked(l1.wrapping_add(l2 << 0x1))?;
+        self.save(s1, ret)
     }
     /// Load a u8 from l1 + l2 and store at s1 as a u32.
-    pub fn op_aloadb(&mut self, l1: u32, l2: u32, s1: Save) {
-        let ret: u8 = self.memory.read(l1 + l2);
-        self.save(s1, ret as u32);
-    }
-    /// Load a bit from l1 + l2/8 and store at s1 as a u32.
-    pub fn op_aloadbit(&mut self, l1: u32, l2: u32, s1: Save) {
-        unimplemented!()
+    pub fn op_aloadb(&mut self, l1: u32, l2: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        let ret: u8 = self.read_checked(l1.wrapping_add(l2))?;
+        self.save(s1, ret as u32)
+    }
+    /// Load bit l2 counting from the low bit of l1, and store at s1 as a
+    /// u32. Negative values of l2 count back from l1.
+    pub fn op_aloadbit(&mut self, l1: u32, l2: i32, s1: Save)
+            -> Result<(), GlulxError> {
+        let ptr = l1.wrapping_add((l2 >> 0x3) as u32);
+        let byte: u8 = self.read_checked(ptr)?;
+        self.save(s1, ((byte >> (l2 & 0x7)) & 0x1) as u32)
     }
     /// Store l3 as a u32 at memory location l1 + 4 * l2
-    pub fn op_astore(&mut self, l1: u32, l2: u32, l3: u32) {
-        self.memory.write(l1 + (l2 << 0x2), l3)
+    pub fn op_astore(&mut self, l1: u32, l2: u32, l3: u32)
+            -> Result<(), GlulxError> {
+        self.write_checked(l1.wrapping_add(l2 << 0x2), l3)
     }
     /// Store l3 as a u16 at memory location l1 + 2 * l2
-    pub fn op_astores(&mut self, l1: u32, l2: u32, l3: u32) {
-        self.memory.write(l1 + (l2 << 0x1), l3 as u16)
+    pub fn op_astores(&mut self, l1: u32, l2: u32, l3: u32)
+            -> Result<(), GlulxError> {
+        self.write_checked(l1.wrapping_add(l2 << 0x1), l3 as u16)
     }
     /// Store l3 as a u8 at memory location l1 + l2
-    pub fn op_astoreb(&mut self, l1: u32, l2: u32, l3: u32) {
-        self.memory.write(l1 + l2, l3 as u8)
-    }
-    /// TODO
-    pub fn op_astorebit(&mut self, l1: u32, l2: u32, l3: u32) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_stkcount(&mut self, s1: Save) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_stkpeek(&mut self, l1: u32, s1: Save) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_stkswap(&mut self) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_stkroll(&mut self, l1: u32, l2: i32) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_stkcopy(&mut self, l1: u32) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_streamchar(&mut self, l1: u32) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_streamnum(&mut self, l1: u32) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_streamstr(&mut self, l1: u32) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_streamunichar(&mut self, l1: u32) {
-        unimplemented!()
+    pub fn op_astoreb(&mut self, l1: u32, l2: u32, l3: u32)
+            -> Result<(), GlulxError> {
+        self.write_checked(l1.wrapping_add(l2), l3 as u8)
+    }
+    /// Set bit l2 counting from the low bit of l1 if l3 is not 0x0, and
+    /// clear it otherwise.
+    pub fn op_astorebit(&mut self, l1: u32, l2: i32, l3: u32)
+            -> Result<(), GlulxError> {
+        let ptr = l1.wrapping_add((l2 >> 0x3) as u32);
+        let byte: u8 = self.read_checked(ptr)?;
+        let mask = 0x1 << (l2 & 0x7);
+        let byte = if l3 != 0x0 { byte | mask } else { byte & !mask };
+        self.write_checked(ptr, byte)
+    }
+    /// Store the number of values on the stack in the current call
+    /// frame at s1.
+    pub fn op_stkcount(&mut self, s1: Save) -> Result<(), GlulxError> {
+        let count = self.stack.value_count();
+        self.save(s1, count)
+    }
+    /// Peek at the l1'th value on the stack and store it at s1.
+    pub fn op_stkpeek(&mut self, l1: u32, s1: Save) -> Result<(), GlulxError> {
+        let value: u32 = self.stack.peek(l1)
+            .map_err(|error| self.stack_error(error))?;
+        self.save(s1, value)
+    }
+    /// Swap the top two values on the stack.
+    pub fn op_stkswap(&mut self) -> Result<(), GlulxError> {
+        self.stack.roll(0x2, 0x1).map_err(|error| self.stack_error(error))
+    }
+    /// Rotate the top l1 values on the stack up by l2 places.
+    pub fn op_stkroll(&mut self, l1: u32, l2: i32) -> Result<(), GlulxError> {
+        self.stack.roll(l1, l2).map_err(|error| self.stack_error(error))
+    }
+    /// Push copies of the top l1 values on the stack.
+    pub fn op_stkcopy(&mut self, l1: u32) -> Result<(), GlulxError> {
+        self.stack.copy(l1).map_err(|error| self.stack_error(error))
+    }
+    /// Print the low byte of l1 as a Latin-1 character.
+    pub fn op_streamchar(&mut self, l1: u32) -> Result<(), GlulxError> {
+        let program_counter = self.program_counter;
+        self.put_char(l1 & 0xFF, (0x0, 0x0, program_counter)).map(|_| ())
+    }
+    /// Print l1 as a signed decimal number.
+    pub fn op_streamnum(&mut self, l1: u32) -> Result<(), GlulxError> {
+        self.stream_num(l1, false, 0x0)
+    }
+    /// Print the string at l1.
+    pub fn op_streamstr(&mut self, l1: u32) -> Result<(), GlulxError> {
+        self.stream_string(l1, 0x0, 0x0)
+    }
+    /// Print l1 as a Unicode character.
+    pub fn op_streamunichar(&mut self, l1: u32) -> Result<(), GlulxError> {
+        let program_counter = self.program_counter;
+        self.put_char(l1, (0x0, 0x0, program_counter)).map(|_| ())
     }
     /// Returns a value indicating if vm features are implemented.
-    pub fn op_gestalt(&mut self, l1: u16, l2: u16, s1: Save) {
+    pub fn op_gestalt(&mut self, l1: u16, l2: u16, s1: Save)
+            -> Result<(), GlulxError> {
         let ret = match (l1, l2) {
             (0x0, _) => self.memory.glulx_version(),
             (0x1, _) => 0x1, // interpreter version
-            (0x2, _) => 0x0, // setmemsize implemented
-            (0x3, _) => 0x0, // saveundo and restoreundo implemented
+            (0x2, _) => 0x1, // setmemsize implemented
+            (0x3, _) => 0x1, // saveundo and restoreundo implemented
             (0x4, 0x0) => 0x1, // iosystem null implemented
-            (0x4, 0x1) => 0x0, // iosystem filter implemented
-            (0x4, 0x2) => 0x0, // iosystem gtk implemented
-            (0x4, 0x20) => 0x0, // iosystem fyrevm implemented
-            (0x5, _) => 0x0, // unicode support implemented
+            (0x4, 0x1) => 0x1, // iosystem filter implemented
+            (0x4, 0x2) => 0x1, // iosystem glk implemented
+            (0x4, 0x14) => 0x1, // iosystem fyrevm channels implemented
+            (0x5, _) => 0x1, // unicode support implemented
             (0x6, _) => 0x1, // mzero and mcopy implemented
-            (0x7, _) => 0x0, // malloc and mfree implemented
-            (0x8, _) => 0x0, // accelfunc and accelparam implemented
-            (0x9, _) => 0x0, // heap start address implemented
-            (0xA, x) => 0x0, // accelfunc `x` implemented
+            (0x7, _) => 0x1, // malloc and mfree implemented
+            (0x8, _) => self.memory.heap().map_or(0x0, |heap| heap.start()),
+            (0x9, _) => 0x0, // accelfunc and accelparam implemented
+            (0xA, _) => 0x0, // accelfunc `x` implemented
             (0xB, _) => 0x1, // float implemented
+            (0xC, _) => 0x0, // hasundo and discardundo implemented
             _ => 0x0, // default to 0x0
         };
         self.save(s1, ret)
     }
-    /// TODO
-    pub fn op_debugtrap(&mut self, l1: u32) {
-        unimplemented!()
+    /// Call the debug trap handler with l1, aborting execution if there
+    /// is no handler.
+    pub fn op_debugtrap(&mut self, l1: u32) -> Result<(), GlulxError> {
+        let action = match self.trap_handler.take() {
+            Some(mut handler) => {
+                let action = handler(l1, self);
+                // the handler may have installed a replacement.
+                if self.trap_handler.is_none() {
+                    self.trap_handler = Some(handler);
+                }
+                action
+            },
+            None => TrapAction::Abort,
+        };
+        match action {
+            TrapAction::Continue => Ok(()),
+            TrapAction::Pause => {
+                self.paused = true;
+                self.trap = Some(l1);
+                Ok(())
+            },
+            TrapAction::Abort => Err(GlulxError::DebugTrap {
+                value: l1,
+                address: self.instruction_ptr,
+            }),
+        }
     }
     /// TODO
-    pub fn op_getmemsize(&mut self, s1: Save) {
+    pub fn op_getmemsize(&mut self, s1: Save) -> Result<(), GlulxError> {
         let mem_size = self.memory.get_mem_size();
         self.save(s1, mem_size)
     }
     /// TODO
-    pub fn op_setmemsize(&mut self, l1: u32, s1: Save) {
+    pub fn op_setmemsize(&mut self, l1: u32, s1: Save)
+            -> Result<(), GlulxError> {
         let mem_resized = self.memory.set_mem_size(l1);
         self.save(s1, mem_resized)
     }
@@ -380,279 +1283,484 @@ impl Glulx {
     pub fn op_jumpabs(&mut self, l1: u32) {
         self.program_counter = l1
     }
-    /// TODO
-    pub fn op_random(&mut self, l1: i32, s1: Save) {
-        unimplemented!()
+    /// Store a random number in the range 0 to l1, exclusive, at s1. A
+    /// negative l1 gives a number from l1 to 0, and 0x0 any number.
+    pub fn op_random(&mut self, l1: i32, s1: Save) -> Result<(), GlulxError> {
+        let value = self.random.range(l1);
+        self.save(s1, value)
     }
-    /// TODO
+    /// Seed the random number generator with l1, or with an unpredictable
+    /// seed if l1 is 0x0.
     pub fn op_setrandom(&mut self, l1: u32) {
-        unimplemented!()
+        let seed = match (l1, self.fixed_seed) {
+            (0x0, Some(seed)) => seed,
+            _ => l1,
+        };
+        self.random.seed(seed)
     }
     /// TODO
     pub fn op_quit(&mut self) {
         self.running = false
     }
-    /// TODO
-    pub fn op_verify(&mut self, s1: Save) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_restart(&mut self) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_save(&mut self, l1: u32, s1: Save) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_restore(&mut self, l1: u32, s1: Save) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_saveundo(&mut self, s1: Save) {
-        unimplemented!()
+    /// Store 0x0 at s1 if the checksum of the story file is correct, and
+    /// 0x1 otherwise.
+    pub fn op_verify(&mut self, s1: Save) -> Result<(), GlulxError> {
+        let result = if self.memory.verify() { 0x0 } else { 0x1 };
+        self.save(s1, result)
+    }
+    /// Return memory and the stack to their initial state, keeping the
+    /// protected range, and call the start function.
+    pub fn op_restart(&mut self) -> Result<(), GlulxError> {
+        let protected = self.protected();
+        self.memory.reset();
+        self.restore_protected(protected);
+        self.stack.clear();
+        self.iosys = (IOSYS_NULL, 0x0);
+        self.string_table = self.memory.decoding_tbl();
+        self.channel = CHANNEL_MAIN;
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.unwind(0x0);
+        }
+        self.init()
+    }
+    /// Save the state of the machine to the Glk stream l1, storing 0x0 at
+    /// s1 on success and 0x1 on failure. When the state is restored, -1
+    /// is stored at s1 instead.
+    pub fn op_save(&mut self, l1: u32, s1: Save) -> Result<(), GlulxError> {
+        self.push_call_stub(s1)?;
+        let bytes = self.quetzal(vec![]).to_bytes(self.memory.story().pages());
+        self.stack.pop_call_stub();
+        let saved = self.glk.write_stream(l1, &bytes, &mut self.memory);
+        self.save(s1, if saved { 0x0 } else { 0x1 })
+    }
+    /// Restore the state of the machine from the Glk stream l1, storing
+    /// 0x1 at s1 on failure.
+    pub fn op_restore(&mut self, l1: u32, s1: Save) -> Result<(), GlulxError> {
+        let quetzal = self.glk.read_stream(l1, &self.memory)
+            .and_then(|bytes| {
+                Quetzal::from_bytes(&bytes, self.memory.story().pages()).ok()
+            });
+        match quetzal {
+            Some(ref quetzal) if self.restore_quetzal(quetzal) => {
+                self.return_to_stub(0xFFFFFFFF)
+            },
+            _ => self.save(s1, 0x1),
+        }
     }
-    /// TODO
-    pub fn op_restoreundo(&mut self, s1: Save) {
-        unimplemented!()
+    /// Save the state of the machine in memory, storing 0x0 at s1. When
+    /// the state is restored, -1 is stored at s1 instead.
+    pub fn op_saveundo(&mut self, s1: Save) -> Result<(), GlulxError> {
+        self.push_call_stub(s1)?;
+        let state = UndoState {
+            memory: self.memory.pages().clone(),
+            stack: self.stack.as_bytes().to_vec(),
+            heap: self.memory.heap().cloned(),
+        };
+        self.stack.pop_call_stub();
+        if self.undo.len() == MAX_UNDO {
+            self.undo.remove(0x0);
+        }
+        self.undo.push(state);
+        self.save(s1, 0x0)
+    }
+    /// Restore the state saved by the last `saveundo`, storing 0x1 at s1
+    /// if there is none.
+    pub fn op_restoreundo(&mut self, s1: Save) -> Result<(), GlulxError> {
+        match self.undo.pop() {
+            Some(state) => {
+                let protected = self.protected();
+                self.memory.restore(state.memory, state.heap);
+                self.restore_protected(protected);
+                self.stack.restore(&state.stack);
+                self.return_to_stub(0xFFFFFFFF)
+            },
+            None => self.save(s1, 0x1),
+        }
     }
-    /// TODO
+    /// Protect l2 bytes of memory from l1 against `restore`, `restoreundo`
+    /// and `restart`.
     pub fn op_protect(&mut self, l1: u32, l2: u32) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_glk(&mut self, l1: u32, l2: u32, s1: Save) {
-        unimplemented!()
+        self.protect = (l1, l2)
+    }
+    /// Call the Glk function with selector l1, with l2 arguments from the
+    /// stack, and store the result at s1. A `glk_select` which must wait
+    /// for an event stores its result once the event arrives.
+    pub fn op_glk(&mut self, l1: u32, l2: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        let args = self.stack.pop_args(l2)
+            .map_err(|error| self.stack_error(error))?;
+        let mut memory = GlkMemory::new(&mut self.memory);
+        let result = self.glk.call(l1, &args, &mut memory)?;
+        for value in memory.into_pushes() {
+            self.push(value)?;
+        }
+        if l1 == 0x1 {
+            self.running = false;
+        }
+        match result {
+            Some(value) => self.save(s1, value),
+            None => {
+                self.waiting = Some(s1);
+                Ok(())
+            },
+        }
     }
-    /// TODO
-    pub fn op_getstringtbl(&mut self, s1: Save) {
-        unimplemented!()
+    /// Store the address of the string decoding table at s1.
+    pub fn op_getstringtbl(&mut self, s1: Save) -> Result<(), GlulxError> {
+        let table = self.string_table;
+        self.save(s1, table)
     }
-    /// TODO
+    /// Decode compressed strings with the table at l1.
     pub fn op_setstringtbl(&mut self, l1: u32) {
-        unimplemented!()
+        self.string_table = l1
     }
-    /// TODO
-    pub fn op_getiosys(&mut self, s1: Save, s2: Save) {
-        unimplemented!()
+    /// Store the I/O system at s1 and its rock at s2.
+    pub fn op_getiosys(&mut self, s1: Save, s2: Save)
+            -> Result<(), GlulxError> {
+        let (mode, rock) = self.iosys;
+        self.save(s1, mode)?;
+        self.save(s2, rock)
     }
-    /// TODO
+    /// Select the I/O system l1 with the rock l2. Unsupported systems
+    /// select the null system.
     pub fn op_setiosys(&mut self, l1: u32, l2: u32) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_linearsearch(&mut self, l1: u32, l2: u32, l3: u32, l4: u32, l5: u32, l6: u32, l7: u32, s1: Save) {
-        unimplemented!()
-    }
-    /// TODO
-    pub fn op_binarysearch(&mut self, l1: u32, l2: u32, l3: u32, l4: u32, l5: u32, l6: u32, l7: u32, s1: Save) {
-        unimplemented!()
+        self.iosys = match l1 {
+            IOSYS_FILTER | IOSYS_GLK | IOSYS_CHANNELS => (l1, l2),
+            _ => (IOSYS_NULL, l2),
+        }
     }
-    /// TODO
-    pub fn op_linkedsearch(&mut self, l1: u32, l2: u32, l3: u32, l4: u32, l5: u32, l6: u32, s1: Save) {
-        unimplemented!()
+    /// Search l5 structures of l4 bytes from l3 for the key l1 of l2
+    /// bytes at offset l6, with the options l7, and store the address or
+    /// index of the match at s1.
+    #[allow(clippy::too_many_arguments)]
+    pub fn op_linearsearch(&mut self,
+            l1: u32,
+            l2: u32,
+            l3: u32,
+            l4: u32,
+            l5: u32,
+            l6: u32,
+            l7: u32,
+            s1: Save) -> Result<(), GlulxError> {
+        let key = self.search_key(l1, l2, l7)?;
+        let mut index = 0x0u32;
+        let result = loop {
+            if l5 != 0xFFFFFFFF && index >= l5 {
+                break None;
+            }
+            let ptr = l3.wrapping_add(index.wrapping_mul(l4));
+            let found = self.search_bytes(ptr.wrapping_add(l6), l2)?;
+            if found == key {
+                break Some((ptr, index));
+            }
+            if l7 & 0x2 != 0x0 && found.iter().all(|&byte| byte == 0x0) {
+                break None;
+            }
+            index += 0x1;
+        };
+        self.save(s1, search_result(result, l7))
+    }
+    /// Search l5 structures of l4 bytes from l3, sorted by the key at
+    /// offset l6, for the key l1 of l2 bytes, with the options l7, and
+    /// store the address or index of the match at s1.
+    #[allow(clippy::too_many_arguments)]
+    pub fn op_binarysearch(&mut self,
+            l1: u32,
+            l2: u32,
+            l3: u32,
+            l4: u32,
+            l5: u32,
+            l6: u32,
+            l7: u32,
+            s1: Save) -> Result<(), GlulxError> {
+        let key = self.search_key(l1, l2, l7)?;
+        let (mut low, mut high) = (0x0u32, l5);
+        let mut result = None;
+        while low < high {
+            let index = low + (high - low) / 0x2;
+            let ptr = l3.wrapping_add(index.wrapping_mul(l4));
+            let found = self.search_bytes(ptr.wrapping_add(l6), l2)?;
+            match found.cmp(&key) {
+                ::std::cmp::Ordering::Equal => {
+                    result = Some((ptr, index));
+                    break;
+                },
+                ::std::cmp::Ordering::Less => low = index + 0x1,
+                ::std::cmp::Ordering::Greater => high = index,
+            }
+        }
+        self.save(s1, search_result(result, l7))
+    }
+    /// Search the linked list of structures from l3, linked by the
+    /// address at offset l5, for the key l1 of l2 bytes at offset l4,
+    /// with the options l6, and store the address of the match at s1.
+    #[allow(clippy::too_many_arguments)]
+    pub fn op_linkedsearch(&mut self,
+            l1: u32,
+            l2: u32,
+            l3: u32,
+            l4: u32,
+            l5: u32,
+            l6: u32,
+            s1: Save) -> Result<(), GlulxError> {
+        let key = self.search_key(l1, l2, l6)?;
+        let mut ptr = l3;
+        let mut result = 0x0;
+        while ptr != 0x0 {
+            let found = self.search_bytes(ptr.wrapping_add(l4), l2)?;
+            if found == key {
+                result = ptr;
+                break;
+            }
+            if l6 & 0x2 != 0x0 && found.iter().all(|&byte| byte == 0x0) {
+                break;
+            }
+            ptr = self.read_checked(ptr.wrapping_add(l5))?;
+        }
+        self.save(s1, result)
     }
     /// Call the function at l1 and save the result at s1.
-    pub fn op_callf(&mut self, l1: u32, s1: Save) {
-        self.push_call_stub(s1);
-        self.call_func(l1, vec![]);
+    pub fn op_callf(&mut self, l1: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.push_call_stub(s1)?;
+        self.call_func(l1, vec![])
     }
     /// Call the function at l1 with one input and save the result at s1.
-    pub fn op_callfi(&mut self, l1: u32, l2: u32, s1: Save) {
-        self.push_call_stub(s1);
-        self.call_func(l1, vec![l2]);
+    pub fn op_callfi(&mut self, l1: u32, l2: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.push_call_stub(s1)?;
+        self.call_func(l1, vec![l2])
     }
     /// Call the function at l1 with two inputs and save the result at s1.
-    pub fn op_callfii(&mut self, l1: u32, l2: u32, l3: u32, s1: Save) {
-        self.push_call_stub(s1);
-        self.call_func(l1, vec![l2, l3]);
+    pub fn op_callfii(&mut self, l1: u32, l2: u32, l3: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.push_call_stub(s1)?;
+        self.call_func(l1, vec![l2, l3])
     }
     /// Call the function at l1 with three inputs and save the result at s1.
-    pub fn op_callfiii(&mut self, l1: u32, l2: u32, l3: u32, l4: u32, s1: Save) {
-        self.push_call_stub(s1);
-        self.call_func(l1, vec![l2, l3, l4]);
+    pub fn op_callfiii(&mut self, l1: u32, l2: u32, l3: u32, l4: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        self.push_call_stub(s1)?;
+        self.call_func(l1, vec![l2, l3, l4])
     }
     /// TODO
-    pub fn op_mzero(&mut self, l1: u32, l2: u32) {
-        self.memory.zero_range(l1, l2)
+    pub fn op_mzero(&mut self, l1: u32, l2: u32) -> Result<(), GlulxError> {
+        self.check_range(l2, l1)?;
+        self.memory.zero_range(l1, l2);
+        Ok(())
     }
     /// TODO
-    pub fn op_mcopy(&mut self, l1: u32, l2: u32, l3: u32) {
-        self.memory.copy_range(l1, l2, l3)
+    pub fn op_mcopy(&mut self, l1: u32, l2: u32, l3: u32)
+            -> Result<(), GlulxError> {
+        self.check_range(l2, l1)?;
+        self.check_range(l3, l1)?;
+        self.memory.copy_range(l1, l2, l3);
+        Ok(())
     }
-    /// TODO
-    pub fn op_malloc(&mut self, l1: u32, s1: Save) {
-        unimplemented!()
+    /// Allocate l1 bytes on the heap and store the address at s1, or 0x0
+    /// if l1 is 0x0.
+    pub fn op_malloc(&mut self, l1: u32, s1: Save) -> Result<(), GlulxError> {
+        let address = self.memory.malloc(l1);
+        self.save(s1, address)
     }
-    /// TODO
+    /// Free the heap block at l1.
     pub fn op_mfree(&mut self, l1: u32) {
-        unimplemented!()
+        self.memory.mfree(l1)
     }
-    /// TODO
+    /// Record that the function at l2 may be replaced by the accelerated
+    /// function l1, or by none if l1 is 0x0.
     pub fn op_accelfunc(&mut self, l1: u32, l2: u32) {
-        unimplemented!()
+        if l1 == 0x0 {
+            self.accel_functions.remove(&l2);
+        } else {
+            self.accel_functions.insert(l2, l1);
+        }
     }
-    /// TODO
+    /// Set the accelerated function parameter l1 to l2.
     pub fn op_accelparam(&mut self, l1: u32, l2: u32) {
-        unimplemented!()
+        if let Some(param) = self.accel_params.get_mut(l1 as usize) {
+            *param = l2;
+        }
     }
-    /// TODO
-    pub fn op_numtof(&mut self, l1: f32, s1: Save) {
+    /// Convert the integer l1 to a float.
+    pub fn op_numtof(&mut self, l1: i32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1 as f32)
     }
     /// TODO
-    pub fn op_ftonumz(&mut self, l1: f32, s1: Save) {
+    pub fn op_ftonumz(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.trunc() as i32)
     }
     /// TODO
-    pub fn op_ftonumn(&mut self, l1: f32, s1: Save) {
+    pub fn op_ftonumn(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.round() as i32)
     }
     /// TODO
-    pub fn op_ceil(&mut self, l1: f32, s1: Save) {
+    pub fn op_ceil(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.ceil())
     }
     /// TODO
-    pub fn op_floor(&mut self, l1: f32, s1: Save) {
+    pub fn op_floor(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.floor())
     }
     /// TODO
-    pub fn op_fadd(&mut self, l1: f32, l2: f32, s1: Save) {
+    pub fn op_fadd(&mut self, l1: f32, l2: f32, s1: Save)
+            -> Result<(), GlulxError> {
         self.save(s1, l1 + l2)
     }
     /// TODO
-    pub fn op_fsub(&mut self, l1: f32, l2: f32, s1: Save) {
+    pub fn op_fsub(&mut self, l1: f32, l2: f32, s1: Save)
+            -> Result<(), GlulxError> {
         self.save(s1, l1 - l2)
     }
     /// TODO
-    pub fn op_fmul(&mut self, l1: f32, l2: f32, s1: Save) {
+    pub fn op_fmul(&mut self, l1: f32, l2: f32, s1: Save)
+            -> Result<(), GlulxError> {
         self.save(s1, l1 * l2)
     }
     /// TODO
-    pub fn op_fdiv(&mut self, l1: f32, l2: f32, s1: Save) {
+    pub fn op_fdiv(&mut self, l1: f32, l2: f32, s1: Save)
+            -> Result<(), GlulxError> {
         self.save(s1, l1 / l2)
     }
     /// TODO
-    pub fn op_fmod(&mut self, l1: f32, l2: f32, s1: Save, s2: Save) {
+    pub fn op_fmod(&mut self, l1: f32, l2: f32, s1: Save, s2: Save)
+            -> Result<(), GlulxError> {
         let (ret1, ret2) = ((l1 / l2).trunc(), l1 % l2);
-        self.save(s1, ret1);
+        self.save(s1, ret1)?;
         self.save(s2, ret2)
     }
     /// TODO
-    pub fn op_sqrt(&mut self, l1: f32, s1: Save) {
+    pub fn op_sqrt(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.sqrt())
     }
     /// TODO
-    pub fn op_exp(&mut self, l1: f32, s1: Save) {
+    pub fn op_exp(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.exp())
     }
     /// TODO
-    pub fn op_log(&mut self, l1: f32, s1: Save) {
+    pub fn op_log(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.ln())
     }
     /// TODO
-    pub fn op_pow(&mut self, l1: f32, l2: f32, s1: Save) {
+    pub fn op_pow(&mut self, l1: f32, l2: f32, s1: Save)
+            -> Result<(), GlulxError> {
         self.save(s1, l1.powf(l2))
     }
     /// TODO
-    pub fn op_sin(&mut self, l1: f32, s1: Save) {
+    pub fn op_sin(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.sin())
     }
     /// TODO
-    pub fn op_cos(&mut self, l1: f32, s1: Save) {
+    pub fn op_cos(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.cos())
     }
     /// TODO
-    pub fn op_tan(&mut self, l1: f32, s1: Save) {
+    pub fn op_tan(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.tan())
     }
     /// TODO
-    pub fn op_asin(&mut self, l1: f32, s1: Save) {
+    pub fn op_asin(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.asin())
     }
     /// TODO
-    pub fn op_acos(&mut self, l1: f32, s1: Save) {
+    pub fn op_acos(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.acos())
     }
     /// TODO
-    pub fn op_atan(&mut self, l1: f32, s1: Save) {
+    pub fn op_atan(&mut self, l1: f32, s1: Save) -> Result<(), GlulxError> {
         self.save(s1, l1.atan())
     }
     /// TODO
-    pub fn op_atan2(&mut self, l1: f32, l2: f32, s1: Save) {
+    pub fn op_atan2(&mut self, l1: f32, l2: f32, s1: Save)
+            -> Result<(), GlulxError> {
         self.save(s1, l1.atan2(l2))
     }
     /// If l2 is between l1 += l3 jump to l4.
-    pub fn op_jfeq(&mut self, l1: f32, l2: f32, l3: f32, l4: u32) {
+    pub fn op_jfeq(&mut self, l1: f32, l2: f32, l3: f32, l4: u32)
+            -> Result<(), GlulxError> {
         let l3 = l3.abs();
-        if (l1 + l3 > l2) && (l2 > l1 - l3) { self.op_jump(l4) }
+        if (l1 + l3 > l2) && (l2 > l1 - l3) { self.op_jump(l4) } else { Ok(()) }
     }
     /// If l2 is not between l1 += l3 jump to l4.
-    pub fn op_jfne(&mut self, l1: f32, l2: f32, l3: f32, l4: u32) {
+    pub fn op_jfne(&mut self, l1: f32, l2: f32, l3: f32, l4: u32)
+            -> Result<(), GlulxError> {
         let l3 = l3.abs();
-        if !(l1 + l3 > l2) || !(l2 > l1 - l3) { self.op_jump(l4) }
+        if !((l1 + l3 > l2) && (l2 > l1 - l3)) { self.op_jump(l4) } else { Ok(()) }
     }
     /// If l1 is less than l2 jump to l3.
-    pub fn op_jflt(&mut self, l1: f32, l2: f32, l3: u32) {
-        if l1 < l2 { self.op_jump(l3) }
+    pub fn op_jflt(&mut self, l1: f32, l2: f32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 < l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If l1 is less than or equal to l2 jump to l3.
-    pub fn op_jfle(&mut self, l1: f32, l2: f32, l3: u32) {
-        if l1 <= l2 { self.op_jump(l3) }
+    pub fn op_jfle(&mut self, l1: f32, l2: f32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 <= l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If l1 is greater than l2 jump to l3.
-    pub fn op_jfgt(&mut self, l1: f32, l2: f32, l3: u32) {
-        if l1 > l2 { self.op_jump(l3) }
+    pub fn op_jfgt(&mut self, l1: f32, l2: f32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 > l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If l1 is greater than or equal to l2 jump to l3.
-    pub fn op_jfge(&mut self, l1: f32, l2: f32, l3: u32) {
-        if l1 >= l2 { self.op_jump(l3) }
+    pub fn op_jfge(&mut self, l1: f32, l2: f32, l3: u32)
+            -> Result<(), GlulxError> {
+        if l1 >= l2 { self.op_jump(l3) } else { Ok(()) }
     }
     /// If l1 is `f32::NAN` jump to l2.
-    pub fn op_jisnan(&mut self, l1: f32, l2: u32) {
-        if l1.is_nan() { self.op_jump(l2) }
+    pub fn op_jisnan(&mut self, l1: f32, l2: u32) -> Result<(), GlulxError> {
+        if l1.is_nan() { self.op_jump(l2) } else { Ok(()) }
     }
     /// If l1 is `f32::INFINITY` jump to l2.
-    pub fn op_jisinf(&mut self, l1: f32, l2: u32) {
-        if l1.is_infinite() { self.op_jump(l2) }
-    }
-
-    /// Split the byte at the current program counter address into two
-    /// bytes, the first representing the lower 4 bits and the second
-    /// representing the upper 4 bits.
-    fn lo_hi(&mut self) -> (u8, u8) {
-        let bytes: u8 = self.memory.read(self.program_counter);
-        self.program_counter += 0x1;
-        (bytes & 0x0F, (bytes & 0xF0) >> 0x4)
-    }
-
-    /// Return the opcode number from memory, incrementing the program
-    /// counter to the end of the opcode, and before operand identifiers.
-    fn opcode_number(&mut self) -> u32 {
-        let top: u8 = self.memory.read(self.program_counter);
-        match top {
-            _ if top < 0x80 => {
-                //println!("raw opcode: {:#X}", top);
-                self.program_counter += 0x1;
-                top as u32
+    pub fn op_jisinf(&mut self, l1: f32, l2: u32) -> Result<(), GlulxError> {
+        if l1.is_infinite() { self.op_jump(l2) } else { Ok(()) }
+    }
+    /// Make the FyreVM call l1 with the arguments l2 and l3, storing the
+    /// result at s1. Reading a line or key waits for input queued by the
+    /// host, and selecting a channel directs the output of the FyreVM I/O
+    /// system to it. Veneer routines are not accelerated, and filters and
+    /// styles are ignored.
+    pub fn op_fyrecall(&mut self, l1: u32, l2: u32, l3: u32, s1: Save)
+            -> Result<(), GlulxError> {
+        let result = match l1 {
+            0x1 => { // ReadLine
+                self.channel_wait = Some(ChannelWait::Line {
+                    buffer: l2,
+                    len: l3,
+                });
+                0x0
             },
-            _ if top < 0xC0 => {
-                let opcode: u16 = self.memory.read(self.program_counter);
-                //println!("raw opcode: {:#X}", opcode);
-                self.program_counter += 0x2;
-                opcode as u32 - 0x8000
+            0x2 => { // ReadKey
+                self.channel_wait = Some(ChannelWait::Key(s1));
+                return self.read_channel_input();
             },
-            _ => {
-                let opcode: u32 = self.memory.read(self.program_counter);
-                //println!("raw opcode: {:#X}", opcode);
-                self.program_counter += 0x4;
-                opcode - 0xC000_0000
+            0x3 | 0x4 => { // ToLower, ToUpper
+                let ch = ::std::char::from_u32(l2 & 0xFF).unwrap();
+                let changed = if l1 == 0x3 {
+                    ch.to_lowercase().next()
+                } else {
+                    ch.to_uppercase().next()
+                };
+                changed.filter(|&ch| (ch as u32) < 0x100).unwrap_or(ch) as u32
             },
-        }
+            0x5 => { // Channel
+                self.channel = l2;
+                0x0
+            },
+            0x6..=0x8 => 0x0, // SetVeneer, XMLFilter, SetStyle
+            _ => return Err(GlulxError::InvalidFyreCall {
+                call: l1,
+                address: self.instruction_ptr,
+            }),
+        };
+        self.save(s1, result)?;
+        self.read_channel_input()
     }
 
-    fn eval(&mut self, opcode: u32) {
-        opcode_match!(self, opcode,
+    /// Executes a decoded instruction. The program counter must already
+    /// point to the following instruction.
+    fn eval(&mut self, instruction: &Instruction) -> Result<(), GlulxError> {
+        opcode_match!(self, instruction,
             0x00 => op_nop(),
             0x10 => op_add(l1, l2, s1),
             0x11 => op_sub(l1, l2, s1),
@@ -769,13 +1877,17 @@ impl Glulx {
             0x1C5 => op_jfge(l1, l2, l3),
             0x1C8 => op_jisnan(l1, l2),
             0x1C9 => op_jisinf(l1, l2),
-        );
+            0x1000 => op_fyrecall(l1, l2, l3, s1),
+        )
     }
 
-    pub fn init(&mut self) {
+    /// Calls the start function. Unlike other calls, no call stub is
+    /// pushed beneath its call frame, so returning from it stops the
+    /// machine.
+    pub fn init(&mut self) -> Result<(), GlulxError> {
         let start = self.memory.start_func();
-        self.op_call(start, 0x0, Save::Null);
         self.running = true;
+        self.call_func(start, vec![])
     }
 
     /// Returns flag which indicates whether the quit opcode has been
@@ -784,22 +1896,587 @@ impl Glulx {
         self.running
     }
 
-    pub fn step(&mut self) {
-        let opcode = self.opcode_number();
-        self.eval(opcode);
+    /// The address of the next instruction to execute.
+    pub fn program_counter(&self) -> u32 {
+        self.program_counter
+    }
+
+    /// The main memory of the machine.
+    pub fn memory(&self) -> &GlulxMemory {
+        &self.memory
+    }
+
+    /// The number of call frames on the stack.
+    pub fn call_depth(&self) -> u32 {
+        self.stack.call_depth()
+    }
+
+    /// The locals of the current call frame.
+    pub fn locals(&self) -> Vec<Local> {
+        self.stack.locals()
+    }
+
+    /// The values pushed onto the stack in the current call frame, from
+    /// the bottom of the frame to the top of the stack.
+    pub fn values(&self) -> Vec<u32> {
+        self.stack.values()
+    }
+
+    /// The call frames on the stack, from the current frame down to the
+    /// frame of the start function.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        self.stack.backtrace()
+    }
+
+    /// Adds the current backtrace to an error which stopped the machine.
+    fn fatal(&self, error: GlulxError) -> GlulxError {
+        match error {
+            GlulxError::Fatal { .. } => error,
+            error => GlulxError::Fatal {
+                error: Box::new(error),
+                backtrace: self.backtrace(),
+            },
+        }
+    }
+
+    /// Whether execution was paused by the last instruction.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Watches the given range of memory, taking the given action after
+    /// any instruction which accesses it. Returns the identifier of the
+    /// watchpoint.
+    pub fn add_watchpoint(&mut self,
+            range: Range<u32>,
+            access: Access,
+            action: WatchAction) -> u32 {
+        let id = self.next_watchpoint;
+        self.next_watchpoint += 0x1;
+        self.memory.watch(id, range, access);
+        self.watch_actions.insert(id, action);
+        id
+    }
+
+    /// Removes the watchpoint with the given identifier, returning whether
+    /// it existed.
+    pub fn remove_watchpoint(&mut self, id: u32) -> bool {
+        self.watch_actions.remove(&id);
+        self.memory.unwatch(id)
+    }
+
+    /// Removes and returns the hits of pausing watchpoints.
+    pub fn take_watch_events(&mut self) -> Vec<WatchEvent> {
+        ::std::mem::take(&mut self.watch_events)
+    }
+
+    /// Reports the watchpoints hit by the current instruction.
+    fn check_watchpoints(&mut self) {
+        for mut event in self.memory.take_hits() {
+            event.program_counter = self.instruction_ptr;
+            match self.watch_actions.get_mut(&event.watchpoint) {
+                Some(&mut WatchAction::Callback(ref mut callback)) => {
+                    callback(&event)
+                },
+                Some(&mut WatchAction::Pause) => {
+                    self.paused = true;
+                    self.watch_events.push(event);
+                },
+                None => (),
+            }
+        }
+    }
+
+    /// Calls the given handler for each debug trap, replacing any previous
+    /// handler.
+    pub fn set_trap_handler(&mut self, handler: TrapHandler) {
+        self.trap_handler = Some(handler);
+    }
+
+    /// Removes the debug trap handler, so debug traps abort execution.
+    pub fn clear_trap_handler(&mut self) {
+        self.trap_handler = None;
+    }
+
+    /// Removes and returns the argument of the debug trap which paused
+    /// execution, if any.
+    pub fn take_trap(&mut self) -> Option<u32> {
+        self.trap.take()
+    }
+
+    /// Sends a record of each instruction executed to the given tracer,
+    /// replacing any previous tracer.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer + Send>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Removes and returns the tracer.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer + Send>> {
+        self.tracer.take()
+    }
+
+    /// Starts counting instructions and calls by function, replacing any
+    /// previous profile. The functions already being executed are
+    /// counted as called.
+    pub fn start_profiling(&mut self) {
+        let mut profiler = Profiler::new();
+        for frame in self.backtrace().iter().rev() {
+            if let Some(function) = frame.function {
+                profiler.enter(function);
+            }
+        }
+        self.profiler = Some(profiler);
+    }
+
+    /// The profile being collected, if any.
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Stops profiling, and returns the finished profile.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take().map(|mut profiler| {
+            profiler.finish();
+            profiler
+        })
+    }
+
+    /// Starts counting the instructions and opcodes executed, replacing
+    /// any previous coverage. The functions already being executed are
+    /// counted as called.
+    pub fn start_coverage(&mut self) {
+        let mut coverage = Coverage::new();
+        for function in self.backtrace().iter().filter_map(|f| f.function) {
+            coverage.enter(function);
+        }
+        self.coverage = Some(coverage);
+    }
+
+    /// The coverage being collected, if any.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Stops collecting coverage, and returns it.
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    /// Adds the value of a load operand to the trace of the current
+    /// instruction.
+    fn trace_load<T: TraceValue>(&mut self, value: &T) {
+        if let Some(ref mut record) = self.trace {
+            record.loads.extend(value.trace_value());
+        }
+    }
+
+    /// Adds a stored value to the trace of the current instruction.
+    fn trace_store(&mut self, value: u32) {
+        if let Some(ref mut record) = self.trace {
+            record.stores.push(value);
+        }
+    }
+
+    /// Decodes the next instruction to execute, without executing it.
+    pub fn next_instruction(&self) -> Result<Instruction, GlulxError> {
+        decode(&self.memory, self.program_counter)
+    }
+
+    /// Decodes and executes the instruction at the program counter. While
+    /// waiting for a Glk event, completes the `glk_select` call instead if
+    /// the event has arrived. Any pause by the previous instruction ends,
+    /// along with its trap and watchpoint hits.
+    pub fn step(&mut self) -> Result<(), GlulxError> {
+        self.paused = false;
+        self.trap = None;
+        self.watch_events.clear();
+        if self.waiting.is_some() {
+            return self.select();
+        }
+        if self.channel_wait.is_some() {
+            return self.read_channel_input();
+        }
+        let instruction = decode(&self.memory, self.program_counter)?;
+        self.instruction_ptr = instruction.address;
+        self.program_counter = instruction.next();
+        if self.tracer.is_some() {
+            self.trace = Some(TraceRecord {
+                program_counter: instruction.address,
+                opcode: instruction.opcode.number,
+                loads: vec![],
+                stores: vec![],
+            });
+        }
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.instruction();
+        }
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.instruction(instruction.address, instruction.opcode.number);
+        }
+        let result = self.eval(&instruction);
+        if let (Some(tracer), Some(record)) =
+                (self.tracer.as_mut(), self.trace.take()) {
+            tracer.trace(&record);
+        }
+        self.check_watchpoints();
+        result
+    }
+
+    /// Starts the machine and executes instructions until it stops or
+    /// pauses.
+    pub fn run(&mut self) -> Result<(), GlulxError> {
+        self.init().map_err(|error| self.fatal(error))?;
+        self.resume()
+    }
+
+    /// Executes instructions until the machine stops or pauses. An error
+    /// stops the machine, and is returned as `GlulxError::Fatal` with the
+    /// backtrace of the failing instruction.
+    pub fn resume(&mut self) -> Result<(), GlulxError> {
+        self.paused = false;
+        while self.running && !self.paused {
+            if let Err(error) = self.step() {
+                self.running = false;
+                return Err(self.fatal(error));
+            }
+            if self.is_waiting() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes at most the given number of instructions, returning why it
+    /// stopped. Unlike `resume`, control always comes back to the host
+    /// within the budget, so a machine can share a thread with others or
+    /// with a game loop. A machine waiting for input returns at once until
+    /// the input is given, and then continues from the waiting call.
+    pub fn run_for(&mut self, budget: u64) -> RunStatus {
+        self.paused = false;
+        for _ in 0x0..budget {
+            if !self.running {
+                return RunStatus::Quit;
+            }
+            if let Err(error) = self.step() {
+                self.running = false;
+                return RunStatus::Error(self.fatal(error));
+            }
+            if self.is_waiting() {
+                return RunStatus::Waiting;
+            }
+            if self.paused {
+                return RunStatus::Paused;
+            }
+        }
+        if self.running {
+            RunStatus::OutOfBudget
+        } else {
+            RunStatus::Quit
+        }
+    }
+
+    /// Completes a waiting `glk_select` call if its event has arrived,
+    /// storing its result.
+    fn select(&mut self) -> Result<(), GlulxError> {
+        let save = match self.waiting {
+            Some(save) => save,
+            None => return Ok(()),
+        };
+        let mut memory = GlkMemory::new(&mut self.memory);
+        if !self.glk.select(&mut memory)? {
+            return Ok(());
+        }
+        for value in memory.into_pushes() {
+            self.push(value)?;
+        }
+        self.waiting = None;
+        self.save(save, 0x0)
+    }
+
+    /// Completes a waiting FyreVM input call if input has been queued.
+    fn read_channel_input(&mut self) -> Result<(), GlulxError> {
+        let wait = match self.channel_wait {
+            Some(wait) => wait,
+            None => return Ok(()),
+        };
+        let input = match self.channel_input.pop_front() {
+            Some(input) => input,
+            None => return Ok(()),
+        };
+        self.channel_wait = None;
+        match wait {
+            ChannelWait::Line { buffer, len } => {
+                let chars: Vec<u8> = input.chars()
+                    .take(len.saturating_sub(0x4) as usize)
+                    .map(|ch| if (ch as u32) < 0x100 { ch as u8 } else { b'?' })
+                    .collect();
+                self.write_checked(buffer, chars.len() as u32)?;
+                for (index, &ch) in chars.iter().enumerate() {
+                    self.write_checked(buffer + 0x4 + index as u32, ch)?;
+                }
+                Ok(())
+            },
+            ChannelWait::Key(save) => {
+                let ch = input.chars().next().map_or(0xA, |ch| ch as u32);
+                self.save(save, ch)
+            },
+        }
+    }
+
+    /// Whether the machine is waiting in `glk_select` for an event, or in
+    /// a FyreVM input call for input. The machine continues once the event
+    /// is given to its Glk, or the input is queued.
+    pub fn is_waiting(&self) -> bool {
+        self.waiting.is_some() || self.channel_wait.is_some()
+    }
+
+    /// Removes and returns the text printed to each channel by the FyreVM
+    /// I/O system since the last call, by the name of the channel. Hosts
+    /// take the channels whenever the machine waits for input, giving the
+    /// output of each turn.
+    pub fn take_channels(&mut self) -> BTreeMap<String, String> {
+        ::std::mem::take(&mut self.channels)
+    }
+
+    /// Queues a line of input for the FyreVM input calls. A call which
+    /// reads a key reads the first character of the line.
+    pub fn push_channel_input(&mut self, input: String) {
+        self.channel_input.push_back(input);
+    }
+
+    /// The Glk library the machine calls.
+    pub fn glk(&self) -> &dyn Glk {
+        &*self.glk
+    }
+
+    /// The Glk library the machine calls, for giving it input.
+    pub fn glk_mut(&mut self) -> &mut dyn Glk {
+        &mut *self.glk
+    }
+
+    /// Replaces the Glk library, which is a `TextGlk` by default.
+    pub fn set_glk(&mut self, glk: Box<dyn Glk>) {
+        self.glk = glk;
+    }
+
+    /// The Glk library, if it is of the given type.
+    pub fn glk_as<T: Glk>(&self) -> Option<&T> {
+        (&*self.glk as &dyn Any).downcast_ref()
+    }
+
+    /// The Glk library, if it is of the given type, for giving it input.
+    pub fn glk_as_mut<T: Glk>(&mut self) -> Option<&mut T> {
+        (&mut *self.glk as &mut dyn Any).downcast_mut()
+    }
+
+    /// Seeds the random number generator, and uses the same seed whenever
+    /// the program asks for an unpredictable seed, so runs repeat exactly.
+    pub fn set_random_seed(&mut self, seed: u32) {
+        self.fixed_seed = Some(seed);
+        self.random.seed(seed);
+    }
+
+    /// The seed given to `set_random_seed`, if any.
+    pub fn random_seed(&self) -> Option<u32> {
+        self.fixed_seed
+    }
+
+    /// Saves the whole state of the machine and its Glk, including the
+    /// state of a waiting `glk_select` or FyreVM input call, as a Quetzal
+    /// save file which `restore_autosave` restores.
+    pub fn autosave(&mut self) -> Result<Vec<u8>, GlulxError> {
+        // how the machine waits, and where its input is stored.
+        let (wait, save, buffer, len) = match (self.waiting, self.channel_wait) {
+            (Some(save), _) => (0x1, save, 0x0, 0x0),
+            (None, Some(ChannelWait::Key(save))) => (0x2, save, 0x0, 0x0),
+            (None, Some(ChannelWait::Line { buffer, len })) => {
+                (0x3, Save::Null, buffer, len)
+            },
+            (None, None) => (0x0, Save::Null, 0x0, 0x0),
+        };
+        let (dest_type, dest_addr) = self.save_stub(save);
+        let program_counter = self.program_counter;
+        self.push_stub(dest_type, dest_addr, program_counter)?;
+
+        let random = self.random.state();
+        let fields = [
+            wait,
+            self.iosys.0,
+            self.iosys.1,
+            self.string_table,
+            (random >> 0x20) as u32,
+            random as u32,
+            self.protect.0,
+            self.protect.1,
+            self.channel,
+            buffer,
+            len,
+        ];
+        let mut state = vec![0x0; fields.len() * 0x4];
+        for (index, &field) in fields.iter().enumerate() {
+            BigEndian::write_u32(&mut state[index * 0x4..], field);
+        }
+        let mut chunks = vec![(*b"VMst", state)];
+        if let Some(glk) = self.glk.save_state() {
+            chunks.push((*b"GlkS", glk));
+        }
+
+        let bytes = self.quetzal(chunks).to_bytes(self.memory.story().pages());
+        self.stack.pop_call_stub();
+        Ok(bytes)
+    }
+
+    /// Takes a snapshot of the whole state of the machine: memory and the
+    /// heap, the stack, the program counter, the I/O system, the string
+    /// table, the random number generator, accelerated functions, the
+    /// protected range, any waiting `glk_select` or FyreVM input call,
+    /// and the state of its Glk. Unlike `autosave`, nothing is encoded,
+    /// and the snapshot can only be restored into a machine running the
+    /// same story.
+    ///
+    /// The undo states, the queued FyreVM input and untaken channel
+    /// output, and the debugging tools of the machine are not included.
+    pub fn snapshot(&self) -> VmSnapshot {
+        let state = SnapshotState {
+            header: self.memory.story().bytes(0x0..0x24),
+            memory: self.memory.pages().clone(),
+            heap: self.memory.heap().cloned(),
+            stack: self.stack.clone(),
+            program_counter: self.program_counter,
+            instruction_ptr: self.instruction_ptr,
+            running: self.running,
+            iosys: self.iosys,
+            string_table: self.string_table,
+            random: self.random,
+            protect: self.protect,
+            accel_functions: self.accel_functions.clone(),
+            accel_params: self.accel_params,
+            waiting: self.waiting,
+            channel: self.channel,
+            channel_wait: self.channel_wait,
+            glk: self.glk.save_state(),
+        };
+        VmSnapshot { state: Arc::new(state) }
     }
 
-    pub fn run(&mut self) {
-        self.init();
-        while self.running {
-            self.step();
+    /// Returns the machine to the state of a snapshot, which must have
+    /// been taken from a machine running the same story. The protected
+    /// range is restored along with the rest of memory.
+    pub fn restore(&mut self, snapshot: &VmSnapshot)
+            -> Result<(), GlulxError> {
+        let state = &snapshot.state;
+        if state.header != self.memory.story().bytes(0x0..0x24) {
+            return Err(GlulxError::IncompatibleSnapshot);
+        }
+        if let Some(ref glk) = state.glk {
+            if !self.glk.restore_state(glk) {
+                return Err(GlulxError::CorruptSnapshot {
+                    message: "glk state could not be restored".to_string(),
+                });
+            }
+        }
+        self.memory.restore(state.memory.clone(), state.heap.clone());
+        self.stack = state.stack.clone();
+        self.program_counter = state.program_counter;
+        self.instruction_ptr = state.instruction_ptr;
+        self.running = state.running;
+        self.paused = false;
+        self.iosys = state.iosys;
+        self.string_table = state.string_table;
+        self.random = state.random;
+        self.protect = state.protect;
+        self.accel_functions = state.accel_functions.clone();
+        self.accel_params = state.accel_params;
+        self.waiting = state.waiting;
+        self.channel = state.channel;
+        self.channel_wait = state.channel_wait;
+        Ok(())
+    }
+
+    /// Restores a state saved by `autosave`, leaving the machine running
+    /// and, if it was waiting for a Glk event or input, waiting again.
+    pub fn restore_autosave(&mut self, bytes: &[u8])
+            -> Result<(), GlulxError> {
+        let quetzal = Quetzal::from_bytes(bytes, self.memory.story().pages())
+            .map_err(|message| GlulxError::CorruptSnapshot { message })?;
+        let state = quetzal.chunk(b"VMst")
+            .filter(|state| state.len() == 0x2C)
+            .ok_or_else(|| GlulxError::CorruptSnapshot {
+                message: "save file is not an autosave".to_string(),
+            })?;
+        let field = |index: usize| BigEndian::read_u32(&state[index * 0x4..]);
+
+        if let Some(glk) = quetzal.chunk(b"GlkS") {
+            if !self.glk.restore_state(glk) {
+                return Err(GlulxError::CorruptSnapshot {
+                    message: "glk state could not be restored".to_string(),
+                });
+            }
+        }
+        self.protect = (0x0, 0x0);
+        if !self.restore_quetzal(&quetzal) {
+            return Err(GlulxError::IncompatibleSnapshot);
+        }
+
+        let (dest_type, dest_addr, program_counter) = self.stack.pop_call_stub();
+        self.program_counter = program_counter;
+        let save = match dest_type {
+            0x1 => Save::Addr(dest_addr),
+            0x2 => Save::Frame(dest_addr),
+            0x3 => Save::Push,
+            _ => Save::Null,
+        };
+        self.waiting = None;
+        self.channel_wait = None;
+        match field(0x0) {
+            0x1 => self.waiting = Some(save),
+            0x2 => self.channel_wait = Some(ChannelWait::Key(save)),
+            0x3 => {
+                self.channel_wait = Some(ChannelWait::Line {
+                    buffer: field(0x9),
+                    len: field(0xA),
+                });
+            },
+            _ => (),
         }
+        self.iosys = (field(0x1), field(0x2));
+        self.string_table = field(0x3);
+        self.random = Random::from_state(
+            (field(0x4) as u64) << 0x20 | field(0x5) as u64);
+        self.protect = (field(0x6), field(0x7));
+        self.channel = field(0x8);
+        self.running = true;
+        self.paused = false;
+        Ok(())
+    }
+}
+
+
+/// The result of a search, which is the address of the match, or its
+/// index when the options ask for one. A failed search gives 0x0, or -1
+/// for an index.
+fn search_result(result: Option<(u32, u32)>, options: u32) -> u32 {
+    match (result, options & 0x4 != 0x0) {
+        (Some((_, index)), true) => index,
+        (Some((ptr, _)), false) => ptr,
+        (None, true) => 0xFFFFFFFF,
+        (None, false) => 0x0,
     }
 }
 
 
+/// The name of a channel, from its four characters packed into a `u32`.
+fn channel_name(channel: u32) -> String {
+    let mut bytes = [0x0; 0x4];
+    BigEndian::write_u32(&mut bytes, channel);
+    String::from_utf8_lossy(&bytes)
+        .trim_end_matches([' ', '\0'])
+        .to_string()
+}
+
+
 /// Data save location information for an opcode
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Save {
 
     /// Discard result.
@@ -819,264 +2496,793 @@ pub enum Save {
 }
 
 
-trait ReadRegister<T> {
-    fn read_register(&mut self, value: u8) -> T;
+/// Why `run_for` returned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunStatus {
+
+    /// The budget of instructions was used up. The machine continues with
+    /// the next call.
+    OutOfBudget,
+
+    /// The machine is waiting in `glk_select` for a Glk event, or in a
+    /// FyreVM input call for input.
+    Waiting,
+
+    /// A trap or watchpoint paused the machine.
+    Paused,
+
+    /// The machine has stopped, because the story quit or returned from
+    /// its start function, or an earlier error stopped it.
+    Quit,
+
+    /// An error stopped the machine. It is given as `GlulxError::Fatal`
+    /// with the backtrace of the failing instruction.
+    Error(GlulxError),
 }
 
 
-macro_rules! read_operand {
-    (@const $self_:ident, $rtype:ty) => {{
-        let data: $rtype = $self_.memory.read($self_.program_counter);
-        $self_.program_counter += size_of::<$rtype>() as u32;
-        data as _
-    }};
-    (@addr $self_:ident, $rtype:ty) => {{
-        let address: $rtype = $self_.memory.read($self_.program_counter);
-        $self_.program_counter += size_of::<$rtype>() as u32;
-        $self_.memory.read(address as u32)
-    }};
-    (@pop $self_:ident, $rtype:ty) => {{
-        let value: $rtype = $self_.stack.pop();
-        value as _
-    }};
-    (@frame $self_:ident, $rtype:ty) => {{
-        let address: $rtype = $self_.memory.read($self_.program_counter);
-        $self_.program_counter += size_of::<$rtype>() as u32;
-        $self_.stack.read(address as u32)
-    }};
-    (@ram $self_:ident, $rtype:ty) => {{
-        let address: $rtype = $self_.memory.read($self_.program_counter);
-        $self_.program_counter += size_of::<$rtype>() as u32;
-        $self_.memory.ram_read(address as u32)
-    }}
+trait ReadRegister<T> {
+    fn read_register(&mut self, operand: Operand) -> Result<T, GlulxError>;
 }
 
 
 impl ReadRegister<u8> for Glulx {
-    fn read_register(&mut self, mode: u8) -> u8 {
-        match mode {
-            0x0 => 0x0,
-            0x1 => read_operand!(@const self, i8),
-            0x2 => panic!("cannot load two byte u8"),
-            0x3 => panic!("cannot load four byte u8"),
-            0x4 => panic!("mode 0x4 not supported"),
-            0x5 => read_operand!(@addr self, u8),
-            0x6 => read_operand!(@addr self, u16),
-            0x7 => read_operand!(@addr self, u32),
-            0x8 => read_operand!(@pop self, u32),
-            0x9 => read_operand!(@frame self, u8),
-            0xA => read_operand!(@frame self, u16),
-            0xC => panic!("mode 0xC not supported"),
-            0xB => read_operand!(@frame self, u32),
-            0xD => read_operand!(@ram self, u8),
-            0xE => read_operand!(@ram self, u16),
-            0xF => read_operand!(@ram self, u32),
-            _ => unreachable!(),
-        }
+    fn read_register(&mut self, operand: Operand) -> Result<u8, GlulxError> {
+        Ok(match operand {
+                Operand::Zero => 0x0,
+                Operand::Const(value) => value as u8,
+                Operand::Addr(ptr) => self.read_checked(ptr)?,
+                Operand::Stack => self.pop()?,
+                Operand::Local(ptr) => self.read_local(ptr)?,
+                Operand::Ram(ptr) => self.read_checked(self.ram_ptr(ptr))?,
+        })
     }
 }
 
 
 impl ReadRegister<u16> for Glulx {
-    fn read_register(&mut self, mode: u8) -> u16 {
-        match mode {
-            0x0 => 0x0,
-            0x1 => read_operand!(@const self, i8),
-            0x2 => read_operand!(@const self, i16),
-            0x3 => panic!("cannot load four byte u16"),
-            0x4 => panic!("mode 0x4 not supported"),
-            0x5 => read_operand!(@addr self, u8),
-            0x6 => read_operand!(@addr self, u16),
-            0x7 => read_operand!(@addr self, u32),
-            0x8 => read_operand!(@pop self, u32),
-            0x9 => read_operand!(@frame self, u8),
-            0xA => read_operand!(@frame self, u16),
-            0xB => read_operand!(@frame self, u32),
-            0xC => panic!("mode 0xC not supported"),
-            0xD => read_operand!(@ram self, u8),
-            0xE => read_operand!(@ram self, u16),
-            0xF => read_operand!(@ram self, u32),
-            _ => unreachable!(),
-        }
+    fn read_register(&mut self, operand: Operand) -> Result<u16, GlulxError> {
+        Ok(match operand {
+                Operand::Zero => 0x0,
+                Operand::Const(value) => value as u16,
+                Operand::Addr(ptr) => self.read_checked(ptr)?,
+                Operand::Stack => self.pop()?,
+                Operand::Local(ptr) => self.read_local(ptr)?,
+                Operand::Ram(ptr) => self.read_checked(self.ram_ptr(ptr))?,
+        })
     }
 }
 
 
 impl ReadRegister<u32> for Glulx {
-    fn read_register(&mut self, mode: u8) -> u32 {
-        match mode {
-            0x0 => 0x0,
-            0x1 => read_operand!(@const self, i8),
-            0x2 => read_operand!(@const self, i16),
-            0x3 => read_operand!(@const self, i32),
-            0x4 => panic!("mode 0x4 not supported"),
-            0x5 => read_operand!(@addr self, u8),
-            0x6 => read_operand!(@addr self, u16),
-            0x7 => read_operand!(@addr self, u32),
-            0x8 => read_operand!(@pop self, u32),
-            0x9 => read_operand!(@frame self, u8),
-            0xA => read_operand!(@frame self, u16),
-            0xB => read_operand!(@frame self, u32),
-            0xC => panic!("mode 0xC not supported"),
-            0xD => read_operand!(@ram self, u8),
-            0xE => read_operand!(@ram self, u16),
-            0xF => read_operand!(@ram self, u32),
-            _ => unreachable!(),
-        }
+    fn read_register(&mut self, operand: Operand) -> Result<u32, GlulxError> {
+        Ok(match operand {
+                Operand::Zero => 0x0,
+                Operand::Const(value) => value as u32,
+                Operand::Addr(ptr) => self.read_checked(ptr)?,
+                Operand::Stack => self.pop()?,
+                Operand::Local(ptr) => self.read_local(ptr)?,
+                Operand::Ram(ptr) => self.read_checked(self.ram_ptr(ptr))?,
+        })
     }
 }
 
 
 impl ReadRegister<i32> for Glulx {
-    fn read_register(&mut self, mode: u8) -> i32 {
-        match mode {
-            0x0 => 0x0,
-            0x1 => read_operand!(@const self, i8),
-            0x2 => read_operand!(@const self, i16),
-            0x3 => read_operand!(@const self, i32),
-            0x4 => panic!("mode 0x4 not supported"),
-            0x5 => read_operand!(@addr self, u8),
-            0x6 => read_operand!(@addr self, u16),
-            0x7 => read_operand!(@addr self, u32),
-            0x8 => read_operand!(@pop self, i32),
-            0x9 => read_operand!(@frame self, u8),
-            0xA => read_operand!(@frame self, u16),
-            0xB => read_operand!(@frame self, u32),
-            0xC => panic!("mode 0xC not supported"),
-            0xD => read_operand!(@ram self, u8),
-            0xE => read_operand!(@ram self, u16),
-            0xF => read_operand!(@ram self, u32),
-            _ => unreachable!(),
-        }
+    fn read_register(&mut self, operand: Operand) -> Result<i32, GlulxError> {
+        Ok(match operand {
+                Operand::Zero => 0x0,
+                Operand::Const(value) => value,
+                Operand::Addr(ptr) => self.read_checked(ptr)?,
+                Operand::Stack => self.pop()?,
+                Operand::Local(ptr) => self.read_local(ptr)?,
+                Operand::Ram(ptr) => self.read_checked(self.ram_ptr(ptr))?,
+        })
     }
 }
 
 
 impl ReadRegister<f32> for Glulx {
-    fn read_register(&mut self, mode: u8) -> f32 {
-        match mode {
-            0x0 => 0.0,
-            0x1 => panic!("cannot load single byte f32"),
-            0x2 => panic!("cannot load two byte f32"),
-            0x3 => read_operand!(@const self, f32),
-            0x4 => panic!("mode 0x4 not supported"),
-            0x5 => read_operand!(@addr self, u8),
-            0x6 => read_operand!(@addr self, u16),
-            0x7 => read_operand!(@addr self, u32),
-            0x8 => read_operand!(@pop self, f32),
-            0x9 => read_operand!(@frame self, u8),
-            0xA => read_operand!(@frame self, u16),
-            0xB => read_operand!(@frame self, u32),
-            0xC => panic!("mode 0xC not supported"),
-            0xD => read_operand!(@ram self, u8),
-            0xE => read_operand!(@ram self, u16),
-            0xF => read_operand!(@ram self, u32),
-            _ => unreachable!(),
-        }
+    fn read_register(&mut self, operand: Operand) -> Result<f32, GlulxError> {
+        Ok(match operand {
+                Operand::Zero => 0.0,
+                Operand::Const(value) => f32::from_bits(value as u32),
+                Operand::Addr(ptr) => self.read_checked(ptr)?,
+                Operand::Stack => self.pop()?,
+                Operand::Local(ptr) => self.read_local(ptr)?,
+                Operand::Ram(ptr) => self.read_checked(self.ram_ptr(ptr))?,
+        })
     }
 }
 
 
 impl ReadRegister<Save> for Glulx {
-    fn read_register(&mut self, mode: u8) -> Save {
-        match mode {
-            0x0 => Save::Null,
-            0x1 => panic!("cannot save to one byte constant"),
-            0x2 => panic!("cannot save to two byte constant"),
-            0x3 => panic!("cannot save to four byte constant"),
-            0x4 => panic!("mode 0x4 not supported"),
-            0x5 => Save::Addr(read_operand!(@const self, u8)),
-            0x6 => Save::Addr(read_operand!(@const self, u16)),
-            0x7 => Save::Addr(read_operand!(@const self, u32)),
-            0x8 => Save::Push,
-            0x9 => Save::Frame(read_operand!(@const self, u8)),
-            0xA => Save::Frame(read_operand!(@const self, u16)),
-            0xB => Save::Frame(read_operand!(@const self, u32)),
-            0xC => panic!("mode 0xC not supported"),
-            0xD => Save::Ram(read_operand!(@const self, u8)),
-            0xE => Save::Ram(read_operand!(@const self, u16)),
-            0xF => Save::Ram(read_operand!(@const self, u32)),
-            _ => unreachable!(),
-        }
+    fn read_register(&mut self, operand: Operand) -> Result<Save, GlulxError> {
+        Ok(match operand {
+                Operand::Zero => Save::Null,
+                Operand::Const(_) => panic!("cannot save to a constant"),
+                Operand::Addr(ptr) => Save::Addr(ptr),
+                Operand::Stack => Save::Push,
+                Operand::Local(ptr) => Save::Frame(ptr),
+                Operand::Ram(ptr) => Save::Ram(ptr),
+        })
     }
 }
 
 
 trait SaveRegister<T> {
-    fn save(&mut self, save: Save, value: T);
+    fn save(&mut self, save: Save, value: T) -> Result<(), GlulxError>;
 }
 
 
 impl SaveRegister<u8> for Glulx {
-    fn save(&mut self, save: Save, value: u8){
+    fn save(&mut self, save: Save, value: u8) -> Result<(), GlulxError> {
         use self::Save::*;
+        self.trace_store(value as u32);
 
         match save {
             Null => {},
-            Addr(ptr) => self.memory.write(ptr, value),
-            Push => self.stack.push(value as u32),
-            Frame(ptr) => self.stack.write(ptr, value),
-            Ram(ptr) => self.memory.ram_write(ptr, value),
+            Addr(ptr) => self.write_checked(ptr, value)?,
+            Push => self.push(value as u32)?,
+            Frame(ptr) => self.write_local(ptr, value)?,
+            Ram(ptr) => {
+                let ptr = self.ram_ptr(ptr);
+                self.write_checked(ptr, value)?
+            },
         }
+        Ok(())
     }
 }
 
 
 impl SaveRegister<u16> for Glulx {
-    fn save(&mut self, save: Save, value: u16){
+    fn save(&mut self, save: Save, value: u16) -> Result<(), GlulxError> {
         use self::Save::*;
+        self.trace_store(value as u32);
 
         match save {
             Null => {},
-            Addr(ptr) => self.memory.write(ptr, value),
-            Push => self.stack.push(value as u32),
-            Frame(ptr) => self.stack.write(ptr, value),
-            Ram(ptr) => self.memory.ram_write(ptr, value),
+            Addr(ptr) => self.write_checked(ptr, value)?,
+            Push => self.push(value as u32)?,
+            Frame(ptr) => self.write_local(ptr, value)?,
+            Ram(ptr) => {
+                let ptr = self.ram_ptr(ptr);
+                self.write_checked(ptr, value)?
+            },
         }
+        Ok(())
     }
 }
 
 
 impl SaveRegister<i32> for Glulx {
-    fn save(&mut self, save: Save, value: i32){
+    fn save(&mut self, save: Save, value: i32) -> Result<(), GlulxError> {
         use self::Save::*;
+        self.trace_store(value as u32);
 
         match save {
             Null => {},
-            Addr(ptr) => self.memory.write(ptr, value),
-            Push => self.stack.push(value),
-            Frame(ptr) => self.stack.write(ptr, value),
-            Ram(ptr) => self.memory.ram_write(ptr, value),
+            Addr(ptr) => self.write_checked(ptr, value)?,
+            Push => self.push(value)?,
+            Frame(ptr) => self.write_local(ptr, value)?,
+            Ram(ptr) => {
+                let ptr = self.ram_ptr(ptr);
+                self.write_checked(ptr, value)?
+            },
         }
+        Ok(())
     }
 }
 
 
 impl SaveRegister<u32> for Glulx {
-    fn save(&mut self, save: Save, value: u32){
+    fn save(&mut self, save: Save, value: u32) -> Result<(), GlulxError> {
         use self::Save::*;
+        self.trace_store(value);
 
         match save {
             Null => {},
-            Addr(ptr) => self.memory.write(ptr, value),
-            Push => self.stack.push(value),
-            Frame(ptr) => self.stack.write(ptr, value),
-            Ram(ptr) => self.memory.ram_write(ptr, value),
+            Addr(ptr) => self.write_checked(ptr, value)?,
+            Push => self.push(value)?,
+            Frame(ptr) => self.write_local(ptr, value)?,
+            Ram(ptr) => {
+                let ptr = self.ram_ptr(ptr);
+                self.write_checked(ptr, value)?
+            },
         }
+        Ok(())
     }
 }
 
 
 impl SaveRegister<f32> for Glulx {
-    fn save(&mut self, save: Save, value: f32){
+    fn save(&mut self, save: Save, value: f32) -> Result<(), GlulxError> {
         use self::Save::*;
+        self.trace_store(value.to_bits());
 
         match save {
             Null => {},
-            Addr(ptr) => self.memory.write(ptr, value),
-            Push => self.stack.push(value),
-            Frame(ptr) => self.stack.write(ptr, value),
-            Ram(ptr) => self.memory.ram_write(ptr, value),
+            Addr(ptr) => self.write_checked(ptr, value)?,
+            Push => self.push(value)?,
+            Frame(ptr) => self.write_local(ptr, value)?,
+            Ram(ptr) => {
+                let ptr = self.ram_ptr(ptr);
+                self.write_checked(ptr, value)?
+            },
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use assembler::Arg::*;
+    use assembler::{Arg, Assembler};
+    use error::GlulxError;
+    use glk::{Input, TextGlk};
+    use memory::Memory;
+    use trap::TrapAction;
+    use watch::{Access, WatchAction, WatchEvent};
+
+    use super::{Glulx, RunStatus};
+
+    /// Builds a machine with `0x10` bytes of RAM at `0x100` and a start
+    /// function at `0x24` with four locals, which `program` fills in.
+    fn build<F>(program: F) -> Glulx where F: FnOnce(&mut Assembler) {
+        let mut asm = Assembler::new();
+        let (main, ram) = (asm.label(), asm.label());
+        asm.start(main)
+            .ram(ram, &[0x0; 0x10])
+            .function(main, 0xC1, &[(0x4, 0x4)]);
+        program(&mut asm);
+        Glulx::from_rom(asm.finish().unwrap()).unwrap()
+    }
+
+    fn run<F>(program: F) -> Glulx where F: FnOnce(&mut Assembler) {
+        let mut glulx = build(program);
+        glulx.run().unwrap();
+        glulx
+    }
+
+    fn ram(glulx: &Glulx, offset: u32) -> i32 {
+        glulx.memory.ram_read(offset)
+    }
+
+    #[test]
+    fn arithmetic() {
+        let glulx = run(|asm| {
+            asm.op("add", &[Const(40), Const(2), Ram(0x0)])
+                .op("sub", &[Const(2), Const(40), Ram(0x4)])
+                .op("div", &[Const(-7), Const(2), Ram(0x8)])
+                .op("mod", &[Const(-7), Const(2), Ram(0xC)])
+                .op("return", &[Zero]);
+        });
+        assert_eq!(ram(&glulx, 0x0), 42);
+        assert_eq!(ram(&glulx, 0x4), -38);
+        assert_eq!(ram(&glulx, 0x8), -3);
+        assert_eq!(ram(&glulx, 0xC), -1);
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        for &opcode in &["div", "mod"] {
+            let mut glulx = build(|asm| {
+                asm.op(opcode, &[Const(7), Zero, Ram(0x0)])
+                    .op("return", &[Zero]);
+            });
+            match glulx.run() {
+                Err(GlulxError::Fatal { error, .. }) => {
+                    assert_eq!(*error,
+                        GlulxError::DivisionByZero { address: 0x29 });
+                },
+                result => panic!("unexpected result: {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn shifts_past_the_word_size() {
+        let glulx = run(|asm| {
+            asm.op("shiftl", &[Const(1), Const(32), Ram(0x0)])
+                .op("shiftl", &[Const(1), Const(40), Ram(0x4)])
+                .op("ushiftr", &[Const(-1), Const(32), Ram(0x8)])
+                .op("ushiftr", &[Const(-1), Const(40), Ram(0xC)])
+                .op("return", &[Zero]);
+        });
+        assert_eq!(ram(&glulx, 0x0), 0x0);
+        assert_eq!(ram(&glulx, 0x4), 0x0);
+        assert_eq!(ram(&glulx, 0x8), 0x0);
+        assert_eq!(ram(&glulx, 0xC), 0x0);
+
+        let glulx = run(|asm| {
+            asm.op("sshiftr", &[Const(-8), Const(32), Ram(0x0)])
+                .op("sshiftr", &[Const(-8), Const(40), Ram(0x4)])
+                .op("sshiftr", &[Const(8), Const(32), Ram(0x8)])
+                .op("sshiftr", &[Const(-8), Const(1), Ram(0xC)])
+                .op("return", &[Zero]);
+        });
+        assert_eq!(ram(&glulx, 0x0), -0x1);
+        assert_eq!(ram(&glulx, 0x4), -0x1);
+        assert_eq!(ram(&glulx, 0x8), 0x0);
+        assert_eq!(ram(&glulx, 0xC), -0x4);
+    }
+
+    #[test]
+    fn branches_and_calls() {
+        let glulx = run(|asm| {
+            let (top, double) = (asm.label(), asm.label());
+            // Sum 1 through 10 in local4, counting in local0.
+            asm.bind(top)
+                .op("add", &[Local(0x0), Const(1), Local(0x0)])
+                .op("add", &[Local(0x4), Local(0x0), Local(0x4)])
+                .op("jlt", &[Local(0x0), Const(10), Branch(top)])
+                .op("callfi", &[Label(double), Local(0x4), Ram(0x0)])
+                .op("return", &[Zero])
+                .function(double, 0xC1, &[(0x4, 0x1)])
+                .op("add", &[Local(0x0), Local(0x0), Stack])
+                .op("return", &[Stack]);
+        });
+        assert_eq!(ram(&glulx, 0x0), 110);
+    }
+
+    #[test]
+    fn stack_operations() {
+        let glulx = run(|asm| {
+            asm.op("copy", &[Const(1), Stack])
+                .op("copy", &[Const(2), Stack])
+                .op("copy", &[Const(3), Stack])
+                .op("stkroll", &[Const(3), Const(1)])
+                .op("stkcount", &[Ram(0x0)])
+                .op("copy", &[Stack, Ram(0x4)])
+                .op("copy", &[Stack, Ram(0x8)])
+                .op("copy", &[Stack, Ram(0xC)])
+                .op("return", &[Zero]);
+        });
+        assert_eq!(ram(&glulx, 0x0), 3);
+        assert_eq!(ram(&glulx, 0x4), 2);
+        assert_eq!(ram(&glulx, 0x8), 1);
+        assert_eq!(ram(&glulx, 0xC), 3);
+    }
+
+    #[test]
+    fn continuations_and_tail_calls() {
+        let glulx = run(|asm| {
+            let (after, thrower, first, second) =
+                (asm.label(), asm.label(), asm.label(), asm.label());
+            asm.op("catch", &[Local(0x0), Branch(after)])
+                .op("copy", &[Local(0x0), Ram(0x0)])
+                .op("callf", &[Label(first), Ram(0x4)])
+                .op("return", &[Zero])
+                .bind(after)
+                .op("callfi", &[Label(thrower), Local(0x0), Zero])
+                .function(thrower, 0xC1, &[(0x4, 0x1)])
+                .op("copy", &[Const(5), Stack])
+                .op("throw", &[Const(9), Local(0x0)])
+                .function(first, 0xC1, &[])
+                .op("copy", &[Const(2), Stack])
+                .op("tailcall", &[Label(second), Const(1)])
+                .function(second, 0xC1, &[(0x4, 0x1)])
+                .op("add", &[Local(0x0), Const(1), Stack])
+                .op("return", &[Stack]);
+        });
+        assert_eq!(ram(&glulx, 0x0), 9);
+        assert_eq!(ram(&glulx, 0x4), 3);
+
+        let mut glulx = build(|asm| {
+            asm.op("throw", &[Const(9), Const(0x4)]);
+        });
+        match glulx.run() {
+            Err(GlulxError::Fatal { error, .. }) => {
+                assert_eq!(*error, GlulxError::InvalidCatchToken {
+                    token: 0x4,
+                    address: 0x29,
+                });
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn fatal_errors_include_backtrace() {
+        let mut glulx = build(|asm| {
+            let inner = asm.label();
+            asm.op("callf", &[Label(inner), Stack])
+                .op("return", &[Zero])
+                .function(inner, 0xC1, &[(0x4, 0x1)])
+                .op("copy", &[Const(7), Local(0x0)])
+                .op("callf", &[Const(0x0), Zero]);
+        });
+
+        let (error, backtrace) = match glulx.run() {
+            Err(GlulxError::Fatal { error, backtrace }) => (error, backtrace),
+            result => panic!("unexpected result: {:?}", result),
+        };
+        assert_eq!(*error, GlulxError::InvalidFunction { address: 0x0 });
+        assert!(!glulx.is_running());
+        assert_eq!(backtrace.len(), 0x2);
+        assert_eq!(backtrace[0x0].locals[0x0].value, 0x7);
+        assert_eq!(backtrace[0x0].stub.unwrap().dest_type, 0x3);
+        assert_eq!(backtrace[0x1].function, Some(0x24));
+        assert_eq!(backtrace[0x1].stub, None);
+
+        let report = GlulxError::Fatal { error, backtrace }.to_string();
+        assert!(report.starts_with("invalid function at 0x0\n  #0 function"));
+        assert!(report.ends_with("#1 function 0x24 (local0 = 0x0, local4 = 0x0, \
+            local8 = 0x0, local12 = 0x0), 0 values"));
+    }
+
+    #[test]
+    fn invalid_memory_access_is_an_error() {
+        let accesses: Vec<(&str, Vec<Arg>, u32)> = vec![
+            ("aload", vec![Const(0x7FFFFFF0), Const(0x10), Ram(0x0)],
+                0x80000030),
+            ("astoreb", vec![Const(-0x1), Zero, Zero], 0xFFFFFFFF),
+            ("copy", vec![Const(0x7), Addr(0x10000)], 0x10000),
+            ("mzero", vec![Const(0x10), Const(0xFFF8)], 0xFFF8),
+            ("callf", vec![Const(0xFFFF), Zero], 0xFFFF),
+        ];
+        for (name, args, address) in accesses {
+            let mut glulx = build(|asm| {
+                asm.op(name, &args);
+            });
+            match glulx.run() {
+                Err(GlulxError::Fatal { error, .. }) => {
+                    let expected = if name == "callf" {
+                        GlulxError::InvalidFunction { address }
+                    } else {
+                        GlulxError::InvalidAddress { address }
+                    };
+                    assert_eq!(*error, expected, "{}", name);
+                },
+                result => panic!("unexpected result: {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn runaway_recursion_overflows_the_stack() {
+        let mut glulx = build(|asm| {
+            let recurse = asm.label();
+            asm.op("callf", &[Label(recurse), Zero])
+                .op("return", &[Zero])
+                .function(recurse, 0xC1, &[(0x4, 0x1)])
+                .op("callf", &[Label(recurse), Zero])
+                .op("return", &[Zero]);
+        });
+        match glulx.run() {
+            Err(GlulxError::Fatal { error, backtrace }) => {
+                assert_eq!(*error, GlulxError::StackOverflow {
+                    call_depth: 0x80,
+                    program_counter: 0x37,
+                });
+                assert_eq!(backtrace.len(), 0x80);
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(!glulx.is_running());
+    }
+
+    #[test]
+    fn invalid_stack_use_is_an_error() {
+        let mut glulx = build(|asm| {
+            asm.op("stkroll", &[Const(5), Const(1)]);
+        });
+        match glulx.run() {
+            Err(GlulxError::Fatal { error, .. }) => {
+                assert_eq!(*error, GlulxError::StackUnderflow { address: 0x29 });
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let mut glulx = build(|asm| {
+            asm.op("copy", &[Local(0x1000), Ram(0x0)]);
+        });
+        match glulx.run() {
+            Err(GlulxError::Fatal { error, .. }) => {
+                assert_eq!(*error, GlulxError::InvalidLocal {
+                    offset: 0x1000,
+                    address: 0x29,
+                });
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let mut glulx = build(|asm| {
+            asm.op("copy", &[Stack, Ram(0x0)]);
+        });
+        match glulx.run() {
+            Err(GlulxError::Fatal { error, .. }) => {
+                assert_eq!(*error, GlulxError::StackUnderflow { address: 0x29 });
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let mut glulx = build(|asm| {
+            let inner = asm.label();
+            asm.op("copy", &[Const(7), Stack])
+                .op("call", &[Label(inner), Const(5), Ram(0x0)])
+                .op("return", &[Zero])
+                .function(inner, 0xC1, &[(0x4, 0x1)])
+                .op("return", &[Zero]);
+        });
+        match glulx.run() {
+            Err(GlulxError::Fatal { error, backtrace }) => {
+                assert_eq!(*error, GlulxError::StackUnderflow { address: 0x2C });
+                assert_eq!(backtrace.len(), 0x1);
+                assert_eq!(backtrace[0x0].value_count, 0x1);
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let mut glulx = build(|asm| {
+            let inner = asm.label();
+            asm.op("callf", &[Label(inner), Zero])
+                .op("return", &[Zero])
+                .function(inner, 0xC1, &[(0x3, 0x1)])
+                .op("return", &[Zero]);
+        });
+        match glulx.run() {
+            Err(GlulxError::Fatal { error, backtrace }) => {
+                assert!(matches!(*error, GlulxError::InvalidFunction { .. }));
+                assert_eq!(backtrace.len(), 0x1);
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn debug_traps_call_handler() {
+        let program = |asm: &mut Assembler| {
+            asm.op("copy", &[Const(5), Ram(0x0)])
+                .op("debugtrap", &[Const(1)])
+                .op("debugtrap", &[Const(2)])
+                .op("debugtrap", &[Const(3)])
+                .op("copy", &[Const(6), Ram(0x0)])
+                .op("return", &[Zero]);
+        };
+
+        let mut glulx = build(program);
+        glulx.set_trap_handler(Box::new(|value, glulx| {
+            let ram: u32 = glulx.memory.ram_read(0x0);
+            match value {
+                0x1 if ram == 0x5 => TrapAction::Continue,
+                0x2 => TrapAction::Pause,
+                _ => TrapAction::Abort,
+            }
+        }));
+        glulx.run().unwrap();
+        assert!(glulx.is_paused());
+        assert_eq!(glulx.take_trap(), Some(0x2));
+        assert_eq!(glulx.take_trap(), None);
+        match glulx.resume() {
+            Err(GlulxError::Fatal { error, .. }) => {
+                assert_eq!(*error, GlulxError::DebugTrap {
+                    value: 0x3,
+                    address: glulx.instruction_ptr,
+                });
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(ram(&glulx, 0x0), 5);
+
+        let mut glulx = build(program);
+        assert!(glulx.run().is_err());
+    }
+
+    /// Copies 5 to RAM offset `0x4`, copies it back to offset `0x0`, and
+    /// zeroes both.
+    fn watched() -> Glulx {
+        build(|asm| {
+            asm.op("copy", &[Const(5), Ram(0x4)])
+                .op("copy", &[Ram(0x4), Ram(0x0)])
+                .op("mzero", &[Const(8), Const(0x100)])
+                .op("return", &[Zero]);
+        })
+    }
+
+    #[test]
+    fn watchpoint_callbacks_see_writes() {
+        let mut glulx = watched();
+        let events = Arc::new(Mutex::new(vec![]));
+        let log = events.clone();
+        glulx.add_watchpoint(0x104..0x108, Access::Write,
+            WatchAction::Callback(Box::new(move |event: &WatchEvent| {
+                log.lock().unwrap().push(event.clone());
+            })));
+        glulx.run().unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(*events, vec![
+            WatchEvent {
+                watchpoint: 0x0,
+                access: Access::Write,
+                address: 0x104,
+                old: vec![0, 0, 0, 0],
+                new: vec![0, 0, 0, 5],
+                program_counter: 0x29,
+            },
+            WatchEvent {
+                watchpoint: 0x0,
+                access: Access::Write,
+                address: 0x100,
+                old: vec![0, 0, 0, 5, 0, 0, 0, 5],
+                new: vec![0; 0x8],
+                program_counter: 0x31,
+            },
+        ]);
+    }
+
+    #[test]
+    fn watchpoints_pause_on_reads() {
+        let mut glulx = watched();
+        let id = glulx.add_watchpoint(0x106..0x107, Access::Read,
+            WatchAction::Pause);
+        glulx.run().unwrap();
+
+        assert!(glulx.is_paused() && glulx.is_running());
+        let events = glulx.take_watch_events();
+        assert_eq!(events.len(), 0x1);
+        assert_eq!((events[0x0].access, events[0x0].program_counter),
+            (Access::Read, 0x2D));
+
+        assert!(glulx.remove_watchpoint(id));
+        glulx.resume().unwrap();
+        assert!(!glulx.is_running());
+    }
+
+    #[test]
+    fn glk_output_input_and_undo() {
+        let mut glulx = build(|asm| {
+            let (text, after) = (asm.label(), asm.label());
+            asm.op("setiosys", &[Const(2), Zero])
+                // glk_window_open(0, 0, 0, textbuffer, 0)
+                .glk(0x23, &[Zero, Zero, Zero, Const(3), Zero], Local(0x0))
+                .glk(0x2F, &[Local(0x0)], Zero)
+                .op("streamstr", &[Label(text)])
+                .op("streamnum", &[Const(-42)])
+                .op("streamchar", &[Const(0xA)])
+                .op("saveundo", &[Ram(0x0)])
+                .op("jne", &[Ram(0x0), Zero, Branch(after)])
+                .op("copy", &[Const(7), Ram(0x4)])
+                .glk(0xD2, &[Local(0x0)], Zero)
+                // glk_select(event) suspends until input arrives.
+                .glk(0xC0, &[Const(0x108)], Zero)
+                .op("restoreundo", &[Ram(0x8)])
+                .bind(after)
+                .op("return", &[Zero])
+                .string(text, "hello ");
+        });
+        glulx.run().unwrap();
+        assert_eq!(glulx.glk_as_mut::<TextGlk>().unwrap().take_output(),
+            "hello -42\n");
+        assert!(glulx.is_waiting());
+        assert_eq!(ram(&glulx, 0x4), 7);
+
+        glulx.glk_as_mut::<TextGlk>().unwrap().push_input(Input::Char(0x41));
+        glulx.resume().unwrap();
+        assert!(!glulx.is_running());
+        assert_eq!(ram(&glulx, 0x0), -1);
+        assert_eq!(ram(&glulx, 0x4), 0);
+    }
+
+    #[test]
+    fn runs_within_a_budget() {
+        let mut glulx = build(|asm| {
+            let top = asm.label();
+            asm.op("setiosys", &[Const(2), Zero])
+                // glk_window_open(0, 0, 0, textbuffer, 0)
+                .glk(0x23, &[Zero, Zero, Zero, Const(3), Zero], Local(0x0))
+                .bind(top)
+                .op("add", &[Ram(0x0), Const(1), Ram(0x0)])
+                .op("jlt", &[Ram(0x0), Const(0x10), Branch(top)])
+                .glk(0xD2, &[Local(0x0)], Zero)
+                .glk(0xC0, &[Const(0x108)], Zero)
+                .op("aload", &[Const(0x108), Const(2), Ram(0x4)])
+                .op("return", &[Zero]);
+        });
+        glulx.init().unwrap();
+        assert_eq!(glulx.run_for(0xA), RunStatus::OutOfBudget);
+        assert!(ram(&glulx, 0x0) < 0x10);
+
+        let mut runs = 0x0;
+        while glulx.run_for(0xA) == RunStatus::OutOfBudget {
+            runs += 0x1;
         }
+        assert!(runs > 0x0 && glulx.is_waiting());
+        assert_eq!(ram(&glulx, 0x0), 0x10);
+
+        // nothing happens until the event arrives.
+        assert_eq!(glulx.run_for(0xA), RunStatus::Waiting);
+        glulx.glk_as_mut::<TextGlk>().unwrap().push_input(Input::Char(0x41));
+        assert_eq!(glulx.run_for(0xA), RunStatus::Quit);
+        assert_eq!(ram(&glulx, 0x4), 0x41);
+        assert_eq!(glulx.run_for(0xA), RunStatus::Quit);
+    }
+
+    #[test]
+    fn snapshots() {
+        let story = || build(|asm| {
+            let top = asm.label();
+            asm.op("setrandom", &[Const(7)])
+                .op("protect", &[Const(0x100), Const(0x4)])
+                .bind(top)
+                .op("random", &[Const(0x1000), Ram(0x4)])
+                .op("add", &[Ram(0x8), Ram(0x4), Ram(0x8)])
+                .op("malloc", &[Const(0x100), Ram(0xC)])
+                .op("add", &[Ram(0x0), Const(1), Ram(0x0)])
+                .op("jlt", &[Ram(0x0), Const(0x10), Branch(top)])
+                .op("return", &[Zero]);
+        });
+        let mut glulx = story();
+        glulx.init().unwrap();
+        assert_eq!(glulx.run_for(0x20), RunStatus::OutOfBudget);
+        let snapshot = glulx.snapshot();
+        let (count, size) = (ram(&glulx, 0x0), glulx.memory.get_mem_size());
+        assert_eq!(snapshot.memory_size(), size);
+
+        assert_eq!(glulx.run_for(0x1000), RunStatus::Quit);
+        let sum = ram(&glulx, 0x8);
+        assert!(glulx.memory.get_mem_size() > size);
+
+        // a restored machine continues exactly as the original did.
+        glulx.restore(&snapshot).unwrap();
+        assert_eq!((ram(&glulx, 0x0), glulx.memory.get_mem_size()),
+            (count, size));
+        assert_eq!(glulx.program_counter(), snapshot.program_counter());
+        assert_eq!(glulx.run_for(0x1000), RunStatus::Quit);
+        assert_eq!(ram(&glulx, 0x8), sum);
+
+        let mut fork = story();
+        fork.restore(&snapshot.clone()).unwrap();
+        assert_eq!(fork.run_for(0x1000), RunStatus::Quit);
+        assert_eq!(ram(&fork, 0x8), sum);
+
+        let mut other = build(|asm| { asm.op("return", &[Const(1)]); });
+        assert_eq!(other.restore(&snapshot),
+            Err(GlulxError::IncompatibleSnapshot));
+        assert_eq!(other.restore_autosave(b"FORM"),
+            Err(GlulxError::CorruptSnapshot {
+                message: "not a Quetzal save file".to_string(),
+            }));
+    }
+
+    #[test]
+    fn fyrevm_channels() {
+        let mut glulx = build(|asm| {
+            let (text, location) = (asm.label(), asm.label());
+            asm.op("setiosys", &[Const(0x14), Zero])
+                .op("streamstr", &[Label(text)])
+                .op("fyrecall", &[Const(5), Const(0x4C4F434E), Const(0), Zero])
+                .op("streamstr", &[Label(location)])
+                .op("fyrecall", &[Const(4), Const(0x61), Const(0), Ram(0x0)])
+                .op("fyrecall", &[Const(1), Const(0x104), Const(0x8), Zero])
+                .op("fyrecall", &[Const(5), Const(0x4D41494E), Const(0), Zero])
+                .op("streamnum", &[Const(7)])
+                .op("fyrecall", &[Const(2), Zero, Zero, Ram(0xC)])
+                .op("return", &[Zero])
+                .string(text, "You see a door.")
+                .string(location, "Hall");
+        });
+        glulx.run().unwrap();
+        assert!(glulx.is_waiting());
+        assert_eq!(ram(&glulx, 0x0), 0x41);
+        let channels = glulx.take_channels();
+        assert_eq!(channels.len(), 0x2);
+        assert_eq!(channels["MAIN"], "You see a door.");
+        assert_eq!(channels["LOCN"], "Hall");
+
+        glulx.push_channel_input("open door".to_string());
+        glulx.resume().unwrap();
+        assert_eq!(ram(&glulx, 0x4), 0x4);
+        assert_eq!(&glulx.memory.bytes(0x108..0x10C)[..], b"open");
+        assert_eq!(glulx.take_channels()["MAIN"], "7");
+
+        glulx.push_channel_input("y".to_string());
+        glulx.resume().unwrap();
+        assert!(!glulx.is_running());
+        assert_eq!(ram(&glulx, 0xC), 0x79);
     }
 }
@@ -1,10 +1,173 @@
 extern crate byteorder;
 
+mod assembler;
+mod blorb;
+mod coverage;
+mod debug_info;
+mod debugger;
+mod disassembler;
+mod error;
+mod glk;
+mod header;
+mod heap;
+mod instruction;
 mod interpreter;
 mod memory;
+mod metadata;
+mod pages;
+mod profile;
+mod quetzal;
+mod random;
+mod replay;
+mod session;
 mod stack;
+mod story;
+mod string;
+mod trace;
+mod trap;
+mod watch;
 
-pub use interpreter::Glulx;
+pub use assembler::{
+    Arg,
+    Assembler,
+    AssemblerError,
+    Label,
+};
+pub use blorb::{
+    Blorb,
+    Resource,
+    Usage,
+};
+pub use coverage::{
+    Coverage,
+    RoutineCoverage,
+};
+pub use debug_info::{
+    DebugInfo,
+    DebugInfoError,
+    Routine,
+    SourceLocation,
+};
+pub use debugger::{
+    Debugger,
+    Stop,
+};
+pub use disassembler::{
+    disassemble,
+    disassemble_functions,
+    Disassembly,
+    Function,
+    WithDebugInfo,
+};
+pub use error::GlulxError;
+pub use glk::{
+    FileUsage,
+    Glk,
+    GlkMemory,
+    Input,
+    PromptHandler,
+    Recorder,
+    TextGlk,
+    WindowInfo,
+    WindowKind,
+    KEY_RETURN,
+    KEY_UNKNOWN,
+};
+pub use header::{
+    GlulxHeader,
+    InformHeader,
+    Version,
+};
+pub use heap::Heap;
+pub use instruction::{
+    decode,
+    opcode,
+    opcode_named,
+    Branch,
+    Instruction,
+    Opcode,
+    Operand,
+    OperandKind,
+};
+pub use interpreter::{
+    Glulx,
+    RunStatus,
+    VmSnapshot,
+};
+pub use memory::{
+    GlulxMemory,
+    Memory,
+};
+pub use metadata::Metadata;
+pub use pages::{
+    Pages,
+    PAGE_SIZE,
+};
+pub use profile::{
+    CallProfile,
+    FunctionProfile,
+    Profiler,
+};
+pub use quetzal::Quetzal;
+pub use random::Random;
+pub use replay::{
+    Divergence,
+    Replay,
+    ReplayError,
+    ReplayEvent,
+};
+pub use session::{
+    Session,
+    TurnOutput,
+};
+pub use stack::{
+    CallStub,
+    Frame,
+    Local,
+};
+pub use story::Story;
+pub use string::{
+    decode_text,
+    StringNode,
+};
+pub use trace::{
+    read_trace,
+    BinaryTracer,
+    TextTracer,
+    TraceRecord,
+    Tracer,
+};
+pub use trap::{
+    TrapAction,
+    TrapHandler,
+};
+pub use watch::{
+    Access,
+    WatchAction,
+    WatchEvent,
+    Watchpoint,
+};
+
+
+// Hosts move machines between threads, so a machine and everything it
+// owns, including the Glk and the hooks given to it, must be `Send`. The
+// stories and snapshots shared between machines must also be `Sync`.
+const _: fn() = || {
+    fn send<T: Send>() {}
+    fn shared<T: Send + Sync>() {}
+    send::<Glulx>();
+    send::<Box<dyn Glk>>();
+    send::<TextGlk>();
+    send::<PromptHandler>();
+    send::<TrapHandler>();
+    send::<WatchAction>();
+    send::<Debugger>();
+    send::<Session>();
+    shared::<Story>();
+    shared::<VmSnapshot>();
+    shared::<Pages>();
+    shared::<GlulxError>();
+};
 
 #[cfg(test)]
 mod tests {
@@ -22,7 +22,7 @@
 //! * The ROM is read-only.
 //! * The ROM must be at least 0x100 bytes long.
 //! * The ROM usually (but not always) contains all the executable code
-//! and constants for the loaded program.
+//!   and constants for the loaded program.
 //!
 //!
 //! ## RAM
@@ -36,28 +36,52 @@
 //! ## Misc
 //!
 //! * RAMSTART, EXTSTART, and ENDMEM must be aligned on 0x100 byte
-//! boundries
+//!   boundries
 //! * A Glulx gamefile only stores data from 0x0 to EXTSTART.
+//!
+//! ## Storage
+//!
+//! Memory is stored as copy-on-write `Pages`. It shares the pages of the
+//! `Story` until they are written, so machines running the same story
+//! share its ROM, and undo states, snapshots and restored save files
+//! share pages with it in turn.
+
+use std::cell::RefCell;
+use std::ops::Range;
+use std::sync::Arc;
 
 use byteorder::{BigEndian, ByteOrder};
 
+use header::GlulxHeader;
 
-/// Glulx magic number
-const MAGIC_NUMBER: u32 = 0x476C756C;
+use heap::Heap;
 
+use metadata::Metadata;
 
-/// Minimum supported glulx target
-const MIN_VERSION: u32 = 0x0020000;
+use pages::Pages;
 
+use story::Story;
 
-/// Maximum supported glulx target
-const MAX_VERSION: u32 = 0x00301FF;
+use watch::{
+    Access,
+    WatchEvent,
+    Watchpoint,
+};
 
 
 /// Struct representing a glulx memory object.
 pub struct GlulxMemory {
-    heap_mode: bool,
-    memory: Vec<u8>,
+    heap: Option<Heap>,
+    memory: Pages,
+
+    /// The story, whose initial contents memory shares until they are
+    /// written, and returns to on restarting.
+    story: Arc<Story>,
+    watchpoints: Vec<Watchpoint>,
+
+    /// Accesses which touched a watchpoint, recorded during reads as well
+    /// as writes.
+    hits: RefCell<Vec<WatchEvent>>,
 }
 
 
@@ -67,146 +91,282 @@ impl GlulxMemory {
     /// Glulx rom, and retuns either an error string or a `GlulxMemory`
     /// TODO: Replace error string with custom glulx error type.
     pub fn from_rom(rom: Vec<u8>) -> Result<GlulxMemory, String> {
-        // Validate magic number.
-        let magic_number = BigEndian::read_u32(&rom[0x0..0x4]);
-        if magic_number != MAGIC_NUMBER {
-            return Err("executable code starts with invalid magic number"
-                .to_string());
+        Story::from_rom(rom)
+            .map(|story| GlulxMemory::from_story(Arc::new(story)))
+    }
+
+    /// Memory holding the initial contents of a story, which it shares
+    /// until they are written.
+    pub fn from_story(story: Arc<Story>) -> GlulxMemory {
+        GlulxMemory {
+            heap: None,
+            memory: story.pages().clone(),
+            story,
+            watchpoints: vec![],
+            hits: RefCell::new(vec![]),
         }
+    }
 
-        // Validate glulx version.
-        let glulx_version = BigEndian::read_u32(&rom[0x4..0x8]);
-        if glulx_version < MIN_VERSION {
-            return Err("executable code glulx version is less than 2.0.0"
-                .to_string());
-        } else if glulx_version > MAX_VERSION {
-            return Err("executable code glulx version is greater than 3.1.x"
-                .to_string());
-        }
+    pub fn zero_range(&mut self, size: u32, ptr: u32){
+        self.write_bytes(ptr, &vec![0x0; size as usize]);
+    }
 
-        // Validate ramstart value.
-        let ramstart = BigEndian::read_u32(&rom[0x8..0xC]);
-        if ramstart < 0x100 {
-            return Err("ramstart is less than 0x100 bytes".to_string());
-        } else if ramstart % 0x100 != 0 {
-            return Err("ramstart is misaligned".to_string());
-        }
+    pub fn copy_range(&mut self, size: u32, from_ptr: u32, to_ptr: u32){
+        let mut from = vec![0x0; size as usize];
+        self.read_bytes(from_ptr, &mut from);
+        self.write_bytes(to_ptr, &from);
+    }
 
-        // Validate extstart value.
-        let extstart = BigEndian::read_u32(&rom[0xC..0x10]);
-        if extstart < ramstart {
-            return Err("extstart is less than ramstart".to_string());
-        } else if extstart % 0x100 != 0 {
-            return Err("extstart is misaligned".to_string());
-        }
+    /// Copies memory from the given address into the buffer, without
+    /// hitting any watchpoints.
+    pub fn peek(&self, ptr: u32, buf: &mut [u8]) {
+        self.memory.read(ptr, buf)
+    }
 
-        // Validate endmem value.
-        let endmem = BigEndian::read_u32(&rom[0x10..0x14]);
-        if endmem < extstart {
-            return Err("endmem is less than extstart".to_string());
-        } else if endmem % 0x100 != 0 {
-            return Err("endmem is misaligned".to_string());
-        }
+    /// The bytes in the given range of memory, read without hitting any
+    /// watchpoints.
+    pub fn bytes(&self, range: Range<u32>) -> Vec<u8> {
+        self.memory.bytes(range)
+    }
 
-        // Validate stack size.
-        let stack_size = BigEndian::read_u32(&rom[0x14..0x18]);
-        if stack_size % 0x100 != 0 {
-            return Err("stack size is misaligned".to_string());
-        }
+    /// The pages holding the contents of memory. Cloning them is cheap,
+    /// as the pages are shared until written.
+    pub fn pages(&self) -> &Pages {
+        &self.memory
+    }
 
-        if (endmem as usize) < rom.len() {
-            return Err("rom size is not correct".to_string());
-        }
+    /// The story whose memory this is.
+    pub fn story(&self) -> &Arc<Story> {
+        &self.story
+    }
 
-        let checksum = BigEndian::read_u32(&rom[0x20..0x24]);
-        let sum = {
-            let mut sum = 0u32;
-            for i in 0..rom.len()/4 {
-                sum = sum.wrapping_add(BigEndian::read_u32(&rom[i*4..]));
-            }
-            sum.wrapping_sub(checksum)
-        };
-        if checksum != sum {
-            return Err("rom checksum is not correct".to_string());
-        }
+    /// Replaces the contents of memory, which must include the ROM, and
+    /// the heap. Watchpoints are not hit.
+    pub fn restore(&mut self, memory: Pages, heap: Option<Heap>) {
+        self.memory = memory;
+        self.heap = heap;
+    }
 
+    /// Returns memory to its initial contents and size, destroying the
+    /// heap.
+    pub fn reset(&mut self) {
+        self.memory = self.story.pages().clone();
+        self.heap = None;
+    }
 
-        let mut rom = rom;
-        let ext_size = (endmem - extstart) as usize;
-        rom.reserve_exact(ext_size);
-        rom.resize(endmem as usize, 0x0);
+    /// Whether the checksum in the header matches the initial contents of
+    /// memory.
+    pub fn verify(&self) -> bool {
+        let original = self.story.pages();
+        let mut word = [0x0; 0x4];
+        let sum = (0x0..original.len() / 0x4).fold(0u32, |sum, index| {
+            original.read(index * 0x4, &mut word);
+            sum.wrapping_add(BigEndian::read_u32(&word))
+        });
+        sum.wrapping_sub(self.checksum()) == self.checksum()
+    }
 
-        Ok(GlulxMemory { heap_mode: false, memory: rom })
+    /// The active heap, if any.
+    pub fn heap(&self) -> Option<&Heap> {
+        self.heap.as_ref()
     }
 
-    pub fn zero_range(&mut self, size: u32, ptr: u32){
-        for i in ptr as usize..(ptr + size) as usize {
-            self.memory[i] = 0;
+    /// Allocates a block of the given length on the heap, creating the
+    /// heap if there is none and growing memory as needed. Returns the
+    /// address of the block, or `0` for a zero length.
+    pub fn malloc(&mut self, len: u32) -> u32 {
+        if len == 0x0 {
+            return 0x0;
+        }
+        let size = self.get_mem_size();
+        let heap = self.heap.get_or_insert_with(|| Heap::new(size));
+        let (address, end) = heap.allocate(len);
+        if end > size {
+            self.memory.resize(end);
+        }
+        address
+    }
+
+    /// Frees a block allocated by `malloc`. Once every block is freed the
+    /// heap is destroyed, and memory shrinks to its size before the heap
+    /// was created.
+    pub fn mfree(&mut self, address: u32) {
+        let start = match self.heap {
+            Some(ref mut heap) => {
+                if !heap.free(address) || !heap.is_empty() {
+                    return;
+                }
+                heap.start()
+            },
+            None => return,
+        };
+        self.heap = None;
+        self.memory.resize(start);
+    }
+
+    /// Watches the given range of addresses for the given kind of access.
+    pub fn watch(&mut self, id: u32, range: Range<u32>, access: Access) {
+        self.watchpoints.push(Watchpoint { id, range, access });
+    }
+
+    /// Stops watching the watchpoint with the given identifier, returning
+    /// whether it existed.
+    pub fn unwatch(&mut self, id: u32) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| watchpoint.id != id);
+        self.watchpoints.len() != len
+    }
+
+    /// Removes and returns the accesses which touched a watchpoint since
+    /// the last call. The program counter of each is left as `0`.
+    pub fn take_hits(&self) -> Vec<WatchEvent> {
+        self.hits.replace(vec![])
+    }
+
+    /// Records a hit for each watchpoint of the given access which the
+    /// bytes `ptr..ptr + old.len()` touch.
+    fn record(&self, access: Access, ptr: u32, old: &[u8], new: &[u8]) {
+        let end = ptr + old.len() as u32;
+        for watchpoint in &self.watchpoints {
+            if watchpoint.access.covers(access)
+                    && ptr < watchpoint.range.end
+                    && watchpoint.range.start < end {
+                self.hits.borrow_mut().push(WatchEvent {
+                    watchpoint: watchpoint.id,
+                    access,
+                    address: ptr,
+                    old: old.to_vec(),
+                    new: new.to_vec(),
+                    program_counter: 0x0,
+                });
+            }
         }
     }
 
-    pub fn copy_range(&mut self, size: u32, from_ptr: u32, to_ptr: u32){
-        let to_ptr = to_ptr as usize;
-        let from_ptr = from_ptr as usize;
+    fn read_bytes(&self, ptr: u32, buf: &mut [u8]) {
+        self.memory.read(ptr, buf);
+        if !self.watchpoints.is_empty() {
+            self.record(Access::Read, ptr, buf, buf);
+        }
+    }
 
-        for i in 0x0..size as usize {
-            self.memory[to_ptr + i] = self.memory[from_ptr + i];
+    fn write_bytes(&mut self, ptr: u32, bytes: &[u8]) {
+        if !self.watchpoints.is_empty() {
+            let old = self.memory.bytes(ptr..ptr + bytes.len() as u32);
+            self.record(Access::Write, ptr, &old, bytes);
         }
+        self.memory.write(ptr, bytes);
     }
 
     // Header value functions.
 
-    /// The glulx magic number, stored from `0x0..0x4` in the header.
-    /// This number is unique to all glulx games. This value should be
-    /// `0x476C756C`, which is equivalent to `b"Glul"`.
-    fn magic_number(&self) -> u32 {
-        BigEndian::read_u32(&self.memory[..0x4])
-    }
-
     /// The glulx version number, stored from `0x4..0x8` in the header.
     /// First two bytes store the major version number. The next byte
     /// stores the minor version number. The final byte stores the patch
     /// version number.
     pub fn glulx_version(&self) -> u32 {
-        BigEndian::read_u32(&self.memory[0x4..0x8])
+        self.story.header().version.to_u32()
     }
 
     /// The address indicating the start of the RAM, stored from
-    /// `0x4..0x8` in the header. TODO: MOAR DATA
-    fn ramstart(&self) -> u32 {
-        BigEndian::read_u32(&self.memory[0x8..0xC])
+    /// `0x8..0xC` in the header.
+    pub fn ramstart(&self) -> u32 {
+        self.story.header().ramstart
     }
 
     fn extstart(&self) -> u32 {
-        BigEndian::read_u32(&self.memory[0xC..0x10])
+        self.story.header().extstart
     }
 
     fn endmem(&self) -> u32 {
-        BigEndian::read_u32(&self.memory[0x10..0x14])
+        self.story.header().endmem
     }
 
     pub fn stack_size(&self) -> u32 {
-        BigEndian::read_u32(&self.memory[0x14..0x18])
+        self.story.header().stack_size
     }
 
     pub fn start_func(&self) -> u32 {
-        BigEndian::read_u32(&self.memory[0x18..0x1C])
+        self.story.header().start_func
     }
 
-    fn decoding_tbl(&self) -> u32 {
-        BigEndian::read_u32(&self.memory[0x1C..0x20])
+    pub fn decoding_tbl(&self) -> u32 {
+        self.story.header().decoding_tbl
     }
 
     /// The sum of the intial contents of memory, considered as an array
     /// of `u32`s. When calculated, the checksum value is considered to
     /// be `0`.
     fn checksum(&self) -> u32 {
-        BigEndian::read_u32(&self.memory[0x20..0x24])
+        self.story.header().checksum
+    }
+
+    /// The header of the story, including the Inform header of stories
+    /// compiled by Inform.
+    pub fn header(&self) -> GlulxHeader {
+        self.story.header().clone()
+    }
+
+    // Inform header functions. Stories compiled by Inform follow the
+    // glulx header with their own, from `0x24..0x3C`.
+
+    /// Whether the story has an Inform header, which starts with
+    /// `b"Info"`.
+    pub fn is_inform(&self) -> bool {
+        self.header().inform.is_some()
+    }
+
+    /// The release number of the story, stored from `0x34..0x36` in the
+    /// Inform header.
+    pub fn release(&self) -> Option<u16> {
+        self.header().inform.map(|inform| inform.release)
+    }
+
+    /// The serial number of the story, stored from `0x36..0x3C` in the
+    /// Inform header. This is usually the date the story was compiled, as
+    /// `YYMMDD`.
+    pub fn serial(&self) -> Option<String> {
+        self.header().inform.map(|inform| inform.serial)
+    }
+
+    /// The IFID of the story, as given by the Treaty of Babel. This is
+    /// the UUID marked with `UUID://` in the story file if there is one,
+    /// and is otherwise made from the release number, serial number, and
+    /// checksum of Inform stories.
+    pub fn ifid(&self) -> Option<String> {
+        let marker = b"UUID://";
+        let story = &self.story.bytes(0x0..self.extstart())[..];
+        let uuid = story.windows(marker.len())
+            .position(|window| window == marker)
+            .and_then(|start| {
+                let rest = &story[start + marker.len()..];
+                let len = rest.windows(0x2).position(|window| window == b"//")?;
+                String::from_utf8(rest[..len].to_vec()).ok()
+            })
+            .filter(|uuid| !uuid.is_empty() && uuid.len() <= 0x40);
+        if uuid.is_some() {
+            return uuid;
+        }
+        match (self.release(), self.serial()) {
+            (Some(release), Some(serial)) => Some(format!("GLULX-{}-{}-{:08X}",
+                release, serial, self.checksum())),
+            _ => None,
+        }
+    }
+
+    /// The metadata which can be found in the story file itself.
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            ifid: self.ifid(),
+            release: self.release().map(u32::from),
+            serial: self.serial(),
+            ..Metadata::default()
+        }
     }
 
 
     pub fn get_mem_size(&self) -> u32 {
-        self.memory.len() as u32
+        self.memory.len()
     }
 
     /// Sets the memory size to the given value. This call only works if
@@ -214,10 +374,10 @@ impl GlulxMemory {
     /// and the value is greater than the ENDMEM value identified in the
     /// header.
     pub fn set_mem_size(&mut self, value: u32) -> u32 {
-        if self.heap_mode
-                && value % 0x100 == 0
+        if self.heap.is_none()
+                && value.is_multiple_of(0x100)
                 && value >= self.endmem() {
-            self.memory.resize(value as usize, 0x0);
+            self.memory.resize(value);
             0
         } else {
             1
@@ -236,146 +396,163 @@ pub trait Memory<T> {
 
 impl Memory<u8> for GlulxMemory {
     fn read(&self, ptr: u32) -> u8 {
-        self.memory[ptr as usize]
+        let mut buf = [0x0; 0x1];
+        self.read_bytes(ptr, &mut buf);
+        buf[0x0]
     }
 
     fn write(&mut self, ptr: u32, value: u8) {
-        self.memory[ptr as usize] = value
+        self.write_bytes(ptr, &[value])
     }
 
     fn ram_read(&self, ptr: u32) -> u8 {
-        let ptr = (ptr + self.ramstart()) as usize;
-        self.memory[ptr]
+        self.read(ptr + self.ramstart())
     }
 
     fn ram_write(&mut self, ptr: u32, value: u8) {
-        let ptr = (ptr + self.ramstart()) as usize;
-        self.memory[ptr] = value
+        let ptr = ptr + self.ramstart();
+        self.write(ptr, value)
     }
 }
 
 
 impl Memory<i8> for GlulxMemory {
     fn read(&self, ptr: u32) -> i8 {
-        self.memory[ptr as usize] as i8
+        let mut buf = [0x0; 0x1];
+        self.read_bytes(ptr, &mut buf);
+        buf[0x0] as i8
     }
 
     fn write(&mut self, ptr: u32, value: i8) {
-        self.memory[ptr as usize] = value as u8
+        self.write_bytes(ptr, &[value as u8])
     }
 
     fn ram_read(&self, ptr: u32) -> i8 {
-        let ptr = (ptr + self.ramstart()) as usize;
-        self.memory[ptr] as i8
+        self.read(ptr + self.ramstart())
     }
 
     fn ram_write(&mut self, ptr: u32, value: i8) {
-        let ptr = (ptr + self.ramstart()) as usize;
-        self.memory[ptr] = value as u8
+        let ptr = ptr + self.ramstart();
+        self.write(ptr, value)
     }
 }
 
 
 impl Memory<u16> for GlulxMemory {
     fn read(&self, ptr: u32) -> u16 {
-        BigEndian::read_u16(&self.memory[ptr as usize..])
+        let mut buf = [0x0; 0x2];
+        self.read_bytes(ptr, &mut buf);
+        BigEndian::read_u16(&buf)
     }
 
     fn write(&mut self, ptr: u32, value: u16) {
-        BigEndian::write_u16(&mut self.memory[ptr as usize..], value)
+        let mut buf = [0x0; 0x2];
+        BigEndian::write_u16(&mut buf, value);
+        self.write_bytes(ptr, &buf)
     }
 
     fn ram_read(&self, ptr: u32) -> u16 {
-        let ptr = (ptr + self.ramstart()) as usize;
-        BigEndian::read_u16(&self.memory[ptr..])
+        self.read(ptr + self.ramstart())
     }
 
     fn ram_write(&mut self, ptr: u32, value: u16) {
-        let ptr = (ptr + self.ramstart()) as usize;
-        BigEndian::write_u16(&mut self.memory[ptr..], value)
+        let ptr = ptr + self.ramstart();
+        self.write(ptr, value)
     }
 }
 
 
 impl Memory<i16> for GlulxMemory {
     fn read(&self, ptr: u32) -> i16 {
-        BigEndian::read_i16(&self.memory[ptr as usize..])
+        let mut buf = [0x0; 0x2];
+        self.read_bytes(ptr, &mut buf);
+        BigEndian::read_i16(&buf)
     }
 
     fn write(&mut self, ptr: u32, value: i16) {
-        BigEndian::write_i16(&mut self.memory[ptr as usize..], value)
+        let mut buf = [0x0; 0x2];
+        BigEndian::write_i16(&mut buf, value);
+        self.write_bytes(ptr, &buf)
     }
 
     fn ram_read(&self, ptr: u32) -> i16 {
-        let ptr = (ptr + self.ramstart()) as usize;
-        BigEndian::read_i16(&self.memory[ptr..])
+        self.read(ptr + self.ramstart())
     }
 
     fn ram_write(&mut self, ptr: u32, value: i16) {
-        let ptr = (ptr + self.ramstart()) as usize;
-        BigEndian::write_i16(&mut self.memory[ptr..], value)
+        let ptr = ptr + self.ramstart();
+        self.write(ptr, value)
     }
 }
 
 
 impl Memory<u32> for GlulxMemory {
     fn read(&self, ptr: u32) -> u32 {
-        BigEndian::read_u32(&self.memory[ptr as usize..])
+        let mut buf = [0x0; 0x4];
+        self.read_bytes(ptr, &mut buf);
+        BigEndian::read_u32(&buf)
     }
 
     fn write(&mut self, ptr: u32, value: u32) {
-        BigEndian::write_u32(&mut self.memory[ptr as usize..], value)
+        let mut buf = [0x0; 0x4];
+        BigEndian::write_u32(&mut buf, value);
+        self.write_bytes(ptr, &buf)
     }
 
     fn ram_read(&self, ptr: u32) -> u32 {
-        let ptr = (ptr + self.ramstart()) as usize;
-        BigEndian::read_u32(&self.memory[ptr..])
+        self.read(ptr + self.ramstart())
     }
 
     fn ram_write(&mut self, ptr: u32, value: u32) {
-        let ptr = (ptr + self.ramstart()) as usize;
-        BigEndian::write_u32(&mut self.memory[ptr..], value)
+        let ptr = ptr + self.ramstart();
+        self.write(ptr, value)
     }
 }
 
 
 impl Memory<i32> for GlulxMemory {
     fn read(&self, ptr: u32) -> i32 {
-        BigEndian::read_i32(&self.memory[ptr as usize..])
+        let mut buf = [0x0; 0x4];
+        self.read_bytes(ptr, &mut buf);
+        BigEndian::read_i32(&buf)
     }
 
     fn write(&mut self, ptr: u32, value: i32) {
-        BigEndian::write_i32(&mut self.memory[ptr as usize..], value)
+        let mut buf = [0x0; 0x4];
+        BigEndian::write_i32(&mut buf, value);
+        self.write_bytes(ptr, &buf)
     }
 
     fn ram_read(&self, ptr: u32) -> i32 {
-        let ptr = (ptr + self.ramstart()) as usize;
-        BigEndian::read_i32(&self.memory[ptr..])
+        self.read(ptr + self.ramstart())
     }
 
     fn ram_write(&mut self, ptr: u32, value: i32) {
-        let ptr = (ptr + self.ramstart()) as usize;
-        BigEndian::write_i32(&mut self.memory[ptr..], value)
+        let ptr = ptr + self.ramstart();
+        self.write(ptr, value)
     }
 }
 
 
 impl Memory<f32> for GlulxMemory {
     fn read(&self, ptr: u32) -> f32 {
-        BigEndian::read_f32(&self.memory[ptr as usize..])
+        let mut buf = [0x0; 0x4];
+        self.read_bytes(ptr, &mut buf);
+        BigEndian::read_f32(&buf)
     }
 
     fn write(&mut self, ptr: u32, value: f32) {
-        BigEndian::write_f32(&mut self.memory[ptr as usize..], value)
+        let mut buf = [0x0; 0x4];
+        BigEndian::write_f32(&mut buf, value);
+        self.write_bytes(ptr, &buf)
     }
 
     fn ram_read(&self, ptr: u32) -> f32 {
-        let ptr = (ptr + self.ramstart()) as usize;
-        BigEndian::read_f32(&self.memory[ptr..])
+        self.read(ptr + self.ramstart())
     }
 
     fn ram_write(&mut self, ptr: u32, value: f32) {
-        let ptr = (ptr + self.ramstart()) as usize;
-        BigEndian::write_f32(&mut self.memory[ptr..], value)
+        let ptr = ptr + self.ramstart();
+        self.write(ptr, value)
     }
 }
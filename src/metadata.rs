@@ -0,0 +1,203 @@
+//! # Story metadata
+//!
+//! The identity of a story can be read without running it. Blorb files
+//! may carry an `IFmd` chunk of iFiction XML, as defined by the Treaty
+//! of Babel, which gives the IFID of the story and its bibliographic
+//! details. Stories compiled by Inform also carry a release number and
+//! serial number in their header, from which an IFID can be made when
+//! the story does not declare one.
+
+use blorb::Blorb;
+use memory::GlulxMemory;
+
+
+/// The identity of a story, as far as it is known.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub ifid: Option<String>,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub headline: Option<String>,
+    pub release: Option<u32>,
+    pub serial: Option<String>,
+}
+
+
+impl Metadata {
+
+    /// Reads the metadata of a story file or Blorb file. Details from the
+    /// iFiction metadata of a Blorb file are preferred to those found in
+    /// the story file.
+    pub fn from_story(bytes: &[u8]) -> Result<Metadata, String> {
+        if !Blorb::is_blorb(bytes) {
+            return Ok(GlulxMemory::from_rom(bytes.to_vec())?.metadata());
+        }
+        let blorb = Blorb::from_bytes(bytes)?;
+        let metadata = blorb.metadata()
+            .map(Metadata::from_ifiction)
+            .unwrap_or_default();
+        match blorb.executable() {
+            Some(executable) => {
+                let story = GlulxMemory::from_rom(executable.to_vec())?;
+                Ok(metadata.or(story.metadata()))
+            },
+            None => Ok(metadata),
+        }
+    }
+
+    /// Reads the metadata of the first story in an iFiction document.
+    /// Missing elements are left as `None`.
+    pub fn from_ifiction(xml: &str) -> Metadata {
+        let story = element(xml, "story").unwrap_or(xml);
+        let text = |name| element(story, name)
+            .filter(|text| !text.contains('<'))
+            .map(unescape)
+            .filter(|text| !text.is_empty());
+        // the release of a glulx story is in its format section.
+        let release = element(story, "glulx")
+            .and_then(|glulx| element(glulx, "release"))
+            .and_then(|release| release.trim().parse().ok());
+        let serial = element(story, "glulx")
+            .and_then(|glulx| element(glulx, "serial"))
+            .map(unescape);
+        Metadata {
+            ifid: text("ifid"),
+            title: text("title"),
+            author: text("author"),
+            headline: text("headline"),
+            release,
+            serial,
+        }
+    }
+
+    /// Fills in any details missing from this metadata from the other.
+    pub fn or(self, other: Metadata) -> Metadata {
+        Metadata {
+            ifid: self.ifid.or(other.ifid),
+            title: self.title.or(other.title),
+            author: self.author.or(other.author),
+            headline: self.headline.or(other.headline),
+            release: self.release.or(other.release),
+            serial: self.serial.or(other.serial),
+        }
+    }
+}
+
+
+/// The contents of the first element with the given name.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut rest = xml;
+    loop {
+        let start = rest.find(&open)? + open.len();
+        rest = &rest[start..];
+        // skip longer names which start with this one.
+        match rest.chars().next() {
+            Some('>') | Some(' ') | Some('\t') | Some('\r') | Some('\n') => (),
+            _ => continue,
+        }
+        let content = rest.find('>')? + 0x1;
+        if rest[..content].ends_with("/>") {
+            return Some("");
+        }
+        let end = rest.find(&close)?;
+        return rest.get(content..end);
+    }
+}
+
+
+/// Replaces the entities of XML text, and trims it.
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text.trim();
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let ch = match &rest[0x1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity if entity.starts_with("#x") => {
+                u32::from_str_radix(&entity[0x2..], 0x10).ok()
+                    .and_then(::std::char::from_u32)
+            },
+            entity if entity.starts_with('#') => entity[0x1..].parse().ok()
+                .and_then(::std::char::from_u32),
+            _ => None,
+        };
+        match ch {
+            Some(ch) => {
+                result.push(ch);
+                rest = &rest[end + 0x1..];
+            },
+            None => {
+                result.push('&');
+                rest = &rest[0x1..];
+            },
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use assembler::Assembler;
+
+    use super::Metadata;
+
+    #[test]
+    fn reads_ifiction() {
+        let xml = "<?xml version=\"1.0\"?>\n\
+            <ifindex version=\"1.0\"><story>\
+            <identification><ifid>\n  ABC-123\n</ifid></identification>\
+            <bibliographic><title>Tom &amp; Jerry</title>\
+            <author>A. N. Author</author><headline/>\
+            <description>A story.<br/>Really.</description>\
+            </bibliographic>\
+            <glulx><serial>260101</serial><release>3</release></glulx>\
+            </story></ifindex>";
+        assert_eq!(Metadata::from_ifiction(xml), Metadata {
+            ifid: Some("ABC-123".to_string()),
+            title: Some("Tom & Jerry".to_string()),
+            author: Some("A. N. Author".to_string()),
+            headline: None,
+            release: Some(0x3),
+            serial: Some("260101".to_string()),
+        });
+    }
+
+    #[test]
+    fn falls_back_to_the_inform_header() {
+        let story = |header: &[u8], text: &str| {
+            let mut asm = Assembler::new();
+            let (main, string) = (asm.label(), asm.label());
+            asm.start(main)
+                .bytes(header)
+                .function(main, 0xC1, &[])
+                .op("return", &[::assembler::Arg::Zero])
+                .string(string, text);
+            asm.finish().unwrap()
+        };
+        assert_eq!(Metadata::from_story(&story(&[], "")).unwrap(),
+            Metadata::default());
+
+        let header = b"Info\x00\x01\x00\x006.420.5.\x00\x05260101";
+        let metadata = Metadata::from_story(&story(header, "")).unwrap();
+        assert_eq!(metadata.release, Some(0x5));
+        assert_eq!(metadata.serial, Some("260101".to_string()));
+        assert!(metadata.ifid.unwrap().starts_with("GLULX-5-260101-"));
+
+        let metadata = Metadata::from_story(&story(header, "UUID://1A2B//"))
+            .unwrap();
+        assert_eq!(metadata.ifid, Some("1A2B".to_string()));
+    }
+}
@@ -0,0 +1,188 @@
+//! # Paged memory
+//!
+//! The contents of memory are kept in fixed-size pages, each shared by
+//! reference counting. Cloning memory only clones the references, and a
+//! page is copied the first time it is written while shared, so undo
+//! states, snapshots and forks of a machine cost as much as the pages
+//! they change rather than the whole of memory.
+//!
+//! Writes which leave a page unchanged do not copy it, and new memory
+//! shares a single page of zeros until it is written.
+
+use std::ops::Range;
+use std::sync::Arc;
+
+
+/// The size of a page, in bytes.
+pub const PAGE_SIZE: u32 = 0x1000;
+
+
+type Page = [u8; PAGE_SIZE as usize];
+
+
+/// Memory stored as copy-on-write pages.
+#[derive(Clone)]
+pub struct Pages {
+    pages: Vec<Arc<Page>>,
+    len: u32,
+
+    /// The page of zeros shared by memory which has not been written.
+    zero: Arc<Page>,
+}
+
+
+impl Pages {
+
+    /// Memory holding the given bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Pages {
+        let mut pages = Pages::new();
+        pages.resize(bytes.len() as u32);
+        pages.write(0x0, bytes);
+        pages
+    }
+
+    /// Empty memory.
+    pub fn new() -> Pages {
+        Pages {
+            pages: vec![],
+            len: 0x0,
+            zero: Arc::new([0x0; PAGE_SIZE as usize]),
+        }
+    }
+
+    /// The size of memory, in bytes.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0x0
+    }
+
+    /// Grows memory with zeros, or shrinks it, to the given size.
+    pub fn resize(&mut self, len: u32) {
+        if len < self.len {
+            // bytes past the end are kept as zeros, for growing again.
+            let offset = (len % PAGE_SIZE) as usize;
+            if offset != 0x0 {
+                let page = &mut self.pages[(len / PAGE_SIZE) as usize];
+                if page[offset..].iter().any(|&byte| byte != 0x0) {
+                    for byte in &mut Arc::make_mut(page)[offset..] {
+                        *byte = 0x0;
+                    }
+                }
+            }
+        }
+        let count = len.div_ceil(PAGE_SIZE) as usize;
+        let zero = &self.zero;
+        self.pages.resize_with(count, || zero.clone());
+        self.len = len;
+    }
+
+    /// Copies the bytes from the given address into the buffer. Panics if
+    /// they do not lie within memory.
+    pub fn read(&self, ptr: u32, buf: &mut [u8]) {
+        self.check(ptr, buf.len());
+        let mut ptr = ptr as usize;
+        let mut done = 0x0;
+        while done < buf.len() {
+            let (page, range) = page_range(ptr, buf.len() - done);
+            let len = range.len();
+            buf[done..done + len].copy_from_slice(&self.pages[page][range]);
+            ptr += len;
+            done += len;
+        }
+    }
+
+    /// Copies the bytes into memory at the given address. Panics if they
+    /// do not lie within memory.
+    pub fn write(&mut self, ptr: u32, bytes: &[u8]) {
+        self.check(ptr, bytes.len());
+        let mut ptr = ptr as usize;
+        let mut done = 0x0;
+        while done < bytes.len() {
+            let (page, range) = page_range(ptr, bytes.len() - done);
+            let len = range.len();
+            let chunk = &bytes[done..done + len];
+            if self.pages[page][range.clone()] != *chunk {
+                Arc::make_mut(&mut self.pages[page])[range]
+                    .copy_from_slice(chunk);
+            }
+            ptr += len;
+            done += len;
+        }
+    }
+
+    /// The bytes in the given range of addresses.
+    pub fn bytes(&self, range: Range<u32>) -> Vec<u8> {
+        let mut bytes = vec![0x0; range.len()];
+        self.read(range.start, &mut bytes);
+        bytes
+    }
+
+    /// The whole contents of memory.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.bytes(0x0..self.len)
+    }
+
+    /// The number of pages of this memory which are shared with the
+    /// other, rather than copied.
+    pub fn shared_pages(&self, other: &Pages) -> usize {
+        self.pages.iter()
+            .zip(&other.pages)
+            .filter(|&(page, other)| Arc::ptr_eq(page, other))
+            .count()
+    }
+
+    fn check(&self, ptr: u32, len: usize) {
+        let end = ptr as usize + len;
+        assert!(end <= self.len as usize,
+            "memory access {:#X}..{:#X} is out of range", ptr, end);
+    }
+}
+
+
+/// The page holding the given address, and the range of the bytes from
+/// the address which lie within it.
+fn page_range(ptr: usize, len: usize) -> (usize, Range<usize>) {
+    let page_size = PAGE_SIZE as usize;
+    let (page, offset) = (ptr / page_size, ptr % page_size);
+    (page, offset..offset + len.min(page_size - offset))
+}
+
+
+impl Default for Pages {
+    fn default() -> Pages {
+        Pages::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Pages, PAGE_SIZE};
+
+    #[test]
+    fn copies_pages_on_write() {
+        let mut bytes = vec![0x0; 0x2800];
+        bytes[0x1FFF] = 0x7;
+        let mut pages = Pages::from_bytes(&bytes);
+        assert_eq!(pages.len(), 0x2800);
+        assert_eq!(pages.to_vec(), bytes);
+
+        // writes across a page boundary, and unchanged writes.
+        let fork = pages.clone();
+        pages.write(0x1FFE, &[0x1, 0x2, 0x3, 0x4]);
+        pages.write(0x0, &[0x0]);
+        assert_eq!(pages.bytes(0x1FFD..0x2003),
+            [0x0, 0x1, 0x2, 0x3, 0x4, 0x0]);
+        assert_eq!(fork.bytes(0x1FFF..0x2000), [0x7]);
+        assert_eq!(pages.shared_pages(&fork), 0x1);
+
+        // memory which shrinks and grows again is zeroed.
+        pages.resize(0x1FFF);
+        pages.resize(0x2000 + PAGE_SIZE);
+        assert_eq!(pages.bytes(0x1FFD..0x2001), [0x0, 0x1, 0x0, 0x0]);
+        assert_eq!(fork.len(), 0x2800);
+    }
+}
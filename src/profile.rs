@@ -0,0 +1,276 @@
+//! # Function profiler
+//!
+//! The profiler counts the instructions executed in each function, and
+//! the calls between functions. It follows calls, returns, tail calls,
+//! and throws, keeping its own stack of the functions being executed.
+//!
+//! * Self -- The instructions executed in a function itself
+//! * Total -- The instructions executed in a function and everything it
+//!   called. Recursive calls are counted once, by the outermost call.
+//! * Calls -- The number of times a function was called
+//!
+//! Profiles are reported as a flat table, or in the callgrind format
+//! read by tools such as KCachegrind. Functions are named from debug
+//! information when it is given.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use debug_info::DebugInfo;
+
+use disassembler::function_name;
+
+
+/// The counts for a single function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+
+    /// The number of times the function was called.
+    pub calls: u64,
+
+    /// The number of instructions executed in the function itself.
+    pub self_instructions: u64,
+
+    /// The number of instructions executed in the function and the
+    /// functions it called.
+    pub total_instructions: u64,
+}
+
+
+/// The counts for calls from one function to another.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallProfile {
+
+    /// The number of calls.
+    pub calls: u64,
+
+    /// The number of instructions executed in the called function and
+    /// the functions it called, during these calls.
+    pub total_instructions: u64,
+}
+
+
+/// Counts instructions and calls by function.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    functions: BTreeMap<u32, FunctionProfile>,
+    calls: BTreeMap<(u32, u32), CallProfile>,
+
+    /// The functions being executed, with the instruction count when
+    /// each was entered.
+    stack: Vec<(u32, u64)>,
+    instructions: u64,
+}
+
+
+impl Profiler {
+
+    /// Creates an empty profile.
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// The counts for each function, by address.
+    pub fn functions(&self) -> &BTreeMap<u32, FunctionProfile> {
+        &self.functions
+    }
+
+    /// The counts for calls, by the addresses of the calling and called
+    /// functions.
+    pub fn calls(&self) -> &BTreeMap<(u32, u32), CallProfile> {
+        &self.calls
+    }
+
+    /// The number of instructions executed while profiling.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Counts an instruction executed in the current function.
+    pub(crate) fn instruction(&mut self) {
+        self.instructions += 0x1;
+        if let Some(&(function, _)) = self.stack.last() {
+            self.functions.entry(function).or_default().self_instructions
+                += 0x1;
+        }
+    }
+
+    /// Counts a call to the given function.
+    pub(crate) fn enter(&mut self, function: u32) {
+        self.functions.entry(function).or_default().calls += 0x1;
+        if let Some(&(caller, _)) = self.stack.last() {
+            self.calls.entry((caller, function)).or_default().calls += 0x1;
+        }
+        self.stack.push((function, self.instructions));
+    }
+
+    /// Leaves the current function.
+    pub(crate) fn leave(&mut self) {
+        let (function, start) = match self.stack.pop() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let total = self.instructions - start;
+
+        // only the outermost of recursive calls counts towards totals.
+        if !self.stack.iter().any(|&(active, _)| active == function) {
+            self.functions.entry(function).or_default().total_instructions
+                += total;
+        }
+        if let Some(&(caller, _)) = self.stack.last() {
+            let recursive = self.stack.windows(0x2)
+                .any(|pair| pair[0x0].0 == caller && pair[0x1].0 == function);
+            if !recursive {
+                self.calls.entry((caller, function)).or_default()
+                    .total_instructions += total;
+            }
+        }
+    }
+
+    /// Leaves functions until the given number remain.
+    pub(crate) fn unwind(&mut self, depth: usize) {
+        while self.stack.len() > depth {
+            self.leave();
+        }
+    }
+
+    /// Finishes the functions still being executed, so their totals are
+    /// counted, and clears the stack.
+    pub fn finish(&mut self) {
+        self.unwind(0x0);
+    }
+
+    /// A table of functions, with the most instructions executed in the
+    /// function itself first.
+    pub fn flat_report(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by(|a, b| {
+            b.1.self_instructions.cmp(&a.1.self_instructions)
+                .then(a.0.cmp(b.0))
+        });
+
+        let mut report = format!("{:>7} {:>12} {:>12} {:>10}  function\n",
+            "self%", "self", "total", "calls");
+        for (&address, profile) in functions {
+            let percent = if self.instructions == 0x0 {
+                0.0
+            } else {
+                profile.self_instructions as f64 * 100.0
+                    / self.instructions as f64
+            };
+            report.push_str(&format!("{:>6.2}% {:>12} {:>12} {:>10}  {}\n",
+                percent, profile.self_instructions,
+                profile.total_instructions, profile.calls,
+                function_name(address, debug_info)));
+        }
+        report
+    }
+
+    /// Writes the profile in the callgrind format. Every function is
+    /// given as a single position, its address.
+    pub fn write_callgrind<W: Write>(&self,
+            writer: &mut W,
+            debug_info: Option<&DebugInfo>) -> io::Result<()> {
+        writeln!(writer, "# callgrind format")?;
+        writeln!(writer, "version: 1")?;
+        writeln!(writer, "creator: glulx-rs")?;
+        writeln!(writer, "positions: instr")?;
+        writeln!(writer, "events: Instructions")?;
+        writeln!(writer, "summary: {}", self.instructions)?;
+
+        for (&address, profile) in &self.functions {
+            writeln!(writer)?;
+            writeln!(writer, "fn={}", function_name(address, debug_info))?;
+            writeln!(writer, "{:#X} {}", address, profile.self_instructions)?;
+            let calls = self.calls
+                .range((address, 0x0)..=(address, u32::MAX));
+            for (&(_, callee), call) in calls {
+                writeln!(writer, "cfn={}", function_name(callee, debug_info))?;
+                writeln!(writer, "calls={} {:#X}", call.calls, callee)?;
+                writeln!(writer, "{:#X} {}", address, call.total_instructions)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use assembler::Arg::*;
+    use assembler::Assembler;
+    use debug_info::DebugInfo;
+    use interpreter::Glulx;
+
+    use super::CallProfile;
+
+    /// The address of `count`, which follows the header, the start
+    /// function's local format, and its `0xF` bytes of code.
+    const COUNT: u32 = 0x24 + 0x3 + 0xF;
+
+    /// Profiles a program where the start function calls `count` with 2,
+    /// which counts down by calling itself, and then tail calls `count`.
+    fn profile() -> super::Profiler {
+        let mut asm = Assembler::new();
+        let (main, count, done) = (asm.label(), asm.label(), asm.label());
+        asm.start(main)
+            .function(main, 0xC1, &[])
+            .op("callfi", &[Label(count), Const(2), Zero])
+            .op("tailcall", &[Label(count), Zero])
+            .function(count, 0xC1, &[(0x4, 0x1)])
+            .op("jz", &[Local(0x0), Branch(done)])
+            .op("sub", &[Local(0x0), Const(1), Local(0x0)])
+            .op("callfi", &[Label(count), Local(0x0), Zero])
+            .bind(done)
+            .op("return", &[Zero]);
+        let mut glulx = Glulx::from_rom(asm.finish().unwrap()).unwrap();
+        glulx.init().unwrap();
+        glulx.start_profiling();
+        glulx.resume().unwrap();
+        glulx.take_profiler().unwrap()
+    }
+
+    #[test]
+    fn counts_calls_and_instructions() {
+        let profiler = profile();
+        assert_eq!(profiler.instructions(), 0xE);
+
+        let main = profiler.functions()[&0x24];
+        assert_eq!((main.calls, main.self_instructions), (0x1, 0x2));
+        assert_eq!(main.total_instructions, 0xC);
+
+        let count = profiler.functions()[&COUNT];
+        assert_eq!(count.calls, 0x4);
+        assert_eq!(count.self_instructions, 0xC);
+        assert_eq!(count.total_instructions, 0xA + 0x2);
+
+        assert_eq!(profiler.calls()[&(0x24, COUNT)], CallProfile {
+            calls: 0x1,
+            total_instructions: 0xA,
+        });
+        assert_eq!(profiler.calls()[&(COUNT, COUNT)].calls, 0x2);
+    }
+
+    #[test]
+    fn reports_with_names() {
+        let profiler = profile();
+        let info = DebugInfo::parse(&format!("<inform-story-file>
+            <routine><identifier>Count</identifier><value>{}</value>
+            <byte-count>20</byte-count></routine>
+            </inform-story-file>", COUNT)).unwrap();
+
+        let report = profiler.flat_report(Some(&info));
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[0x1].ends_with("  Count"));
+        assert!(lines[0x2].ends_with("  Func_00000024"));
+
+        let mut callgrind = vec![];
+        profiler.write_callgrind(&mut callgrind, Some(&info)).unwrap();
+        let callgrind = String::from_utf8(callgrind).unwrap();
+        assert!(callgrind.contains("events: Instructions\nsummary: 14\n"));
+        assert!(callgrind.contains(&format!(
+            "fn=Func_00000024\n0x24 2\ncfn=Count\ncalls=1 {:#X}\n0x24 10\n",
+            COUNT)));
+    }
+}
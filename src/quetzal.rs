@@ -0,0 +1,281 @@
+//! # Quetzal save files
+//!
+//! Saved games use the Quetzal format, an IFF `FORM` of type `IFZS`
+//! holding the following chunks:
+//!
+//! * `IFhd` -- The first 128 bytes of the original story file, which
+//!   identify the story the game was saved from
+//! * `CMem` -- The size of memory, followed by RAM compressed as the
+//!   exclusive or of its contents with the original RAM, with runs of
+//!   zeros written as a zero followed by the length of the run minus one
+//! * `Stks` -- The contents of the stack, exactly as stored by the machine
+//! * `MAll` -- The start of the heap, the number of allocated blocks, and
+//!   the address and length of each, when a heap is active
+//!
+//! Any other chunks are kept as they are, so hosts may store extra state
+//! alongside the machine.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use pages::Pages;
+
+
+/// The length of the `IFhd` chunk.
+const HEADER_LEN: usize = 0x80;
+
+
+/// The state of the machine held by a save file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quetzal {
+
+    /// The first 128 bytes of the original story file.
+    pub header: Vec<u8>,
+
+    /// The contents of RAM, from RAMSTART to the end of memory.
+    pub ram: Vec<u8>,
+
+    /// The contents of the stack.
+    pub stack: Vec<u8>,
+
+    /// The start of the heap and the address and length of each allocated
+    /// block, if a heap is active.
+    pub heap: Option<(u32, Vec<(u32, u32)>)>,
+
+    /// Any other chunks, by type.
+    pub chunks: Vec<([u8; 0x4], Vec<u8>)>,
+}
+
+
+impl Quetzal {
+
+    /// Writes the save file, compressing RAM against the initial
+    /// contents of memory.
+    pub fn to_bytes(&self, original: &Pages) -> Vec<u8> {
+        let ramstart = BigEndian::read_u32(&original.bytes(0x8..0xC));
+        let mut body = b"IFZS".to_vec();
+        write_chunk(&mut body, b"IFhd", &self.header);
+
+        let mut memory = vec![0x0; 0x4];
+        BigEndian::write_u32(&mut memory, ramstart + self.ram.len() as u32);
+        compress(&self.ram, &original.bytes(ramstart..original.len()),
+            &mut memory);
+        write_chunk(&mut body, b"CMem", &memory);
+
+        write_chunk(&mut body, b"Stks", &self.stack);
+
+        if let Some((start, ref blocks)) = self.heap {
+            let mut heap = vec![0x0; 0x8 + blocks.len() * 0x8];
+            BigEndian::write_u32(&mut heap, start);
+            BigEndian::write_u32(&mut heap[0x4..], blocks.len() as u32);
+            for (index, &(address, len)) in blocks.iter().enumerate() {
+                BigEndian::write_u32(&mut heap[0x8 + index * 0x8..], address);
+                BigEndian::write_u32(&mut heap[0xC + index * 0x8..], len);
+            }
+            write_chunk(&mut body, b"MAll", &heap);
+        }
+
+        for (id, data) in &self.chunks {
+            write_chunk(&mut body, id, data);
+        }
+
+        let mut bytes = vec![];
+        write_chunk(&mut bytes, b"FORM", &body);
+        bytes
+    }
+
+    /// Reads a save file, decompressing RAM against the initial contents
+    /// of memory.
+    pub fn from_bytes(bytes: &[u8], original: &Pages)
+            -> Result<Quetzal, String> {
+        if bytes.len() < 0xC || &bytes[..0x4] != b"FORM"
+                || &bytes[0x8..0xC] != b"IFZS" {
+            return Err("not a Quetzal save file".to_string());
+        }
+        let len = BigEndian::read_u32(&bytes[0x4..0x8]) as usize;
+        let end = (0x8 + len).min(bytes.len());
+        let ramstart = BigEndian::read_u32(&original.bytes(0x8..0xC)) as usize;
+
+        let mut quetzal = Quetzal {
+            header: vec![],
+            ram: vec![],
+            stack: vec![],
+            heap: None,
+            chunks: vec![],
+        };
+        let (mut has_memory, mut has_stack) = (false, false);
+
+        let mut ptr = 0xC;
+        while ptr + 0x8 <= end {
+            let mut id = [0x0; 0x4];
+            id.copy_from_slice(&bytes[ptr..ptr + 0x4]);
+            let len = BigEndian::read_u32(&bytes[ptr + 0x4..]) as usize;
+            let data = bytes.get(ptr + 0x8..ptr + 0x8 + len)
+                .ok_or_else(|| "truncated chunk".to_string())?;
+            match &id {
+                b"IFhd" => quetzal.header = data.to_vec(),
+                b"CMem" => {
+                    if data.len() < 0x4 {
+                        return Err("truncated memory chunk".to_string());
+                    }
+                    let size = BigEndian::read_u32(data) as usize;
+                    if size < ramstart {
+                        return Err("memory size is less than ramstart"
+                            .to_string());
+                    }
+                    let ram = original.bytes(ramstart as u32..original.len());
+                    quetzal.ram = decompress(&data[0x4..], &ram,
+                        size - ramstart)?;
+                    has_memory = true;
+                },
+                b"UMem" => {
+                    if data.len() < 0x4 {
+                        return Err("truncated memory chunk".to_string());
+                    }
+                    quetzal.ram = data[0x4..].to_vec();
+                    has_memory = true;
+                },
+                b"Stks" => {
+                    quetzal.stack = data.to_vec();
+                    has_stack = true;
+                },
+                b"MAll" => {
+                    if data.len() < 0x8 {
+                        return Err("truncated heap chunk".to_string());
+                    }
+                    let count = BigEndian::read_u32(&data[0x4..]) as usize;
+                    if data.len() < 0x8 + count * 0x8 {
+                        return Err("truncated heap chunk".to_string());
+                    }
+                    let blocks = (0x0..count)
+                        .map(|index| (
+                            BigEndian::read_u32(&data[0x8 + index * 0x8..]),
+                            BigEndian::read_u32(&data[0xC + index * 0x8..]),
+                        ))
+                        .collect();
+                    quetzal.heap = Some((BigEndian::read_u32(data), blocks));
+                },
+                _ => quetzal.chunks.push((id, data.to_vec())),
+            }
+            // chunks are padded to an even length.
+            ptr += 0x8 + len + len % 0x2;
+        }
+
+        if quetzal.header.len() != HEADER_LEN
+                || quetzal.header != original.bytes(0x0..HEADER_LEN as u32) {
+            return Err("save file is for a different story".to_string());
+        }
+        if !has_memory || !has_stack {
+            return Err("save file is missing memory or stack".to_string());
+        }
+        Ok(quetzal)
+    }
+
+    /// The data of the chunk with the given type, among the other chunks.
+    pub fn chunk(&self, id: &[u8; 0x4]) -> Option<&[u8]> {
+        self.chunks.iter()
+            .find(|&(chunk, _)| chunk == id)
+            .map(|(_, data)| &data[..])
+    }
+}
+
+
+fn write_chunk(bytes: &mut Vec<u8>, id: &[u8; 0x4], data: &[u8]) {
+    let mut len = [0x0; 0x4];
+    BigEndian::write_u32(&mut len, data.len() as u32);
+    bytes.extend_from_slice(id);
+    bytes.extend_from_slice(&len);
+    bytes.extend_from_slice(data);
+    if data.len() % 0x2 == 0x1 {
+        bytes.push(0x0);
+    }
+}
+
+
+/// Compresses memory against its original contents, which are zero past
+/// their end. Trailing zeros are left out.
+fn compress(memory: &[u8], original: &[u8], out: &mut Vec<u8>) {
+    let mut zeros = 0x0;
+    for (index, &byte) in memory.iter().enumerate() {
+        let diff = byte ^ original.get(index).cloned().unwrap_or(0x0);
+        if diff == 0x0 {
+            zeros += 0x1;
+            continue;
+        }
+        while zeros > 0x0 {
+            let run = zeros.min(0x100);
+            out.push(0x0);
+            out.push((run - 0x1) as u8);
+            zeros -= run;
+        }
+        out.push(diff);
+    }
+}
+
+
+fn decompress(data: &[u8], original: &[u8], len: usize)
+        -> Result<Vec<u8>, String> {
+    let mut memory = original.to_vec();
+    memory.resize(len, 0x0);
+
+    let mut index = 0x0;
+    let mut ptr = 0x0;
+    while ptr < data.len() {
+        let byte = data[ptr];
+        ptr += 0x1;
+        if byte == 0x0 {
+            let run = *data.get(ptr)
+                .ok_or_else(|| "truncated memory chunk".to_string())?;
+            ptr += 0x1;
+            index += run as usize + 0x1;
+        } else {
+            match memory.get_mut(index) {
+                Some(value) => *value ^= byte,
+                None => return Err("memory chunk is too long".to_string()),
+            }
+            index += 0x1;
+        }
+    }
+    if index > len {
+        return Err("memory chunk is too long".to_string());
+    }
+    Ok(memory)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use pages::Pages;
+
+    use super::Quetzal;
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut original = vec![0x0; 0x200];
+        original[0x8..0xC].copy_from_slice(&[0x0, 0x0, 0x1, 0x0]);
+        original[0x180] = 0x7;
+
+        let mut ram = original[0x100..].to_vec();
+        ram[0x0] = 0x1;
+        ram[0x80] = 0x0;
+        ram[0x1FF - 0x100] = 0x3;
+        ram.extend_from_slice(&[0x0, 0x9]);
+
+        let quetzal = Quetzal {
+            header: original[..0x80].to_vec(),
+            ram,
+            stack: vec![0x1, 0x2, 0x3],
+            heap: Some((0x200, vec![(0x200, 0x2)])),
+            chunks: vec![(*b"Test", vec![0x5])],
+        };
+        let pages = Pages::from_bytes(&original);
+        let bytes = quetzal.to_bytes(&pages);
+        assert_eq!(&bytes[..0x4], b"FORM");
+        assert_eq!(Quetzal::from_bytes(&bytes, &pages).unwrap(), quetzal);
+        assert_eq!(quetzal.chunk(b"Test"), Some(&[0x5][..]));
+
+        let mut other = original.clone();
+        other[0x0] = 0x1;
+        assert!(Quetzal::from_bytes(&bytes, &Pages::from_bytes(&other))
+            .is_err());
+    }
+}
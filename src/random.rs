@@ -0,0 +1,102 @@
+//! # Random number generation
+//!
+//! The `random` opcode draws from a generator owned by the machine. The
+//! generator is a xorshift generator, so the same seed always produces
+//! the same sequence on every host. The `setrandom` opcode reseeds it,
+//! with a seed of zero choosing an unpredictable seed from the clock.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+/// A seedable pseudo-random number generator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Random {
+    state: u64,
+}
+
+
+impl Random {
+
+    /// Creates a generator with the given seed. A seed of zero chooses
+    /// a seed from the clock.
+    pub fn new(seed: u32) -> Random {
+        let mut random = Random { state: 0x0 };
+        random.seed(seed);
+        random
+    }
+
+    /// Reseeds the generator. A seed of zero chooses a seed from the
+    /// clock.
+    pub fn seed(&mut self, seed: u32) {
+        let seed = if seed == 0x0 { clock_seed() } else { seed as u64 };
+
+        // the state must never be zero, and seeds are mixed so that
+        // nearby seeds give unrelated sequences.
+        let mut state = seed.wrapping_mul(0x9E3779B97F4A7C15);
+        state ^= state >> 0x1F;
+        self.state = if state == 0x0 { 0x1 } else { state };
+    }
+
+    /// The current state of the generator, which restores it exactly
+    /// through `from_state`.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    /// Creates a generator from a state given by `state`.
+    pub fn from_state(state: u64) -> Random {
+        Random { state: if state == 0x0 { 0x1 } else { state } }
+    }
+
+    /// The next 32 random bits.
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 0xD;
+        x ^= x >> 0x7;
+        x ^= x << 0x11;
+        self.state = x;
+        (x.wrapping_mul(0x2545F4914F6CDD1D) >> 0x20) as u32
+    }
+
+    /// A random value as produced by the `random` opcode. For a positive
+    /// range the value is in `0..range`, for a negative range it is in
+    /// `range + 1..=0`, and for zero it is any 32 bit value.
+    pub fn range(&mut self, range: i32) -> i32 {
+        match range {
+            0x0 => self.next_u32() as i32,
+            r if r > 0x0 => (self.next_u32() % r as u32) as i32,
+            r => -((self.next_u32() % r.unsigned_abs()) as i32),
+        }
+    }
+}
+
+
+/// A seed taken from the current time.
+fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or(0x1)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Random;
+
+    #[test]
+    fn seeded_sequences_repeat() {
+        let mut a = Random::new(0x2A);
+        let mut b = Random::new(0x2A);
+        let values: Vec<u32> = (0x0..0x8).map(|_| a.next_u32()).collect();
+        assert_eq!(values, (0x0..0x8).map(|_| b.next_u32()).collect::<Vec<_>>());
+
+        let mut restored = Random::from_state(a.state());
+        assert_eq!(restored.next_u32(), a.next_u32());
+
+        for _ in 0x0..0x100 {
+            assert!((0x0..0x6).contains(&a.range(0x6)));
+            assert!((-0x5..=0x0).contains(&a.range(-0x6)));
+        }
+    }
+}
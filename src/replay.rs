@@ -0,0 +1,504 @@
+//! # Replays
+//!
+//! A `Replay` records a session so that it can be reproduced exactly, such
+//! as to turn a bug reported by a player into a regression test. It holds
+//! the seed of the random number generator and every input event given to
+//! the story by its Glk library -- lines, characters, timer ticks and
+//! changes to the size of the screen -- along with the text printed to
+//! text buffer windows before each one. Any Glk library which implements
+//! the recording methods of `Glk`, as `TextGlk` does, can be recorded and
+//! replayed.
+//!
+//! Replaying gives a machine the same seed and events, and checks that it
+//! prints the same text before each. The first difference is reported as a
+//! `Divergence`, with the program counter and the turn it happened in.
+//! Stories which read the clock may diverge for that reason alone.
+//!
+//! Only Glk input and output is recorded. The input and output of FyreVM
+//! channels bypass the Glk library, so a story using them records no
+//! events and its replays find no divergence.
+//!
+//! Replay files are text, with one item on each line:
+//!
+//! ```text
+//! glulx replay 1
+//! seed 1234
+//! output "Hello.\n>"
+//! line "look"
+//! output "It is dark.\n>"
+//! char 65
+//! timer
+//! arrange 80 24
+//! output ""
+//! ```
+
+use std::any::Any;
+use std::error;
+use std::fmt;
+use std::mem;
+use std::str;
+
+use error::GlulxError;
+use glk::{Input, Recorder};
+use interpreter::Glulx;
+
+
+/// The first line of a replay file.
+const MAGIC: &str = "glulx replay 1";
+
+
+/// An input event, and the text printed before it was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayEvent {
+    pub input: Input,
+
+    /// The text printed to text buffer windows since the last event.
+    pub output: String,
+}
+
+
+/// A recorded session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Replay {
+
+    /// The seed of the random number generator.
+    pub seed: u32,
+    pub events: Vec<ReplayEvent>,
+
+    /// The text printed after the last event.
+    pub output: String,
+}
+
+
+/// Where a replay first printed different text from the recording, or
+/// stopped before all of the events were given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+
+    /// The number of events given before the divergence.
+    pub turn: usize,
+
+    /// The address of the next instruction, when the divergence was found.
+    pub program_counter: u32,
+    pub expected: String,
+    pub actual: String,
+
+    /// The error which stopped the machine, if any.
+    pub error: Option<GlulxError>,
+}
+
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the texts are shown from the first character which differs.
+        let start = self.expected.chars().zip(self.actual.chars())
+            .take_while(|&(expected, actual)| expected == actual)
+            .count();
+        let excerpt = |text: &str| -> String {
+            text.chars().skip(start).take(0x28).collect()
+        };
+        write!(f, "replay diverged in turn {} at {:#X}: expected {:?}, \
+            found {:?}", self.turn, self.program_counter,
+            excerpt(&self.expected), excerpt(&self.actual))?;
+        if let Some(ref error) = self.error {
+            write!(f, " ({})", error)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Why a replay failed to reproduce its recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+
+    /// The Glk library of the machine cannot be recorded, so cannot be
+    /// given the recorded events.
+    UnsupportedGlk,
+
+    /// The machine printed different text from the recording.
+    Diverged(Divergence),
+}
+
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplayError::UnsupportedGlk => {
+                write!(f, "the Glk library cannot be replayed")
+            },
+            ReplayError::Diverged(ref divergence) => divergence.fmt(f),
+        }
+    }
+}
+
+
+impl error::Error for ReplayError {}
+
+
+impl Replay {
+
+    /// Seeds a machine which has not been started, and starts recording
+    /// the input given to its Glk library. Returns false, recording
+    /// nothing, if the library cannot be recorded.
+    pub fn record(glulx: &mut Glulx, seed: u32) -> bool {
+        if !glulx.glk_mut().set_recorder(Box::new(Replay::default())) {
+            return false;
+        }
+        glulx.set_random_seed(seed);
+        true
+    }
+
+    /// Stops recording a machine, returning what was recorded.
+    pub fn finish(glulx: &mut Glulx) -> Option<Replay> {
+        let seed = glulx.random_seed().unwrap_or(0x0);
+        let recorder: Box<dyn Any> = glulx.glk_mut().take_recorder()?;
+        let mut replay = recorder.downcast::<Replay>().ok()?;
+        replay.seed = seed;
+        Some(*replay)
+    }
+
+    /// The recording of a machine so far, if it is being recorded.
+    fn recording(glulx: &Glulx) -> Option<&Replay> {
+        let recorder: &dyn Any = glulx.glk().recorder()?;
+        recorder.downcast_ref()
+    }
+
+    /// Plays the recorded events on a machine which has not been started,
+    /// and whose Glk library is set up as the recorded one was, stopping
+    /// at the first divergence. Otherwise returns the error which stopped
+    /// the machine, if any, as replays of failed sessions fail again.
+    pub fn run(&self, glulx: &mut Glulx)
+            -> Result<Option<GlulxError>, ReplayError> {
+        if !Replay::record(glulx, self.seed) {
+            return Err(ReplayError::UnsupportedGlk);
+        }
+        let mut error = glulx.run().err();
+        for turn in 0x0..=self.events.len() {
+            let event = self.events.get(turn);
+            let expected = event.map_or(&self.output, |event| &event.output);
+            let actual = Replay::recording(glulx)
+                .map(|recording| recording.output.clone())
+                .unwrap_or_default();
+            let stopped = event.is_some() && !glulx.is_waiting();
+            if *expected != actual || stopped {
+                return Err(ReplayError::Diverged(Divergence {
+                    turn,
+                    program_counter: glulx.program_counter(),
+                    expected: expected.clone(),
+                    actual,
+                    error,
+                }));
+            }
+            if let Some(event) = event {
+                if !glulx.glk_mut().queue_input(event.input.clone()) {
+                    return Err(ReplayError::UnsupportedGlk);
+                }
+                error = glulx.resume().err();
+            }
+        }
+        Replay::finish(glulx);
+        Ok(error)
+    }
+
+    /// Writes the replay as a replay file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = format!("{}\nseed {}\n", MAGIC, self.seed);
+        for event in &self.events {
+            text.push_str(&format!("output {}\n", quote(&event.output)));
+            let input = match event.input {
+                Input::Line(ref line) => format!("line {}", quote(line)),
+                Input::Char(key) => format!("char {}", key),
+                Input::Timer => "timer".to_string(),
+                Input::Arrange { width, height } => {
+                    format!("arrange {} {}", width, height)
+                },
+            };
+            text.push_str(&input);
+            text.push('\n');
+        }
+        text.push_str(&format!("output {}\n", quote(&self.output)));
+        text.into_bytes()
+    }
+
+    /// Reads a replay file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Replay, String> {
+        let text = str::from_utf8(bytes)
+            .map_err(|_| "replay file is not UTF-8".to_string())?;
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, line)| line.trim_end()) != Some(MAGIC) {
+            return Err("not a replay file".to_string());
+        }
+
+        let mut replay = Replay::default();
+        // the output of the next event, which comes before its input.
+        let mut output = None;
+        for (index, line) in lines {
+            let error = |message: &str| format!("line {}: {}", index + 0x1,
+                message);
+            let number = |text: &str| text.trim().parse::<u32>()
+                .map_err(|_| error("invalid number"));
+            let string = |text: &str| unquote(text)
+                .ok_or_else(|| error("invalid string"));
+            let (item, rest) = match line.find(' ') {
+                Some(space) => (&line[..space], &line[space + 0x1..]),
+                None => (line.trim(), ""),
+            };
+            let input = match item {
+                "" => continue,
+                "seed" => {
+                    replay.seed = number(rest)?;
+                    continue;
+                },
+                "output" if output.is_some() => {
+                    return Err(error("output without input"));
+                },
+                "output" => {
+                    output = Some(string(rest)?);
+                    continue;
+                },
+                "line" => Input::Line(string(rest)?),
+                "char" => Input::Char(number(rest)?),
+                "timer" => Input::Timer,
+                "arrange" => {
+                    let mut sizes = rest.split_whitespace();
+                    let mut size = || number(sizes.next().unwrap_or(""));
+                    Input::Arrange { width: size()?, height: size()? }
+                },
+                _ => return Err(error("unknown item")),
+            };
+            let output = output.take()
+                .ok_or_else(|| error("input without output"))?;
+            replay.events.push(ReplayEvent { input, output });
+        }
+        replay.output = output
+            .ok_or_else(|| "replay file has no final output".to_string())?;
+        Ok(replay)
+    }
+}
+
+
+impl Recorder for Replay {
+    fn input(&mut self, input: &Input) {
+        let output = mem::take(&mut self.output);
+        self.events.push(ReplayEvent { input: input.clone(), output });
+    }
+
+    fn output(&mut self, chars: &[u32]) {
+        self.output.extend(chars.iter()
+            .map(|&ch| ::std::char::from_u32(ch).unwrap_or('\u{FFFD}')));
+    }
+}
+
+
+/// Quotes text, escaping quotes, backslashes and control characters.
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in text.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            ch if ch.is_control() => {
+                quoted.push_str(&format!("\\u{{{:X}}}", ch as u32));
+            },
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+
+/// Reads text written by `quote`.
+fn unquote(text: &str) -> Option<String> {
+    let mut chars = text.trim().strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut text = String::new();
+    while let Some(ch) = chars.next() {
+        let ch = match ch {
+            '"' => return None,
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '"' => '"',
+                '\\' => '\\',
+                'u' if chars.next()? == '{' => {
+                    let hex: String = chars.by_ref()
+                        .take_while(|&ch| ch != '}')
+                        .collect();
+                    ::std::char::from_u32(u32::from_str_radix(&hex, 0x10)
+                        .ok()?)?
+                },
+                _ => return None,
+            },
+            ch => ch,
+        };
+        text.push(ch);
+    }
+    Some(text)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use assembler::Arg::*;
+    use assembler::Assembler;
+    use error::GlulxError;
+    use glk::{Glk, GlkMemory, Input, Recorder, TextGlk};
+    use interpreter::Glulx;
+    use memory::GlulxMemory;
+
+    use super::{Replay, ReplayError};
+
+    /// A Glk library which passes its calls to a `TextGlk`, and which can
+    /// be recorded only if `recordable` is set.
+    struct Wrapper {
+        glk: TextGlk,
+        recordable: bool,
+    }
+
+    impl Glk for Wrapper {
+        fn call(&mut self, selector: u32, args: &[u32], memory: &mut GlkMemory)
+                -> Result<Option<u32>, GlulxError> {
+            self.glk.call(selector, args, memory)
+        }
+
+        fn select(&mut self, memory: &mut GlkMemory)
+                -> Result<bool, GlulxError> {
+            self.glk.select(memory)
+        }
+
+        fn write_stream(&mut self,
+                stream: u32,
+                bytes: &[u8],
+                memory: &mut GlulxMemory) -> bool {
+            self.glk.write_stream(stream, bytes, memory)
+        }
+
+        fn read_stream(&mut self, stream: u32, memory: &GlulxMemory)
+                -> Option<Vec<u8>> {
+            self.glk.read_stream(stream, memory)
+        }
+
+        fn queue_input(&mut self, input: Input) -> bool {
+            self.recordable && self.glk.queue_input(input)
+        }
+
+        fn set_recorder(&mut self, recorder: Box<dyn Recorder>) -> bool {
+            self.recordable && self.glk.set_recorder(recorder)
+        }
+
+        fn recorder(&self) -> Option<&dyn Recorder> {
+            self.glk.recorder()
+        }
+
+        fn take_recorder(&mut self) -> Option<Box<dyn Recorder>> {
+            self.glk.take_recorder()
+        }
+    }
+
+    /// A story which prints a random number and the first character of
+    /// each line until it is given an empty line.
+    fn story() -> Glulx {
+        let mut asm = Assembler::new();
+        let (main, top, done) = (asm.label(), asm.label(), asm.label());
+        let (event, buffer, greeting) = (asm.label(), asm.label(), asm.label());
+        asm.start(main)
+            .ram(event, &[0x0; 0x10])
+            .ram(buffer, &[0x0; 0x10])
+            .function(main, 0xC1, &[(0x4, 0x2)])
+            .op("setiosys", &[Const(2), Zero])
+            // glk_window_open(0, 0, 0, textbuffer, 0)
+            .glk(0x23, &[Zero, Zero, Zero, Const(3), Zero], Local(0x0))
+            .glk(0x2F, &[Local(0x0)], Zero)
+            .op("setrandom", &[Zero])
+            .op("streamstr", &[Label(greeting)])
+            .bind(top)
+            // glk_request_line_event(main, buffer, 16, 0)
+            .glk(0xD0, &[Local(0x0), Label(buffer), Const(0x10), Zero], Zero)
+            .glk(0xC0, &[Label(event)], Zero)
+            .op("aload", &[Label(event), Const(2), Local(0x4)])
+            .op("jz", &[Local(0x4), Branch(done)])
+            .op("aloadb", &[Label(buffer), Zero, Stack])
+            .op("streamchar", &[Stack])
+            .op("random", &[Const(0x3E8), Stack])
+            .op("streamnum", &[Stack])
+            .op("streamchar", &[Const(0xA)])
+            .op("jump", &[Branch(top)])
+            .bind(done)
+            .op("return", &[Zero])
+            .string(greeting, "Hello.\n");
+        Glulx::from_rom(asm.finish().unwrap()).unwrap()
+    }
+
+    fn play(glulx: &mut Glulx, lines: &[&str]) {
+        glulx.run().unwrap();
+        for line in lines {
+            glulx.glk_as_mut::<TextGlk>().unwrap()
+                .push_input(Input::Line(line.to_string()));
+            glulx.resume().unwrap();
+        }
+    }
+
+    #[test]
+    fn records_and_replays() {
+        let mut glulx = story();
+        assert!(Replay::record(&mut glulx, 0x7));
+        play(&mut glulx, &["abc", "x\"\\y", ""]);
+        let replay = Replay::finish(&mut glulx).unwrap();
+        assert_eq!(replay.seed, 0x7);
+        assert_eq!(replay.events.len(), 0x3);
+        assert_eq!(replay.events[0x0].output, "Hello.\n");
+        assert_eq!(replay.events[0x1].input, Input::Line("x\"\\y".to_string()));
+        assert!(replay.events[0x1].output.starts_with("abc\na"));
+
+        let bytes = replay.to_bytes();
+        assert_eq!(Replay::from_bytes(&bytes).unwrap(), replay);
+        assert!(Replay::from_bytes(b"glulx replay 1\nline \"a\"\n").is_err());
+        assert_eq!(replay.run(&mut story()), Ok(None));
+
+        // changing the second line changes the output of the second turn.
+        let mut changed = replay.clone();
+        changed.events[0x1].input = Input::Line("yes".to_string());
+        let divergence = match changed.run(&mut story()) {
+            Err(ReplayError::Diverged(divergence)) => divergence,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        assert_eq!(divergence.turn, 0x2);
+        assert!(divergence.program_counter != 0x0);
+        assert!(divergence.to_string().contains("turn 2"));
+
+        let mut reseeded = replay.clone();
+        reseeded.seed = 0x8;
+        match reseeded.run(&mut story()) {
+            Err(ReplayError::Diverged(divergence)) => {
+                assert_eq!(divergence.turn, 0x1)
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn replays_any_recordable_glk() {
+        let wrapped = |recordable| {
+            let mut glulx = story();
+            glulx.set_glk(Box::new(Wrapper { glk: TextGlk::new(), recordable }));
+            glulx
+        };
+
+        let mut glulx = story();
+        assert!(Replay::record(&mut glulx, 0x7));
+        play(&mut glulx, &["abc", ""]);
+        let replay = Replay::finish(&mut glulx).unwrap();
+        assert_eq!(replay.run(&mut wrapped(true)), Ok(None));
+
+        let mut glulx = wrapped(false);
+        assert!(!Replay::record(&mut glulx, 0x7));
+        assert!(Replay::finish(&mut glulx).is_none());
+        assert_eq!(replay.run(&mut wrapped(false)),
+            Err(ReplayError::UnsupportedGlk));
+    }
+}
@@ -0,0 +1,251 @@
+//! # Sessions
+//!
+//! A `Session` plays a story one turn at a time, for hosts which treat a
+//! story as a function from input to output. It runs the machine with a
+//! `TextGlk` until the story asks for input, and gathers what was printed
+//! along the way into a `TurnOutput`.
+//!
+//! ```no_run
+//! use glulx::Session;
+//!
+//! let story = std::fs::read("story.gblorb").unwrap();
+//! let mut session = Session::new(story).unwrap();
+//! println!("{}", session.start().text());
+//! let turn = session.send("look");
+//! println!("{}", turn.text());
+//! ```
+
+use std::collections::BTreeMap;
+
+use blorb::Blorb;
+use error::GlulxError;
+use glk::{Input, TextGlk, WindowKind, KEY_RETURN};
+use interpreter::Glulx;
+
+
+/// What the story printed during a turn.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TurnOutput {
+
+    /// The text printed to each text buffer window, by window, for the
+    /// windows which printed any.
+    pub windows: BTreeMap<u32, String>,
+
+    /// The lines of the status line, which is the first text grid window,
+    /// as they stand at the end of the turn.
+    pub status: Vec<String>,
+
+    /// The text printed to each channel by stories using the FyreVM I/O
+    /// system, by the name of the channel.
+    pub channels: BTreeMap<String, String>,
+
+    /// Whether the story has ended, either by quitting or by an error.
+    pub ended: bool,
+
+    /// The error which stopped the story, if any.
+    pub error: Option<GlulxError>,
+}
+
+
+impl TurnOutput {
+
+    /// All of the text printed to windows, in the order the windows were
+    /// opened, followed by the text of the `MAIN` channel.
+    pub fn text(&self) -> String {
+        let mut text: String = self.windows.values().map(|text| &text[..])
+            .collect();
+        if let Some(main) = self.channels.get("MAIN") {
+            text.push_str(main);
+        }
+        text
+    }
+}
+
+
+/// A story played one turn at a time.
+pub struct Session {
+    glulx: Glulx,
+    started: bool,
+}
+
+
+impl Session {
+
+    /// Loads a story file or Blorb file, which is not started until the
+    /// first turn.
+    pub fn new(story: Vec<u8>) -> Result<Session, String> {
+        let (story, blorb) = Blorb::load(story)?;
+        let mut glk = TextGlk::new();
+        glk.set_echo_input(false);
+        if let Some(blorb) = blorb {
+            glk.set_blorb(blorb);
+        }
+        let mut glulx = Glulx::from_rom(story)?;
+        glulx.set_glk(Box::new(glk));
+        Ok(Session { glulx, started: false })
+    }
+
+    /// Plays a machine which has not been started. Input is not echoed to
+    /// the output. Returns `None` if the machine does not use a `TextGlk`.
+    pub fn from_glulx(mut glulx: Glulx) -> Option<Session> {
+        glulx.glk_as_mut::<TextGlk>()?.set_echo_input(false);
+        Some(Session { glulx, started: false })
+    }
+
+    /// Starts the story, returning what it printed before first asking
+    /// for input. Starting a started story does nothing.
+    pub fn start(&mut self) -> TurnOutput {
+        if self.started {
+            return self.output(None);
+        }
+        self.started = true;
+        let result = self.glulx.run();
+        self.output(result.err())
+    }
+
+    /// Sends a line of input to the story, returning what it printed
+    /// until it next asked for input. A story waiting for a key is given
+    /// the first character of the line, or return for an empty line.
+    /// Stories which have not been started are started first.
+    pub fn send(&mut self, input: &str) -> TurnOutput {
+        let opening = if self.started { None } else { Some(self.start()) };
+        if self.is_ended() {
+            return opening.unwrap_or_else(|| self.output(None));
+        }
+
+        match self.glulx.glk_as_mut::<TextGlk>() {
+            Some(glk) if glk.line_requested() => {
+                glk.push_input(Input::Line(input.to_string()));
+            },
+            Some(glk) if glk.char_requested() => {
+                let key = input.chars().next()
+                    .map_or(KEY_RETURN, |ch| ch as u32);
+                glk.push_input(Input::Char(key));
+            },
+            _ => self.glulx.push_channel_input(input.to_string()),
+        }
+        let result = self.glulx.resume();
+        let mut output = self.output(result.err());
+
+        if let Some(mut opening) = opening {
+            for (window, text) in output.windows {
+                opening.windows.entry(window).or_default().push_str(&text);
+            }
+            for (channel, text) in output.channels {
+                opening.channels.entry(channel).or_default().push_str(&text);
+            }
+            output.windows = opening.windows;
+            output.channels = opening.channels;
+        }
+        output
+    }
+
+    /// Whether the story has ended.
+    pub fn is_ended(&self) -> bool {
+        self.started && !self.glulx.is_running()
+    }
+
+    /// The machine playing the story.
+    pub fn glulx(&self) -> &Glulx {
+        &self.glulx
+    }
+
+    /// The machine playing the story, for inspecting or changing it
+    /// between turns. A machine given another Glk prints nothing to
+    /// windows, and is given input through its channels.
+    pub fn glulx_mut(&mut self) -> &mut Glulx {
+        &mut self.glulx
+    }
+
+    /// Takes what was printed since the last turn.
+    fn output(&mut self, error: Option<GlulxError>) -> TurnOutput {
+        let channels = self.glulx.take_channels();
+        let ended = !self.glulx.is_running();
+        let (windows, status) = match self.glulx.glk_as_mut::<TextGlk>() {
+            Some(glk) => {
+                let windows = glk.windows();
+                let status = windows.iter()
+                    .find(|window| window.kind == WindowKind::TextGrid)
+                    .map(|window| glk.grid_lines(window.id))
+                    .unwrap_or_default();
+                let windows = windows.iter()
+                    .filter(|window| window.kind == WindowKind::TextBuffer)
+                    .map(|window| (window.id, glk.take_text(window.id)))
+                    .filter(|(_, text)| !text.is_empty())
+                    .collect();
+                (windows, status)
+            },
+            None => (BTreeMap::new(), vec![]),
+        };
+        TurnOutput { windows, status, channels, ended, error }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use assembler::Arg::*;
+    use assembler::Assembler;
+    use interpreter::Glulx;
+
+    use super::Session;
+
+    /// A story with a status line, which echoes the first character of
+    /// each line until it is given an empty line.
+    fn story() -> Glulx {
+        let mut asm = Assembler::new();
+        let (main, top, done) = (asm.label(), asm.label(), asm.label());
+        let (event, buffer, greeting, room) =
+            (asm.label(), asm.label(), asm.label(), asm.label());
+        asm.start(main)
+            .ram(event, &[0x0; 0x10])
+            .ram(buffer, &[0x0; 0x10])
+            .function(main, 0xC1, &[(0x4, 0x3)])
+            .op("setiosys", &[Const(2), Zero])
+            // glk_window_open(0, 0, 0, textbuffer, 0)
+            .glk(0x23, &[Zero, Zero, Zero, Const(3), Zero], Local(0x0))
+            // glk_window_open(main, above | fixed, 1, textgrid, 0)
+            .glk(0x23, &[Local(0x0), Const(0x12), Const(1), Const(4), Zero],
+                Local(0x8))
+            .glk(0x2F, &[Local(0x8)], Zero)
+            .op("streamstr", &[Label(room)])
+            .glk(0x2F, &[Local(0x0)], Zero)
+            .op("streamstr", &[Label(greeting)])
+            .bind(top)
+            // glk_request_line_event(main, buffer, 16, 0)
+            .glk(0xD0, &[Local(0x0), Label(buffer), Const(0x10), Zero], Zero)
+            .glk(0xC0, &[Label(event)], Zero)
+            .op("aload", &[Label(event), Const(2), Local(0x4)])
+            .op("jz", &[Local(0x4), Branch(done)])
+            .op("aloadb", &[Label(buffer), Zero, Stack])
+            .op("streamchar", &[Stack])
+            .op("streamchar", &[Const(0xA)])
+            .op("jump", &[Branch(top)])
+            .bind(done)
+            .op("return", &[Zero])
+            .string(greeting, "Hello.\n")
+            .string(room, "Hall");
+        Glulx::from_rom(asm.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn plays_turns() {
+        let mut session = Session::from_glulx(story()).unwrap();
+        let opening = session.start();
+        assert_eq!(opening.text(), "Hello.\n");
+        assert_eq!(opening.status, vec!["Hall".to_string()]);
+        assert!(!opening.ended);
+
+        let turn = session.send("xyzzy");
+        assert_eq!(turn.text(), "x\n");
+        assert!(turn.windows.len() == 0x1 && !turn.ended);
+
+        let turn = session.send("");
+        assert!(turn.ended && turn.error.is_none());
+        assert!(session.is_ended());
+
+        // the opening is included when the first line starts the story.
+        let mut session = Session::from_glulx(story()).unwrap();
+        assert_eq!(session.send("abc").text(), "Hello.\na\n");
+    }
+}
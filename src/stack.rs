@@ -1,9 +1,164 @@
-use byteorder::{ByteOrder, NativeEndian};
+//! # Glulx stack
+//!
+//! The stack is stored as a vector of bytes laid out exactly as described
+//! in section 1.3 of the Glulx specification. Every value, including the
+//! locals of each call frame, is stored in big-endian order, so the
+//! contents of the stack are identical on every host and can be copied
+//! directly into the `Stks` chunk of a save file.
+//!
+//!
+//! ## Call Frames
+//!
+//! A call frame begins at the frame pointer, and consists of:
+//!
+//! * Frame Len -- The length of the frame up to the first value (4 bytes)
+//! * Locals Pos -- The offset of the locals from the frame pointer (4 bytes)
+//! * Format of Locals -- The local type and count pairs copied from the
+//!   function header, terminated by a pair of zeros, and padded with
+//!   zeros to a 4 byte boundary
+//! * Locals -- Each local is 1, 2, or 4 bytes, aligned to its own size,
+//!   and the whole segment is padded to a 4 byte boundary
+//! * Values -- Values pushed onto the stack, always 4 bytes each
+//!
+//!
+//! ## Call Stubs
+//!
+//! A call stub consists of four 4 byte values, pushed in order:
+//!
+//! * DestType -- Where the result of the operation is stored
+//! * DestAddr -- The address the result is stored at
+//! * PC -- The program counter to resume execution at
+//! * FramePtr -- The frame pointer of the calling function
+//!
+//!
+//! ## Size
+//!
+//! The stack size given in the header is a hard limit. Any push which
+//! would grow the stack past it fails with `StackError::Overflow`, and
+//! leaves the stack unchanged. Likewise an operation which needs more
+//! values than the current call frame holds fails with
+//! `StackError::Underflow`.
+
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+
+
+/// A local of a call frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Local {
+
+    /// The offset of the local from the start of the locals.
+    pub offset: u32,
+
+    /// The size of the local in bytes, either 1, 2, or 4.
+    pub size: u8,
+
+    /// The value of the local, zero extended.
+    pub value: u32,
+}
+
+
+/// A call stub, as saved beneath a call frame by the call which pushed
+/// the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallStub {
+
+    /// Where the result of the call is stored.
+    pub dest_type: u32,
+
+    /// The address the result of the call is stored at.
+    pub dest_addr: u32,
+
+    /// The program counter execution resumes at when the call returns.
+    pub program_counter: u32,
+}
+
+
+impl fmt::Display for CallStub {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "returns to {:#X}", self.program_counter)?;
+        match self.dest_type {
+            0x0 => Ok(()),
+            0x1 => write!(f, ", storing to *{:#X}", self.dest_addr),
+            0x2 => write!(f, ", storing to local{}", self.dest_addr),
+            0x3 => write!(f, ", pushing the result"),
+            x => write!(f, ", with destination type {:#X} ({:#X})",
+                x, self.dest_addr),
+        }
+    }
+}
+
+
+/// A call frame on the stack, as found by walking the call stubs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+
+    /// The address of the function which pushed the frame, if known.
+    pub function: Option<u32>,
+
+    /// The call stub beneath the frame, or `None` for the frame of the
+    /// start function.
+    pub stub: Option<CallStub>,
+
+    /// The locals of the frame, decoded with the frame's format of
+    /// locals.
+    pub locals: Vec<Local>,
+
+    /// The number of values pushed onto the stack in the frame.
+    pub value_count: u32,
+}
+
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.function {
+            Some(function) => write!(f, "function {:#X} (", function)?,
+            None => write!(f, "unknown function (")?,
+        }
+        for (index, local) in self.locals.iter().enumerate() {
+            if index > 0x0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "local{} = {:#X}", local.offset, local.value)?;
+        }
+        write!(f, "), {} values", self.value_count)?;
+        if let Some(stub) = self.stub {
+            write!(f, ", {}", stub)?;
+        }
+        Ok(())
+    }
+}
 
 
+/// Errors returned by operations on the stack, which leave it unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+
+    /// A push would grow the stack past its size.
+    Overflow,
+
+    /// An operation needs more values than the current call frame holds.
+    Underflow,
+
+    /// A format of locals has a local type other than 1, 2 or 4.
+    InvalidLocalType(u8),
+
+    /// A local was accessed at an offset which lies outside the locals
+    /// of the current call frame.
+    InvalidLocal(u32),
+}
+
+
+#[derive(Clone)]
 pub struct GlulxStack {
     frame_ptr: u32,
+    size: u32,
     stack: Vec<u8>,
+
+    /// The frame pointer and function address of each call frame whose
+    /// function is known, from the bottom of the stack.
+    functions: Vec<(u32, u32)>,
 }
 
 
@@ -11,316 +166,766 @@ impl GlulxStack {
     pub fn new(size: u32) -> GlulxStack {
         GlulxStack {
             frame_ptr: 0x0,
-            stack: vec![0x0; size as usize],
+            size,
+            stack: Vec::with_capacity(size as usize),
+            functions: vec![],
         }
     }
 
     pub fn push_call_stub(&mut self,
             dest_type: u32,
             dest_addr: u32,
-            program_counter: u32) {
-        self.push(dest_type);
-        self.push(dest_addr);
-        self.push(program_counter);
+            program_counter: u32) -> Result<(), StackError> {
+        self.reserve(0x10)?;
+        self.push_u32(dest_type);
+        self.push_u32(dest_addr);
+        self.push_u32(program_counter);
         let frame_ptr = self.frame_ptr;
-        self.push(frame_ptr);
+        self.push_u32(frame_ptr);
+        Ok(())
     }
 
     pub fn pop_call_stub(&mut self) -> (u32, u32, u32) {
-        self.frame_ptr = self.pop();
+        self.frame_ptr = self.pop_u32();
 
         let (
             program_counter,
             dest_addr,
             dest_type,
-        ) = (self.pop(), self.pop(), self.pop());
+        ) = (self.pop_u32(), self.pop_u32(), self.pop_u32());
 
         (dest_type, dest_addr, program_counter)
     }
 
     pub fn pop_call_frame(&mut self) {
         let frame_ptr = self.frame_ptr;
-        self.stack.resize(frame_ptr as usize, 0x0);
+        self.stack.truncate(frame_ptr as usize);
+        self.forget_functions(frame_ptr);
+    }
+
+    /// The number of bytes on the stack, which is the stack pointer.
+    pub fn len(&self) -> u32 {
+        self.stack.len() as u32
+    }
+
+    /// The contents of the stack, as stored in the `Stks` chunk of a save
+    /// file.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.stack
+    }
+
+    /// Replaces the contents of the stack with bytes from `as_bytes`,
+    /// which must end with a call stub to pop. Returns false, leaving the
+    /// stack unchanged, if the bytes do not fit.
+    pub fn restore(&mut self, bytes: &[u8]) -> bool {
+        if bytes.len() > self.size as usize || bytes.len() < 0x10 {
+            return false;
+        }
+        self.stack.clear();
+        self.stack.extend_from_slice(bytes);
+        self.frame_ptr = 0x0;
+        self.functions.clear();
+        true
+    }
+
+    /// Empties the stack.
+    pub fn clear(&mut self) {
+        self.stack.clear();
+        self.frame_ptr = 0x0;
+        self.functions.clear();
+    }
+
+    /// Pops values until the stack pointer is the given catch token, which
+    /// must leave a call stub on top of the stack. Returns whether the
+    /// token was valid, leaving the stack unchanged if it was not.
+    pub fn unwind(&mut self, token: u32) -> bool {
+        if token < 0x10 || token as usize > self.stack.len() {
+            return false;
+        }
+        self.stack.truncate(token as usize);
+        self.forget_functions(token);
+        true
+    }
+
+    /// Records the address of the function which pushed the current call
+    /// frame.
+    pub fn set_function(&mut self, address: u32) {
+        let frame_ptr = self.frame_ptr;
+        self.forget_functions(frame_ptr);
+        self.functions.push((frame_ptr, address));
     }
 
-    pub fn push_call_frame_c0(&mut self,
-            locals: Vec<u8>,
-            args: Vec<u32>) {
-        self.frame_ptr = self.stack.len() as u32;
+    /// Forgets the functions of the call frames at or above the given
+    /// frame pointer.
+    fn forget_functions(&mut self, frame_ptr: u32) {
+        while self.functions.last().is_some_and(|&(ptr, _)| ptr >= frame_ptr) {
+            self.functions.pop();
+        }
+    }
 
-        let locals_len: u32 = locals.chunks(0x2)
-            .map(|pair| pair.into_iter()
-                .map(|&val| val as u32)
-                .product::<u32>())
-            .sum();
+    /// The call frames on the stack, from the current frame down to the
+    /// frame of the start function.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let mut frames = vec![];
+        if self.stack.is_empty() {
+            return frames;
+        }
 
-        let mut locals = locals;
-        if locals.len() & 0x3 != 0 {
-            locals.push(0x0);
-            locals.push(0x0);
+        let mut frame_ptr = self.frame_ptr as usize;
+        let mut end = self.stack.len();
+        loop {
+            let frame_len = BigEndian::read_u32(&self.stack[frame_ptr..]);
+            let values_ptr = frame_ptr + frame_len as usize;
+            let stub = if frame_ptr >= 0x10 {
+                let stub = &self.stack[frame_ptr - 0x10..frame_ptr];
+                Some(CallStub {
+                    dest_type: BigEndian::read_u32(stub),
+                    dest_addr: BigEndian::read_u32(&stub[0x4..]),
+                    program_counter: BigEndian::read_u32(&stub[0x8..]),
+                })
+            } else {
+                None
+            };
+            let function = self.functions.iter()
+                .rev()
+                .find(|&&(ptr, _)| ptr as usize == frame_ptr)
+                .map(|&(_, function)| function);
+
+            frames.push(Frame {
+                function,
+                stub,
+                locals: self.locals_at(frame_ptr),
+                value_count: ((end - values_ptr) / 0x4) as u32,
+            });
+
+            if stub.is_none() {
+                return frames;
+            }
+            end = frame_ptr - 0x10;
+            frame_ptr = BigEndian::read_u32(&self.stack[frame_ptr - 0x4..])
+                as usize;
         }
-        let locals = locals;
+    }
 
-        let local_pos = locals.len() as u32;
-        let frame_len = local_pos + locals_len;
+    /// Whether a call stub lies beneath the current call frame. This is
+    /// false only for the frame of the start function.
+    pub fn has_call_stub(&self) -> bool {
+        self.frame_ptr >= 0x10
+    }
 
-        self.push(frame_len);
-        self.push(local_pos);
-        self.stack.extend(locals);
+    /// The number of call frames on the stack, found by following the
+    /// frame pointers saved in the call stub beneath each frame.
+    pub fn call_depth(&self) -> u32 {
+        if self.stack.is_empty() {
+            return 0x0;
+        }
 
-        for arg in args.into_iter().rev() {
-            self.push(arg);
+        let mut depth = 0x1;
+        let mut frame_ptr = self.frame_ptr as usize;
+        while frame_ptr >= 0x10 {
+            frame_ptr = BigEndian::read_u32(&self.stack[frame_ptr - 0x4..])
+                as usize;
+            depth += 0x1;
         }
+        depth
     }
 
-    pub fn push_call_frame_c1(&mut self,
-            locals: Vec<u8>,
-            args: Vec<u32>) {
-        self.frame_ptr = self.stack.len() as u32;
+    /// Push a call frame for a `0xC0` function. All locals are zeroed,
+    /// and the arguments are pushed onto the stack last to first,
+    /// followed by the number of arguments.
+    pub fn push_call_frame_c0(&mut self, format: &[u8], args: &[u32])
+            -> Result<(), StackError> {
+        let caller_frame_ptr = self.frame_ptr;
+        self.push_call_frame(format)?;
 
-        /// placeholder values
-        self.push(0x0u32);
-        self.push(0x0u32);
+        if let Err(err) = self.reserve((args.len() + 0x1) * 0x4) {
+            self.pop_call_frame();
+            self.frame_ptr = caller_frame_ptr;
+            return Err(err);
+        }
+        for &arg in args.iter().rev() {
+            self.push_u32(arg);
+        }
+        self.push_u32(args.len() as u32);
+        Ok(())
+    }
 
-        self.stack.extend(locals.clone());
+    /// Push a call frame for a `0xC1` function. The arguments are
+    /// written into the locals in order, truncated to the size of each
+    /// local. Extra arguments are discarded, and any locals without an
+    /// argument are zeroed.
+    pub fn push_call_frame_c1(&mut self, format: &[u8], args: &[u32])
+            -> Result<(), StackError> {
+        let locals = self.push_call_frame(format)?;
 
-        while self.stack.len() & 0x3 != 0 {
-            self.stack.push(0x0);
+        for (&(offset, local_type), &arg) in locals.iter().zip(args) {
+            match local_type {
+                0x1 => self.write(offset, arg as u8),
+                0x2 => self.write(offset, arg as u16),
+                _ => self.write(offset, arg),
+            }?;
+        }
+        Ok(())
+    }
+
+    /// Builds a call frame with the given format of locals, which must
+    /// include the terminating pair of zeros. All locals are zeroed.
+    /// Returns the offset and type of each local in the frame.
+    fn push_call_frame(&mut self, format: &[u8])
+            -> Result<Vec<(u32, u8)>, StackError> {
+        let invalid = format.chunks(0x2)
+            .map(|pair| pair[0x0])
+            .take_while(|&local_type| local_type != 0x0)
+            .find(|&local_type| ![0x1, 0x2, 0x4].contains(&local_type));
+        if let Some(local_type) = invalid {
+            return Err(StackError::InvalidLocalType(local_type));
         }
 
-        let local_pos = self.stack.len() as u32 - self.frame_ptr;
-        NativeEndian::write_u32(
-            &mut self.stack[self.frame_ptr as usize + 0x4..],
-            local_pos);
+        let frame_ptr = self.stack.len();
+        let caller_frame_ptr = self.frame_ptr;
+        self.frame_ptr = frame_ptr as u32;
 
+        // placeholders for the frame length and locals position.
+        self.stack.resize(frame_ptr + 0x8, 0x0);
 
-        let mut args = args;
-        for pair in locals.chunks(0x2) {
+        self.stack.extend_from_slice(format);
+        self.align(0x4);
+        let local_pos = self.stack.len() - frame_ptr;
+
+        let mut locals = Vec::new();
+        for pair in format.chunks(0x2) {
             let (local_type, local_count) = (pair[0], pair[1]);
 
-            match local_type {
-                1 => {
-                    for _ in 0x0..local_count {
-                        if args.len() != 0 {
-                            self.push(args.pop().unwrap() as u8);
-                        } else {
-                            self.push(0x0u8);
-                        }
-                    }
-                },
-                2 => {
-                    if self.stack.len() & 0x1  != 0 { self.stack.push(0x0) };
-                    for _ in 0x0..local_count {
-                        if args.len() != 0 {
-                            self.push(args.pop().unwrap() as u16);
-                        } else {
-                            self.push(0x0u16);
-                        }
-                    }
-                }
-                4 => {
-                    while self.stack.len() & 0x3  != 0x0 {
-                        self.push(0x0);
-                    };
-                    for _ in 0x0..local_count {
-                        if args.len() != 0 {
-                            self.push(args.pop().unwrap());
-                        } else {
-                            self.push(0x0u32);
-                        }
-                    }
-                },
-                _ => {},
+            if local_type == 0x0 {
+                break;
+            }
+
+            self.align(local_type as usize);
+            for _ in 0x0..local_count {
+                let offset = self.stack.len() - frame_ptr - local_pos;
+                locals.push((offset as u32, local_type));
+                let len = self.stack.len() + local_type as usize;
+                self.stack.resize(len, 0x0);
             }
         }
-        let args = args;
+        self.align(0x4);
+
+        // the frame is built before checking its size, so an overflow
+        // grows the stack by at most a single frame before unwinding.
+        if self.stack.len() > self.size as usize {
+            self.stack.truncate(frame_ptr);
+            self.frame_ptr = caller_frame_ptr;
+            return Err(StackError::Overflow);
+        }
 
-        while self.stack.len() & 0x3 != 0x0 { self.push(0x0) };
+        let frame_len = self.stack.len() - frame_ptr;
+        BigEndian::write_u32(&mut self.stack[frame_ptr..], frame_len as u32);
+        BigEndian::write_u32(
+            &mut self.stack[frame_ptr + 0x4..],
+            local_pos as u32);
 
-        let frame_len = self.stack.len() as u32;
-        NativeEndian::write_u32(
-            &mut self.stack[self.frame_ptr as usize..],
-            frame_len);
+        Ok(locals)
     }
 
-    pub fn pop_args(&mut self, nargs: u32) -> Vec<u32> {
+    /// Checks that `len` more bytes can be pushed onto the stack.
+    fn reserve(&mut self, len: usize) -> Result<(), StackError> {
+        if self.stack.len() + len > self.size as usize {
+            Err(StackError::Overflow)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Pads the stack with zeros until its length is a multiple of the
+    /// given alignment.
+    fn align(&mut self, alignment: usize) {
+        while !self.stack.len().is_multiple_of(alignment) {
+            self.stack.push(0x0);
+        }
+    }
+
+    /// Pops `nargs` values off the stack, from the top down.
+    pub fn pop_args(&mut self, nargs: u32) -> Result<Vec<u32>, StackError> {
+        if nargs > self.value_count() {
+            return Err(StackError::Underflow);
+        }
+
         let mut vec = Vec::new();
-        for x in 0x0..nargs {
-            vec.push(self.pop());
+        for _ in 0x0..nargs {
+            vec.push(self.pop_u32());
         }
-        vec
+        Ok(vec)
+    }
+
+    pub fn frame_len(&self) -> u32 {
+        BigEndian::read_u32(&self.stack[self.frame_ptr as usize..])
     }
 
     pub fn local_pos(&self) -> u32 {
-        NativeEndian::read_u32(&self.stack[(self.frame_ptr as usize + 0x4)..])
+        BigEndian::read_u32(&self.stack[(self.frame_ptr as usize + 0x4)..])
+    }
+
+    /// The number of values pushed onto the stack in the current call
+    /// frame.
+    pub fn value_count(&self) -> u32 {
+        if self.stack.is_empty() {
+            return 0x0;
+        }
+        let values_ptr = self.frame_ptr + self.frame_len();
+        (self.stack.len() as u32 - values_ptr) / 0x4
+    }
+
+    /// Rotates the top `count` values of the stack up by `shift` places.
+    /// Negative values of `shift` rotate the values down.
+    pub fn roll(&mut self, count: u32, shift: i32) -> Result<(), StackError> {
+        if count == 0x0 {
+            return Ok(());
+        }
+        if count > self.value_count() {
+            return Err(StackError::Underflow);
+        }
+
+        let start = self.stack.len() - count as usize * 0x4;
+        let shift = (shift as i64).rem_euclid(count as i64) as usize;
+        self.stack[start..].rotate_right(shift * 0x4);
+        Ok(())
+    }
+
+    /// Pushes copies of the top `count` values of the stack, in the same
+    /// order.
+    pub fn copy(&mut self, count: u32) -> Result<(), StackError> {
+        if count > self.value_count() {
+            return Err(StackError::Underflow);
+        }
+
+        let start = self.stack.len() - count as usize * 0x4;
+        self.reserve(count as usize * 0x4)?;
+        let values = self.stack[start..].to_vec();
+        self.stack.extend(values);
+        Ok(())
+    }
+
+    fn checked_push_u32(&mut self, val: u32) -> Result<(), StackError> {
+        self.reserve(0x4)?;
+        self.push_u32(val);
+        Ok(())
+    }
+
+    /// Pushes a value without checking the size of the stack. Callers
+    /// must reserve the space first.
+    fn push_u32(&mut self, val: u32) {
+        let pos = self.stack.len();
+        self.stack.resize(pos + 0x4, 0x0);
+        BigEndian::write_u32(&mut self.stack[pos..], val);
+    }
+
+    fn checked_pop_u32(&mut self) -> Result<u32, StackError> {
+        if self.value_count() == 0x0 {
+            return Err(StackError::Underflow);
+        }
+        Ok(self.pop_u32())
+    }
+
+    /// Pops a value without checking the values of the current call
+    /// frame, as when popping a call stub.
+    fn pop_u32(&mut self) -> u32 {
+        let pos = self.stack.len() - 0x4;
+        let ret = BigEndian::read_u32(&self.stack[pos..]);
+        self.stack.truncate(pos);
+        ret
+    }
+
+    fn peek_u32(&self, depth: u32) -> Result<u32, StackError> {
+        if depth >= self.value_count() {
+            return Err(StackError::Underflow);
+        }
+
+        let pos = self.stack.len() - (depth as usize + 0x1) * 0x4;
+        Ok(BigEndian::read_u32(&self.stack[pos..]))
+    }
+
+    /// The locals of the current call frame, decoded with the frame's
+    /// format of locals.
+    pub fn locals(&self) -> Vec<Local> {
+        self.locals_at(self.frame_ptr as usize)
+    }
+
+    /// The locals of the call frame at the given frame pointer.
+    fn locals_at(&self, frame_ptr: usize) -> Vec<Local> {
+        if frame_ptr + 0x8 > self.stack.len() {
+            return vec![];
+        }
+        let local_pos = BigEndian::read_u32(&self.stack[frame_ptr + 0x4..]);
+        let locals_ptr = frame_ptr + local_pos as usize;
+
+        let mut locals = Vec::new();
+        let mut offset = 0x0u32;
+        for pair in self.stack[frame_ptr + 0x8..locals_ptr].chunks(0x2) {
+            let (size, count) = (pair[0], pair[1]);
+            if size == 0x0 {
+                break;
+            }
+
+            offset = offset.div_ceil(size as u32) * size as u32;
+            for _ in 0x0..count {
+                let ptr = locals_ptr + offset as usize;
+                let value = match size {
+                    0x1 => self.stack[ptr] as u32,
+                    0x2 => BigEndian::read_u16(&self.stack[ptr..]) as u32,
+                    _ => BigEndian::read_u32(&self.stack[ptr..]),
+                };
+                locals.push(Local { offset, size, value });
+                offset += size as u32;
+            }
+        }
+        locals
+    }
+
+    /// The values pushed onto the stack in the current call frame, from
+    /// the bottom of the frame to the top of the stack.
+    pub fn values(&self) -> Vec<u32> {
+        let values_ptr = (self.frame_ptr + self.frame_len()) as usize;
+        self.stack[values_ptr..].chunks(0x4)
+            .map(BigEndian::read_u32)
+            .collect()
+    }
+
+    /// The position in the stack of the local of the given size at the
+    /// given offset, which must lie within the locals of the current call
+    /// frame.
+    fn local_ptr(&self, offset: u32, size: usize) -> Result<usize, StackError> {
+        if self.stack.is_empty() {
+            return Err(StackError::InvalidLocal(offset));
+        }
+        let (local_pos, frame_len) = (self.local_pos(), self.frame_len());
+        if offset as usize + size > (frame_len - local_pos) as usize {
+            return Err(StackError::InvalidLocal(offset));
+        }
+        Ok((self.frame_ptr + local_pos + offset) as usize)
     }
 }
 
 
+/// Values pushed onto and popped off of the stack are always 4 bytes,
+/// with smaller values zero extended and truncated as needed. Locals are
+/// read and written with the size of the given type.
 pub trait Stack<T> {
-    fn push(&mut self, val: T);
-    fn pop(&mut self) -> T;
-    fn peek(&self) -> T;
-    fn read(& self, offset: u32) -> T;
-    fn write(&mut self, offset: u32,  val: T);
+    fn push(&mut self, val: T) -> Result<(), StackError>;
+    fn pop(&mut self) -> Result<T, StackError>;
+    fn peek(&self, depth: u32) -> Result<T, StackError>;
+    fn read(&self, offset: u32) -> Result<T, StackError>;
+    fn write(&mut self, offset: u32, val: T) -> Result<(), StackError>;
 }
 
 
 impl Stack<u8> for GlulxStack {
-
-    /// take a `u8` and push onto the stack.
-    fn push(&mut self, val: u8) {
-        self.stack.push(val);
+    fn push(&mut self, val: u8) -> Result<(), StackError> {
+        self.checked_push_u32(val as u32)
     }
 
-    /// pop a `u8` from the stack.
-    fn pop(&mut self) -> u8 {
-        self.stack.pop().unwrap()
+    fn pop(&mut self) -> Result<u8, StackError> {
+        self.checked_pop_u32().map(|val| val as u8)
     }
 
-    fn peek(&self) -> u8 {
-        let pos = self.stack.len() - 0x4;
-        NativeEndian::read_u32(&self.stack[pos..]) as u8
+    fn peek(&self, depth: u32) -> Result<u8, StackError> {
+        self.peek_u32(depth).map(|val| val as u8)
     }
 
-    fn read(&self, offset: u32) -> u8 {
-        let pos = (self.frame_ptr + self.local_pos() + offset) as usize;
-        self.stack[pos]
+    fn read(&self, offset: u32) -> Result<u8, StackError> {
+        Ok(self.stack[self.local_ptr(offset, 0x1)?])
     }
 
-    // Writes a u8 as normal
-    fn write(&mut self, offset: u32, val: u8) {
-        let pos = (self.frame_ptr + self.local_pos() + offset) as usize;
+    fn write(&mut self, offset: u32, val: u8) -> Result<(), StackError> {
+        let pos = self.local_ptr(offset, 0x1)?;
         self.stack[pos] = val;
+        Ok(())
     }
 }
 
 
 impl Stack<u16> for GlulxStack {
-
-    /// take a `u16` and push onto the stack.
-    fn push(&mut self, val: u16) {
-        let pos = self.stack.len();
-        for _ in 0x0..0x4 { self.stack.push(0) };
-        NativeEndian::write_u16(&mut self.stack[pos..], val);
+    fn push(&mut self, val: u16) -> Result<(), StackError> {
+        self.checked_push_u32(val as u32)
     }
 
-    /// pop a `u16` from the stack.
-    fn pop(&mut self) -> u16 {
-        let pos = self.stack.len() - 0x2;
-        let ret = NativeEndian::read_u16(&self.stack[pos..]);
-        for _ in 0x0..0x2 { self.stack.pop(); };
-        ret
+    fn pop(&mut self) -> Result<u16, StackError> {
+        self.checked_pop_u32().map(|val| val as u16)
     }
 
-    fn peek(&self) -> u16 {
-        let pos = self.stack.len() - 0x2;
-        NativeEndian::read_u16(&self.stack[pos..])
+    fn peek(&self, depth: u32) -> Result<u16, StackError> {
+        self.peek_u32(depth).map(|val| val as u16)
     }
 
-    fn read(&self, offset: u32) -> u16 {
-        let pos = (self.frame_ptr + self.local_pos() + offset) as usize;
-        NativeEndian::read_u16(&self.stack[pos..])
+    fn read(&self, offset: u32) -> Result<u16, StackError> {
+        Ok(BigEndian::read_u16(&self.stack[self.local_ptr(offset, 0x2)?..]))
     }
 
-    // Writes a u16 as normal
-    fn write(&mut self, offset: u32, val: u16) {
-        let pos = (self.frame_ptr + self.local_pos() + offset) as usize;
-        NativeEndian::write_u16(&mut self.stack[pos..], val)
+    fn write(&mut self, offset: u32, val: u16) -> Result<(), StackError> {
+        let pos = self.local_ptr(offset, 0x2)?;
+        BigEndian::write_u16(&mut self.stack[pos..], val);
+        Ok(())
     }
 }
 
 
 impl Stack<i32> for GlulxStack {
-    fn push(&mut self, val: i32) {
-        let pos = self.stack.len();
-        for _ in 0x0..0x4 { self.stack.push(0) };
-        NativeEndian::write_i32(&mut self.stack[pos..], val);
+    fn push(&mut self, val: i32) -> Result<(), StackError> {
+        self.checked_push_u32(val as u32)
     }
 
-    fn pop(&mut self) -> i32 {
-        let pos = self.stack.len() - 0x4;
-        let ret = NativeEndian::read_i32(&self.stack[pos..]);
-        for _ in 0x0..0x4 { self.stack.pop(); };
-        ret
+    fn pop(&mut self) -> Result<i32, StackError> {
+        self.checked_pop_u32().map(|val| val as i32)
     }
 
-    fn peek(&self) -> i32 {
-        let pos = self.stack.len() - 0x4;
-        NativeEndian::read_i32(&self.stack[pos..])
+    fn peek(&self, depth: u32) -> Result<i32, StackError> {
+        self.peek_u32(depth).map(|val| val as i32)
     }
 
-    fn read(&self, offset: u32) -> i32 {
-        let pos = (self.frame_ptr + self.local_pos() + offset) as usize;
-        NativeEndian::read_i32(&self.stack[pos..])
+    fn read(&self, offset: u32) -> Result<i32, StackError> {
+        Ok(BigEndian::read_i32(&self.stack[self.local_ptr(offset, 0x4)?..]))
     }
 
-    fn write(&mut self, offset: u32, val: i32) {
-        let pos = (self.frame_ptr + self.local_pos() + offset) as usize;
-        NativeEndian::write_i32(&mut self.stack[pos..], val)
+    fn write(&mut self, offset: u32, val: i32) -> Result<(), StackError> {
+        let pos = self.local_ptr(offset, 0x4)?;
+        BigEndian::write_i32(&mut self.stack[pos..], val);
+        Ok(())
     }
 }
 
 
 impl Stack<u32> for GlulxStack {
-    fn push(&mut self, val: u32) {
-        let pos = self.stack.len();
-        for _ in 0x0..0x4 { self.stack.push(0) };
-        NativeEndian::write_u32(&mut self.stack[pos..], val);
+    fn push(&mut self, val: u32) -> Result<(), StackError> {
+        self.checked_push_u32(val)
     }
 
-    fn pop(&mut self) -> u32 {
-        let pos = self.stack.len() - 0x4;
-        let ret = NativeEndian::read_u32(&self.stack[pos..]);
-        for _ in 0x0..0x4 { self.stack.pop(); };
-        ret
+    fn pop(&mut self) -> Result<u32, StackError> {
+        self.checked_pop_u32()
     }
 
-    fn peek(&self) -> u32 {
-        let pos = self.stack.len() - 0x4;
-        NativeEndian::read_u32(&self.stack[pos..])
+    fn peek(&self, depth: u32) -> Result<u32, StackError> {
+        self.peek_u32(depth)
     }
 
-    fn read(&self, offset: u32) -> u32 {
-        let pos = (self.frame_ptr + self.local_pos() + offset) as usize;
-        NativeEndian::read_u32(&self.stack[pos..])
+    fn read(&self, offset: u32) -> Result<u32, StackError> {
+        Ok(BigEndian::read_u32(&self.stack[self.local_ptr(offset, 0x4)?..]))
     }
 
-    fn write(&mut self, offset: u32, val: u32) {
-        let pos = (self.frame_ptr + self.local_pos() + offset) as usize;
-        NativeEndian::write_u32(&mut self.stack[pos..], val)
+    fn write(&mut self, offset: u32, val: u32) -> Result<(), StackError> {
+        let pos = self.local_ptr(offset, 0x4)?;
+        BigEndian::write_u32(&mut self.stack[pos..], val);
+        Ok(())
     }
 }
 
 
 impl Stack<f32> for GlulxStack {
-    /// push a `f32` onto the stack.
-    fn push(&mut self, val: f32) {
-        let pos = self.stack.len();
-        for _ in 0x0..0x4 { self.stack.push(0) };
-        NativeEndian::write_f32(&mut self.stack[pos..], val);
+    fn push(&mut self, val: f32) -> Result<(), StackError> {
+        self.checked_push_u32(val.to_bits())
     }
 
-    /// pop a `f32` off the stack.
-    fn pop(&mut self) -> f32 {
-        let pos = self.stack.len() - 0x4;
-        let ret = NativeEndian::read_f32(&self.stack[pos..]);
-        for _ in 0x0..0x4 { self.stack.pop(); };
-        ret
+    fn pop(&mut self) -> Result<f32, StackError> {
+        self.checked_pop_u32().map(f32::from_bits)
     }
 
-    fn peek(&self) -> f32 {
-        let pos = self.stack.len() - 0x4;
-        NativeEndian::read_f32(&self.stack[pos..])
+    fn peek(&self, depth: u32) -> Result<f32, StackError> {
+        self.peek_u32(depth).map(f32::from_bits)
     }
 
-    fn read(&self, offset: u32) -> f32 {
-        let pos = (self.frame_ptr + self.local_pos() + offset) as usize;
-        NativeEndian::read_f32(&self.stack[pos..])
+    fn read(&self, offset: u32) -> Result<f32, StackError> {
+        Ok(BigEndian::read_f32(&self.stack[self.local_ptr(offset, 0x4)?..]))
+    }
+
+    fn write(&mut self, offset: u32, val: f32) -> Result<(), StackError> {
+        let pos = self.local_ptr(offset, 0x4)?;
+        BigEndian::write_f32(&mut self.stack[pos..], val);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{CallStub, Frame, GlulxStack, Local, Stack, StackError};
+
+    #[test]
+    fn call_frame_matches_spec_layout() {
+        let mut stack = GlulxStack::new(0x100);
+        stack.push_call_frame_c1(&[0x1, 0x3, 0x2, 0x6, 0x0, 0x0], &[])
+            .unwrap();
+
+        assert_eq!(stack.frame_len(), 0x8 + 0x8 + 0x10);
+        assert_eq!(stack.local_pos(), 0x10);
+        assert_eq!(&stack.stack[0x8..0x10], &[1, 3, 2, 6, 0, 0, 0, 0]);
+        assert_eq!(stack.stack.len(), 0x20);
+    }
+
+    #[test]
+    fn c1_args_fill_aligned_locals() {
+        let mut stack = GlulxStack::new(0x100);
+        stack.push_call_frame_c1(
+            &[0x1, 0x1, 0x4, 0x1, 0x2, 0x1, 0x0, 0x0],
+            &[0x1FF, 0xDEADBEEF, 0x12345, 0x99]).unwrap();
+
+        let byte: u8 = stack.read(0x0).unwrap();
+        let word: u32 = stack.read(0x4).unwrap();
+        let short: u16 = stack.read(0x8).unwrap();
+        assert_eq!((byte, word, short), (0xFF, 0xDEADBEEF, 0x2345));
+        let word: Result<u32, _> = stack.read(0xA);
+        assert_eq!(word, Err(StackError::InvalidLocal(0xA)));
+        assert_eq!(stack.write(0x1000, 0x1u8), Err(StackError::InvalidLocal(0x1000)));
+        assert_eq!(&stack.stack[0x10..0x18],
+            &[0xFF, 0, 0, 0, 0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(stack.value_count(), 0x0);
+        assert_eq!(stack.locals(), vec![
+            Local { offset: 0x0, size: 0x1, value: 0xFF },
+            Local { offset: 0x4, size: 0x4, value: 0xDEADBEEF },
+            Local { offset: 0x8, size: 0x2, value: 0x2345 },
+        ]);
+    }
+
+    #[test]
+    fn c0_args_pushed_with_count() {
+        let mut stack = GlulxStack::new(0x100);
+        stack.push_call_frame_c0(&[0x4, 0x2, 0x0, 0x0], &[0x1, 0x2, 0x3])
+            .unwrap();
+
+        assert_eq!(stack.value_count(), 0x4);
+        let values: Vec<u32> = (0x0..0x4).map(|i| stack.peek(i).unwrap())
+            .collect();
+        assert_eq!(values, vec![0x3, 0x1, 0x2, 0x3]);
+        assert_eq!(stack.values(), vec![0x3, 0x2, 0x1, 0x3]);
+    }
+
+    #[test]
+    fn small_values_use_full_slots() {
+        let mut stack = GlulxStack::new(0x100);
+        stack.push_call_frame_c1(&[0x0, 0x0], &[]).unwrap();
+        stack.push(0xABu8).unwrap();
+        stack.push(0x1234u16).unwrap();
+
+        assert_eq!(stack.value_count(), 0x2);
+        let short: u16 = stack.pop().unwrap();
+        let byte: u8 = stack.pop().unwrap();
+        assert_eq!((short, byte), (0x1234, 0xAB));
+        assert_eq!(stack.value_count(), 0x0);
+    }
+
+    #[test]
+    fn roll_matches_spec_example() {
+        let mut stack = GlulxStack::new(0x100);
+        stack.push_call_frame_c1(&[0x0, 0x0], &[]).unwrap();
+        for i in (0x0..0x9u32).rev() {
+            stack.push(i).unwrap();
+        }
+
+        stack.roll(0x5, 0x1).unwrap();
+        stack.roll(0x9, -0x3).unwrap();
+        assert_eq!(stack.values(), vec![5, 0, 4, 3, 2, 1, 8, 7, 6]);
+    }
+
+    #[test]
+    fn underflow_leaves_stack_unchanged() {
+        let mut stack = GlulxStack::new(0x100);
+        stack.push_call_frame_c1(&[0x0, 0x0], &[]).unwrap();
+        stack.push(0x1u32).unwrap();
+
+        assert_eq!(stack.roll(0x5, 0x1), Err(StackError::Underflow));
+        assert_eq!(stack.copy(0x2), Err(StackError::Underflow));
+        let value: Result<u32, _> = stack.peek(0x1);
+        assert_eq!(value, Err(StackError::Underflow));
+        assert_eq!(stack.pop_args(0x2), Err(StackError::Underflow));
+        assert_eq!(stack.values(), vec![0x1]);
+        let value: Result<u32, _> = stack.pop();
+        assert_eq!(value, Ok(0x1));
+        let value: Result<u32, _> = stack.pop();
+        assert_eq!(value, Err(StackError::Underflow));
+        stack.push(0x1u32).unwrap();
+
+        assert_eq!(stack.push_call_frame_c1(&[0x4, 0x1, 0x3, 0x1, 0x0, 0x0], &[]),
+            Err(StackError::InvalidLocalType(0x3)));
+        assert_eq!(stack.values(), vec![0x1]);
+    }
+
+    #[test]
+    fn call_stub_round_trip() {
+        let mut stack = GlulxStack::new(0x100);
+        stack.push_call_frame_c1(&[0x4, 0x1, 0x0, 0x0], &[]).unwrap();
+        stack.push_call_stub(0x2, 0x4, 0x1234).unwrap();
+        stack.push_call_frame_c1(&[0x0, 0x0], &[]).unwrap();
+
+        stack.pop_call_frame();
+        assert_eq!(stack.pop_call_stub(), (0x2, 0x4, 0x1234));
+        assert_eq!(stack.frame_ptr, 0x0);
+        assert_eq!(stack.stack.len(), 0x10);
+    }
+
+    #[test]
+    fn overflow_leaves_stack_unchanged() {
+        let mut stack = GlulxStack::new(0x1C);
+        stack.push_call_frame_c1(&[0x4, 0x2, 0x0, 0x0], &[]).unwrap();
+        stack.push(0x1u32).unwrap();
+        stack.push(0x2u32).unwrap();
+
+        assert_eq!(stack.push(0x3u32), Err(StackError::Overflow));
+        assert_eq!(stack.push_call_stub(0x0, 0x0, 0x0), Err(StackError::Overflow));
+        assert_eq!(stack.copy(0x2), Err(StackError::Overflow));
+        assert_eq!(stack.stack.len(), 0x1C);
+
+        let mut stack = GlulxStack::new(0x20);
+        stack.push_call_frame_c0(&[0x0, 0x0], &[]).unwrap();
+        stack.push_call_stub(0x0, 0x0, 0x0).unwrap();
+        assert_eq!(
+            stack.push_call_frame_c1(&[0x4, 0x1, 0x0, 0x0], &[]),
+            Err(StackError::Overflow));
+        assert_eq!(stack.frame_ptr, 0x0);
+        assert_eq!(stack.call_depth(), 0x1);
+    }
+
+    #[test]
+    fn call_depth_follows_call_stubs() {
+        let mut stack = GlulxStack::new(0x100);
+        assert_eq!(stack.call_depth(), 0x0);
+        stack.push_call_frame_c1(&[0x0, 0x0], &[]).unwrap();
+        stack.push(0x7u32).unwrap();
+        for depth in 0x2..0x5 {
+            stack.push_call_stub(0x0, 0x0, 0x0).unwrap();
+            stack.push_call_frame_c0(&[0x4, 0x1, 0x0, 0x0], &[0x1]).unwrap();
+            assert_eq!(stack.call_depth(), depth);
+        }
     }
 
-    fn write(&mut self, offset: u32, val: f32) {
-        let pos = (self.frame_ptr + self.local_pos() + offset) as usize;
-        NativeEndian::write_f32(&mut self.stack[pos..], val)
+    #[test]
+    fn backtrace_walks_call_stubs() {
+        let mut stack = GlulxStack::new(0x100);
+        stack.push_call_frame_c1(&[0x4, 0x1, 0x0, 0x0], &[0x9]).unwrap();
+        stack.set_function(0x40);
+        stack.push(0x7u32).unwrap();
+        stack.push_call_stub(0x2, 0x0, 0x1234).unwrap();
+        stack.push_call_frame_c0(&[0x2, 0x1, 0x0, 0x0], &[0x1]).unwrap();
+        stack.set_function(0x80);
+
+        assert_eq!(stack.backtrace(), vec![
+            Frame {
+                function: Some(0x80),
+                stub: Some(CallStub {
+                    dest_type: 0x2,
+                    dest_addr: 0x0,
+                    program_counter: 0x1234,
+                }),
+                locals: vec![Local { offset: 0x0, size: 0x2, value: 0x0 }],
+                value_count: 0x2,
+            },
+            Frame {
+                function: Some(0x40),
+                stub: None,
+                locals: vec![Local { offset: 0x0, size: 0x4, value: 0x9 }],
+                value_count: 0x1,
+            },
+        ]);
+
+        stack.pop_call_frame();
+        stack.pop_call_stub();
+        assert_eq!(stack.backtrace()[0x0].function, Some(0x40));
+        assert_eq!(stack.functions, vec![(0x0, 0x40)]);
     }
 }
@@ -0,0 +1,167 @@
+//! # Stories
+//!
+//! A `Story` is a story file which has been validated and laid out as the
+//! initial contents of memory, once. Any number of machines can then be
+//! made from the same story, shared as an `Arc<Story>`. Each machine
+//! shares the pages of the story until it writes them, so it only owns
+//! the RAM it has changed, and its ROM is read from the story.
+//!
+//! ```
+//! # use glulx::{Assembler, Arg};
+//! use std::sync::Arc;
+//!
+//! use glulx::{Glulx, Story};
+//! # let mut asm = Assembler::new();
+//! # let main = asm.label();
+//! # asm.start(main).function(main, 0xC1, &[]).op("return", &[Arg::Zero]);
+//! # let bytes = asm.finish().unwrap();
+//!
+//! let story = Arc::new(Story::from_rom(bytes).unwrap());
+//! let sessions: Vec<Glulx> = (0..0x4)
+//!     .map(|_| Glulx::from_story(story.clone()))
+//!     .collect();
+//! ```
+
+use std::ops::Range;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use header::{
+    GlulxHeader,
+    Version,
+};
+
+use pages::Pages;
+
+
+/// A validated story file, which is never changed.
+pub struct Story {
+    header: GlulxHeader,
+
+    /// The initial contents of memory, up to ENDMEM.
+    pages: Pages,
+}
+
+
+impl Story {
+
+    /// Validates a story file, and lays it out as the initial contents of
+    /// memory.
+    pub fn from_rom(rom: Vec<u8>) -> Result<Story, String> {
+        // Validate magic number.
+        let header = GlulxHeader::from_bytes(&rom)?;
+
+        // Validate glulx version.
+        if header.version < Version::MIN_SUPPORTED {
+            return Err(format!("executable code glulx version is less than {}",
+                Version::MIN_SUPPORTED));
+        } else if !header.is_supported() {
+            return Err(format!("executable code glulx version is greater \
+                than {}.{}.x", Version::MAX_SUPPORTED.major,
+                Version::MAX_SUPPORTED.minor));
+        }
+
+        // Validate ramstart value.
+        let ramstart = header.ramstart;
+        if ramstart < 0x100 {
+            return Err("ramstart is less than 0x100 bytes".to_string());
+        } else if ramstart % 0x100 != 0 {
+            return Err("ramstart is misaligned".to_string());
+        }
+
+        // Validate extstart value.
+        let extstart = header.extstart;
+        if extstart < ramstart {
+            return Err("extstart is less than ramstart".to_string());
+        } else if extstart % 0x100 != 0 {
+            return Err("extstart is misaligned".to_string());
+        }
+
+        // Validate endmem value.
+        let endmem = header.endmem;
+        if endmem < extstart {
+            return Err("endmem is less than extstart".to_string());
+        } else if endmem % 0x100 != 0 {
+            return Err("endmem is misaligned".to_string());
+        }
+
+        // Validate stack size.
+        let stack_size = header.stack_size;
+        if stack_size % 0x100 != 0 {
+            return Err("stack size is misaligned".to_string());
+        }
+
+        if (endmem as usize) < rom.len() {
+            return Err("rom size is not correct".to_string());
+        }
+
+        let checksum = header.checksum;
+        let sum = {
+            let mut sum = 0u32;
+            for i in 0..rom.len()/4 {
+                sum = sum.wrapping_add(BigEndian::read_u32(&rom[i*4..]));
+            }
+            sum.wrapping_sub(checksum)
+        };
+        if checksum != sum {
+            return Err("rom checksum is not correct".to_string());
+        }
+
+
+        let mut pages = Pages::from_bytes(&rom);
+        pages.resize(endmem);
+        Ok(Story { header, pages })
+    }
+
+    /// The header of the story.
+    pub fn header(&self) -> &GlulxHeader {
+        &self.header
+    }
+
+    /// A copy of the initial contents of the given range of memory.
+    pub fn bytes(&self, range: Range<u32>) -> Vec<u8> {
+        self.pages.bytes(range)
+    }
+
+    /// The initial contents of memory, as the pages which the memory of
+    /// each machine shares.
+    pub fn pages(&self) -> &Pages {
+        &self.pages
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use assembler::Arg::*;
+    use assembler::Assembler;
+    use interpreter::Glulx;
+
+    use super::Story;
+
+    #[test]
+    fn machines_share_a_story() {
+        let mut asm = Assembler::new();
+        let (main, ram) = (asm.label(), asm.label());
+        asm.start(main)
+            .ram(ram, &[0x0; 0x10])
+            .function(main, 0xC1, &[])
+            .op("copy", &[Const(7), Ram(0x0)])
+            .op("return", &[Zero]);
+        let story = Arc::new(Story::from_rom(asm.finish().unwrap()).unwrap());
+        let ramstart = story.header().ramstart;
+
+        let mut first = Glulx::from_story(story.clone());
+        let second = Glulx::from_story(story.clone());
+        first.run().unwrap();
+        let read = |glulx: &Glulx| glulx.memory().bytes(ramstart..ramstart + 0x4);
+        assert_eq!(read(&first), [0x0, 0x0, 0x0, 0x7]);
+        assert_eq!(read(&second), [0x0; 0x4]);
+        assert_eq!(second.memory().pages().shared_pages(story.pages()),
+            story.pages().len().div_ceil(::pages::PAGE_SIZE) as usize);
+
+        assert!(Story::from_rom(b"Glul".to_vec()).is_err());
+    }
+}
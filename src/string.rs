@@ -0,0 +1,228 @@
+//! # Glulx strings
+//!
+//! Glulx strings begin with a type byte:
+//!
+//! * `0xE0` -- An unencoded string of Latin-1 bytes, terminated by a zero
+//!   byte
+//! * `0xE1` -- A Huffman compressed string, decoded with the string
+//!   decoding table
+//! * `0xE2` -- An unencoded string of 4 byte Unicode values, after three
+//!   bytes of padding, terminated by a zero value
+//!
+//!
+//! ## String Decoding Table
+//!
+//! The table is a binary tree of nodes. Compressed strings are read as a
+//! stream of bits from the low bit of each byte, starting at the root
+//! node and following a branch for each bit until a leaf node is found.
+//! Leaf nodes print text, call functions, or end the string.
+
+use error::GlulxError;
+
+use memory::{
+    GlulxMemory,
+    Memory,
+};
+
+
+/// The largest number of nodes decoded from a single compressed string,
+/// which guards against invalid tables looping forever.
+const MAX_NODES: usize = 0x10000;
+
+
+/// The largest depth of indirect strings printed by `decode_text`.
+const MAX_DEPTH: u32 = 0x8;
+
+
+/// A node of the string decoding table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StringNode {
+
+    /// Continue with the left node on a `0` bit, and the right on a `1`.
+    Branch(u32, u32),
+
+    /// End the string.
+    Terminator,
+
+    /// Print a single Latin-1 character.
+    Char(u8),
+
+    /// Print the zero terminated Latin-1 string at the contained address.
+    CString(u32),
+
+    /// Print a single Unicode character.
+    UniChar(u32),
+
+    /// Print the zero terminated Unicode string at the contained address.
+    UniString(u32),
+
+    /// Print the string or call the function at the contained address,
+    /// with the given arguments. When double is set, the address holds
+    /// the address of the string or function.
+    Indirect {
+        address: u32,
+        double: bool,
+        args: Vec<u32>,
+    },
+}
+
+
+/// Checks that `len` bytes at `ptr` lie within memory.
+fn check(memory: &GlulxMemory, ptr: u32, len: u32) -> Result<u32, GlulxError> {
+    match ptr.checked_add(len) {
+        Some(end) if end <= memory.get_mem_size() => Ok(ptr),
+        _ => Err(GlulxError::InvalidAddress { address: ptr }),
+    }
+}
+
+
+/// Reads the string decoding table node at the given address.
+pub fn read_node(memory: &GlulxMemory, address: u32)
+        -> Result<StringNode, GlulxError> {
+    let node_type: u8 = memory.read(check(memory, address, 0x1)?);
+    let data = address + 0x1;
+
+    let node = match node_type {
+        0x00 => StringNode::Branch(
+            memory.read(check(memory, data, 0x8)?),
+            memory.read(data + 0x4)),
+        0x01 => StringNode::Terminator,
+        0x02 => StringNode::Char(memory.read(check(memory, data, 0x1)?)),
+        0x03 => StringNode::CString(data),
+        0x04 => StringNode::UniChar(memory.read(check(memory, data, 0x4)?)),
+        0x05 => StringNode::UniString(data),
+        0x08 | 0x09 => StringNode::Indirect {
+            address: memory.read(check(memory, data, 0x4)?),
+            double: node_type == 0x09,
+            args: vec![],
+        },
+        0x0A | 0x0B => {
+            let count: u32 = memory.read(check(memory, data + 0x4, 0x4)?);
+            check(memory, data + 0x8, count.saturating_mul(0x4))?;
+            StringNode::Indirect {
+                address: memory.read(data),
+                double: node_type == 0x0B,
+                args: (0x0..count)
+                    .map(|i| memory.read(data + 0x8 + i * 0x4))
+                    .collect(),
+            }
+        },
+        _ => return Err(GlulxError::InvalidStringNode { address }),
+    };
+    Ok(node)
+}
+
+
+/// Reads the zero terminated Latin-1 string at the given address.
+pub fn read_c_string(memory: &GlulxMemory, address: u32)
+        -> Result<String, GlulxError> {
+    let mut text = String::new();
+    let mut ptr = address;
+    loop {
+        let ch: u8 = memory.read(check(memory, ptr, 0x1)?);
+        if ch == 0x0 {
+            return Ok(text);
+        }
+        text.push(ch as char);
+        ptr += 0x1;
+    }
+}
+
+
+/// Reads the zero terminated string of Unicode values at the given
+/// address. Invalid values are replaced with `U+FFFD`.
+pub fn read_unicode_string(memory: &GlulxMemory, address: u32)
+        -> Result<String, GlulxError> {
+    let mut text = String::new();
+    let mut ptr = address;
+    loop {
+        let ch: u32 = memory.read(check(memory, ptr, 0x4)?);
+        if ch == 0x0 {
+            return Ok(text);
+        }
+        text.push(::std::char::from_u32(ch).unwrap_or('\u{FFFD}'));
+        ptr += 0x4;
+    }
+}
+
+
+/// Decodes the text of the string at the given address, using the given
+/// string decoding table for compressed strings. Indirect references to
+/// strings are printed in place, while function calls are shown as
+/// `[call 0x...]`.
+pub fn decode_text(memory: &GlulxMemory, address: u32, table: u32)
+        -> Result<String, GlulxError> {
+    let mut text = String::new();
+    decode_into(memory, address, table, 0x0, &mut text)?;
+    Ok(text)
+}
+
+
+fn decode_into(memory: &GlulxMemory,
+        address: u32,
+        table: u32,
+        depth: u32,
+        text: &mut String) -> Result<(), GlulxError> {
+    let string_type: u8 = memory.read(check(memory, address, 0x1)?);
+    match string_type {
+        0xE0 => text.push_str(&read_c_string(memory, address + 0x1)?),
+        0xE1 => decode_compressed(memory, address, table, depth, text)?,
+        0xE2 => text.push_str(&read_unicode_string(memory, address + 0x4)?),
+        _ => return Err(GlulxError::InvalidString { address }),
+    }
+    Ok(())
+}
+
+
+fn decode_compressed(memory: &GlulxMemory,
+        address: u32,
+        table: u32,
+        depth: u32,
+        text: &mut String) -> Result<(), GlulxError> {
+    if table == 0x0 {
+        return Err(GlulxError::InvalidString { address });
+    }
+    let root: u32 = memory.read(check(memory, table + 0x8, 0x4)?);
+
+    let mut ptr = address + 0x1;
+    let mut bit = 0x0;
+    for _ in 0x0..MAX_NODES {
+        let mut node = read_node(memory, root)?;
+        while let StringNode::Branch(left, right) = node {
+            let byte: u8 = memory.read(check(memory, ptr, 0x1)?);
+            let next = if byte & (0x1 << bit) == 0x0 { left } else { right };
+            bit += 0x1;
+            if bit == 0x8 {
+                bit = 0x0;
+                ptr += 0x1;
+            }
+            node = read_node(memory, next)?;
+        }
+
+        match node {
+            StringNode::Branch(..) => unreachable!(),
+            StringNode::Terminator => return Ok(()),
+            StringNode::Char(ch) => text.push(ch as char),
+            StringNode::CString(ptr) =>
+                text.push_str(&read_c_string(memory, ptr)?),
+            StringNode::UniChar(ch) =>
+                text.push(::std::char::from_u32(ch).unwrap_or('\u{FFFD}')),
+            StringNode::UniString(ptr) =>
+                text.push_str(&read_unicode_string(memory, ptr)?),
+            StringNode::Indirect { address, double, .. } => {
+                let address = if double {
+                    memory.read(check(memory, address, 0x4)?)
+                } else {
+                    address
+                };
+                let object_type: u8 = memory.read(check(memory, address, 0x1)?);
+                match object_type {
+                    0xE0..=0xE2 if depth < MAX_DEPTH =>
+                        decode_into(memory, address, table, depth + 0x1, text)?,
+                    _ => text.push_str(&format!("[call {:#X}]", address)),
+                }
+            },
+        }
+    }
+    Err(GlulxError::InvalidString { address })
+}
@@ -0,0 +1,283 @@
+//! # Execution tracing
+//!
+//! A tracer installed on a machine sees a record of every instruction
+//! executed, with the values of its load operands, after they are
+//! resolved, and the values it stored. Records are made in execution
+//! order, including for an instruction which fails.
+//!
+//! Two sinks are provided. `TextTracer` writes one line per instruction,
+//! such as `0000002A add 3 4 -> 7`, with every number in hex. Two text
+//! traces can be compared with `diff`.
+//!
+//! `BinaryTracer` writes the compact format below, which `read_trace`
+//! reads back. Other interpreters can produce the same format to be
+//! compared with this one. All numbers are big-endian.
+//!
+//! * Magic -- The bytes `GlTr`
+//! * Version -- The format version, `0x1` (4 bytes)
+//! * Records -- Until the end of the file, each made of:
+//!   * PC -- The address of the instruction (4 bytes)
+//!   * Opcode -- The opcode number (4 bytes)
+//!   * Load Count -- The number of loaded values (1 byte)
+//!   * Store Count -- The number of stored values (1 byte)
+//!   * Values -- The loaded values, then the stored values (4 bytes
+//!     each)
+
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use instruction::opcode;
+
+
+/// The bytes at the start of a binary trace.
+const MAGIC: &[u8; 0x4] = b"GlTr";
+
+
+/// The version of the binary trace format.
+const VERSION: u32 = 0x1;
+
+
+/// An instruction which was executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+
+    /// The address of the instruction.
+    pub program_counter: u32,
+
+    /// The opcode number of the instruction.
+    pub opcode: u32,
+
+    /// The values of the load operands, in order. Floating point values
+    /// are given by their bits.
+    pub loads: Vec<u32>,
+
+    /// The values stored by the instruction, in order, including values
+    /// which were discarded and the results of returns.
+    pub stores: Vec<u32>,
+}
+
+
+/// Receives a record of each instruction a machine executes.
+pub trait Tracer {
+
+    /// Called after each instruction is executed.
+    fn trace(&mut self, record: &TraceRecord);
+}
+
+
+/// Writes a line of text for each instruction.
+pub struct TextTracer<W> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+
+impl<W: Write> TextTracer<W> {
+
+    /// Creates a tracer writing to the given writer.
+    pub fn new(writer: W) -> TextTracer<W> {
+        TextTracer { writer, error: None }
+    }
+
+    /// Flushes the trace, returning the writer or the first error met
+    /// while writing.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn trace(&mut self, record: &TraceRecord) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let mut line = format!("{:08X} ", record.program_counter);
+        match opcode(record.opcode) {
+            Some(opcode) => line.push_str(opcode.name),
+            None => line.push_str(&format!("{:#X}", record.opcode)),
+        }
+        for value in &record.loads {
+            line.push_str(&format!(" {:X}", value));
+        }
+        if !record.stores.is_empty() {
+            line.push_str(" ->");
+            for value in &record.stores {
+                line.push_str(&format!(" {:X}", value));
+            }
+        }
+        if let Err(error) = writeln!(self.writer, "{}", line) {
+            self.error = Some(error);
+        }
+    }
+}
+
+
+/// Writes a binary record for each instruction.
+pub struct BinaryTracer<W> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+
+impl<W: Write> BinaryTracer<W> {
+
+    /// Creates a tracer writing to the given writer, and writes the
+    /// header of the trace.
+    pub fn new(mut writer: W) -> io::Result<BinaryTracer<W>> {
+        let mut header = [0x0; 0x8];
+        header[..0x4].copy_from_slice(MAGIC);
+        BigEndian::write_u32(&mut header[0x4..], VERSION);
+        writer.write_all(&header)?;
+        Ok(BinaryTracer { writer, error: None })
+    }
+
+    /// Flushes the trace, returning the writer or the first error met
+    /// while writing.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+
+impl<W: Write> Tracer for BinaryTracer<W> {
+    fn trace(&mut self, record: &TraceRecord) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let values = record.loads.len() + record.stores.len();
+        let mut bytes = vec![0x0; 0xA + values * 0x4];
+        BigEndian::write_u32(&mut bytes, record.program_counter);
+        BigEndian::write_u32(&mut bytes[0x4..], record.opcode);
+        bytes[0x8] = record.loads.len() as u8;
+        bytes[0x9] = record.stores.len() as u8;
+        let all = record.loads.iter().chain(&record.stores);
+        for (index, &value) in all.enumerate() {
+            BigEndian::write_u32(&mut bytes[0xA + index * 0x4..], value);
+        }
+        if let Err(error) = self.writer.write_all(&bytes) {
+            self.error = Some(error);
+        }
+    }
+}
+
+
+/// Reads a trace written by `BinaryTracer`.
+pub fn read_trace<R: Read>(mut reader: R) -> io::Result<Vec<TraceRecord>> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    if bytes.len() < 0x8 || &bytes[..0x4] != MAGIC {
+        return Err(invalid("not a glulx trace"));
+    }
+    if BigEndian::read_u32(&bytes[0x4..]) != VERSION {
+        return Err(invalid("unsupported trace version"));
+    }
+
+    let mut records = vec![];
+    let mut pos = 0x8;
+    while pos < bytes.len() {
+        if pos + 0xA > bytes.len() {
+            return Err(invalid("truncated trace record"));
+        }
+        let (loads, stores) = (bytes[pos + 0x8] as usize,
+            bytes[pos + 0x9] as usize);
+        let end = pos + 0xA + (loads + stores) * 0x4;
+        if end > bytes.len() {
+            return Err(invalid("truncated trace record"));
+        }
+        let mut values = bytes[pos + 0xA..end].chunks(0x4)
+            .map(BigEndian::read_u32);
+        records.push(TraceRecord {
+            program_counter: BigEndian::read_u32(&bytes[pos..]),
+            opcode: BigEndian::read_u32(&bytes[pos + 0x4..]),
+            loads: values.by_ref().take(loads).collect(),
+            stores: values.collect(),
+        });
+        pos = end;
+    }
+    Ok(records)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use assembler::Arg::*;
+    use assembler::Assembler;
+    use interpreter::Glulx;
+
+    use super::{read_trace, BinaryTracer, TextTracer, TraceRecord, Tracer};
+
+    struct Records(Arc<Mutex<Vec<TraceRecord>>>);
+
+    impl Tracer for Records {
+        fn trace(&mut self, record: &TraceRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    fn records() -> Vec<TraceRecord> {
+        let mut asm = Assembler::new();
+        let main = asm.label();
+        asm.start(main)
+            .function(main, 0xC1, &[(0x4, 0x1)])
+            .op("add", &[Const(3), Const(4), Local(0x0)])
+            .op("copy", &[Local(0x0), Stack])
+            .op("return", &[Stack]);
+        let mut glulx = Glulx::from_rom(asm.finish().unwrap()).unwrap();
+
+        let records = Arc::new(Mutex::new(vec![]));
+        glulx.set_tracer(Box::new(Records(records.clone())));
+        glulx.run().unwrap();
+        let records = records.lock().unwrap().clone();
+        records
+    }
+
+    #[test]
+    fn records_loads_and_stores() {
+        let records = records();
+        assert_eq!(records.len(), 0x3);
+        assert_eq!(records[0x0], TraceRecord {
+            program_counter: 0x29,
+            opcode: 0x10,
+            loads: vec![0x3, 0x4],
+            stores: vec![0x7],
+        });
+        assert_eq!(records[0x1].loads, vec![0x7]);
+        assert_eq!(records[0x2].stores, vec![]);
+    }
+
+    #[test]
+    fn writes_text_and_binary_traces() {
+        let records = records();
+
+        let mut text = TextTracer::new(vec![]);
+        let mut binary = BinaryTracer::new(vec![]).unwrap();
+        for record in &records {
+            text.trace(record);
+            binary.trace(record);
+        }
+
+        let text = String::from_utf8(text.finish().unwrap()).unwrap();
+        assert_eq!(text.lines().next(), Some("00000029 add 3 4 -> 7"));
+        assert_eq!(text.lines().nth(0x2), Some("00000032 return 7"));
+
+        let binary = binary.finish().unwrap();
+        assert_eq!(read_trace(&binary[..]).unwrap(), records);
+        assert!(read_trace(&binary[..binary.len() - 0x1]).is_err());
+    }
+}
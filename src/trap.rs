@@ -0,0 +1,32 @@
+//! # Debug traps
+//!
+//! The `debugtrap` opcode stops the machine for a debugger. A host can
+//! instead install a handler, which is called with the opcode's argument
+//! and the machine itself, and decides how execution goes on. Story files
+//! written as tests can use this to make assertions about their own
+//! state.
+//!
+//! Without a handler, a debug trap aborts execution, as it does in
+//! interpreters without a debugger.
+
+use interpreter::Glulx;
+
+
+/// What the machine does after a debug trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapAction {
+
+    /// Continue with the next instruction.
+    Continue,
+
+    /// Pause execution after the `debugtrap` instruction.
+    Pause,
+
+    /// Stop the machine with `GlulxError::DebugTrap`.
+    Abort,
+}
+
+
+/// A handler for debug traps, called with the argument of the
+/// `debugtrap` opcode.
+pub type TrapHandler = Box<dyn FnMut(u32, &mut Glulx) -> TrapAction + Send>;
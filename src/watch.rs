@@ -0,0 +1,99 @@
+//! # Memory watchpoints
+//!
+//! A watchpoint covers a range of memory addresses. Whenever an access of
+//! the watched kind touches any byte of the range, memory records a hit,
+//! and the machine reports it once the accessing instruction finishes.
+//!
+//! Every access through `Memory<T>`, including `ram_read` and
+//! `ram_write`, is watched, along with the ranges written by `mzero` and
+//! `mcopy`. Instruction fetches are not reads of memory, and never hit a
+//! watchpoint.
+
+use std::fmt;
+use std::ops::Range;
+
+
+/// The kinds of memory access a watchpoint covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+
+    /// The watchpoint is hit by reads.
+    Read,
+
+    /// The watchpoint is hit by writes.
+    Write,
+
+    /// The watchpoint is hit by reads and writes.
+    ReadWrite,
+}
+
+
+impl Access {
+
+    /// Whether this kind of watchpoint is hit by the given access.
+    pub fn covers(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+
+/// A watched range of memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+
+    /// The identifier of the watchpoint.
+    pub id: u32,
+
+    /// The watched addresses.
+    pub range: Range<u32>,
+
+    /// The kinds of access the watchpoint covers.
+    pub access: Access,
+}
+
+
+/// An access which touched a watched range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+
+    /// The identifier of the watchpoint.
+    pub watchpoint: u32,
+
+    /// Whether the memory was read or written.
+    pub access: Access,
+
+    /// The first address of the access, which may start before the
+    /// watched range.
+    pub address: u32,
+
+    /// The bytes of the access before it happened.
+    pub old: Vec<u8>,
+
+    /// The bytes of the access after it happened. For reads, these are
+    /// the same as the old bytes.
+    pub new: Vec<u8>,
+
+    /// The address of the instruction which made the access.
+    pub program_counter: u32,
+}
+
+
+/// What the machine does when a watchpoint is hit.
+pub enum WatchAction {
+
+    /// Pause execution after the accessing instruction.
+    Pause,
+
+    /// Call the contained function, and continue execution.
+    Callback(Box<dyn FnMut(&WatchEvent) + Send>),
+}
+
+
+impl fmt::Debug for WatchAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WatchAction::Pause => write!(f, "Pause"),
+            WatchAction::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}